@@ -0,0 +1,138 @@
+//! Measures how many rays per second `Scene::intersect` traces through the spheres scene and a large mesh,
+//! with each BVH layout.
+//! Run with `cargo bench --bench traversal`.
+
+use std::hint::black_box;
+use std::time::Instant;
+use nalgebra::{Isometry3, Point3, Vector3};
+use reflection::camera::Camera;
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::ray::Ray;
+use reflection::scene::bvh::BVHLayout;
+use reflection::scene::Scene;
+use reflection::world::World;
+use reflection::Float;
+
+const WIDTH: u32 = 640;
+const HEIGHT: u32 = 360;
+const ROUNDS: u32 = 5;
+
+/// The same layout as the spheres scene of the binary.
+fn build_world<R: Randomness>(rng: &mut R) -> World {
+    let mut world = World::new();
+    let grey = world.add_solid_albedo(Vector3::new(0.5, 0.5, 0.5));
+    let material = world.add_lambertian_material(grey);
+
+    let ground = world.add_sphere(1000.0);
+    world.add_object(ground, material, Isometry3::translation(0.0, -1000.0, 0.0));
+
+    let small_sphere = world.add_sphere(0.2);
+    for a in -11..11 {
+        for b in -11..11 {
+            let center = Point3::new(a as Float + 0.9 * rng.float(), 0.2, b as Float + 0.9 * rng.float());
+
+            if (center - Point3::new(4.0, 0.2, 0.0)).magnitude() > 0.9 {
+                world.add_object(small_sphere, material, Isometry3::translation(center.x, center.y, center.z));
+            }
+        }
+    }
+
+    let sphere = world.add_sphere(1.0);
+    world.add_object(sphere, material, Isometry3::translation(0.0, 1.0, 0.0));
+    world.add_object(sphere, material, Isometry3::translation(-4.0, 1.0, 0.0));
+    world.add_object(sphere, material, Isometry3::translation(4.0, 1.0, 0.0));
+
+    world
+}
+
+/// A wavy terrain of many small triangles, in view of the same camera.
+fn build_terrain_world() -> World {
+    const SIZE: usize = 256;
+
+    let mut world = World::new();
+    let grey = world.add_solid_albedo(Vector3::new(0.5, 0.5, 0.5));
+    let material = world.add_lambertian_material(grey);
+
+    let positions = (0..=SIZE)
+        .flat_map(|z| (0..=SIZE).map(move |x| {
+            let (x, z) = (x as Float / SIZE as Float * 30.0 - 15.0, z as Float / SIZE as Float * 30.0 - 15.0);
+            Point3::new(x, (x * 0.7).sin() * (z * 0.5).cos(), z)
+        }))
+        .collect();
+    let indices = (0..SIZE)
+        .flat_map(|z| (0..SIZE).flat_map(move |x| {
+            let i = z * (SIZE + 1) + x;
+            [[i, i + SIZE + 1, i + 1], [i + 1, i + SIZE + 1, i + SIZE + 2]]
+        }))
+        .collect();
+
+    let terrain = world.add_triangle_mesh(positions, indices, None, None);
+    world.add_object(terrain, material, Isometry3::identity());
+
+    world
+}
+
+/// Camera rays, and rays leaving the surfaces they hit in random directions, like the second bounce of a path.
+fn generate_rays<R: Randomness>(scene: &Scene, rng: &mut R) -> (Vec<Ray>, Vec<Ray>) {
+    let camera = Camera::new(
+        Point3::new(13.0, 2.0, 3.0) * 2.0,
+        Point3::origin(),
+        Vector3::y(),
+        (20.0 as Float).to_radians(),
+        WIDTH as Float / HEIGHT as Float,
+    );
+
+    let mut primary = Vec::new();
+    let mut secondary = Vec::new();
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let s = (x as Float + rng.float()) / WIDTH as Float;
+            let t = (y as Float + rng.float()) / HEIGHT as Float;
+            let ray = camera.get_ray(s, t);
+
+            if let Some(int) = scene.intersect(&ray, 0.001, Float::INFINITY) {
+                let mut direction = rng.unit_vector();
+                if direction.dot(&int.normal) < 0.0 {
+                    direction = -direction;
+                }
+                secondary.push(Ray::new(int.point, direction));
+            }
+            primary.push(ray);
+        }
+    }
+
+    (primary, secondary)
+}
+
+fn measure(name: &str, scene: &Scene, rays: &[Ray]) {
+    let start = Instant::now();
+    let mut hits = 0;
+
+    for _ in 0..ROUNDS {
+        for ray in rays {
+            if black_box(scene.intersect(ray, 0.001, Float::INFINITY)).is_some() {
+                hits += 1;
+            }
+        }
+    }
+
+    let took = start.elapsed().as_secs_f64();
+    let rays_per_second = (rays.len() as f64 * ROUNDS as f64) / took;
+    println!("{:<26} {:>8} rays, {:>6.2} Mrays/s, {} hits", name, rays.len(), rays_per_second / 1.0e6, hits / ROUNDS);
+}
+
+fn main() {
+    let mut rng = DefaultRandomness::new(100);
+    let worlds = [("spheres", build_world(&mut rng)), ("terrain", build_terrain_world())];
+
+    for (world_name, world) in &worlds {
+        let mut scene = world.build_scene(&mut rng);
+        let (primary, secondary) = generate_rays(&scene, &mut rng);
+
+        for (name, layout) in [("binary", BVHLayout::Binary), ("wide", BVHLayout::Wide)] {
+            scene.set_bvh_layout(layout);
+            measure(&format!("{} {} primary", world_name, name), &scene, &primary);
+            measure(&format!("{} {} secondary", world_name, name), &scene, &secondary);
+        }
+    }
+}
@@ -23,6 +23,10 @@ impl AABB {
             max
         }
     }
+    /// A box containing nothing, which merging with another box leaves unchanged.
+    pub fn empty() -> Self {
+        Self::new_unchecked(Point3::from(Vector3::repeat(Float::INFINITY)), Point3::from(Vector3::repeat(-Float::INFINITY)))
+    }
     pub fn from_points(points: &[Point3<Float>]) -> Self {
         let mut min = points[0];
         let mut max = points[0];
@@ -37,7 +41,31 @@ impl AABB {
         Self::new(min, max)
     }
     pub fn merged(a: AABB, b: AABB) -> Self {
-        Self::from_points(&[a.min, a.max, b.min, b.max])
+        Self::new_unchecked(a.min.inf(&b.min), a.max.sup(&b.max))
+    }
+
+    /// Extends the box to contain `p`.
+    pub fn grow(&mut self, p: &Point3<Float>) {
+        for a in 0..3 {
+            self.min[a] = self.min[a].min(p[a]);
+            self.max[a] = self.max[a].max(p[a]);
+        }
+    }
+
+    /// Grows the box around its center to be at least `thickness` thick along every axis.
+    /// Flat primitives use this to get boxes with a volume, which traversal doesn't have to treat specially.
+    pub fn padded(&self, thickness: Float) -> Self {
+        let mut aabb = *self;
+
+        for a in 0..3 {
+            let missing = thickness - (aabb.max[a] - aabb.min[a]);
+            if missing > 0.0 {
+                aabb.min[a] -= missing * 0.5;
+                aabb.max[a] += missing * 0.5;
+            }
+        }
+
+        aabb
     }
 
     pub fn is_flat(&self) -> bool {
@@ -84,7 +112,9 @@ impl AABB {
             if inv_d < 0.0 {
                 swap(&mut t0, &mut t1);
             }
-
+            // Rounding could otherwise put points on the surface of the box, like the vertices of a triangle, just outside of it.
+            // This covers the error of both distances, like the conservative test of Physically Based Rendering.
+            t1 *= 1.0 + 6.0 * Float::EPSILON;
 
             t_min = if t0 > t_min { t0 } else { t_min };
             t_max = if t1 < t_max { t1 } else { t_max };
@@ -96,4 +126,21 @@ impl AABB {
 
         true
     }
+    /// The part of `t_min..=t_max` in which the ray is inside of the box, if any.
+    pub fn ray_interval(&self, r: &Ray, mut t_min: Float, mut t_max: Float) -> Option<(Float, Float)> {
+        for a in 0..3 {
+            let inv_d = 1.0 / r.direction[a];
+            let mut t0 = (self.min[a] - r.origin[a]) * inv_d;
+            let mut t1 = (self.max[a] - r.origin[a]) * inv_d;
+
+            if inv_d < 0.0 {
+                swap(&mut t0, &mut t1);
+            }
+
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+        }
+
+        (t_min <= t_max).then_some((t_min, t_max))
+    }
 }
@@ -3,6 +3,7 @@ use crate::Float;
 use crate::ray::Ray;
 
 pub struct Camera {
+    parameters: CameraParameters,
     origin: Point3<Float>,
     lower_left_corner: Point3<Float>,
     horizontal: Vector3<Float>,
@@ -16,37 +17,65 @@ impl Camera {
         vfov_radians: Float,
         aspect_ratio: Float,
     ) -> Self {
+        Self::from_parameters(CameraParameters {
+            look_from,
+            look_at,
+            up,
+            vfov_radians,
+            aspect_ratio,
+        })
+    }
+    pub fn from_parameters(parameters: CameraParameters) -> Self {
+        let CameraParameters { look_from, look_at, up, vfov_radians, aspect_ratio } = parameters;
+
         let theta = vfov_radians;
         let h = (theta / 2.0).tan();
         let viewport_height = h * 2.0;
         let viewport_width = aspect_ratio * viewport_height;
 
-        let w = (&look_from - look_at).normalize();
+        let w = (look_from - look_at).normalize();
         let u = up.cross(&w).normalize();
         let v = w.cross(&u);
 
         let origin = look_from;
         let horizontal = u * viewport_width;
         let vertical = v * viewport_height;
-        let lower_left_corner = &origin
+        let lower_left_corner = origin
             - horizontal / 2.0
             - vertical / 2.0
             - w;
 
         Self {
+            parameters,
             origin,
             horizontal,
             vertical,
             lower_left_corner,
         }
     }
+
+    /// The parameters this camera was constructed from.
+    pub fn parameters(&self) -> CameraParameters {
+        self.parameters
+    }
+
     pub fn get_ray(&self, s: Float, t: Float) -> Ray {
-        let origin = self.origin.clone();
-        let dir = &self.lower_left_corner
-            + &self.horizontal * s
-            + &self.vertical * t
-            - &origin;
+        let origin = self.origin;
+        let dir = self.lower_left_corner
+            + self.horizontal * s
+            + self.vertical * t
+            - origin;
 
         Ray::new(origin, Unit::new_normalize(dir))
     }
 }
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CameraParameters {
+    pub look_from: Point3<Float>,
+    pub look_at: Point3<Float>,
+    pub up: Vector3<Float>,
+    pub vfov_radians: Float,
+    pub aspect_ratio: Float,
+}
@@ -1,16 +1,19 @@
 use nalgebra::Vector3;
 use crate::camera::Camera;
 use crate::{Float};
-use crate::randomness::Randomness;
+use crate::randomness::{Randomness, SeedingRandomness};
 use crate::ray::Ray;
 use crate::scene::Scene;
+use crate::texture::PixelCoord2D;
 
 pub mod normal_integrator;
 pub mod path_integrator;
 
 pub trait Integrator {
     fn cast_ray<R: Randomness>(&self, ray: &Ray, t_min: Float, t_max: Float, scene: &Scene, depth: u32, rng: &mut R) -> Vector3<Float>;
-    fn render_line<R: Randomness>(
+    #[allow(clippy::too_many_arguments)]
+    /// Renders one line of pixels, using a random stream derived from `rng` and the pixel position for every pixel.
+    fn render_line<R: Randomness + SeedingRandomness>(
         &self,
         line: &mut [Vector3<Float>],
         camera: &Camera,
@@ -21,13 +24,14 @@ pub trait Integrator {
         scene: &Scene,
         t_min: Float,
         t_max: Float,
-        rng: &mut R,
+        rng: &R,
     ) {
         let t_width = width as Float;
         let t_height = height as Float;
         let factor = 1.0 / samples as Float;
 
         for x in 0..width {
+            let mut rng = rng.derive(PixelCoord2D::new(x, y).to_pixel_index(width) as u64);
             let mut color = Vector3::zeros();
 
             for _ in 0..samples {
@@ -38,7 +42,7 @@ pub trait Integrator {
                 let y_coord = y as Float + y_offset;
                 let ray = camera.get_ray(x_coord / t_width, y_coord / t_height);
 
-                let ray_color = self.cast_ray(&ray, t_min, t_max, scene, 0, rng);
+                let ray_color = self.cast_ray(&ray, t_min, t_max, scene, 0, &mut rng);
                 let sample = ray_color * factor;
                 color += sample;
             }
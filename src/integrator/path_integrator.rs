@@ -1,4 +1,4 @@
-use nalgebra::{UnitVector3, vector, Vector3};
+use nalgebra::{Point3, UnitVector3, vector, Vector3};
 use crate::{Float, Integrator, Randomness, Scene};
 use crate::intersection::Intersection;
 use crate::pdf::PDF;
@@ -6,92 +6,162 @@ use crate::ray::Ray;
 use crate::scene::primitive::{PrimitiveDirectionPDF, PrimitiveRef};
 use crate::world::material::{ScatteredRay};
 
+/// The depth after which paths are terminated by russian roulette, unless configured otherwise.
+pub const DEFAULT_MIN_DEPTH: u32 = 3;
+
 pub struct PathTracingIntegrator {
+    min_depth: u32,
     max_depth: u32,
     background_color: Vector3<Float>,
     lights: Vec<PrimitiveRef>,
 }
 impl PathTracingIntegrator {
+    /// Paths are terminated by russian roulette after [DEFAULT_MIN_DEPTH] bounces.
+    /// `depth` is a hard limit that only exists as a safety net, since paths cut off by it lose their remaining light.
     pub fn new(depth: u32, background_color: Vector3<Float>, scene: &Scene) -> Self {
-        let mut lights = Vec::new();
-
-        for (i, p) in scene.primitives.iter().enumerate() {
-            if scene.world.emits(scene.materials[p.object_id]) {
-                lights.push(PrimitiveRef(i));
-            }
-        }
+        let lights = scene.primitive_refs()
+            .filter(|p| scene.world.emits(scene.primitive_material(*p)))
+            .collect();
 
         Self {
+            min_depth: DEFAULT_MIN_DEPTH,
             max_depth: depth,
             background_color,
             lights,
         }
     }
 
-    fn bias_light<R: Randomness>(&self, int: &Intersection, scene: &Scene, rng: &mut R) -> Ray {
-        let choice = rng.usize_range_exclusive(0, self.lights.len());
-        let pdf = PrimitiveDirectionPDF::new(int.point, self.lights[choice]);
-        let direction = pdf.generate(rng, scene);
-
-        Ray::new(int.point, direction)
+    /// Sets the amount of bounces every path makes before russian roulette may terminate it.
+    pub fn with_min_depth(mut self, min_depth: u32) -> Self {
+        self.min_depth = min_depth;
+        self
     }
-    fn light_pdf_value(&self, value: UnitVector3<Float>, int: &Intersection, scene: &Scene) -> Float {
-        let total_value: Float = self.lights.iter()
-            .map(|l| PrimitiveDirectionPDF::new(int.point, *l).value(&value, scene))
-            .sum();
-
-        let dividend = self.lights.len() as Float;
 
-        total_value / dividend
+    /// The density of picking `light` and sampling `direction` towards it from `origin`.
+    fn light_pdf_value(&self, origin: Point3<Float>, light: PrimitiveRef, direction: UnitVector3<Float>, scene: &Scene) -> Float {
+        let pdf = PrimitiveDirectionPDF::new(origin, light).value(&direction, scene);
+        pdf / self.lights.len() as Float
     }
-    fn generate_new_ray<R: Randomness>(&self, scattered: &ScatteredRay, int: &Intersection, scene: &Scene, rng: &mut R) -> (Ray, Float) {
-        if self.lights.len() != 0 && !scattered.is_specular {
-            let choice = rng.usize_range_exclusive(0, 2);
-
-            let ray = if choice == 0 {
-                self.bias_light(int, scene, rng)
-            } else {
-                let dir = scattered.pdf.generate(rng, scene);
-                Ray::new(int.point, dir)
-            };
 
-            let light_pdf_value = self.light_pdf_value(ray.direction, int, scene);
-            let scattered_pdf_value = scattered.pdf.value(&ray.direction, scene);
+    /// Next event estimation: samples a light and casts a shadow ray towards it.
+    /// The result is weighted against the chance of the BSDF sampling the same direction.
+    #[allow(clippy::too_many_arguments)]
+    fn sample_direct_light<R: Randomness>(
+        &self,
+        int: &Intersection,
+        ray_out: UnitVector3<Float>,
+        scattered: &ScatteredRay,
+        t_min: Float,
+        t_max: Float,
+        scene: &Scene,
+        rng: &mut R,
+    ) -> Vector3<Float> {
+        let choice = self.lights[rng.usize_range_exclusive(0, self.lights.len())];
+        let direction = PrimitiveDirectionPDF::new(int.point, choice).generate(rng, scene);
+        let light_pdf = self.light_pdf_value(int.point, choice, direction, scene);
+        if light_pdf <= 0.0 {
+            return Vector3::zeros();
+        }
 
-            let total_pdf_value = 0.5 * scattered_pdf_value + 0.5 * light_pdf_value;
-            (ray, total_pdf_value)
-        } else {
-            let ray = Ray::new(int.point, scattered.pdf.generate(rng, scene));
-            let pdf = scattered.pdf.value(&ray.direction, scene);
-            (ray, pdf)
+        let shadow_ray = Ray::new(int.point, direction);
+        let light = match scene.intersect_primitive(choice, &shadow_ray, t_min, t_max) {
+            Some(light) => light,
+            None => return Vector3::zeros(),
+        };
+        if scene.occluded(&shadow_ray, t_min, light.t - t_min) {
+            return Vector3::zeros();
         }
+
+        let emitted = scene.world.emit(light.material, -direction, &light);
+        let brdf = scene.world.brdf(int.material, direction, int, ray_out);
+        let scattered_pdf = scattered.pdf.value(&direction, scene);
+
+        let weight = power_heuristic(light_pdf, scattered_pdf);
+        mul_vectors(&mul_vectors(&scattered.attenuation, &emitted), &brdf) * (weight / light_pdf)
     }
 }
 impl Integrator for PathTracingIntegrator {
     fn cast_ray<R: Randomness>(&self, ray: &Ray, t_min: Float, t_max: Float, scene: &Scene, depth: u32, rng: &mut R) -> Vector3<Float> {
-        if depth >= self.max_depth {
-            return vector!(0.0, 0.0, 0.0);
-        }
+        let mut radiance = Vector3::zeros();
+        let mut throughput = vector!(1.0, 1.0, 1.0);
+        let mut ray = *ray;
+        // Where the last BSDF sampled ray started and its density, if next event estimation could also have found what it hits.
+        let mut light_sampled_from: Option<(Point3<Float>, Float)> = None;
+
+        for depth in depth..self.max_depth {
+            let int = match scene.intersect(&ray, t_min, t_max) {
+                Some(int) => int,
+                None => {
+                    radiance += mul_vectors(&throughput, &self.background_color);
+                    break;
+                }
+            };
 
-        if let Some(int) = scene.intersect(ray, t_min, t_max) {
             let mat = int.material;
-            let emitted = scene.world.emit(mat, -ray.direction, &int);
+            let ray_out = -ray.direction;
+            if scene.world.emits(mat) {
+                let emission_weight = match light_sampled_from {
+                    Some((origin, pdf)) => power_heuristic(pdf, self.light_pdf_value(origin, int.primitive, ray.direction, scene)),
+                    None => 1.0,
+                };
+                radiance += mul_vectors(&throughput, &scene.world.emit(mat, ray_out, &int)) * emission_weight;
+            }
 
-            if let Some(scattered) = scene.world.scatter_ray(mat, -ray.direction, &int, scene) {
-                let (ray_out, pdf) = self.generate_new_ray(&scattered, &int, scene, rng);
+            let scattered = match scene.world.scatter_ray(mat, ray_out, &int, scene) {
+                Some(scattered) => scattered,
+                None => break,
+            };
 
-                let brdf = scene.world.brdf(mat, ray_out.direction, &int, -ray.direction);
+            // Light found by next event estimation is only counted if the next bounce could count it too.
+            let use_lights = !self.lights.is_empty() && !scattered.is_specular && depth + 1 < self.max_depth;
 
-                let mut recursed = self.cast_ray(&ray_out, t_min, t_max, scene, depth + 1, rng);
-                correct_abnormal_color(&mut recursed);
+            if use_lights {
+                let mut direct = self.sample_direct_light(&int, ray_out, &scattered, t_min, t_max, scene, rng);
+                correct_abnormal_color(&mut direct);
+                radiance += mul_vectors(&throughput, &direct);
+            }
 
-                emitted + (mul_vectors(&scattered.attenuation, &recursed) * brdf) / pdf
-            } else {
-                emitted
+            let direction = scattered.pdf.generate(rng, scene);
+            let pdf = scattered.pdf.value(&direction, scene);
+            if pdf <= 0.0 {
+                break;
             }
-        } else {
-            self.background_color
+
+            light_sampled_from = if use_lights { Some((int.point, pdf)) } else { None };
+
+            let brdf = scene.world.brdf(mat, direction, &int, ray_out);
+            throughput = mul_vectors(&mul_vectors(&throughput, &scattered.attenuation), &brdf) / pdf;
+            correct_abnormal_color(&mut throughput);
+
+            // Russian roulette, which keeps the result unbiased by boosting the paths that survive.
+            if depth + 1 >= self.min_depth {
+                let survival = throughput.max().min(1.0);
+                if survival <= 0.0 || rng.float() >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+
+            ray = Ray::new(int.point, direction);
         }
+
+        radiance
+    }
+}
+
+/// Veach's power heuristic with an exponent of 2, the weight of a sample taken with density `pdf` against `other_pdf`.
+fn power_heuristic(pdf: Float, other_pdf: Float) -> Float {
+    if pdf.is_infinite() {
+        return 1.0;
+    }
+
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    if a + b == 0.0 {
+        0.0
+    }
+    else {
+        a / (a + b)
     }
 }
 
@@ -1,5 +1,6 @@
 use nalgebra::{Point3, Unit, Vector3};
 use crate::Float;
+use crate::scene::primitive::PrimitiveRef;
 use crate::texture::TextureCoord2D;
 use crate::world::material::MaterialRef;
 
@@ -11,4 +12,6 @@ pub struct Intersection {
     pub outside: bool,
     pub material: MaterialRef,
     pub tex_coord: TextureCoord2D,
+    /// The primitive that was hit.
+    pub primitive: PrimitiveRef,
 }
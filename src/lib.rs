@@ -1,11 +1,12 @@
 use nalgebra::Vector3;
-use parking_lot::Mutex;
 use crate::camera::Camera;
 use crate::integrator::Integrator;
+use crate::integrator::path_integrator::DEFAULT_MIN_DEPTH;
 use crate::randomness::{Randomness, SeedingRandomness};
 use crate::scene::Scene;
 use rayon::prelude::*;
 use crate::texture::Texture2D;
+use serde::{Deserialize, Serialize};
 
 pub mod world;
 pub mod scene;
@@ -17,6 +18,7 @@ pub mod camera;
 pub mod integrator;
 pub mod texture;
 pub mod pdf;
+pub mod loader;
 
 #[cfg(not(feature = "wide"))]
 pub type Float = f32;
@@ -24,16 +26,17 @@ pub type Float = f32;
 pub type Float = f64;
 
 
-pub fn render<I: Integrator + Sync, R: Randomness + SeedingRandomness + Send>(desc: RenderDescriptor<I, R>) -> Texture2D<Vector3<Float>> {
+/// Renders an image.
+/// Every pixel gets its own random stream derived from `desc.rng` and its position,
+/// so the result doesn't depend on the amount of threads or the order they run in.
+pub fn render<I: Integrator + Sync, R: Randomness + SeedingRandomness + Sync>(desc: RenderDescriptor<I, R>) -> Texture2D<Vector3<Float>> {
     let mut pixels = vec![Vector3::zeros(); desc.width as usize * desc.height as usize];
 
-    let rng = Mutex::new(desc.rng);
+    let rng = desc.rng.seed_new();
 
-    pixels.chunks_exact_mut(desc.width as usize).enumerate()
-        .par_bridge()
+    pixels.par_chunks_exact_mut(desc.width as usize).enumerate()
         .for_each(|(i, p)| {
             let y = desc.height - i as u32 - 1;
-            let mut rng = rng.lock().seed_new();
 
             desc.integrator.render_line(
                 p,
@@ -45,7 +48,7 @@ pub fn render<I: Integrator + Sync, R: Randomness + SeedingRandomness + Send>(de
                 &desc.scene,
                 desc.t_min,
                 desc.t_max,
-                &mut rng
+                &rng
             );
 
             eprintln!("Finished line {}", y);
@@ -68,3 +71,37 @@ pub struct RenderDescriptor<'a, I, R> {
     pub scene: Scene<'a>,
     pub camera: Camera,
 }
+
+
+/// The parameters of a render that are independent of the scene.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,
+    /// The hard limit on the amount of bounces per path.
+    pub depth: u32,
+    /// The amount of bounces before paths may be terminated by russian roulette.
+    pub min_depth: u32,
+    pub t_min: Float,
+    pub t_max: Float,
+}
+impl RenderSettings {
+    pub fn aspect_ratio(&self) -> Float {
+        self.width as Float / self.height as Float
+    }
+}
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            samples: 1024,
+            depth: 64,
+            min_depth: DEFAULT_MIN_DEPTH,
+            t_min: 0.001,
+            t_max: Float::INFINITY,
+        }
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ::gltf::{Document, Gltf, Mesh, Node};
+use ::gltf::camera::Projection;
+use ::gltf::material::AlphaMode;
+use ::gltf::mesh::Mode;
+use image::ImageError;
+use nalgebra::{Affine3, Matrix4, Point3, Vector3};
+use crate::camera::Camera;
+use crate::Float;
+use crate::texture::{Texture2D, TextureCoord2D};
+use crate::world::albedo::AlbedoRef;
+use crate::world::material::MaterialRef;
+use crate::world::principled::{ColorParameter, Principled, ScalarParameter};
+use crate::world::shape::ShapeRef;
+use crate::world::{ObjectRef, World};
+
+
+/// Loads the default scene (or the first one) of a glTF 2.0 file, either `.gltf` or `.glb`, into `world`.
+///
+/// Every mesh primitive becomes one object, placed with its node's world transform.
+/// Meshes used by multiple nodes share their shapes.
+/// Perspective cameras use `default_aspect_ratio` if they don't specify one themselves.
+///
+/// Materials become principled materials with the base color, metallic, roughness and emission of the file.
+/// Everything else that can't be represented is skipped and reported in [`GltfScene::warnings`].
+pub fn load_gltf<P: AsRef<Path>>(path: P, world: &mut World, default_aspect_ratio: Float) -> Result<GltfScene, GltfError> {
+    let path = path.as_ref();
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let Gltf { document, blob } = Gltf::open(path).map_err(GltfError::Gltf)?;
+
+    let buffers = document.buffers()
+        .map(|buffer| {
+            let data = match buffer.source() {
+                ::gltf::buffer::Source::Bin => blob.clone().ok_or(GltfError::MissingBlob)?,
+                ::gltf::buffer::Source::Uri(uri) => load_uri(uri, base_dir)?,
+            };
+
+            if data.len() < buffer.length() {
+                return Err(GltfError::BufferLength {
+                    buffer: buffer.index(),
+                    expected: buffer.length(),
+                    actual: data.len(),
+                });
+            }
+
+            Ok(data)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut importer = Importer {
+        document: &document,
+        buffers,
+        base_dir,
+        default_aspect_ratio,
+        world,
+        materials: HashMap::new(),
+        shapes: HashMap::new(),
+        scene: GltfScene {
+            objects: Vec::new(),
+            cameras: Vec::new(),
+            warnings: Vec::new(),
+        },
+    };
+    importer.check_document();
+
+    match document.default_scene().or_else(|| document.scenes().next()) {
+        Some(scene) => {
+            for node in scene.nodes() {
+                importer.import_node(node, Matrix4::identity())?;
+            }
+        }
+        None => importer.warn("File contains no scene"),
+    }
+
+    Ok(importer.scene)
+}
+
+
+/// Everything that was created by [`load_gltf`].
+pub struct GltfScene {
+    pub objects: Vec<ObjectRef>,
+    pub cameras: Vec<Camera>,
+    /// Human readable descriptions of everything that was skipped or approximated.
+    pub warnings: Vec<String>,
+}
+
+
+#[derive(Debug)]
+pub enum GltfError {
+    Gltf(::gltf::Error),
+    Io(PathBuf, io::Error),
+    UnsupportedUri(String),
+    Base64(base64::DecodeError),
+    MissingBlob,
+    BufferLength {
+        buffer: usize,
+        expected: usize,
+        actual: usize,
+    },
+    /// A buffer view that reaches past the end of its buffer.
+    BufferViewRange {
+        view: usize,
+        buffer: usize,
+    },
+    Image(ImageError),
+}
+impl Display for GltfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Gltf(e) => write!(f, "Invalid glTF file: {}", e),
+            Self::Io(path, e) => write!(f, "Could not read {}: {}", path.display(), e),
+            Self::UnsupportedUri(uri) => write!(f, "Unsupported uri '{}'", uri),
+            Self::Base64(e) => write!(f, "Invalid base64 data: {}", e),
+            Self::MissingBlob => write!(f, "Binary chunk is referenced but missing"),
+            Self::BufferLength { buffer, expected, actual } => {
+                write!(f, "Buffer {} should be {} bytes long, but only {} are available", buffer, expected, actual)
+            }
+            Self::BufferViewRange { view, buffer } => write!(f, "Buffer view {} reaches past the end of buffer {}", view, buffer),
+            Self::Image(e) => write!(f, "Could not decode image: {}", e),
+        }
+    }
+}
+impl Error for GltfError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Gltf(e) => Some(e),
+            Self::Io(_, e) => Some(e),
+            Self::Base64(e) => Some(e),
+            Self::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+
+struct Importer<'a> {
+    document: &'a Document,
+    buffers: Vec<Vec<u8>>,
+    base_dir: &'a Path,
+    default_aspect_ratio: Float,
+    world: &'a mut World,
+    materials: HashMap<Option<usize>, MaterialRef>,
+    /// Shapes of mesh primitives that were already imported, by mesh and primitive index.
+    shapes: HashMap<(usize, usize), Option<ShapeRef>>,
+    scene: GltfScene,
+}
+impl<'a> Importer<'a> {
+    fn warn<S: Into<String>>(&mut self, warning: S) {
+        self.scene.warnings.push(warning.into());
+    }
+
+    fn check_document(&mut self) {
+        for extension in self.document.extensions_used() {
+            self.warn(format!("Extension {} is not supported", extension));
+        }
+        if self.document.animations().len() != 0 {
+            self.warn("Animations are not supported");
+        }
+    }
+
+    fn import_node(&mut self, node: Node, parent: Matrix4<Float>) -> Result<(), GltfError> {
+        let local: Matrix4<f32> = node.transform().matrix().into();
+        let transform = parent * local.cast::<Float>();
+
+        if node.skin().is_some() {
+            self.warn(format!("Skin of node {} is ignored", node.index()));
+        }
+        if let Some(mesh) = node.mesh() {
+            self.import_mesh(mesh, &transform)?;
+        }
+        if let Some(camera) = node.camera() {
+            self.import_camera(camera, &transform);
+        }
+
+        for child in node.children() {
+            self.import_node(child, transform)?;
+        }
+
+        Ok(())
+    }
+
+    fn import_camera(&mut self, camera: ::gltf::Camera, transform: &Matrix4<Float>) {
+        match camera.projection() {
+            Projection::Perspective(p) => {
+                let look_from = transform.transform_point(&Point3::origin());
+                let forward = transform.transform_vector(&-Vector3::z());
+                let up = transform.transform_vector(&Vector3::y());
+                let aspect_ratio = p.aspect_ratio().map(|a| a as Float).unwrap_or(self.default_aspect_ratio);
+
+                self.scene.cameras.push(Camera::new(
+                    look_from,
+                    look_from + forward,
+                    up,
+                    p.yfov() as Float,
+                    aspect_ratio,
+                ));
+            }
+            Projection::Orthographic(_) => self.warn(format!("Orthographic camera {} is not supported", camera.index())),
+        }
+    }
+
+    fn import_mesh(&mut self, mesh: Mesh, transform: &Matrix4<Float>) -> Result<(), GltfError> {
+        if transform.fixed_slice::<3, 3>(0, 0).determinant().abs() < Float::EPSILON {
+            self.warn(format!("Mesh {} has a degenerate transform and is skipped", mesh.index()));
+            return Ok(());
+        }
+        let transform = Affine3::from_matrix_unchecked(*transform);
+
+        for primitive in mesh.primitives() {
+            let key = (mesh.index(), primitive.index());
+            let shape = match self.shapes.get(&key) {
+                Some(shape) => *shape,
+                None => {
+                    let shape = self.import_primitive(&mesh, &primitive);
+                    self.shapes.insert(key, shape);
+                    shape
+                }
+            };
+
+            if let Some(shape) = shape {
+                let material = self.material(primitive.material())?;
+                let object = self.world.add_affine_object(shape, material, transform);
+                self.scene.objects.push(object);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn import_primitive(&mut self, mesh: &Mesh, primitive: &::gltf::Primitive) -> Option<ShapeRef> {
+        let name = format!("Primitive {} of mesh {}", primitive.index(), mesh.index());
+
+        let buffers = &self.buffers;
+        let reader = primitive.reader(|b| buffers.get(b.index()).map(|d| d.as_slice()));
+
+        let positions: Vec<Point3<Float>> = match reader.read_positions() {
+            Some(p) => p.map(|p| Point3::from(p).cast()).collect(),
+            None => {
+                self.warn(format!("{} has no positions and is skipped", name));
+                return None;
+            }
+        };
+        let mut normals: Option<Vec<Vector3<Float>>> = reader.read_normals()
+            .map(|n| n.map(|n| Vector3::from(n).cast()).collect());
+        let tex_coords: Option<Vec<TextureCoord2D>> = reader.read_tex_coords(0)
+            .map(|t| t.into_f32().map(|[u, v]| TextureCoord2D::new(u as Float, 1.0 - v as Float)).collect());
+        let indices: Vec<usize> = match reader.read_indices() {
+            Some(i) => i.into_u32().map(|i| i as usize).collect(),
+            None => (0..positions.len()).collect(),
+        };
+
+        let triangles: Vec<[usize; 3]> = match primitive.mode() {
+            Mode::Triangles => indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect(),
+            Mode::TriangleStrip => indices.windows(3).enumerate()
+                .map(|(i, t)| if i % 2 == 0 { [t[0], t[1], t[2]] } else { [t[1], t[0], t[2]] })
+                .collect(),
+            Mode::TriangleFan => indices.windows(2).skip(1)
+                .map(|t| [indices[0], t[0], t[1]])
+                .collect(),
+            mode => {
+                self.warn(format!("{} uses unsupported mode {:?} and is skipped", name, mode));
+                return None;
+            }
+        };
+
+        if primitive.morph_targets().len() != 0 {
+            self.warn(format!("Morph targets of {} are ignored", name));
+        }
+        if triangles.iter().flatten().any(|&i| i >= positions.len()) {
+            self.warn(format!("{} has out of range indices and is skipped", name));
+            return None;
+        }
+        if normals.as_ref().is_some_and(|n| n.len() != positions.len()) {
+            self.warn(format!("{} has a wrong amount of normals, they are ignored", name));
+            normals = None;
+        }
+        let tex_coords = match tex_coords {
+            Some(t) if t.len() != positions.len() => {
+                self.warn(format!("{} has a wrong amount of texture coordinates, they are ignored", name));
+                None
+            }
+            t => t,
+        };
+
+        Some(self.world.add_triangle_mesh(positions, triangles, normals, tex_coords))
+    }
+
+    fn material(&mut self, material: ::gltf::Material) -> Result<MaterialRef, GltfError> {
+        if let Some(m) = self.materials.get(&material.index()) {
+            return Ok(*m);
+        }
+
+        let name = match material.index() {
+            Some(i) => format!("Material {}", i),
+            None => "Default material".to_owned(),
+        };
+        let pbr = material.pbr_metallic_roughness();
+
+        if material.normal_texture().is_some() {
+            self.warn(format!("Normal texture of {} is ignored", name));
+        }
+        if material.occlusion_texture().is_some() {
+            self.warn(format!("Occlusion texture of {} is ignored", name));
+        }
+        if material.alpha_mode() != AlphaMode::Opaque {
+            self.warn(format!("Transparency of {} is ignored", name));
+        }
+
+        let [r, g, b, _] = pbr.base_color_factor();
+        let base_color = Vector3::new(r, g, b).cast::<Float>();
+        let base_color = match pbr.base_color_texture() {
+            Some(info) => {
+                let albedo = self.texture_albedo(&name, info, |p| p.map(srgb_to_linear).component_mul(&base_color))?;
+                ColorParameter::Texture(albedo)
+            }
+            None => ColorParameter::Constant(base_color),
+        };
+
+        let metallic = pbr.metallic_factor() as Float;
+        let roughness = pbr.roughness_factor() as Float;
+        // Roughness is stored in the green channel and metallic in the blue one.
+        let (metallic, roughness) = match pbr.metallic_roughness_texture() {
+            Some(info) => {
+                let factors = Vector3::new(1.0, roughness, metallic);
+                let albedo = self.texture_albedo(&name, info, |p| p.component_mul(&factors))?;
+                (ScalarParameter::Texture(albedo, 2), ScalarParameter::Texture(albedo, 1))
+            }
+            None => (ScalarParameter::Constant(metallic), ScalarParameter::Constant(roughness)),
+        };
+
+        let emissive = Vector3::from(material.emissive_factor()).cast::<Float>();
+        let emission = match material.emissive_texture() {
+            Some(info) => {
+                let albedo = self.texture_albedo(&name, info, |p| p.map(srgb_to_linear).component_mul(&emissive))?;
+                ColorParameter::Texture(albedo)
+            }
+            None => ColorParameter::Constant(emissive),
+        };
+
+        let m = self.world.add_principled_material(Principled {
+            base_color,
+            metallic,
+            roughness,
+            emission,
+            ..Principled::default()
+        });
+
+        self.materials.insert(material.index(), m);
+        Ok(m)
+    }
+
+    /// Loads the texture of `info` as an albedo, transforming every pixel with `map`.
+    fn texture_albedo(
+        &mut self,
+        material: &str,
+        info: ::gltf::texture::Info,
+        map: impl Fn(Vector3<Float>) -> Vector3<Float>,
+    ) -> Result<AlbedoRef, GltfError> {
+        if info.tex_coord() != 0 {
+            self.warn(format!("{} uses texture coordinate set {}, but only set 0 is supported", material, info.tex_coord()));
+        }
+
+        let texture = self.load_image(info.texture().source())?;
+        let pixels = texture.pixels().map(|p| map(*p)).collect();
+
+        Ok(self.world.add_texture_albedo(Texture2D::new_from_pixels(texture.width(), texture.height(), pixels)))
+    }
+
+    fn load_image(&self, image: ::gltf::Image) -> Result<Texture2D<Vector3<Float>>, GltfError> {
+        let data = match image.source() {
+            ::gltf::image::Source::View { view, .. } => {
+                let range = view.offset()..view.offset().saturating_add(view.length());
+                self.buffers.get(view.buffer().index())
+                    .and_then(|buffer| buffer.get(range))
+                    .ok_or(GltfError::BufferViewRange {
+                        view: view.index(),
+                        buffer: view.buffer().index(),
+                    })?
+                    .to_vec()
+            }
+            ::gltf::image::Source::Uri { uri, .. } => load_uri(uri, self.base_dir)?,
+        };
+
+        let image = image::load_from_memory(&data).map_err(GltfError::Image)?.to_rgb32f();
+        let pixels = image.pixels()
+            .map(|p| Vector3::new(p[0], p[1], p[2]).cast())
+            .collect();
+
+        Ok(Texture2D::new_from_pixels(image.width(), image.height(), pixels))
+    }
+}
+
+
+fn load_uri(uri: &str, base_dir: &Path) -> Result<Vec<u8>, GltfError> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        let (header, payload) = data.split_once(',').ok_or_else(|| GltfError::UnsupportedUri(uri.to_owned()))?;
+        if !header.ends_with(";base64") {
+            return Err(GltfError::UnsupportedUri(uri.to_owned()));
+        }
+
+        BASE64.decode(payload).map_err(GltfError::Base64)
+    }
+    else if uri.contains("://") {
+        Err(GltfError::UnsupportedUri(uri.to_owned()))
+    }
+    else {
+        let path = base_dir.join(percent_decode(uri));
+        std::fs::read(&path).map_err(|e| GltfError::Io(path, e))
+    }
+}
+
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn srgb_to_linear(c: Float) -> Float {
+    if c <= 0.04045 {
+        c / 12.92
+    }
+    else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
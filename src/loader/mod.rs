@@ -0,0 +1,39 @@
+use std::path::Path;
+use image::ImageError;
+use nalgebra::Vector3;
+use crate::Float;
+use crate::texture::Texture2D;
+
+pub mod obj;
+pub mod gltf;
+pub mod scene_file;
+
+
+/// Loads an image file as an rgb texture, guessing the format from its contents.
+pub fn load_texture<P: AsRef<Path>>(path: P) -> Result<Texture2D<Vector3<Float>>, ImageError> {
+    let image = image::io::Reader::open(path)?
+        .with_guessed_format()?
+        .decode()?
+        .to_rgb32f();
+
+    let pixels = image.pixels()
+        .map(|p| Vector3::new(p[0], p[1], p[2]).cast())
+        .collect();
+
+    Ok(Texture2D::new_from_pixels(image.width(), image.height(), pixels))
+}
+
+/// Loads an image file as a heightmap from 0 to 1, guessing the format from its contents.
+/// Colors are turned into grayscale, and 16 bits per pixel are kept.
+pub fn load_heightmap<P: AsRef<Path>>(path: P) -> Result<Texture2D<Float>, ImageError> {
+    let image = image::io::Reader::open(path)?
+        .with_guessed_format()?
+        .decode()?
+        .to_luma16();
+
+    let pixels = image.pixels()
+        .map(|p| p[0] as Float / u16::MAX as Float)
+        .collect();
+
+    Ok(Texture2D::new_from_pixels(image.width(), image.height(), pixels))
+}
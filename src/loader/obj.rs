@@ -0,0 +1,517 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use image::ImageError;
+use nalgebra::{Affine3, Point3, Vector3};
+use crate::Float;
+use crate::loader::load_texture;
+use crate::texture::TextureCoord2D;
+use crate::world::material::MaterialRef;
+use crate::world::{ObjectRef, World};
+
+
+/// Loads a Wavefront OBJ file and the MTL files it references into `world`.
+///
+/// Every `o`/`g` group becomes one object per material used inside of it, all placed with `transform`.
+/// Polygons are triangulated as fans.
+pub fn load_obj<P: AsRef<Path>>(path: P, world: &mut World, transform: Affine3<Float>) -> Result<Vec<ObjGroup>, ObjError> {
+    let path = path.as_ref();
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let source = read_file(path)?;
+    let obj = ObjFile::parse(path, &source)?;
+
+    let mut mtl_materials = HashMap::new();
+    for lib in &obj.material_libs {
+        let lib_path = base_dir.join(lib);
+        let source = read_file(&lib_path)?;
+        parse_mtl(&lib_path, &source, &mut mtl_materials)?;
+    }
+
+    let mut materials: HashMap<String, MaterialRef> = HashMap::new();
+    let mut default_material = None;
+    let mut groups = Vec::with_capacity(obj.groups.len());
+
+    for group in obj.groups {
+        let mut objects = Vec::with_capacity(group.meshes.len());
+
+        for mesh in group.meshes {
+            if mesh.faces.is_empty() {
+                continue;
+            }
+
+            let material = match &mesh.material {
+                Some((name, line)) => match materials.get(name) {
+                    Some(m) => *m,
+                    None => {
+                        let mtl = mtl_materials.get(name).ok_or_else(|| ObjError::Parse {
+                            path: path.to_owned(),
+                            line: *line,
+                            message: format!("Unknown material '{}'", name),
+                        })?;
+                        let m = mtl.add_to_world(world)?;
+                        materials.insert(name.clone(), m);
+                        m
+                    }
+                },
+                None => match default_material {
+                    Some(m) => m,
+                    None => {
+                        let m = MtlMaterial::default().add_to_world(world)?;
+                        default_material = Some(m);
+                        m
+                    }
+                },
+            };
+
+            let (positions, indices, normals, tex_coords) = mesh.build(&obj.positions, &obj.normals, &obj.tex_coords);
+            let shape = world.add_triangle_mesh(positions, indices, normals, tex_coords);
+            objects.push(world.add_affine_object(shape, material, transform));
+        }
+
+        groups.push(ObjGroup {
+            name: group.name,
+            objects,
+        });
+    }
+
+    Ok(groups)
+}
+
+fn read_file(path: &Path) -> Result<String, ObjError> {
+    std::fs::read_to_string(path).map_err(|e| ObjError::Io(path.to_owned(), e))
+}
+
+
+/// The objects created for one `o` or `g` group of an OBJ file.
+#[derive(Clone, Debug)]
+pub struct ObjGroup {
+    pub name: String,
+    pub objects: Vec<ObjectRef>,
+}
+
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(PathBuf, io::Error),
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    Texture(PathBuf, ImageError),
+}
+impl Display for ObjError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "Could not read {}: {}", path.display(), e),
+            Self::Parse { path, line, message } => write!(f, "{}:{}: {}", path.display(), line, message),
+            Self::Texture(path, e) => write!(f, "Could not load texture {}: {}", path.display(), e),
+        }
+    }
+}
+impl Error for ObjError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(_, e) => Some(e),
+            Self::Parse { .. } => None,
+            Self::Texture(_, e) => Some(e),
+        }
+    }
+}
+
+
+struct ObjFile {
+    positions: Vec<Point3<Float>>,
+    normals: Vec<Vector3<Float>>,
+    tex_coords: Vec<TextureCoord2D>,
+    material_libs: Vec<String>,
+    groups: Vec<GroupBuilder>,
+}
+impl ObjFile {
+    fn parse(path: &Path, source: &str) -> Result<Self, ObjError> {
+        let mut obj = ObjFile {
+            positions: Vec::new(),
+            normals: Vec::new(),
+            tex_coords: Vec::new(),
+            material_libs: Vec::new(),
+            groups: vec![GroupBuilder::new("default".to_owned())],
+        };
+        let mut group_indices = HashMap::new();
+        let mut current_group = 0;
+        let mut current_material = None;
+
+        for (line_i, line) in source.lines().enumerate() {
+            let mut parser = LineParser::new(path, line_i + 1, line);
+            let keyword = match parser.next_token() {
+                Some(k) => k,
+                None => continue,
+            };
+
+            match keyword {
+                "v" => {
+                    let [x, y, z] = parser.floats()?;
+                    obj.positions.push(Point3::new(x, y, z));
+                }
+                "vn" => {
+                    let [x, y, z] = parser.floats()?;
+                    obj.normals.push(Vector3::new(x, y, z));
+                }
+                "vt" => {
+                    let u = parser.float()?;
+                    let v = parser.optional_float()?.unwrap_or(0.0);
+                    obj.tex_coords.push(TextureCoord2D::new(u, v));
+                }
+                "f" => {
+                    let mut vertices = Vec::with_capacity(4);
+                    while let Some(token) = parser.next_token() {
+                        vertices.push(obj.parse_face_vertex(&parser, token)?);
+                    }
+                    if vertices.len() < 3 {
+                        return Err(parser.error("Face needs at least 3 vertices"));
+                    }
+
+                    let group = &mut obj.groups[current_group];
+                    let mesh = group.mesh_for(&current_material);
+                    for i in 1..vertices.len() - 1 {
+                        mesh.faces.push([vertices[0], vertices[i], vertices[i + 1]]);
+                    }
+                }
+                "o" | "g" => {
+                    let name = parser.rest();
+                    let name = if name.is_empty() { "default" } else { name };
+
+                    let is_fresh = obj.groups[current_group].is_empty() && !group_indices.contains_key(&obj.groups[current_group].name);
+                    current_group = match group_indices.get(name) {
+                        Some(&i) => i,
+                        None if is_fresh => {
+                            name.clone_into(&mut obj.groups[current_group].name);
+                            current_group
+                        }
+                        None => {
+                            obj.groups.push(GroupBuilder::new(name.to_owned()));
+                            obj.groups.len() - 1
+                        }
+                    };
+                    group_indices.insert(name.to_owned(), current_group);
+                }
+                "usemtl" => {
+                    let name = parser.rest();
+                    if name.is_empty() {
+                        return Err(parser.error("Missing material name"));
+                    }
+                    current_material = Some((name.to_owned(), line_i + 1));
+                }
+                "mtllib" => {
+                    while let Some(lib) = parser.next_token() {
+                        obj.material_libs.push(lib.to_owned());
+                    }
+                }
+                // Smoothing groups, lines, points and free-form geometry are not supported.
+                _ => (),
+            }
+        }
+
+        obj.groups.retain(|g| !g.is_empty());
+        Ok(obj)
+    }
+
+    fn parse_face_vertex(&self, parser: &LineParser, token: &str) -> Result<FaceVertex, ObjError> {
+        let mut parts = token.split('/');
+
+        let position = parts.next().unwrap_or("");
+        let tex_coord = parts.next().filter(|s| !s.is_empty());
+        let normal = parts.next().filter(|s| !s.is_empty());
+
+        let position = parser.index(position, self.positions.len())?;
+        let tex_coord = tex_coord.map(|t| parser.index(t, self.tex_coords.len())).transpose()?;
+        let normal = normal.map(|n| parser.index(n, self.normals.len())).transpose()?;
+
+        Ok(FaceVertex {
+            position,
+            tex_coord,
+            normal,
+        })
+    }
+}
+
+
+struct GroupBuilder {
+    name: String,
+    meshes: Vec<MeshBuilder>,
+}
+impl GroupBuilder {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            meshes: Vec::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.meshes.iter().all(|m| m.faces.is_empty())
+    }
+
+    fn mesh_for(&mut self, material: &Option<(String, usize)>) -> &mut MeshBuilder {
+        let name = material.as_ref().map(|(n, _)| n);
+        let existing = self.meshes.iter()
+            .position(|m| m.material.as_ref().map(|(n, _)| n) == name);
+
+        let i = match existing {
+            Some(i) => i,
+            None => {
+                self.meshes.push(MeshBuilder {
+                    material: material.clone(),
+                    faces: Vec::new(),
+                });
+                self.meshes.len() - 1
+            }
+        };
+
+        &mut self.meshes[i]
+    }
+}
+
+
+struct MeshBuilder {
+    material: Option<(String, usize)>,
+    faces: Vec<[FaceVertex; 3]>,
+}
+impl MeshBuilder {
+    /// Deduplicates the face vertices into a triangle mesh with per-vertex attributes.
+    /// Normals and texture coordinates are only kept if every vertex has them.
+    #[allow(clippy::type_complexity)]
+    fn build(
+        &self,
+        positions: &[Point3<Float>],
+        normals: &[Vector3<Float>],
+        tex_coords: &[TextureCoord2D],
+    ) -> (Vec<Point3<Float>>, Vec<[usize; 3]>, Option<Vec<Vector3<Float>>>, Option<Vec<TextureCoord2D>>) {
+        let vertices = || self.faces.iter().flatten();
+        let has_normals = vertices().all(|v| v.normal.is_some());
+        let has_tex_coords = vertices().all(|v| v.tex_coord.is_some());
+
+        let mut vertex_map = HashMap::new();
+        let mut out_positions = Vec::new();
+        let mut out_normals = Vec::new();
+        let mut out_tex_coords = Vec::new();
+
+        let indices = self.faces.iter()
+            .map(|face| face.map(|v| {
+                let key = (
+                    v.position,
+                    v.normal.filter(|_| has_normals),
+                    v.tex_coord.filter(|_| has_tex_coords),
+                );
+
+                *vertex_map.entry(key).or_insert_with(|| {
+                    out_positions.push(positions[v.position]);
+                    if let Some(n) = key.1 {
+                        out_normals.push(normals[n]);
+                    }
+                    if let Some(t) = key.2 {
+                        out_tex_coords.push(tex_coords[t]);
+                    }
+                    out_positions.len() - 1
+                })
+            }))
+            .collect();
+
+        (
+            out_positions,
+            indices,
+            Some(out_normals).filter(|_| has_normals),
+            Some(out_tex_coords).filter(|_| has_tex_coords),
+        )
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct FaceVertex {
+    position: usize,
+    tex_coord: Option<usize>,
+    normal: Option<usize>,
+}
+
+
+struct MtlMaterial {
+    diffuse: Vector3<Float>,
+    diffuse_map: Option<PathBuf>,
+    emission: Vector3<Float>,
+    ior: Float,
+    illumination: u32,
+}
+impl MtlMaterial {
+    fn add_to_world(&self, world: &mut World) -> Result<MaterialRef, ObjError> {
+        if self.emission != Vector3::zeros() {
+            let albedo = world.add_solid_albedo(self.emission);
+            return Ok(world.add_emitting_material(albedo, 1.0));
+        }
+
+        // Illumination model 3 is "reflection on and ray trace on".
+        if self.illumination == 3 {
+            return Ok(world.add_mirror_material());
+        }
+        // Models 4, 6 and 7 all describe glass with ray traced refraction.
+        if matches!(self.illumination, 4 | 6 | 7) {
+            return Ok(world.add_dielectric_material(self.ior));
+        }
+
+        let albedo = match &self.diffuse_map {
+            Some(path) => {
+                let texture = load_texture(path).map_err(|e| ObjError::Texture(path.clone(), e))?;
+                world.add_texture_albedo(texture)
+            }
+            None => world.add_solid_albedo(self.diffuse),
+        };
+
+        Ok(world.add_lambertian_material(albedo))
+    }
+}
+impl Default for MtlMaterial {
+    fn default() -> Self {
+        Self {
+            diffuse: Vector3::new(0.8, 0.8, 0.8),
+            diffuse_map: None,
+            emission: Vector3::zeros(),
+            ior: 1.0,
+            illumination: 2,
+        }
+    }
+}
+
+fn parse_mtl(path: &Path, source: &str, materials: &mut HashMap<String, MtlMaterial>) -> Result<(), ObjError> {
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut current = None;
+
+    for (line_i, line) in source.lines().enumerate() {
+        let mut parser = LineParser::new(path, line_i + 1, line);
+        let keyword = match parser.next_token() {
+            Some(k) => k,
+            None => continue,
+        };
+
+        if keyword == "newmtl" {
+            let name = parser.rest();
+            if name.is_empty() {
+                return Err(parser.error("Missing material name"));
+            }
+
+            materials.insert(name.to_owned(), MtlMaterial::default());
+            current = Some(name);
+            continue;
+        }
+
+        let material = match (keyword, current.and_then(|name| materials.get_mut(name))) {
+            ("Kd" | "Ke" | "Ni" | "map_Kd" | "illum", None) => return Err(parser.error("Material property before newmtl")),
+            (_, Some(m)) => m,
+            (_, None) => continue,
+        };
+
+        match keyword {
+            "Kd" => {
+                let [r, g, b] = parser.floats()?;
+                material.diffuse = Vector3::new(r, g, b);
+            }
+            "Ke" => {
+                let [r, g, b] = parser.floats()?;
+                material.emission = Vector3::new(r, g, b);
+            }
+            "Ni" => {
+                let [ior] = parser.floats()?;
+                material.ior = ior;
+            }
+            "map_Kd" => {
+                // Texture options come before the file name.
+                let file = parser.rest().split_whitespace().last()
+                    .ok_or_else(|| parser.error("Missing texture file name"))?;
+                material.diffuse_map = Some(base_dir.join(file));
+            }
+            "illum" => {
+                let token = parser.next_token().ok_or_else(|| parser.error("Missing illumination model"))?;
+                material.illumination = token.parse()
+                    .map_err(|_| parser.error(format!("Invalid illumination model '{}'", token)))?;
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+
+struct LineParser<'a> {
+    path: &'a Path,
+    line: usize,
+    rest: &'a str,
+}
+impl<'a> LineParser<'a> {
+    fn new(path: &'a Path, line: usize, source: &'a str) -> Self {
+        let without_comment = source.split('#').next().unwrap_or("");
+
+        Self {
+            path,
+            line,
+            rest: without_comment.trim(),
+        }
+    }
+
+    fn error<S: Into<String>>(&self, message: S) -> ObjError {
+        ObjError::Parse {
+            path: self.path.to_owned(),
+            line: self.line,
+            message: message.into(),
+        }
+    }
+
+    fn next_token(&mut self) -> Option<&'a str> {
+        if self.rest.is_empty() {
+            return None;
+        }
+
+        let (token, rest) = self.rest.split_once(char::is_whitespace).unwrap_or((self.rest, ""));
+        self.rest = rest.trim_start();
+        Some(token)
+    }
+    fn rest(&mut self) -> &'a str {
+        std::mem::take(&mut self.rest)
+    }
+
+    fn optional_float(&mut self) -> Result<Option<Float>, ObjError> {
+        self.next_token()
+            .map(|t| t.parse().map_err(|_| self.error(format!("Invalid number '{}'", t))))
+            .transpose()
+    }
+    fn float(&mut self) -> Result<Float, ObjError> {
+        self.optional_float()?.ok_or_else(|| self.error("Expected a number"))
+    }
+    fn floats<const N: usize>(&mut self) -> Result<[Float; N], ObjError> {
+        let mut values = [0.0; N];
+        for v in &mut values {
+            *v = self.float()?;
+        }
+        Ok(values)
+    }
+
+    /// Resolves a one-based or negative relative OBJ index into a zero-based one.
+    fn index(&self, token: &str, len: usize) -> Result<usize, ObjError> {
+        let i: isize = token.parse().map_err(|_| self.error(format!("Invalid index '{}'", token)))?;
+
+        let resolved = if i > 0 {
+            i as usize - 1
+        } else if i < 0 && i.unsigned_abs() <= len {
+            len - i.unsigned_abs()
+        } else {
+            return Err(self.error(format!("Invalid index '{}'", token)));
+        };
+
+        if resolved >= len {
+            return Err(self.error(format!("Index {} out of range, only {} elements defined so far", token, len)));
+        }
+
+        Ok(resolved)
+    }
+}
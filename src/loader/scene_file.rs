@@ -0,0 +1,977 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use image::ImageError;
+use nalgebra::{Affine3, Isometry3, Matrix3, Matrix4, Point3, Quaternion, Rotation3, Translation3, Unit, UnitQuaternion, Vector3};
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+use crate::camera::{Camera, CameraParameters};
+use crate::{Float, RenderSettings};
+use crate::loader::{load_heightmap, load_texture};
+use crate::loader::obj::{load_obj, ObjError};
+use crate::texture::{Texture2D, TextureCoord2D};
+use crate::world::albedo::{Albedo, AlbedoRef};
+use crate::world::material::Material;
+use crate::world::microfacet::ConductorIOR;
+use crate::world::principled::{ColorParameter, Principled, ScalarParameter};
+use crate::world::sdf::Sdf;
+use crate::world::shape::{CsgOperand, CsgOperation, Shape, ShapeRef};
+use crate::world::World;
+
+
+/// Loads a scene description file and builds its world and camera.
+/// Relative paths inside of the file are resolved relative to the file itself.
+pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<(World, Camera, RenderSettings), SceneFileError> {
+    let path = path.as_ref();
+    let file = SceneFile::load(path)?;
+
+    let world = file.build_world(path.parent().unwrap_or_else(|| Path::new("")))?;
+    let camera = file.build_camera();
+
+    Ok((world, camera, file.settings))
+}
+
+
+/// A declarative description of everything needed to render a scene, stored as RON.
+///
+/// Shapes, albedos and materials are named, so that they can be referenced by name from materials and objects.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SceneFile {
+    #[serde(default)]
+    pub settings: RenderSettings,
+    pub camera: CameraDescription,
+    #[serde(default)]
+    pub shapes: BTreeMap<String, ShapeDescription>,
+    #[serde(default)]
+    pub albedos: BTreeMap<String, AlbedoDescription>,
+    #[serde(default)]
+    pub materials: BTreeMap<String, MaterialDescription>,
+    #[serde(default)]
+    pub objects: Vec<ObjectDescription>,
+    /// Other files whose contents get added to the world, after the objects.
+    #[serde(default)]
+    pub imports: Vec<ImportDescription>,
+}
+impl SceneFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SceneFileError> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path).map_err(|e| SceneFileError::Io(path.to_owned(), e))?;
+        Self::parse(&source)
+    }
+    pub fn parse(source: &str) -> Result<Self, SceneFileError> {
+        ron::from_str(source).map_err(SceneFileError::Parse)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SceneFileError> {
+        let path = path.as_ref();
+        let source = self.to_ron_string()?;
+        std::fs::write(path, source).map_err(|e| SceneFileError::Io(path.to_owned(), e))
+    }
+    pub fn to_ron_string(&self) -> Result<String, SceneFileError> {
+        ron::ser::to_string_pretty(self, PrettyConfig::default()).map_err(SceneFileError::Serialize)
+    }
+
+
+    /// Describes the current contents of `world`.
+    /// Textures are stored inline, since the world doesn't know where they were loaded from.
+    pub fn from_world(world: &World, camera: &Camera, settings: RenderSettings) -> Self {
+        let shape_names: HashMap<_, _> = world.shapes.iter().enumerate()
+            .map(|(i, (index, _))| (index, format!("shape{}", i)))
+            .collect();
+        let albedo_names: HashMap<_, _> = world.albedos.iter().enumerate()
+            .map(|(i, (index, _))| (index, format!("albedo{}", i)))
+            .collect();
+        let material_names: HashMap<_, _> = world.materials.iter().enumerate()
+            .map(|(i, (index, _))| (index, format!("material{}", i)))
+            .collect();
+
+        let shapes = world.shapes.iter()
+            .map(|(index, shape)| (shape_names[&index].clone(), ShapeDescription::from_shape(shape, &|s| shape_names[&s.0].clone())))
+            .collect();
+        let albedos = world.albedos.iter()
+            .map(|(index, albedo)| (albedo_names[&index].clone(), AlbedoDescription::from_albedo(albedo)))
+            .collect();
+        let materials = world.materials.iter()
+            .map(|(index, material)| {
+                let description = match material {
+                    Material::Lambertian(a) => MaterialDescription::Lambertian(albedo_names[&a.0].clone()),
+                    Material::Mirror => MaterialDescription::Mirror,
+                    Material::Emitting(a, factor) => MaterialDescription::Emitting {
+                        albedo: albedo_names[&a.0].clone(),
+                        factor: *factor,
+                    },
+                    Material::Dielectric { ior } => MaterialDescription::Dielectric { ior: *ior },
+                    Material::Conductor { ior, roughness_u, roughness_v } => MaterialDescription::Conductor {
+                        ior: ConductorDescription::Complex {
+                            eta: ior.eta.into(),
+                            k: ior.k.into(),
+                        },
+                        roughness: *roughness_u,
+                        roughness_v: Some(*roughness_v),
+                    },
+                    Material::Principled(p) => {
+                        MaterialDescription::Principled(Box::new(PrincipledDescription::from_principled(p, &|a| albedo_names[&a.0].clone())))
+                    }
+                };
+                (material_names[&index].clone(), description)
+            })
+            .collect();
+        let objects = world.objects.iter()
+            .map(|(_, o)| ObjectDescription {
+                shape: shape_names[&o.shape.0].clone(),
+                material: material_names[&o.material.0].clone(),
+                transform: TransformDescription::from_affine(&o.transform),
+            })
+            .collect();
+
+        Self {
+            settings,
+            camera: CameraDescription::from_parameters(camera.parameters(), &settings),
+            shapes,
+            albedos,
+            materials,
+            objects,
+            imports: Vec::new(),
+        }
+    }
+
+    /// Builds the described world, resolving relative paths from `base_dir`.
+    pub fn build_world<P: AsRef<Path>>(&self, base_dir: P) -> Result<World, SceneFileError> {
+        let base_dir = base_dir.as_ref();
+        let mut world = World::new();
+
+        let mut shapes = HashMap::new();
+        for name in self.shapes.keys() {
+            self.add_shape(name, base_dir, &mut world, &mut shapes, &mut Vec::new())?;
+        }
+
+        let mut albedos = HashMap::new();
+        for (name, albedo) in &self.albedos {
+            let a = match albedo {
+                AlbedoDescription::Solid(c) => world.add_solid_albedo(Vector3::from(*c)),
+                AlbedoDescription::Texture(path) => {
+                    let path = base_dir.join(path);
+                    let texture = load_texture(&path).map_err(|e| SceneFileError::Texture(path, e))?;
+                    world.add_texture_albedo(texture)
+                }
+                AlbedoDescription::Pixels { width, height, pixels } => {
+                    if pixels.len() != *width as usize * *height as usize {
+                        return Err(SceneFileError::InvalidTexture(name.clone()));
+                    }
+
+                    let pixels = pixels.iter().map(|p| Vector3::from(*p)).collect();
+                    world.add_texture_albedo(Texture2D::new_from_pixels(*width, *height, pixels))
+                }
+            };
+            albedos.insert(name, a);
+        }
+        let albedo = |name: &String| albedos.get(name).copied().ok_or_else(|| SceneFileError::unknown("albedo", name));
+
+        let mut materials = HashMap::new();
+        for (name, material) in &self.materials {
+            let invalid_material = |message: &str| SceneFileError::InvalidMaterial {
+                material: name.clone(),
+                message: message.to_owned(),
+            };
+
+            let m = match material {
+                MaterialDescription::Lambertian(a) => world.add_lambertian_material(albedo(a)?),
+                MaterialDescription::Mirror => world.add_mirror_material(),
+                MaterialDescription::Emitting { albedo: a, factor } => world.add_emitting_material(albedo(a)?, *factor),
+                MaterialDescription::Dielectric { ior } => {
+                    let valid = *ior > 0.0;
+                    if !valid {
+                        return Err(invalid_material("Needs a positive index of refraction"));
+                    }
+
+                    world.add_dielectric_material(*ior)
+                }
+                MaterialDescription::Conductor { ior, roughness, roughness_v } => {
+                    let roughness_v = roughness_v.unwrap_or(*roughness);
+                    if ![*roughness, roughness_v].iter().all(|r| (0.0..=1.0).contains(r)) {
+                        return Err(invalid_material("Roughness must be between 0 and 1"));
+                    }
+
+                    world.add_conductor_material(ior.to_ior(), *roughness, roughness_v)
+                }
+                MaterialDescription::Principled(p) => {
+                    let principled = p.to_principled(name, &albedo)?;
+                    world.add_principled_material(principled)
+                }
+            };
+            materials.insert(name, m);
+        }
+
+        for object in &self.objects {
+            let shape = shapes.get(object.shape.as_str()).ok_or_else(|| SceneFileError::unknown("shape", &object.shape))?;
+            let material = materials.get(&object.material).ok_or_else(|| SceneFileError::unknown("material", &object.material))?;
+
+            world.add_affine_object(*shape, *material, object.transform.to_affine()?);
+        }
+
+        for import in &self.imports {
+            match import {
+                ImportDescription::Obj { path, transform } => {
+                    load_obj(base_dir.join(path), &mut world, transform.to_affine()?).map_err(SceneFileError::Obj)?;
+                }
+            }
+        }
+
+        Ok(world)
+    }
+
+    /// Adds the shape called `name` unless it was added already, after the shapes it is made of.
+    /// `pending` are the shapes waiting for this one, which it can't be made of in turn.
+    fn add_shape<'s>(
+        &'s self,
+        name: &'s str,
+        base_dir: &Path,
+        world: &mut World,
+        shapes: &mut HashMap<&'s str, ShapeRef>,
+        pending: &mut Vec<&'s str>,
+    ) -> Result<ShapeRef, SceneFileError> {
+        if let Some(shape) = shapes.get(name) {
+            return Ok(*shape);
+        }
+        let (name, description) = self.shapes.get_key_value(name).ok_or_else(|| SceneFileError::unknown("shape", name))?;
+        if pending.contains(&name.as_str()) {
+            return Err(SceneFileError::InvalidShape {
+                shape: name.clone(),
+                message: "Is made of itself".to_owned(),
+            });
+        }
+
+        pending.push(name);
+        let shape = description.add_to_world(name, base_dir, world, &mut |operand, world| self.add_shape(operand, base_dir, world, shapes, pending))?;
+        pending.pop();
+
+        shapes.insert(name, shape);
+        Ok(shape)
+    }
+
+    pub fn build_camera(&self) -> Camera {
+        Camera::from_parameters(self.camera.to_parameters(&self.settings))
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CameraDescription {
+    pub look_from: [Float; 3],
+    pub look_at: [Float; 3],
+    #[serde(default = "default_up")]
+    pub up: [Float; 3],
+    /// The vertical field of view in degrees.
+    pub vfov: Float,
+    /// Defaults to the aspect ratio of the rendered image.
+    #[serde(default)]
+    pub aspect_ratio: Option<Float>,
+}
+impl CameraDescription {
+    fn from_parameters(p: CameraParameters, settings: &RenderSettings) -> Self {
+        Self {
+            look_from: p.look_from.into(),
+            look_at: p.look_at.into(),
+            up: p.up.into(),
+            vfov: p.vfov_radians.to_degrees(),
+            aspect_ratio: Some(p.aspect_ratio).filter(|&a| a != settings.aspect_ratio()),
+        }
+    }
+    fn to_parameters(&self, settings: &RenderSettings) -> CameraParameters {
+        CameraParameters {
+            look_from: Point3::from(self.look_from),
+            look_at: Point3::from(self.look_at),
+            up: Vector3::from(self.up),
+            vfov_radians: self.vfov.to_radians(),
+            aspect_ratio: self.aspect_ratio.unwrap_or_else(|| settings.aspect_ratio()),
+        }
+    }
+}
+fn default_up() -> [Float; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ShapeDescription {
+    Sphere {
+        radius: Float,
+    },
+    TriangleMesh {
+        positions: Vec<[Float; 3]>,
+        indices: Vec<[usize; 3]>,
+        #[serde(default)]
+        normals: Option<Vec<[Float; 3]>>,
+        #[serde(default)]
+        tex_coords: Option<Vec<[Float; 2]>>,
+    },
+    /// A parallelogram spanned by two edges from a corner, facing along the cross product of the edges.
+    Quad {
+        corner: [Float; 3],
+        edge_u: [Float; 3],
+        edge_v: [Float; 3],
+    },
+    Disk {
+        center: [Float; 3],
+        normal: [Float; 3],
+        radius: Float,
+    },
+    /// A solid axis aligned box.
+    Box {
+        min: [Float; 3],
+        max: [Float; 3],
+    },
+    Cylinder {
+        radius: Float,
+        height: Float,
+        capped: bool,
+    },
+    /// A cone that comes to a point at the top, unless it has a top radius.
+    Cone {
+        base_radius: Float,
+        #[serde(default)]
+        top_radius: Float,
+        height: Float,
+        capped: bool,
+    },
+    Torus {
+        major_radius: Float,
+        minor_radius: Float,
+    },
+    /// The height is the one of the cylinder between the hemispheres.
+    Capsule {
+        radius: Float,
+        height: Float,
+    },
+    /// The union, intersection or difference of two other shapes, which are referenced by name.
+    Csg {
+        op: CsgOperation,
+        a: Box<CsgOperandDescription>,
+        b: Box<CsgOperandDescription>,
+    },
+    /// A signed distance field, which is only traced within the box from `min` to `max`.
+    Sdf {
+        sdf: Sdf,
+        min: [Float; 3],
+        max: [Float; 3],
+    },
+    /// Terrain over the xz-plane, with the pixels of the map `horizontal_scale` apart.
+    Heightfield {
+        heights: HeightmapDescription,
+        horizontal_scale: Float,
+        vertical_scale: Float,
+    },
+}
+impl ShapeDescription {
+    fn from_shape(shape: &Shape, shape_name: &dyn Fn(ShapeRef) -> String) -> Self {
+        match shape {
+            Shape::Sphere { radius } => Self::Sphere { radius: *radius },
+            Shape::TriangleMesh { positions, indices, normals, tex_coords } => Self::TriangleMesh {
+                positions: positions.iter().map(|&p| p.into()).collect(),
+                indices: indices.clone(),
+                normals: normals.as_ref().map(|n| n.iter().map(|&n| n.into()).collect()),
+                tex_coords: tex_coords.as_ref().map(|t| t.iter().map(|t| [t.x, t.y]).collect()),
+            },
+            Shape::Quad { corner, edge_u, edge_v } => Self::Quad {
+                corner: (*corner).into(),
+                edge_u: (*edge_u).into(),
+                edge_v: (*edge_v).into(),
+            },
+            Shape::Disk { center, normal, radius } => Self::Disk {
+                center: (*center).into(),
+                normal: normal.into_inner().into(),
+                radius: *radius,
+            },
+            Shape::Box { min, max } => Self::Box {
+                min: (*min).into(),
+                max: (*max).into(),
+            },
+            Shape::Cylinder { radius, height, capped } => Self::Cylinder { radius: *radius, height: *height, capped: *capped },
+            Shape::Cone { base_radius, top_radius, height, capped } => Self::Cone {
+                base_radius: *base_radius,
+                top_radius: *top_radius,
+                height: *height,
+                capped: *capped,
+            },
+            Shape::Torus { major_radius, minor_radius } => Self::Torus { major_radius: *major_radius, minor_radius: *minor_radius },
+            Shape::Capsule { radius, height } => Self::Capsule { radius: *radius, height: *height },
+            Shape::Csg { op, a, b } => {
+                let operand = |o: &CsgOperand| Box::new(CsgOperandDescription {
+                    shape: shape_name(o.shape),
+                    transform: TransformDescription::from_affine(&Affine3::from_matrix_unchecked(o.transform.to_homogeneous())),
+                });
+                Self::Csg { op: *op, a: operand(a), b: operand(b) }
+            }
+            Shape::Sdf { sdf, min, max } => Self::Sdf {
+                sdf: sdf.clone(),
+                min: (*min).into(),
+                max: (*max).into(),
+            },
+            Shape::Heightfield { heights, horizontal_scale, vertical_scale } => Self::Heightfield {
+                heights: HeightmapDescription::Samples {
+                    width: heights.width(),
+                    height: heights.height(),
+                    heights: heights.pixels().copied().collect(),
+                },
+                horizontal_scale: *horizontal_scale,
+                vertical_scale: *vertical_scale,
+            },
+        }
+    }
+
+    /// Shapes this one is made of are added with `add_operand`, which is given their names.
+    /// Relative paths are resolved from `base_dir`.
+    fn add_to_world<'s>(
+        &'s self,
+        name: &str,
+        base_dir: &Path,
+        world: &mut World,
+        add_operand: &mut dyn FnMut(&'s str, &mut World) -> Result<ShapeRef, SceneFileError>,
+    ) -> Result<ShapeRef, SceneFileError> {
+        let invalid_shape = |message: &str| SceneFileError::InvalidShape {
+            shape: name.to_owned(),
+            message: message.to_owned(),
+        };
+
+        match self {
+            Self::Sphere { radius } => {
+                let valid = *radius > 0.0;
+                if !valid {
+                    return Err(invalid_shape("Needs a positive radius"));
+                }
+
+                Ok(world.add_sphere(*radius))
+            }
+            Self::TriangleMesh { positions, indices, normals, tex_coords } => {
+                let invalid = |message: &str| SceneFileError::InvalidMesh {
+                    shape: name.to_owned(),
+                    message: message.to_owned(),
+                };
+
+                if indices.iter().flatten().any(|&i| i >= positions.len()) {
+                    return Err(invalid("Index out of bounds"));
+                }
+                if normals.as_ref().is_some_and(|n| n.len() != positions.len()) {
+                    return Err(invalid("Needs exactly one normal per position"));
+                }
+                if tex_coords.as_ref().is_some_and(|t| t.len() != positions.len()) {
+                    return Err(invalid("Needs exactly one texture coordinate per position"));
+                }
+
+                Ok(world.add_triangle_mesh(
+                    positions.iter().map(|&p| Point3::from(p)).collect(),
+                    indices.clone(),
+                    normals.as_ref().map(|n| n.iter().map(|&n| Vector3::from(n)).collect()),
+                    tex_coords.as_ref().map(|t| t.iter().map(|&[x, y]| TextureCoord2D::new(x, y)).collect()),
+                ))
+            }
+            Self::Quad { corner, edge_u, edge_v } => {
+                let (edge_u, edge_v) = (Vector3::from(*edge_u), Vector3::from(*edge_v));
+                let valid = edge_u.cross(&edge_v).magnitude_squared() > 0.0;
+                if !valid {
+                    return Err(invalid_shape("Edges must not be parallel"));
+                }
+
+                Ok(world.add_quad(Point3::from(*corner), edge_u, edge_v))
+            }
+            Self::Disk { center, normal, radius } => {
+                let normal = Vector3::from(*normal);
+                let valid = normal.magnitude_squared() > 0.0 && *radius > 0.0;
+                if !valid {
+                    return Err(invalid_shape("Needs a normal and a positive radius"));
+                }
+
+                Ok(world.add_disk(Point3::from(*center), normal, *radius))
+            }
+            Self::Box { min, max } => {
+                if !(0..3).all(|a| min[a] < max[a]) {
+                    return Err(invalid_shape("Min must be below max along every axis"));
+                }
+
+                Ok(world.add_box(Point3::from(*min), Point3::from(*max)))
+            }
+            Self::Cylinder { radius, height, capped } => {
+                if !(*radius > 0.0 && *height > 0.0) {
+                    return Err(invalid_shape("Needs a positive radius and height"));
+                }
+
+                Ok(world.add_cylinder(*radius, *height, *capped))
+            }
+            Self::Cone { base_radius, top_radius, height, capped } => {
+                let valid = *base_radius >= 0.0 && *top_radius >= 0.0 && base_radius + top_radius > 0.0 && *height > 0.0;
+                if !valid {
+                    return Err(invalid_shape("Needs radii that aren't negative or both zero, and a positive height"));
+                }
+
+                Ok(world.add_cone(*base_radius, *top_radius, *height, *capped))
+            }
+            Self::Torus { major_radius, minor_radius } => {
+                if !(*minor_radius > 0.0 && minor_radius < major_radius) {
+                    return Err(invalid_shape("Needs a positive minor radius below the major radius"));
+                }
+
+                Ok(world.add_torus(*major_radius, *minor_radius))
+            }
+            Self::Capsule { radius, height } => {
+                if !(*radius > 0.0 && *height >= 0.0) {
+                    return Err(invalid_shape("Needs a positive radius and a height that isn't negative"));
+                }
+
+                Ok(world.add_capsule(*radius, *height))
+            }
+            Self::Csg { op, a, b } => {
+                let mut operand = |o: &'s CsgOperandDescription, world: &mut World| {
+                    let transform = o.transform.to_isometry()?.ok_or_else(|| invalid_shape("Operands can only be moved and rotated"))?;
+                    Ok(CsgOperand::new(add_operand(&o.shape, world)?, transform))
+                };
+                let (a, b) = (operand(a, world)?, operand(b, world)?);
+
+                Ok(world.add_csg(*op, a, b))
+            }
+            Self::Sdf { sdf, min, max } => {
+                if !(0..3).all(|a| min[a] < max[a]) {
+                    return Err(invalid_shape("Min must be below max along every axis"));
+                }
+                sdf.validate().map_err(invalid_shape)?;
+
+                Ok(world.add_sdf(sdf.clone(), Point3::from(*min), Point3::from(*max)))
+            }
+            Self::Heightfield { heights, horizontal_scale, vertical_scale } => {
+                let heights = match heights {
+                    HeightmapDescription::Image(path) => {
+                        let path = base_dir.join(path);
+                        load_heightmap(&path).map_err(|e| SceneFileError::Texture(path, e))?
+                    }
+                    HeightmapDescription::Samples { width, height, heights } => {
+                        if heights.len() != *width as usize * *height as usize {
+                            return Err(invalid_shape("Sample count doesn't match the size of the map"));
+                        }
+
+                        Texture2D::new_from_pixels(*width, *height, heights.clone())
+                    }
+                };
+                if !(heights.width() >= 2 && heights.height() >= 2 && *horizontal_scale > 0.0) {
+                    return Err(invalid_shape("Needs a map of at least 2 by 2 pixels and a positive horizontal scale"));
+                }
+
+                Ok(world.add_heightfield(heights, *horizontal_scale, *vertical_scale))
+            }
+        }
+    }
+}
+
+
+/// The heights of a heightfield, from 0 to 1 for image files.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HeightmapDescription {
+    /// An image file, which is turned into grayscale.
+    Image(PathBuf),
+    /// Inline heights, row by row starting at the upper left corner.
+    Samples {
+        width: u32,
+        height: u32,
+        heights: Vec<Float>,
+    },
+}
+
+
+/// A shape as part of another one, placed without scaling it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CsgOperandDescription {
+    pub shape: String,
+    #[serde(default)]
+    pub transform: TransformDescription,
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AlbedoDescription {
+    Solid([Float; 3]),
+    /// An image file.
+    Texture(PathBuf),
+    /// Inline texture data, row by row starting at the upper left corner.
+    Pixels {
+        width: u32,
+        height: u32,
+        pixels: Vec<[Float; 3]>,
+    },
+}
+impl AlbedoDescription {
+    fn from_albedo(albedo: &Albedo) -> Self {
+        match albedo {
+            Albedo::SolidColor(c) => Self::Solid((*c).into()),
+            Albedo::Texture(t) => Self::Pixels {
+                width: t.width(),
+                height: t.height(),
+                pixels: t.pixels().map(|&p| p.into()).collect(),
+            },
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MaterialDescription {
+    Lambertian(String),
+    Mirror,
+    Emitting {
+        albedo: String,
+        factor: Float,
+    },
+    Dielectric {
+        ior: Float,
+    },
+    /// A rough metal. `roughness_v` defaults to `roughness`, which gives isotropic roughness.
+    Conductor {
+        ior: ConductorDescription,
+        roughness: Float,
+        #[serde(default)]
+        roughness_v: Option<Float>,
+    },
+    Principled(Box<PrincipledDescription>),
+}
+
+
+/// All parameters are optional and default to those of [Principled::default].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrincipledDescription {
+    pub base_color: ColorDescription,
+    pub metallic: ScalarDescription,
+    pub roughness: ScalarDescription,
+    pub specular: ScalarDescription,
+    pub specular_tint: ScalarDescription,
+    pub sheen: ScalarDescription,
+    pub sheen_tint: ScalarDescription,
+    pub clearcoat: ScalarDescription,
+    pub clearcoat_roughness: ScalarDescription,
+    pub transmission: ScalarDescription,
+    pub ior: Float,
+    pub emission: ColorDescription,
+    pub emission_strength: Float,
+}
+impl PrincipledDescription {
+    pub fn from_principled(p: &Principled, albedo_name: &dyn Fn(AlbedoRef) -> String) -> Self {
+        let color = |c: &ColorParameter| match c {
+            ColorParameter::Constant(c) => ColorDescription::Constant((*c).into()),
+            ColorParameter::Texture(a) => ColorDescription::Texture(albedo_name(*a)),
+        };
+        let scalar = |s: &ScalarParameter| match s {
+            ScalarParameter::Constant(c) => ScalarDescription::Constant(*c),
+            ScalarParameter::Texture(a, channel) => ScalarDescription::Texture {
+                albedo: albedo_name(*a),
+                channel: *channel,
+            },
+        };
+
+        Self {
+            base_color: color(&p.base_color),
+            metallic: scalar(&p.metallic),
+            roughness: scalar(&p.roughness),
+            specular: scalar(&p.specular),
+            specular_tint: scalar(&p.specular_tint),
+            sheen: scalar(&p.sheen),
+            sheen_tint: scalar(&p.sheen_tint),
+            clearcoat: scalar(&p.clearcoat),
+            clearcoat_roughness: scalar(&p.clearcoat_roughness),
+            transmission: scalar(&p.transmission),
+            ior: p.ior,
+            emission: color(&p.emission),
+            emission_strength: p.emission_strength,
+        }
+    }
+
+    fn to_principled(
+        &self,
+        material: &str,
+        albedo: &dyn Fn(&String) -> Result<AlbedoRef, SceneFileError>,
+    ) -> Result<Principled, SceneFileError> {
+        let color = |c: &ColorDescription| match c {
+            ColorDescription::Constant(c) => Ok(ColorParameter::Constant(Vector3::from(*c))),
+            ColorDescription::Texture(a) => Ok(ColorParameter::Texture(albedo(a)?)),
+        };
+        let scalar = |s: &ScalarDescription| match s {
+            ScalarDescription::Constant(c) => Ok(ScalarParameter::Constant(*c)),
+            ScalarDescription::Texture { albedo: a, channel } => {
+                if *channel >= 3 {
+                    return Err(SceneFileError::InvalidChannel {
+                        material: material.to_owned(),
+                        channel: *channel,
+                    });
+                }
+                Ok(ScalarParameter::Texture(albedo(a)?, *channel))
+            }
+        };
+
+        Ok(Principled {
+            base_color: color(&self.base_color)?,
+            metallic: scalar(&self.metallic)?,
+            roughness: scalar(&self.roughness)?,
+            specular: scalar(&self.specular)?,
+            specular_tint: scalar(&self.specular_tint)?,
+            sheen: scalar(&self.sheen)?,
+            sheen_tint: scalar(&self.sheen_tint)?,
+            clearcoat: scalar(&self.clearcoat)?,
+            clearcoat_roughness: scalar(&self.clearcoat_roughness)?,
+            transmission: scalar(&self.transmission)?,
+            ior: self.ior,
+            emission: color(&self.emission)?,
+            emission_strength: self.emission_strength,
+        })
+    }
+}
+impl Default for PrincipledDescription {
+    fn default() -> Self {
+        Self::from_principled(&Principled::default(), &|_| unreachable!("The default has no textures"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ColorDescription {
+    Constant([Float; 3]),
+    /// The name of an albedo.
+    Texture(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ScalarDescription {
+    Constant(Float),
+    /// One channel of an albedo, 0 to 2 for red, green and blue.
+    Texture {
+        albedo: String,
+        channel: usize,
+    },
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ConductorDescription {
+    /// The complex index of refraction per RGB channel.
+    Complex {
+        eta: [Float; 3],
+        k: [Float; 3],
+    },
+    /// The color at normal incidence and towards grazing angles.
+    EdgeTint {
+        reflectivity: [Float; 3],
+        edge_tint: [Float; 3],
+    },
+}
+impl ConductorDescription {
+    pub fn to_ior(&self) -> ConductorIOR {
+        match self {
+            Self::Complex { eta, k } => ConductorIOR {
+                eta: Vector3::from(*eta),
+                k: Vector3::from(*k),
+            },
+            Self::EdgeTint { reflectivity, edge_tint } => {
+                ConductorIOR::from_edge_tint(Vector3::from(*reflectivity), Vector3::from(*edge_tint))
+            }
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ObjectDescription {
+    pub shape: String,
+    pub material: String,
+    #[serde(default)]
+    pub transform: TransformDescription,
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ImportDescription {
+    /// A Wavefront OBJ file and its materials.
+    Obj {
+        path: PathBuf,
+        #[serde(default)]
+        transform: TransformDescription,
+    },
+}
+
+
+/// Scales, then rotates and then translates.
+/// Transforms that can't be described this way, like shearing, are given as a `matrix` instead.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TransformDescription {
+    #[serde(default)]
+    pub translation: [Float; 3],
+    #[serde(default)]
+    pub rotation: RotationDescription,
+    /// The scale along each axis, negative to mirror.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<[Float; 3]>,
+    /// The top three rows of an affine transformation matrix, which replaces everything else.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<[[Float; 4]; 3]>,
+}
+impl TransformDescription {
+    fn from_affine(affine: &Affine3<Float>) -> Self {
+        let m = affine.matrix();
+        let linear: Matrix3<Float> = m.fixed_slice::<3, 3>(0, 0).into();
+        let translation = [m[(0, 3)], m[(1, 3)], m[(2, 3)]];
+
+        // Try to split the linear part into a rotation and a scale along each axis.
+        let mut scale = Vector3::from_fn(|i, _| linear.column(i).magnitude());
+        if linear.determinant() < 0.0 {
+            scale.x = -scale.x;
+        }
+        let rotation = linear * Matrix3::from_diagonal(&scale.map(|s| 1.0 / s));
+        let is_rotation = scale.iter().all(|s| *s != 0.0)
+            && (rotation.transpose() * rotation - Matrix3::identity()).amax() < 1.0e-5;
+
+        if !is_rotation {
+            return Self {
+                matrix: Some([0, 1, 2].map(|r| [m[(r, 0)], m[(r, 1)], m[(r, 2)], m[(r, 3)]])),
+                ..Default::default()
+            };
+        }
+
+        let rotation = UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(rotation));
+        let rotation = if rotation.angle() < 1.0e-6 {
+            RotationDescription::Identity
+        } else {
+            let q = rotation.quaternion();
+            RotationDescription::Quaternion([q.i, q.j, q.k, q.w])
+        };
+        let is_unscaled = (scale - Vector3::repeat(1.0)).amax() < 1.0e-5;
+
+        Self {
+            translation,
+            rotation,
+            scale: if is_unscaled { None } else { Some(scale.into()) },
+            matrix: None,
+        }
+    }
+    fn to_affine(&self) -> Result<Affine3<Float>, SceneFileError> {
+        if let Some(rows) = self.matrix {
+            let mut m = Matrix4::identity();
+            for (r, row) in rows.iter().enumerate() {
+                for (c, v) in row.iter().enumerate() {
+                    m[(r, c)] = *v;
+                }
+            }
+            return Ok(Affine3::from_matrix_unchecked(m));
+        }
+
+        let isometry = Isometry3::from_parts(Translation3::from(self.translation), self.rotation.to_quaternion()?);
+        let scale = Vector3::from(self.scale.unwrap_or([1.0; 3]));
+
+        Ok(Affine3::from_matrix_unchecked(isometry.to_homogeneous() * Matrix4::new_nonuniform_scaling(&scale)))
+    }
+    /// The transform if it only translates and rotates.
+    fn to_isometry(&self) -> Result<Option<Isometry3<Float>>, SceneFileError> {
+        let unscaled = self.scale.unwrap_or([1.0; 3]) == [1.0; 3];
+        if self.matrix.is_some() || !unscaled {
+            return Ok(None);
+        }
+
+        Ok(Some(Isometry3::from_parts(Translation3::from(self.translation), self.rotation.to_quaternion()?)))
+    }
+}
+
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum RotationDescription {
+    #[default]
+    Identity,
+    /// Roll, pitch and yaw in degrees.
+    Euler([Float; 3]),
+    AxisAngle {
+        axis: [Float; 3],
+        degrees: Float,
+    },
+    /// The i, j, k and w components of a unit quaternion.
+    Quaternion([Float; 4]),
+}
+impl RotationDescription {
+    fn to_quaternion(&self) -> Result<UnitQuaternion<Float>, SceneFileError> {
+        let invalid = |message: &str| SceneFileError::InvalidRotation(message.to_owned());
+
+        Ok(match self {
+            Self::Identity => UnitQuaternion::identity(),
+            Self::Euler([roll, pitch, yaw]) => UnitQuaternion::from_euler_angles(roll.to_radians(), pitch.to_radians(), yaw.to_radians()),
+            Self::AxisAngle { axis, degrees } => {
+                let axis = Unit::try_new(Vector3::from(*axis), 0.0).ok_or_else(|| invalid("Axis must not be zero"))?;
+                UnitQuaternion::from_axis_angle(&axis, degrees.to_radians())
+            }
+            Self::Quaternion([i, j, k, w]) => {
+                Unit::try_new(Quaternion::new(*w, *i, *j, *k), 0.0).ok_or_else(|| invalid("Quaternion must not be zero"))?
+            }
+        })
+    }
+}
+
+
+#[derive(Debug)]
+pub enum SceneFileError {
+    Io(PathBuf, io::Error),
+    Parse(ron::error::SpannedError),
+    Serialize(ron::Error),
+    UnknownReference {
+        kind: &'static str,
+        name: String,
+    },
+    InvalidMesh {
+        shape: String,
+        message: String,
+    },
+    InvalidShape {
+        shape: String,
+        message: String,
+    },
+    InvalidMaterial {
+        material: String,
+        message: String,
+    },
+    InvalidRotation(String),
+    InvalidTexture(String),
+    InvalidChannel {
+        material: String,
+        channel: usize,
+    },
+    Texture(PathBuf, ImageError),
+    Obj(ObjError),
+}
+impl SceneFileError {
+    fn unknown(kind: &'static str, name: &str) -> Self {
+        Self::UnknownReference {
+            kind,
+            name: name.to_owned(),
+        }
+    }
+}
+impl Display for SceneFileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "Could not access {}: {}", path.display(), e),
+            Self::Parse(e) => write!(f, "Invalid scene file: {}", e),
+            Self::Serialize(e) => write!(f, "Could not serialize scene: {}", e),
+            Self::UnknownReference { kind, name } => write!(f, "Unknown {} '{}'", kind, name),
+            Self::InvalidMesh { shape, message } => write!(f, "Invalid mesh '{}': {}", shape, message),
+            Self::InvalidShape { shape, message } => write!(f, "Invalid shape '{}': {}", shape, message),
+            Self::InvalidMaterial { material, message } => write!(f, "Invalid material '{}': {}", material, message),
+            Self::InvalidRotation(message) => write!(f, "Invalid rotation: {}", message),
+            Self::InvalidTexture(name) => write!(f, "Pixel count of albedo '{}' doesn't match its size", name),
+            Self::InvalidChannel { material, channel } => write!(f, "Material '{}' uses channel {}, but albedos only have 3", material, channel),
+            Self::Texture(path, e) => write!(f, "Could not load texture {}: {}", path.display(), e),
+            Self::Obj(e) => e.fmt(f),
+        }
+    }
+}
+impl Error for SceneFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(_, e) => Some(e),
+            Self::Parse(e) => Some(e),
+            Self::Serialize(e) => Some(e),
+            Self::Texture(_, e) => Some(e),
+            Self::Obj(e) => Some(e),
+            _ => None,
+        }
+    }
+}
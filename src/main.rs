@@ -1,76 +1,222 @@
 #![allow(dead_code)]
 
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::time::Instant;
-use image::{ImageFormat};
-use nalgebra::{Isometry3, Point3, Unit, vector, Vector3};
+use clap::{Parser, ValueEnum};
+use image::ImageFormat;
+use nalgebra::{Isometry3, Point3, vector, Vector3};
 use reflection::camera::Camera;
-use reflection::{Float, render, RenderDescriptor};
-use rand::prelude::*;
-use rand_distr::UnitSphere;
+use reflection::{Float, render, RenderDescriptor, RenderSettings};
+use reflection::integrator::Integrator;
+use reflection::integrator::normal_integrator::NormalIntegrator;
 use reflection::integrator::path_integrator::PathTracingIntegrator;
-use reflection::randomness::{Randomness, SeedingRandomness};
-use reflection::texture::{Texture2D};
-use reflection::world::albedo::AlbedoRef;
+use reflection::loader::load_texture;
+use reflection::loader::scene_file::SceneFile;
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::scene::bvh::BVHLayout;
+use reflection::scene::Scene;
+use reflection::texture::Texture2D;
 use reflection::world::material::MaterialRef;
 use reflection::world::World;
 
 
-const WIDTH: u32 = 1920;
-const HEIGHT: u32 = 1080;
-const SAMPLES: u32 = 1024;
-const DEPTH: u32 = 4;
-const ASPECT: Float = WIDTH as Float / HEIGHT as Float;
+/// Renders a scene description file with a path tracer.
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// The scene description file to render. Renders the built in spheres scene if omitted.
+    scene: Option<PathBuf>,
+
+    /// Image width in pixels, overriding the scene file.
+    #[arg(long)]
+    width: Option<u32>,
+    /// Image height in pixels, overriding the scene file.
+    #[arg(long)]
+    height: Option<u32>,
+    /// Samples per pixel, overriding the scene file.
+    #[arg(short, long)]
+    samples: Option<u32>,
+    /// Maximum path depth, overriding the scene file.
+    #[arg(short, long)]
+    depth: Option<u32>,
+    /// Path depth after which russian roulette may end paths, overriding the scene file.
+    #[arg(long)]
+    min_depth: Option<u32>,
+
+    #[arg(short, long, value_enum, default_value_t = IntegratorChoice::Path)]
+    integrator: IntegratorChoice,
+    #[arg(long, default_value_t = 100)]
+    seed: u64,
+    /// Amount of render threads. Defaults to one per logical core.
+    #[arg(short = 'j', long)]
+    threads: Option<usize>,
+    #[arg(long, default_value_t = 2.2)]
+    gamma: Float,
+
+    /// How the BVH nodes are laid out for traversal.
+    #[arg(long, value_enum, default_value_t = BVHChoice::Binary)]
+    bvh: BVHChoice,
+    /// A file to keep the BVHs of the scene in, so that later renders of the same geometry don't have to build them again.
+    #[arg(long)]
+    bvh_cache: Option<PathBuf>,
+
+    /// Where to write the image. Defaults to a name made up of the render settings inside of `images`.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Image format, given as its usual file extension. Guessed from the output path if omitted.
+    #[arg(short, long, value_parser = parse_format)]
+    format: Option<ImageFormat>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum IntegratorChoice {
+    Path,
+    Normal,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum BVHChoice {
+    Binary,
+    Wide,
+}
+
+fn parse_format(s: &str) -> Result<ImageFormat, String> {
+    ImageFormat::from_extension(s).ok_or_else(|| format!("Unknown image format '{}'", s))
+}
 
-const RNG_SEED: u64 = 100;
-const GAMMA: Float = 2.2;
 
-fn main() {
-    let out_dir = PathBuf::from("images");
-    if !out_dir.exists() {
-        std::fs::create_dir("images").unwrap();
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
     }
+}
 
-    let rng = StdRng::seed_from_u64(RNG_SEED);
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
 
-    let mut randomness = DefaultRandomness { rng };
+    let mut randomness = DefaultRandomness::new(args.seed);
 
-    let (world, camera) = build_world(&mut randomness);
+    let (world, camera, settings) = match &args.scene {
+        Some(path) => {
+            let mut file = SceneFile::load(path)?;
+            apply_overrides(&args, &mut file.settings);
+
+            let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+            let world = file.build_world(base_dir)?;
+            (world, file.build_camera(), file.settings)
+        }
+        None => {
+            let mut settings = RenderSettings::default();
+            apply_overrides(&args, &mut settings);
+
+            let (world, camera) = build_world(&mut randomness, settings.aspect_ratio())?;
+            (world, camera, settings)
+        }
+    };
+
+    if settings.width == 0 || settings.height == 0 || settings.samples == 0 {
+        return Err("Width, height and samples must be at least 1".into());
+    }
+
+    let output = args.output.clone().unwrap_or_else(|| {
+        let name = format!("image{}x{}@{}d{}.png", settings.width, settings.height, settings.samples, settings.depth);
+        Path::new("images").join(name)
+    });
+    let format = match args.format {
+        Some(format) => format,
+        None => ImageFormat::from_path(&output)
+            .map_err(|_| format!("Can't guess the image format of {}, use --format", output.display()))?,
+    };
 
     let build_start = Instant::now();
-    let scene = world.build_scene(&mut randomness);
+    let mut scene = match &args.bvh_cache {
+        Some(path) => world.build_scene_cached(path, &mut randomness)?,
+        None => world.build_scene(&mut randomness),
+    };
+    scene.set_bvh_layout(match args.bvh {
+        BVHChoice::Binary => BVHLayout::Binary,
+        BVHChoice::Wide => BVHLayout::Wide,
+    });
     let build_took = build_start.elapsed();
 
-    let integrator = PathTracingIntegrator::new(DEPTH, vector!(1.0, 1.0, 1.0), &scene);
-
     let start = Instant::now();
-    let render = render(RenderDescriptor {
-        width: WIDTH,
-        height: HEIGHT,
-        samples: SAMPLES,
-        t_min: 0.001,
-        t_max: Float::INFINITY,
-        integrator,
-        rng: &mut randomness,
-        scene,
-        camera
-    });
+    let render = match args.integrator {
+        IntegratorChoice::Path => {
+            let integrator = PathTracingIntegrator::new(settings.depth, vector!(1.0, 1.0, 1.0), &scene)
+                .with_min_depth(settings.min_depth);
+            render_with(integrator, &settings, &mut randomness, scene, camera)
+        }
+        IntegratorChoice::Normal => render_with(NormalIntegrator, &settings, &mut randomness, scene, camera),
+    };
     let took = start.elapsed();
 
     let max_white = max_luminance(render.pixels());
 
     let pixels: Vec<_> = render.into_pixels()
         .map(|c| tonemap(c, max_white))
-        .map(color_to_rgb)
-        .flatten()
+        .flat_map(|c| color_to_rgb(c, args.gamma))
         .collect();
-    let image = image::RgbImage::from_raw(WIDTH, HEIGHT, pixels).unwrap();
-    let name = format!("images/image{}x{}@{}<{}.png", WIDTH, HEIGHT, SAMPLES, DEPTH);
-    image.save_with_format(name, ImageFormat::Png).unwrap();
+    let image = image::RgbImage::from_raw(settings.width, settings.height, pixels)
+        .ok_or("Rendered image has the wrong size")?;
+
+    if let Some(dir) = output.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+    }
+    image.save_with_format(&output, format).map_err(|e| format!("Could not save {}: {}", output.display(), e))?;
 
     println!("Built scene in {} milliseconds", build_took.as_millis());
     println!("Finished render in {:.2} seconds", took.as_secs_f64());
+    Ok(())
+}
+
+fn apply_overrides(args: &Args, settings: &mut RenderSettings) {
+    if let Some(width) = args.width {
+        settings.width = width;
+    }
+    if let Some(height) = args.height {
+        settings.height = height;
+    }
+    if let Some(samples) = args.samples {
+        settings.samples = samples;
+    }
+    if let Some(depth) = args.depth {
+        settings.depth = depth;
+    }
+    if let Some(min_depth) = args.min_depth {
+        settings.min_depth = min_depth;
+    }
+}
+
+fn render_with<I: Integrator + Sync>(
+    integrator: I,
+    settings: &RenderSettings,
+    rng: &mut DefaultRandomness,
+    scene: Scene,
+    camera: Camera,
+) -> Texture2D<Vector3<Float>> {
+    render(RenderDescriptor {
+        width: settings.width,
+        height: settings.height,
+        samples: settings.samples,
+        t_min: settings.t_min,
+        t_max: settings.t_max,
+        integrator,
+        rng,
+        scene,
+        camera,
+    })
 }
 
 
@@ -90,12 +236,12 @@ fn tonemap(c: Vector3<Float>, max_white_l: Float) -> Vector3<Float> {
 }
 fn max_luminance<'a>(pixels: impl Iterator<Item = &'a Vector3<Float>>) -> Float {
     pixels.map(|p| luminance(*p))
-        .max_by(|a, b| a.partial_cmp(&b).unwrap())
-        .unwrap()
+        .filter(|l| l.is_finite())
+        .fold(0.0, Float::max)
 }
 
-fn color_to_rgb(mut c: Vector3<Float>) -> [u8; 3] {
-    c = color_correct(c);
+fn color_to_rgb(mut c: Vector3<Float>, gamma: Float) -> [u8; 3] {
+    c = color_correct(c, gamma);
 
     let r = (c[0] * 255.0).clamp(0.0, 255.0) as u8;
     let g = (c[1] * 255.0).clamp(0.0, 255.0) as u8;
@@ -104,22 +250,22 @@ fn color_to_rgb(mut c: Vector3<Float>) -> [u8; 3] {
 
     [r, g, b]
 }
-fn color_correct(mut c: Vector3<Float>) -> Vector3<Float> {
-    c[0] = c[0].powf(1.0 / GAMMA);
-    c[1] = c[1].powf(1.0 / GAMMA);
-    c[2] = c[2].powf(1.0 / GAMMA);
+fn color_correct(mut c: Vector3<Float>, gamma: Float) -> Vector3<Float> {
+    c[0] = c[0].powf(1.0 / gamma);
+    c[1] = c[1].powf(1.0 / gamma);
+    c[2] = c[2].powf(1.0 / gamma);
 
     c
 }
-fn color_decorrect(mut c: Vector3<Float>) -> Vector3<Float> {
-    c[0] = c[0].powf(GAMMA);
-    c[1] = c[1].powf(GAMMA);
-    c[2] = c[2].powf(GAMMA);
+fn color_decorrect(mut c: Vector3<Float>, gamma: Float) -> Vector3<Float> {
+    c[0] = c[0].powf(gamma);
+    c[1] = c[1].powf(gamma);
+    c[2] = c[2].powf(gamma);
 
     c
 }
 
-fn build_world<R: Randomness>(rng: &mut R) -> (World, Camera) {
+fn build_world<R: Randomness>(rng: &mut R, aspect_ratio: Float) -> Result<(World, Camera), Box<dyn Error>> {
     let mut world = World::new();
 
     let ground_shape = world.add_sphere(1000.0);
@@ -145,7 +291,8 @@ fn build_world<R: Randomness>(rng: &mut R) -> (World, Camera) {
 
     let sphere = world.add_sphere(1.0);
 
-    let mat0_albedo = load_texture_albedo_jpeg("resources/earthmap.jpg", &mut world);
+    let earth = load_texture("resources/earthmap.jpg").map_err(|e| format!("Could not load resources/earthmap.jpg: {}", e))?;
+    let mat0_albedo = world.add_texture_albedo(earth);
     let mat0 = world.add_lambertian_material(mat0_albedo);
     let mat1 = random_lambertian(&mut world, rng);
     let mat2 = world.add_mirror_material();
@@ -160,27 +307,10 @@ fn build_world<R: Randomness>(rng: &mut R) -> (World, Camera) {
         Point3::origin(),
         Vector3::new(0.0, 1.0, 0.0),
         (20.0 as Float).to_radians(),
-        ASPECT,
+        aspect_ratio,
     );
 
-    (world, camera)
-}
-fn load_texture_albedo_jpeg(name: &str, world: &mut World) -> AlbedoRef {
-    let file = std::fs::File::open(name).unwrap();
-    let bufread = BufReader::new(file);
-    let image = image::load(bufread, ImageFormat::Jpeg).unwrap();
-
-    let mut pixels = Vec::new();
-
-    let image = image.to_rgb32f();
-
-    image.pixels().for_each(|p| {
-        let pixel = Vector3::new(p[0], p[1], p[2]).cast();
-        pixels.push(pixel);
-    });
-
-    let texture = Texture2D::new_from_pixels(image.width(), image.height(), pixels);
-    world.add_texture_albedo(texture)
+    Ok((world, camera))
 }
 fn random_lambertian<R: Randomness>(world: &mut World, rng: &mut R) -> MaterialRef {
     let albedo = random_albedo(rng);
@@ -194,27 +324,3 @@ fn random_albedo<R: Randomness>(rng: &mut R) -> Vector3<Float> {
 
     Vector3::new(r, g, b)
 }
-
-pub struct DefaultRandomness {
-    pub rng: StdRng,
-}
-impl Randomness for DefaultRandomness {
-    fn float(&mut self) -> Float {
-        self.rng.gen()
-    }
-    fn usize_range_exclusive(&mut self, min: usize, max: usize) -> usize {
-        self.rng.gen_range(min..max)
-    }
-
-    fn unit_vector(&mut self) -> Unit<Vector3<Float>> {
-        let [x, y, z] = UnitSphere.sample(&mut self.rng);
-        Unit::new_unchecked(vector!(x, y, z))
-    }
-}
-impl SeedingRandomness for DefaultRandomness {
-    fn seed_new(&mut self) -> Self {
-        Self {
-            rng: StdRng::seed_from_u64(self.rng.gen())
-        }
-    }
-}
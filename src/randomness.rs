@@ -1,13 +1,63 @@
-use nalgebra::{Unit, Vector3};
+use nalgebra::{Unit, vector, Vector3};
+use rand::prelude::*;
+use rand_distr::UnitSphere;
 use crate::Float;
 
 pub trait Randomness {
     fn float(&mut self) -> Float;
 
     fn usize_range_exclusive(&mut self, min: usize, max: usize) -> usize;
-    
+
     fn unit_vector(&mut self) -> Unit<Vector3<Float>>;
 }
 pub trait SeedingRandomness {
     fn seed_new(&mut self) -> Self;
+
+    /// Derives an independent stream from the seed of this one and `stream`, without advancing this one.
+    /// The same seed and stream always give the same result.
+    fn derive(&self, stream: u64) -> Self;
+}
+
+
+pub struct DefaultRandomness {
+    seed: u64,
+    rng: StdRng,
+}
+impl DefaultRandomness {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+impl Randomness for DefaultRandomness {
+    fn float(&mut self) -> Float {
+        self.rng.gen()
+    }
+    fn usize_range_exclusive(&mut self, min: usize, max: usize) -> usize {
+        self.rng.gen_range(min..max)
+    }
+
+    fn unit_vector(&mut self) -> Unit<Vector3<Float>> {
+        let [x, y, z] = UnitSphere.sample(&mut self.rng);
+        Unit::new_unchecked(vector!(x, y, z))
+    }
+}
+impl SeedingRandomness for DefaultRandomness {
+    fn seed_new(&mut self) -> Self {
+        Self::new(self.rng.gen())
+    }
+
+    fn derive(&self, stream: u64) -> Self {
+        Self::new(split_mix(self.seed ^ split_mix(stream)))
+    }
+}
+
+/// The SplitMix64 finalizer, which turns similar inputs like neighbouring pixel indices into unrelated seeds.
+fn split_mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
 }
@@ -1,347 +1,753 @@
-use std::cmp::Ordering;
+use nalgebra::Point3;
+use rayon::prelude::*;
 use crate::aabb::AABB;
 use crate::Float;
 use crate::randomness::Randomness;
 use crate::ray::Ray;
-use crate::scene::primitive::PrimitiveRef;
+use crate::scene::bvh4::BVH4;
+use crate::scene::cache::{Decoder, Encoder};
+
+
+/// How the nodes of a [BVH] are laid out for traversal.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BVHLayout {
+    /// Nodes with two children, whose boxes are intersected one at a time.
+    #[default]
+    Binary,
+    /// The binary tree collapsed into nodes with four children, whose boxes are intersected at once with SIMD.
+    Wide,
+}
 
 
+#[derive(Default)]
 pub struct BVH {
     nodes: Vec<BVHNode>,
+    /// The indices of the primitives of all leaves, each leaf refers to a range of them.
+    primitives: Vec<usize>,
+    /// The surface area of every node when it was built, to tell how much refitting has loosened it.
+    built_areas: Vec<Float>,
+    /// The SAH cost right after building.
+    built_cost: Float,
+    /// The collapsed tree that is traversed instead of [Self::nodes] if the layout is [BVHLayout::Wide].
+    wide: Option<BVH4>,
 }
 impl BVH {
-    pub fn new<R: Randomness>(primitives: &mut [(PrimitiveRef, AABB)], _rng: &mut R) -> Self {
-        if primitives.len() == 0 {
-            return Self {
-                nodes: Vec::new()
-            }
+    /// Builds a BVH over primitives given by an index and their bounds. The index is what queries report back.
+    /// Large trees are built on multiple threads.
+    pub fn new<R: Randomness>(primitives: &mut [(usize, AABB)], _rng: &mut R) -> Self {
+        Self::build(primitives, true)
+    }
+    /// Like [Self::new], but on the current thread only. The tree is exactly the same.
+    pub fn new_serial<R: Randomness>(primitives: &mut [(usize, AABB)], _rng: &mut R) -> Self {
+        Self::build(primitives, false)
+    }
+
+    fn build(primitives: &mut [(usize, AABB)], parallel: bool) -> Self {
+        if primitives.is_empty() {
+            return Self::default();
         }
 
-        let mut nodes = Vec::new();
-        BVHNode::build_sah(primitives, &mut nodes);
+        let mut build_primitives: Vec<BuildPrimitive> = primitives.iter()
+            .map(|&(primitive, aabb)| BuildPrimitive::new(primitive, aabb))
+            .collect();
 
-        let mut ret = Self {
+        let nodes = if parallel {
+            BVHNode::build_sah_parallel(&mut build_primitives, 0).into_iter()
+                .flat_map(|(shift, nodes)| nodes.into_iter().map(move |n| n.shifted(shift)))
+                .collect()
+        }
+        else {
+            let mut nodes = Vec::new();
+            BVHNode::build_sah(&mut build_primitives, 0, &mut nodes);
+            nodes
+        };
+
+        let mut bvh = Self {
             nodes,
+            primitives: build_primitives.iter().map(|p| p.primitive).collect(),
+            built_areas: Vec::new(),
+            built_cost: 0.0,
+            wide: None,
+        };
+        bvh.mark_built();
+        bvh
+    }
+
+    pub fn layout(&self) -> BVHLayout {
+        if self.wide.is_some() { BVHLayout::Wide } else { BVHLayout::Binary }
+    }
+    /// Changes how the nodes are laid out for traversal, which doesn't change the tree itself.
+    /// The binary nodes are always kept, since building, refitting and caching work on them.
+    pub fn set_layout(&mut self, layout: BVHLayout) {
+        self.wide = match layout {
+            BVHLayout::Binary => None,
+            BVHLayout::Wide => Some(BVH4::collapse(&self.nodes)),
         };
-        ret.reverse();
-        ret
     }
 
     pub fn top(&self) -> AABB {
         self.nodes[0].aabb()
     }
-    pub fn reverse(&mut self) {
-        let len = self.nodes.len();
 
-        for node in &mut self.nodes {
-            node.reverse(len)
+    /// The expected cost of finding the closest hit of a random ray according to the surface area heuristic,
+    /// measured in primitive intersections. Lower is better.
+    pub fn sah_cost(&self) -> Float {
+        self.sah_cost_with(|_| 1.0)
+    }
+    /// Like [Self::sah_cost], with a cost for intersecting each primitive, given its index.
+    pub fn sah_cost_with<F: Fn(usize) -> Float>(&self, primitive_cost: F) -> Float {
+        if self.nodes.is_empty() {
+            return 0.0;
+        }
+
+        let top_area = self.top().surface_area();
+        if top_area <= 0.0 {
+            return self.primitives.iter().map(|p| primitive_cost(*p)).sum();
         }
 
-        self.nodes.reverse();
+        let cost: Float = self.nodes.iter()
+            .map(|node| match node {
+                BVHNode::Leaf { aabb, start, count } => {
+                    let cost: Float = self.primitives[*start..*start + *count].iter().map(|p| primitive_cost(*p)).sum();
+                    cost * aabb.surface_area()
+                }
+                BVHNode::Binary { aabb, .. } => TRAVERSAL_COST * aabb.surface_area(),
+            })
+            .sum();
+
+        cost / top_area
     }
 
-    pub fn find_intersection<F, C, O>(&self, ray: &Ray, find: F, comp: C, t_min: Float, t_max: Float) -> Option<O>
-        where F: Fn(&Ray, PrimitiveRef) -> Option<O>,
-              C: Fn(O, O) -> O {
-        if self.nodes.len() == 0 {
+    /// Updates the bounds of all nodes after primitives moved, given the new bounds of every primitive by index.
+    ///
+    /// Refitting keeps the structure of the tree, which gets worse the further primitives move.
+    /// Once the SAH cost grew too much, the subtrees whose bounds grew the most are rebuilt,
+    /// or the whole tree if that includes the root.
+    pub fn refit<R: Randomness>(&mut self, aabbs: &[AABB], rng: &mut R) {
+        let layout = self.layout();
+        self.refit_binary(aabbs, rng);
+        self.set_layout(layout);
+    }
+    fn refit_binary<R: Randomness>(&mut self, aabbs: &[AABB], rng: &mut R) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        // Children always come after their parents.
+        for i in (0..self.nodes.len()).rev() {
+            let aabb = match self.nodes[i] {
+                BVHNode::Leaf { start, count, .. } => self.primitives[start..start + count].iter()
+                    .fold(AABB::empty(), |aabb, p| AABB::merged(aabb, aabbs[*p])),
+                BVHNode::Binary { left, right, .. } => AABB::merged(self.nodes[left].aabb(), self.nodes[right].aabb()),
+            };
+            self.nodes[i].set_aabb(aabb);
+        }
+
+        if self.sah_cost() <= self.built_cost * MAX_REFIT_COST_GROWTH {
+            return;
+        }
+
+        let degraded = |bvh: &Self, i: usize| bvh.nodes[i].aabb().surface_area() > bvh.built_areas[i] * MAX_REFIT_AREA_GROWTH;
+        let mut rebuilt = false;
+
+        if !degraded(self, 0) {
+            let mut stack = vec![0];
+            while let Some(i) = stack.pop() {
+                if degraded(self, i) {
+                    self.rebuild_subtree(i, aabbs);
+                    rebuilt = true;
+                }
+                else if let BVHNode::Binary { left, right, .. } = self.nodes[i] {
+                    stack.push(right);
+                    stack.push(left);
+                }
+            }
+            self.compact();
+            self.built_areas = self.nodes.iter().map(|n| n.aabb().surface_area()).collect();
+        }
+
+        // The cost to compare against stays that of the last full build, so that the tree can't slowly get worse.
+        if !rebuilt || self.sah_cost() > self.built_cost * MAX_REFIT_COST_GROWTH {
+            let mut primitives: Vec<(usize, AABB)> = aabbs.iter().copied().enumerate().collect();
+            *self = Self::new(&mut primitives, rng);
+        }
+    }
+
+    /// Builds the subtree at `i` again over the same primitives.
+    /// The new nodes are appended, and replace the old ones once the tree is compacted.
+    fn rebuild_subtree(&mut self, i: usize, aabbs: &[AABB]) {
+        let (start, end) = self.subtree_range(i);
+        let mut build_primitives: Vec<BuildPrimitive> = self.primitives[start..end].iter()
+            .map(|&p| BuildPrimitive::new(p, aabbs[p]))
+            .collect();
+
+        let mut subtree = Vec::new();
+        BVHNode::build_sah(&mut build_primitives, start, &mut subtree);
+
+        let shift = self.nodes.len();
+        self.nodes.extend(subtree.into_iter().map(|n| n.shifted(shift)));
+        self.nodes[i] = self.nodes[shift];
+
+        for (p, b) in self.primitives[start..end].iter_mut().zip(build_primitives) {
+            *p = b.primitive;
+        }
+    }
+    /// The range of [Self::primitives] the subtree at `i` covers, since left children always come first.
+    fn subtree_range(&self, i: usize) -> (usize, usize) {
+        let mut first = i;
+        while let BVHNode::Binary { left, .. } = self.nodes[first] {
+            first = left;
+        }
+        let mut last = i;
+        while let BVHNode::Binary { right, .. } = self.nodes[last] {
+            last = right;
+        }
+
+        match (&self.nodes[first], &self.nodes[last]) {
+            (BVHNode::Leaf { start, .. }, BVHNode::Leaf { start: last_start, count, .. }) => (*start, last_start + count),
+            _ => unreachable!(),
+        }
+    }
+    /// Drops nodes that aren't reachable anymore, and stores the rest depth first again.
+    fn compact(&mut self) {
+        fn copy(old: &[BVHNode], i: usize, nodes: &mut Vec<BVHNode>) -> usize {
+            let j = nodes.len();
+            nodes.push(old[i]);
+
+            if let BVHNode::Binary { aabb, left, right, axis } = old[i] {
+                let left = copy(old, left, nodes);
+                let right = copy(old, right, nodes);
+                nodes[j] = BVHNode::Binary { aabb, left, right, axis };
+            }
+            j
+        }
+
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        copy(&self.nodes, 0, &mut nodes);
+        self.nodes = nodes;
+    }
+    fn mark_built(&mut self) {
+        self.built_areas = self.nodes.iter().map(|n| n.aabb().surface_area()).collect();
+        self.built_cost = self.sah_cost();
+    }
+
+    /// Writes the nodes and the order of the primitives, everything else is derived from them when decoding.
+    pub(crate) fn encode(&self, encoder: &mut Encoder) {
+        encoder.usize(self.nodes.len());
+        for node in &self.nodes {
+            match node {
+                BVHNode::Leaf { aabb, start, count } => {
+                    encoder.u8(0);
+                    encoder.aabb(aabb);
+                    encoder.usize(*start);
+                    encoder.usize(*count);
+                }
+                BVHNode::Binary { aabb, left, right, axis } => {
+                    encoder.u8(1);
+                    encoder.aabb(aabb);
+                    encoder.usize(*left);
+                    encoder.usize(*right);
+                    encoder.u8(axis.to_index() as u8);
+                }
+            }
+        }
+
+        encoder.usize(self.primitives.len());
+        for p in &self.primitives {
+            encoder.usize(*p);
+        }
+    }
+    /// Reads a BVH written by [Self::encode] over `primitive_count` primitives.
+    /// Everything traversal relies on is checked, so that a damaged file can't make it index out of bounds or loop forever.
+    pub(crate) fn decode(decoder: &mut Decoder, primitive_count: usize) -> Result<Self, String> {
+        let node_count = decoder.count(1 + 6 * std::mem::size_of::<Float>())?;
+        let mut nodes = Vec::with_capacity(node_count);
+        // Leaves are stored in the order of their primitives, so each has to start where the one before ended.
+        let mut next_start = 0;
+
+        for i in 0..node_count {
+            let node = match decoder.u8()? {
+                0 => {
+                    let aabb = decoder.aabb()?;
+                    let start = decoder.usize()?;
+                    let count = decoder.usize()?;
+                    if start != next_start || count == 0 {
+                        return Err(format!("Leaf {} has primitives {}+{}, but the next ones are at {}", i, start, count, next_start));
+                    }
+                    next_start = start.saturating_add(count);
+
+                    BVHNode::Leaf { aabb, start, count }
+                }
+                1 => {
+                    let aabb = decoder.aabb()?;
+                    let left = decoder.usize()?;
+                    let right = decoder.usize()?;
+                    let axis = match decoder.u8()? {
+                        0 => Axis::X,
+                        1 => Axis::Y,
+                        2 => Axis::Z,
+                        a => return Err(format!("Node {} has an invalid axis {}", i, a)),
+                    };
+                    if left != i + 1 || right <= left || right >= node_count {
+                        return Err(format!("Node {} has invalid children {} and {}", i, left, right));
+                    }
+
+                    BVHNode::Binary { aabb, left, right, axis }
+                }
+                tag => return Err(format!("Node {} has an invalid type {}", i, tag)),
+            };
+            nodes.push(node);
+        }
+        if next_start != primitive_count {
+            return Err(format!("Leaves hold {} primitives, but there are {}", next_start, primitive_count));
+        }
+
+        let count = decoder.count(8)?;
+        if count != primitive_count {
+            return Err(format!("Orders {} primitives, but there are {}", count, primitive_count));
+        }
+        let mut seen = vec![false; primitive_count];
+        let mut primitives = Vec::with_capacity(primitive_count);
+        for _ in 0..count {
+            let p = decoder.usize()?;
+            if p >= primitive_count || std::mem::replace(&mut seen[p], true) {
+                return Err(format!("Primitive {} is out of range or used twice", p));
+            }
+            primitives.push(p);
+        }
+
+        let mut bvh = Self {
+            nodes,
+            primitives,
+            built_areas: Vec::new(),
+            built_cost: 0.0,
+            wide: None,
+        };
+        bvh.mark_built();
+        Ok(bvh)
+    }
+
+    /// Finds the closest of the intersections `find` reports for the primitives the ray might hit.
+    ///
+    /// Nodes are visited front to back, near child first according to the sign of the ray direction along the split axis.
+    /// `find` is given the distance of the closest hit found so far as its `t_max` and `distance` tells how far away a hit is,
+    /// so that nodes behind the closest hit are skipped.
+    pub fn find_closest<F, D, O>(&self, ray: &Ray, find: F, distance: D, t_min: Float, mut t_max: Float) -> Option<O>
+        where F: Fn(&Ray, usize, Float, Float) -> Option<O>,
+              D: Fn(&O) -> Float {
+        if let Some(wide) = &self.wide {
+            return wide.find_closest(&self.primitives, ray, find, distance, t_min, t_max);
+        }
+        if self.nodes.is_empty() {
             return None;
         }
 
-        let start = 0;
-        self.find_intersection_rec(ray, &find, &comp, start, t_min, t_max)
+        let direction_negative = [ray.direction.x < 0.0, ray.direction.y < 0.0, ray.direction.z < 0.0];
+        let mut closest = None;
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            // Leaves too, since their bounds are much cheaper to test than their primitives.
+            if !node.aabb().intersects_ray(ray, t_min, t_max) {
+                continue;
+            }
+
+            match node {
+                BVHNode::Leaf { start, count, .. } => {
+                    for primitive in &self.primitives[*start..*start + *count] {
+                        if let Some(int) = find(ray, *primitive, t_min, t_max) {
+                            t_max = distance(&int);
+                            closest = Some(int);
+                        }
+                    }
+                }
+                BVHNode::Binary { left, right, axis, .. } => {
+                    // The left child holds the smaller centroids along the axis, so it is nearer for positive directions.
+                    let (near, far) = if direction_negative[axis.to_index()] { (*right, *left) } else { (*left, *right) };
+                    stack.push(far);
+                    stack.push(near);
+                }
+            }
+        }
+
+        closest
     }
-    fn find_intersection_rec<F, C, O>(&self, ray: &Ray, find: &F, comp: &C, node: usize, t_min: Float, t_max: Float) -> Option<O>
-        where F: Fn(&Ray, PrimitiveRef) -> Option<O>,
-              C: Fn(O, O) -> O {
-        match &self.nodes[node] {
-            BVHNode::Leaf { primitive, .. } => {
-                find(ray, *primitive)
+
+    /// Whether `hit` reports a hit for any of the primitives the ray might hit, stopping at the first one.
+    pub fn find_any<F>(&self, ray: &Ray, hit: F, t_min: Float, t_max: Float) -> bool
+        where F: Fn(&Ray, usize) -> bool {
+        if let Some(wide) = &self.wide {
+            return wide.find_any(&self.primitives, ray, hit, t_min, t_max);
+        }
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if !node.aabb().intersects_ray(ray, t_min, t_max) {
+                continue;
             }
-            BVHNode::Binary { aabb, left, right } => {
-                if aabb.intersects_ray(ray, t_min, t_max) {
-                    let l_int = self.find_intersection_rec(ray, find, comp, *left, t_min, t_max);
-                    let r_int = self.find_intersection_rec(ray, find, comp, *right, t_min, t_max);
-
-                    match (l_int, r_int) {
-                        (None, None) => None,
-                        (Some(i), None) => Some(i),
-                        (None, Some(i)) => Some(i),
-                        (Some(a), Some(b)) => Some(comp(a, b))
+
+            match node {
+                BVHNode::Leaf { start, count, .. } => {
+                    if self.primitives[*start..*start + *count].iter().any(|p| hit(ray, *p)) {
+                        return true;
                     }
-                } else {
-                    None
+                }
+                BVHNode::Binary { left, right, .. } => {
+                    stack.push(*right);
+                    stack.push(*left);
                 }
             }
         }
+
+        false
     }
 }
 
-enum BVHNode {
+/// Trees are equal if they have the same nodes and order of primitives, regardless of their layout.
+impl PartialEq for BVH {
+    fn eq(&self, other: &Self) -> bool {
+        self.nodes == other.nodes && self.primitives == other.primitives
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(super) enum BVHNode {
     Leaf {
         aabb: AABB,
-        primitive: PrimitiveRef,
+        /// The first of the leaf's primitives in [BVH::primitives].
+        start: usize,
+        count: usize,
     },
     Binary {
         aabb: AABB,
         left: usize,
         right: usize,
+        /// The axis the primitives were split along.
+        axis: Axis,
     },
 }
 impl BVHNode {
-    fn aabb(&self) -> AABB {
+    pub(super) fn aabb(&self) -> AABB {
         match self {
             Self::Leaf { aabb, .. } => *aabb,
             Self::Binary { aabb, .. } => *aabb,
         }
     }
 
-    fn reverse(&mut self, len: usize) {
+    fn set_aabb(&mut self, new: AABB) {
         match self {
-            Self::Binary { left, right, .. } => {
-                *left = len - *left - 1;
-                *right = len - *right - 1;
-            }
-            _ => (),
+            Self::Leaf { aabb, .. } => *aabb = new,
+            Self::Binary { aabb, .. } => *aabb = new,
         }
     }
 
-    fn choose_split_axis(primitives: &[(PrimitiveRef, AABB)]) -> (Axis, Float, Float) {
-        let mut min_x = Float::INFINITY;
-        let mut max_x = -Float::INFINITY;
-        let mut min_y = Float::INFINITY;
-        let mut max_y = -Float::INFINITY;
-        let mut min_z = Float::INFINITY;
-        let mut max_z = -Float::INFINITY;
+    /// Moves the child indices of the node by `shift`, for when the subtree it is in is moved.
+    fn shifted(self, shift: usize) -> Self {
+        match self {
+            Self::Leaf { .. } => self,
+            Self::Binary { aabb, left, right, axis } => Self::Binary {
+                aabb,
+                left: left + shift,
+                right: right + shift,
+                axis,
+            },
+        }
+    }
 
-        for (_, aabb) in primitives {
-            let centroid = aabb.centroid();
+    /// Decides whether `primitives` should be split using a binned surface area heuristic, which takes linear time.
+    /// If so, they are partitioned and the axis and index of the split are returned along with their bounds.
+    /// Large slices are binned on multiple threads, which gives the same result since merging bins is exact.
+    fn split(primitives: &mut [BuildPrimitive], parallel: bool) -> (AABB, Option<(Axis, usize)>) {
+        let (aabb, centroid_bounds) = if parallel {
+            primitives.par_chunks(PARALLEL_CHUNK_SIZE)
+                .map(BuildPrimitive::bounds)
+                .reduce(|| (AABB::empty(), AABB::empty()), |(a, ca), (b, cb)| (AABB::merged(a, b), AABB::merged(ca, cb)))
+        }
+        else {
+            BuildPrimitive::bounds(primitives)
+        };
 
-            min_x = min_x.min(centroid[0]);
-            max_x = max_x.max(centroid[0]);
-            min_y = min_y.min(centroid[1]);
-            max_y = max_y.max(centroid[1]);
-            min_z = min_z.min(centroid[2]);
-            max_z = max_z.max(centroid[2]);
+        if primitives.len() == 1 {
+            return (aabb, None);
         }
 
-        let extent_x = max_x - min_x;
-        let extent_y = max_y - min_y;
-        let extent_z = max_z - min_z;
+        let axis = Axis::largest(&centroid_bounds);
+        let axis_i = axis.to_index();
+        let min = centroid_bounds.min[axis_i];
+        let extent = centroid_bounds.max[axis_i] - min;
 
+        if extent > 0.0 {
+            let bins = if parallel {
+                primitives.par_chunks(PARALLEL_CHUNK_SIZE)
+                    .map(|chunk| Bin::fill(chunk, axis_i, min, extent))
+                    .reduce(|| [Bin::empty(); BIN_COUNT], |a, b| Bin::merged(&a, &b))
+            }
+            else {
+                Bin::fill(primitives, axis_i, min, extent)
+            };
+            let (split, cost) = Bin::best_split(&bins, aabb.surface_area());
+
+            // Splitting isn't worth it if intersecting every primitive is cheaper than traversing the children.
+            if primitives.len() <= MAX_LEAF_SIZE && primitives.len() as Float <= cost {
+                return (aabb, None);
+            }
 
-        if extent_x.abs() > extent_y.abs() && extent_x.abs() > extent_z.abs() {
-            (Axis::X, min_x, extent_x)
+            let split_i = partition(primitives, |p| Bin::index(p.centroid[axis_i], min, extent) < split);
+            (aabb, Some((axis, split_i)))
         }
-        else if extent_y.abs() > extent_z.abs() {
-            (Axis::Y, min_y, extent_y)
+        else if primitives.len() <= MAX_LEAF_SIZE {
+            (aabb, None)
         }
         else {
-            (Axis::Z, min_z, extent_z)
+            // All centroids are in the same place (e.g. coincident triangles), so there's nothing better than the middle.
+            (aabb, Some((axis, primitives.len() / 2)))
         }
     }
 
-    #[allow(dead_code)]
-    fn build_stupid(primitives: &mut [(PrimitiveRef, AABB)], nodes: &mut Vec<Self>) -> usize {
+    /// Builds the subtree over `primitives` on the current thread.
+    /// Nodes are stored depth first, so a parent always comes before its children.
+    /// `offset` is where `primitives` starts in the slice of all primitives, which leaves refer to.
+    fn build_sah(primitives: &mut [BuildPrimitive], offset: usize, nodes: &mut Vec<Self>) -> usize {
         assert_ne!(primitives.len(), 0);
 
+        let i = nodes.len();
+        let (aabb, split) = Self::split(primitives, false);
+        let leaf = Self::Leaf {
+            aabb,
+            start: offset,
+            count: primitives.len(),
+        };
 
-        if primitives.len() == 1 {
-            let i = nodes.len();
-
-            nodes.push(Self::Leaf {
-                aabb: primitives[0].1,
-                primitive: primitives[0].0,
-            });
-            i
-        } else {
-            let axis = Self::choose_split_axis(primitives).0.to_index();
-            primitives.sort_by(|a, b| a.1.min[axis].partial_cmp(&b.1.min[axis]).unwrap_or(Ordering::Equal));
-            let mid = primitives.len() / 2;
-
-            let (left, right) = primitives.split_at_mut(mid);
+        let (axis, split_i) = match split {
+            Some(split) => split,
+            None => {
+                nodes.push(leaf);
+                return i;
+            }
+        };
 
-            let left = Self::build_stupid(left, nodes);
-            let right = Self::build_stupid(right, nodes);
+        // Reserve the parent's slot, it is filled in once the children are known.
+        nodes.push(leaf);
 
-            let l_aabb = nodes[left].aabb();
-            let r_aabb = nodes[right].aabb();
+        let (left, right) = primitives.split_at_mut(split_i);
+        let left = Self::build_sah(left, offset, nodes);
+        let right = Self::build_sah(right, offset + split_i, nodes);
 
-            let aabb = AABB::merged(l_aabb, r_aabb);
-            let i = nodes.len();
-            nodes.push(Self::Binary {
-                aabb,
-                left,
-                right,
-            });
-            i
-        }
+        nodes[i] = Self::Binary {
+            aabb,
+            left,
+            right,
+            axis,
+        };
+        i
     }
-    #[allow(dead_code)]
-    fn build_sah(primitives: &mut [(PrimitiveRef, AABB)], nodes: &mut Vec<Self>) -> usize {
-        assert_ne!(primitives.len(), 0);
 
-        if primitives.len() == 1 {
-            let i = nodes.len();
-            nodes.push(BVHNode::Leaf { primitive: primitives[0].0, aabb: primitives[0].1 });
-            i
+    /// Builds the same subtree as [Self::build_sah], with the children of large nodes built in parallel.
+    /// The subtree is returned as segments of nodes in the order the serial build stores them.
+    /// Each segment comes with the amount its child indices have to be shifted by, so that nodes are only moved once when they are joined.
+    fn build_sah_parallel(primitives: &mut [BuildPrimitive], offset: usize) -> Vec<(usize, Vec<Self>)> {
+        if primitives.len() < PARALLEL_THRESHOLD {
+            let mut nodes = Vec::new();
+            Self::build_sah(primitives, offset, &mut nodes);
+            return vec![(0, nodes)];
         }
-        else {
-            let (axis, min, extent) = Self::choose_split_axis(primitives);
-            let bucket_amount = (primitives.len() as Float).log2().ceil() as usize * 2;
-
-            let buckets = Self::build_buckets(primitives, bucket_amount, min, extent, axis);
-
-            let total_bounds = Self::compute_total_bounds(&buckets);
-            let costs = Self::compute_costs(&buckets, &total_bounds);
-            let bucket_split_i = Self::compute_bucket_split_i(&costs);
-            let dividing_line = buckets[bucket_split_i].dividing_line;
-
-            let axis = axis.to_index();
-            primitives.sort_by(|(_, a), (_, b)| a.centroid()[axis].partial_cmp(&b.centroid()[axis]).unwrap());
-            let split_i = Self::compute_primitive_split_i(primitives, dividing_line, axis);
 
+        let (aabb, split) = Self::split(primitives, true);
+        let (axis, split_i) = match split {
+            Some(split) => split,
+            None => return vec![(0, vec![Self::Leaf { aabb, start: offset, count: primitives.len() }])],
+        };
 
-            let (left, right) = primitives.split_at_mut(split_i);
+        let (left, right) = primitives.split_at_mut(split_i);
+        let (left, right) = rayon::join(
+            || Self::build_sah_parallel(left, offset),
+            || Self::build_sah_parallel(right, offset + split_i),
+        );
+        let left_len: usize = left.iter().map(|(_, nodes)| nodes.len()).sum();
+
+        let parent = Self::Binary {
+            aabb,
+            left: 1,
+            right: 1 + left_len,
+            axis,
+        };
 
-            let left = Self::build_sah(left, nodes);
-            let right = Self::build_sah(right, nodes);
+        let mut segments = vec![(0, vec![parent])];
+        segments.extend(left.into_iter().map(|(shift, nodes)| (shift + 1, nodes)));
+        segments.extend(right.into_iter().map(|(shift, nodes)| (shift + 1 + left_len, nodes)));
+        segments
+    }
+}
 
-            let l_aabb = nodes[left].aabb();
-            let r_aabb = nodes[right].aabb();
 
-            let aabb = AABB::merged(l_aabb, r_aabb);
-            let i = nodes.len();
-            nodes.push(Self::Binary {
-                aabb,
-                left,
-                right,
-            });
-            i
+/// How many bins the centroids are sorted into along the split axis.
+const BIN_COUNT: usize = 16;
+/// The cost of traversing a node, relative to the cost of intersecting a primitive.
+const TRAVERSAL_COST: Float = 0.125;
+/// Leaves hold at most this many primitives, unless they can't be split.
+const MAX_LEAF_SIZE: usize = 8;
+/// How much refitting may increase the SAH cost before parts of the tree are rebuilt.
+const MAX_REFIT_COST_GROWTH: Float = 1.5;
+/// Subtrees whose surface area grew more than this through refitting are rebuilt.
+const MAX_REFIT_AREA_GROWTH: Float = 2.0;
+/// Subtrees with fewer primitives than this are built on a single thread.
+const PARALLEL_THRESHOLD: usize = 4096;
+/// How many primitives each thread bins at once.
+const PARALLEL_CHUNK_SIZE: usize = 1024;
+
+
+/// A primitive during construction, with its centroid cached.
+#[derive(Copy, Clone, Debug)]
+struct BuildPrimitive {
+    primitive: usize,
+    aabb: AABB,
+    centroid: Point3<Float>,
+}
+impl BuildPrimitive {
+    fn new(primitive: usize, aabb: AABB) -> Self {
+        Self {
+            primitive,
+            aabb,
+            centroid: aabb.centroid(),
         }
     }
 
-    fn build_buckets(primitives: &[(PrimitiveRef, AABB)], bucket_amount: usize, min: Float, extent: Float, axis: Axis) -> Vec<Bucket> {
-        let mut buckets = Vec::with_capacity(bucket_amount);
-
-        let step = extent / bucket_amount as Float;
-
-        let dividing_lines: Vec<Float> = (0..bucket_amount)
-            .map(|i| min + step * i as Float)
-            .collect();
-
-
-        for i in 0..bucket_amount {
-            let my_line = dividing_lines[i];
-
-            let in_bucket: Vec<usize> = (0..primitives.len())
-                .filter(|&p_i| {
-                    let centroid = primitives[p_i].1.centroid();
-                    let pos = centroid[axis.to_index()];
+    /// The bounds of the primitives, and the bounds of their centroids.
+    fn bounds(primitives: &[Self]) -> (AABB, AABB) {
+        let mut aabb = AABB::empty();
+        let mut centroid_bounds = AABB::empty();
 
-                    if i == bucket_amount - 1 {
-                        pos >= my_line
-                    }
-                    else {
-                        let next_line = dividing_lines[i + 1];
-                        pos >= my_line && pos < next_line
-                    }
-                })
-                .collect();
+        for p in primitives {
+            aabb = AABB::merged(aabb, p.aabb);
+            centroid_bounds.grow(&p.centroid);
+        }
 
-            let count = in_bucket.len();
-            if count != 0 {
-                let mut aabbs = in_bucket.into_iter()
-                    .map(|i| primitives[i].1);
+        (aabb, centroid_bounds)
+    }
+}
 
-                let init = aabbs.next().unwrap();
-                let aabb = aabbs.fold(init, |a, b| AABB::merged(a, b));
 
+#[derive(Copy, Clone, Debug)]
+struct Bin {
+    aabb: AABB,
+    count: usize,
+}
+impl Bin {
+    fn index(centroid: Float, min: Float, extent: Float) -> usize {
+        let i = ((centroid - min) / extent * BIN_COUNT as Float) as usize;
+        i.min(BIN_COUNT - 1)
+    }
 
-                buckets.push(Bucket {
-                    amount: count,
-                    dividing_line: my_line,
-                    aabb,
-                });
-            }
+    fn empty() -> Self {
+        Self {
+            aabb: AABB::empty(),
+            count: 0,
         }
-
-        buckets
     }
-    fn compute_total_bounds(buckets: &[Bucket]) -> AABB {
-        let total_bounds_init: AABB = buckets[0].aabb;
-        let total_bounds: AABB = buckets.iter().skip(1)
-            .map(|b| b.aabb)
-            .fold(total_bounds_init, |a, b| AABB::merged(a, b));
+    fn merged(a: &[Self; BIN_COUNT], b: &[Self; BIN_COUNT]) -> [Self; BIN_COUNT] {
+        let mut bins = *a;
 
-        total_bounds
+        for (bin, other) in bins.iter_mut().zip(b) {
+            bin.aabb = AABB::merged(bin.aabb, other.aabb);
+            bin.count += other.count;
+        }
+
+        bins
     }
-    fn compute_costs(buckets: &[Bucket], total_bounds: &AABB) -> Vec<Float> {
-        let mut costs = Vec::with_capacity(buckets.len() - 1);
-        for i in 1..buckets.len() {
-            let mut b0 = buckets[0].aabb;
-            let mut b1 = buckets.last().unwrap().aabb;
-            let mut count0 = 0;
-            let mut count1 = 1;
 
-            for j in 0..i {
-                b0 = AABB::merged(b0, buckets[j].aabb);
-                count0 += buckets[j].amount;
-            }
-            for j in i..buckets.len() {
-                b1 = AABB::merged(b1, buckets[j].aabb);
-                count1 += buckets[j].amount;
-            }
+    fn fill(primitives: &[BuildPrimitive], axis: usize, min: Float, extent: Float) -> [Self; BIN_COUNT] {
+        let mut bins = [Self::empty(); BIN_COUNT];
 
-            let cost = 0.125 + (count0 as Float * b0.surface_area() + count1 as Float * b1.surface_area()) / total_bounds.surface_area();
-            costs.push(cost);
+        for p in primitives {
+            let bin = &mut bins[Self::index(p.centroid[axis], min, extent)];
+            bin.aabb = AABB::merged(bin.aabb, p.aabb);
+            bin.count += 1;
         }
 
-        costs
+        bins
     }
-    fn compute_bucket_split_i(costs: &[Float]) -> usize {
-        let mut min_cost = costs[0];
-        let mut split_i = 0;
 
-        for i in 1..costs.len() {
-            if costs[i] < min_cost {
-                min_cost = costs[i];
-                split_i = i;
-            }
+    /// Finds the split with the lowest cost, as the index of the first bin on the right side, and that cost.
+    /// The costs of all splits are found with one sweep from each side.
+    fn best_split(bins: &[Self; BIN_COUNT], area: Float) -> (usize, Float) {
+        // The area and count of everything right of each split.
+        let mut right_area = [0.0; BIN_COUNT];
+        let mut right_count = [0; BIN_COUNT];
+        let mut aabb = AABB::empty();
+        let mut count = 0;
+        for i in (1..BIN_COUNT).rev() {
+            aabb = AABB::merged(aabb, bins[i].aabb);
+            count += bins[i].count;
+            right_area[i] = if count == 0 { 0.0 } else { aabb.surface_area() };
+            right_count[i] = count;
         }
 
+        let mut best = (0, Float::INFINITY);
+        let mut aabb = AABB::empty();
+        let mut count = 0;
+        for i in 1..BIN_COUNT {
+            aabb = AABB::merged(aabb, bins[i - 1].aabb);
+            count += bins[i - 1].count;
+            if count == 0 || right_count[i] == 0 {
+                continue;
+            }
 
-        split_i + 1
-    }
-    fn compute_primitive_split_i(primitives: &[(PrimitiveRef, AABB)], dividing_line: Float, axis: usize) -> usize {
-        for i in 1..primitives.len() {
-            let my_pos = primitives[i].1.centroid()[axis];
-            let last_pos = primitives[i - 1].1.centroid()[axis];
-
-            if my_pos >= dividing_line && last_pos < dividing_line {
-                return i;
+            let cost = TRAVERSAL_COST + (count as Float * aabb.surface_area() + right_count[i] as Float * right_area[i]) / area;
+            if cost < best.1 {
+                best = (i, cost);
             }
         }
 
-        unreachable!()
+        best
     }
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
-struct Bucket {
-    amount: usize,
-    dividing_line: Float,
-    aabb: AABB,
+/// Moves the elements for which `left` is true to the front, and returns how many there are.
+fn partition<T, F: Fn(&T) -> bool>(slice: &mut [T], left: F) -> usize {
+    let mut split = 0;
+
+    for i in 0..slice.len() {
+        if left(&slice[i]) {
+            slice.swap(i, split);
+            split += 1;
+        }
+    }
+
+    split
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
-enum Axis {
+pub(super) enum Axis {
     X,
     Y,
     Z
 }
 impl Axis {
-    fn to_index(&self) -> usize {
+    /// The axis along which `aabb` is the longest.
+    fn largest(aabb: &AABB) -> Self {
+        let d = aabb.diagonal();
+
+        if d.x > d.y && d.x > d.z {
+            Self::X
+        }
+        else if d.y > d.z {
+            Self::Y
+        }
+        else {
+            Self::Z
+        }
+    }
+
+    fn to_index(self) -> usize {
         match self {
             Self::X => 0,
             Self::Y => 1,
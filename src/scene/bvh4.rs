@@ -0,0 +1,250 @@
+use wide::CmpGt;
+use crate::Float;
+use crate::ray::Ray;
+use crate::scene::bvh::BVHNode;
+
+
+/// Four floats in the precision of [Float], processed at once.
+#[cfg(not(feature = "wide"))]
+type FloatX4 = wide::f32x4;
+#[cfg(feature = "wide")]
+type FloatX4 = wide::f64x4;
+
+
+/// A binary BVH collapsed into nodes with up to four children, whose boxes are intersected at once with SIMD.
+/// Leaves refer to the same ranges of primitives as in the binary BVH it was made from.
+pub(crate) struct BVH4 {
+    nodes: Vec<BVH4Node>,
+    /// Where traversal starts, which is a leaf if the binary BVH is a single leaf, so that its box isn't tested.
+    /// Shapes made of a single primitive are common, and their box was already tested as part of the BVH over the objects.
+    root: Option<BVH4Child>,
+}
+impl BVH4 {
+    pub(crate) fn collapse(binary: &[BVHNode]) -> Self {
+        let mut nodes = Vec::new();
+        let root = match binary.first() {
+            None => None,
+            Some(BVHNode::Leaf { start, count, .. }) => Some(BVH4Child::Leaf { start: *start, count: *count }),
+            Some(BVHNode::Binary { .. }) => Some(BVH4Child::Node(Self::collapse_node(binary, 0, &mut nodes))),
+        };
+
+        Self {
+            nodes,
+            root,
+        }
+    }
+    /// Gathers up to four descendants of the binary node at `i`, always opening the largest inner node next,
+    /// since that is the one most likely to be hit. Returns the index of the new node.
+    fn collapse_node(binary: &[BVHNode], i: usize, nodes: &mut Vec<BVH4Node>) -> usize {
+        let mut children = match binary[i] {
+            BVHNode::Binary { left, right, .. } => vec![left, right],
+            BVHNode::Leaf { .. } => unreachable!(),
+        };
+
+        while children.len() < 4 {
+            let largest = children.iter()
+                .enumerate()
+                .filter(|(_, c)| matches!(binary[**c], BVHNode::Binary { .. }))
+                .max_by(|(_, a), (_, b)| binary[**a].aabb().surface_area().total_cmp(&binary[**b].aabb().surface_area()))
+                .map(|(k, _)| k);
+
+            match largest {
+                Some(k) => {
+                    if let BVHNode::Binary { left, right, .. } = binary[children[k]] {
+                        children[k] = left;
+                        children.insert(k + 1, right);
+                    }
+                }
+                None => break,
+            }
+        }
+
+        let index = nodes.len();
+        nodes.push(BVH4Node::empty());
+
+        let mut min = [[0.0; 4]; 3];
+        let mut max = [[0.0; 4]; 3];
+        for (lane, c) in children.iter().enumerate() {
+            let aabb = binary[*c].aabb();
+            for a in 0..3 {
+                // Flat boxes aren't tested along the axis they are flat in, the same as in `AABB::intersects_ray`.
+                let flat = aabb.min[a] == aabb.max[a];
+                min[a][lane] = if flat { -Float::INFINITY } else { aabb.min[a] };
+                max[a][lane] = if flat { Float::INFINITY } else { aabb.max[a] };
+            }
+
+            nodes[index].children[lane] = match binary[*c] {
+                BVHNode::Leaf { start, count, .. } => BVH4Child::Leaf { start, count },
+                BVHNode::Binary { .. } => BVH4Child::Node(Self::collapse_node(binary, *c, nodes)),
+            };
+        }
+
+        let node = &mut nodes[index];
+        node.min = min.map(FloatX4::from);
+        node.max = max.map(FloatX4::from);
+        node.count = children.len();
+        index
+    }
+
+    /// Works like [crate::scene::bvh::BVH::find_closest], with `primitives` being the primitive order of the binary BVH.
+    /// The children of a node that are hit are visited front to back by the distance they are entered at.
+    pub(crate) fn find_closest<F, D, O>(&self, primitives: &[usize], ray: &Ray, find: F, distance: D, t_min: Float, mut t_max: Float) -> Option<O>
+        where F: Fn(&Ray, usize, Float, Float) -> Option<O>,
+              D: Fn(&O) -> Float {
+        let root = self.root?;
+        if let BVH4Child::Leaf { start, count } = root {
+            return primitives[start..start + count].iter()
+                .fold(None, |closest, p| find(ray, *p, t_min, closest.as_ref().map_or(t_max, &distance)).or(closest));
+        }
+
+        let simd_ray = SimdRay::new(ray);
+        let mut closest = None;
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push((root, t_min));
+
+        while let Some((child, near)) = stack.pop() {
+            // Something closer was found since the child was pushed.
+            if near > t_max {
+                continue;
+            }
+
+            match child {
+                BVH4Child::Leaf { start, count } => {
+                    for primitive in &primitives[start..start + count] {
+                        if let Some(int) = find(ray, *primitive, t_min, t_max) {
+                            t_max = distance(&int);
+                            closest = Some(int);
+                        }
+                    }
+                }
+                BVH4Child::Node(node) => {
+                    let node = &self.nodes[node];
+                    let (mask, nears) = node.intersect(&simd_ray, t_min, t_max);
+
+                    // Sorted by insertion so that the farthest is pushed first, and the nearest is visited next.
+                    let start = stack.len();
+                    for (lane, (child, near)) in node.children.iter().zip(nears).take(node.count).enumerate() {
+                        if mask & (1 << lane) != 0 {
+                            let mut i = stack.len();
+                            stack.push((*child, near));
+                            while i > start && stack[i - 1].1 < stack[i].1 {
+                                stack.swap(i - 1, i);
+                                i -= 1;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+
+    /// Works like [crate::scene::bvh::BVH::find_any], with `primitives` being the primitive order of the binary BVH.
+    pub(crate) fn find_any<F>(&self, primitives: &[usize], ray: &Ray, hit: F, t_min: Float, t_max: Float) -> bool
+        where F: Fn(&Ray, usize) -> bool {
+        let root = match self.root {
+            Some(BVH4Child::Leaf { start, count }) => return primitives[start..start + count].iter().any(|p| hit(ray, *p)),
+            Some(root) => root,
+            None => return false,
+        };
+        let simd_ray = SimdRay::new(ray);
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(root);
+
+        while let Some(child) = stack.pop() {
+            match child {
+                BVH4Child::Leaf { start, count } => {
+                    if primitives[start..start + count].iter().any(|p| hit(ray, *p)) {
+                        return true;
+                    }
+                }
+                BVH4Child::Node(node) => {
+                    let node = &self.nodes[node];
+                    let (mask, _) = node.intersect(&simd_ray, t_min, t_max);
+
+                    for lane in 0..node.count {
+                        if mask & (1 << lane) != 0 {
+                            stack.push(node.children[lane]);
+                        }
+                    }
+                }
+            }
+        }
+
+        false
+    }
+}
+
+
+struct BVH4Node {
+    /// The bounds of the children per axis, with one lane per child.
+    min: [FloatX4; 3],
+    max: [FloatX4; 3],
+    children: [BVH4Child; 4],
+    /// How many of the lanes hold children.
+    count: usize,
+}
+impl BVH4Node {
+    fn empty() -> Self {
+        Self {
+            min: [FloatX4::ZERO; 3],
+            max: [FloatX4::ZERO; 3],
+            children: [BVH4Child::Leaf { start: 0, count: 0 }; 4],
+            count: 0,
+        }
+    }
+
+    /// Intersects the ray with the boxes of all children at once.
+    /// Returns a bit for every child that is hit, and the distances the ray enters their boxes at.
+    fn intersect(&self, ray: &SimdRay, t_min: Float, t_max: Float) -> (i32, [Float; 4]) {
+        let mut near = FloatX4::splat(t_min);
+        let mut far = FloatX4::splat(t_max);
+
+        for a in 0..3 {
+            let (entry, exit) = if ray.negative[a] { (self.max[a], self.min[a]) } else { (self.min[a], self.max[a]) };
+
+            near = near.fast_max((entry - ray.origin[a]) * ray.inv_direction[a]);
+            far = far.fast_min((exit - ray.origin[a]) * ray.inv_direction[a]);
+        }
+
+        (far.cmp_gt(near).move_mask(), near.to_array())
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum BVH4Child {
+    Node(usize),
+    /// A range of the primitive order of the binary BVH.
+    Leaf {
+        start: usize,
+        count: usize,
+    },
+}
+
+
+/// A ray with its components repeated in every lane.
+struct SimdRay {
+    origin: [FloatX4; 3],
+    inv_direction: [FloatX4; 3],
+    negative: [bool; 3],
+}
+impl SimdRay {
+    fn new(ray: &Ray) -> Self {
+        let inv_direction = [0, 1, 2].map(|a| {
+            let d = ray.direction[a];
+            let inv = 1.0 / d;
+
+            // Dividing by zero would give NaN for boxes that start exactly at the origin, a huge value leaves their side on the ray.
+            FloatX4::splat(if inv.is_finite() { inv } else { Float::MAX.copysign(d) })
+        });
+
+        Self {
+            origin: [0, 1, 2].map(|a| FloatX4::splat(ray.origin[a])),
+            inv_direction,
+            negative: [0, 1, 2].map(|a| ray.direction[a].is_sign_negative()),
+        }
+    }
+}
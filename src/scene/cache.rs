@@ -0,0 +1,242 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::aabb::AABB;
+use crate::Float;
+use crate::scene::bvh::BVH;
+
+
+/// Identifies BVH cache files.
+const MAGIC: &[u8; 4] = b"RBVH";
+/// Increased whenever the layout of cache files changes, files of other versions are rebuilt.
+const VERSION: u32 = 1;
+
+
+/// Hashes the inputs of all BVHs of a scene, which are all they depend on.
+/// FNV-1a is used since it is stable across platforms and compiler versions.
+pub(crate) fn geometry_hash(inputs: &[Vec<(usize, AABB)>]) -> u64 {
+    let mut hash = GeometryHash::new();
+
+    hash.write_u64(inputs.len() as u64);
+    for input in inputs {
+        hash.write_u64(input.len() as u64);
+        for (i, aabb) in input {
+            hash.write_u64(*i as u64);
+            for a in 0..3 {
+                hash.write_float(aabb.min[a]);
+                hash.write_float(aabb.max[a]);
+            }
+        }
+    }
+
+    hash.0
+}
+
+struct GeometryHash(u64);
+impl GeometryHash {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+    fn write_u64(&mut self, v: u64) {
+        self.write(&v.to_le_bytes());
+    }
+    fn write_float(&mut self, v: Float) {
+        self.write(&v.to_le_bytes());
+    }
+}
+
+
+/// Loads the BVHs stored at `path`, given how many primitives each of them has to refer to.
+///
+/// Returns `None` if there is no file yet, or it was written for other geometry, another version or another float precision,
+/// in which case the BVHs have to be built again.
+pub(crate) fn load(path: &Path, hash: u64, primitive_counts: &[usize]) -> Result<Option<Vec<BVH>>, BVHCacheError> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(BVHCacheError::Io(path.to_owned(), e)),
+    };
+    let corrupt = |message: String| BVHCacheError::Corrupt(path.to_owned(), message);
+
+    let mut decoder = Decoder::new(&bytes);
+    if decoder.bytes(MAGIC.len()).map_err(corrupt)? != MAGIC {
+        return Err(corrupt("Not a BVH cache file".to_owned()));
+    }
+    let version = decoder.u32().map_err(corrupt)?;
+    let float_size = decoder.u32().map_err(corrupt)?;
+    let file_hash = decoder.u64().map_err(corrupt)?;
+    if version != VERSION || float_size as usize != std::mem::size_of::<Float>() || file_hash != hash {
+        return Ok(None);
+    }
+
+    let count = decoder.usize().map_err(corrupt)?;
+    if count != primitive_counts.len() {
+        return Err(corrupt(format!("Holds {} BVHs, but the scene has {}", count, primitive_counts.len())));
+    }
+
+    let bvhs = primitive_counts.iter()
+        .map(|n| BVH::decode(&mut decoder, *n))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(corrupt)?;
+
+    if !decoder.is_empty() {
+        return Err(corrupt("Unexpected data after the last BVH".to_owned()));
+    }
+
+    Ok(Some(bvhs))
+}
+
+/// Stores the BVHs at `path`, replacing what is there.
+/// The file is written next to it first, so that an interrupted write can't leave a corrupt cache behind.
+pub(crate) fn save(path: &Path, hash: u64, bvhs: &[&BVH]) -> Result<(), BVHCacheError> {
+    let mut encoder = Encoder::default();
+    encoder.bytes(MAGIC);
+    encoder.u32(VERSION);
+    encoder.u32(std::mem::size_of::<Float>() as u32);
+    encoder.u64(hash);
+    encoder.usize(bvhs.len());
+    for bvh in bvhs {
+        bvh.encode(&mut encoder);
+    }
+
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    std::fs::write(&temporary, &encoder.0)
+        .and_then(|_| std::fs::rename(&temporary, path))
+        .map_err(|e| BVHCacheError::Io(path.to_owned(), e))
+}
+
+
+/// Writes values in little endian.
+#[derive(Default)]
+pub(crate) struct Encoder(Vec<u8>);
+impl Encoder {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+    pub(crate) fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+    pub(crate) fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+    pub(crate) fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+    pub(crate) fn usize(&mut self, v: usize) {
+        self.u64(v as u64);
+    }
+    pub(crate) fn float(&mut self, v: Float) {
+        self.bytes(&v.to_le_bytes());
+    }
+    pub(crate) fn aabb(&mut self, aabb: &AABB) {
+        for a in 0..3 {
+            self.float(aabb.min[a]);
+        }
+        for a in 0..3 {
+            self.float(aabb.max[a]);
+        }
+    }
+}
+
+/// Reads what [Encoder] wrote, failing with a message once the data ends early.
+pub(crate) struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            position: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self.position.checked_add(count)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| format!("Ends early, expected {} more bytes at offset {}", count, self.position))?;
+
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.array::<1>()?[0])
+    }
+    pub(crate) fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+    pub(crate) fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+    pub(crate) fn usize(&mut self) -> Result<usize, String> {
+        let v = self.u64()?;
+        usize::try_from(v).map_err(|_| format!("Index {} is too large", v))
+    }
+    /// Reads a count of items that take at least `item_size` bytes each, making sure the data can hold that many
+    /// before anything is allocated for them.
+    pub(crate) fn count(&mut self, item_size: usize) -> Result<usize, String> {
+        let count = self.usize()?;
+        let remaining = self.bytes.len() - self.position;
+
+        if count.checked_mul(item_size).filter(|size| *size <= remaining).is_none() {
+            return Err(format!("Holds {} items, which don't fit into the remaining {} bytes", count, remaining));
+        }
+        Ok(count)
+    }
+    pub(crate) fn float(&mut self) -> Result<Float, String> {
+        Ok(Float::from_le_bytes(self.array()?))
+    }
+    pub(crate) fn aabb(&mut self) -> Result<AABB, String> {
+        let mut aabb = AABB::empty();
+        for a in 0..3 {
+            aabb.min[a] = self.float()?;
+        }
+        for a in 0..3 {
+            aabb.max[a] = self.float()?;
+        }
+        Ok(aabb)
+    }
+}
+
+
+#[derive(Debug)]
+pub enum BVHCacheError {
+    Io(PathBuf, io::Error),
+    Corrupt(PathBuf, String),
+}
+impl Display for BVHCacheError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "Could not access BVH cache {}: {}", path.display(), e),
+            Self::Corrupt(path, message) => write!(f, "Corrupt BVH cache {}: {}", path.display(), message),
+        }
+    }
+}
+impl Error for BVHCacheError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(_, e) => Some(e),
+            Self::Corrupt(..) => None,
+        }
+    }
+}
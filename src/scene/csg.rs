@@ -0,0 +1,192 @@
+use nalgebra::{Affine3, Point3, UnitVector3, Vector3};
+use crate::aabb::AABB;
+use crate::Float;
+use crate::randomness::Randomness;
+use crate::ray::Ray;
+use crate::scene::instance::ShapeData;
+use crate::scene::primitive::{Primitive, PrimitiveIntersection, traced_solid_angle};
+use crate::world::shape::CsgOperation;
+
+
+/// How far past a crossing the next one is looked for, relative to its distance, so that the same crossing isn't found again.
+const STEP: Float = 1.0e-5;
+
+
+/// The primitive of a CSG shape, which holds the primitives of both operands with a BVH over each of them.
+///
+/// Rays are intersected by walking along the crossings of both operands in order, keeping track of whether they are inside of each,
+/// until the result is entered or left. Whether a ray starts inside of an operand is told by whether its first crossing leaves it.
+pub struct Csg {
+    op: CsgOperation,
+    a: ShapeData,
+    b: ShapeData,
+    aabb: AABB,
+    /// The summed up areas of the primitives of both operands, in the order of `a` and then `b`, to pick them by area.
+    areas: Vec<Float>,
+}
+impl Csg {
+    /// Returns `None` if the result is empty, since the bounds of the operands don't overlap as needed.
+    pub(crate) fn new(op: CsgOperation, a: ShapeData, b: ShapeData) -> Option<Self> {
+        let aabb = combined_aabb(op, operand_aabb(&a, |p| p.aabb()), operand_aabb(&b, |p| p.aabb()))?;
+
+        let areas = a.primitives.iter()
+            .chain(&b.primitives)
+            .scan(0.0, |sum, p| {
+                *sum += p.area();
+                Some(*sum)
+            })
+            .collect();
+
+        Some(Self {
+            op,
+            a,
+            b,
+            aabb,
+            areas,
+        })
+    }
+
+    pub(super) fn aabb(&self) -> AABB {
+        self.aabb
+    }
+    pub(super) fn transformed_aabb(&self, t: &Affine3<Float>) -> AABB {
+        let a = operand_aabb(&self.a, |p| p.transformed_aabb(t));
+        let b = operand_aabb(&self.b, |p| p.transformed_aabb(t));
+
+        // The transformed bounds of overlapping operands can miss each other, which leaves a point where nothing is hit.
+        combined_aabb(self.op, a, b).unwrap_or_else(|| {
+            let center = t.transform_point(&self.aabb.centroid());
+            AABB::new(center, center)
+        })
+    }
+
+    /// The crossing of an operand where the ray enters or leaves the result first.
+    /// The normal is the one of the operand, which faces the ray like for all primitives,
+    /// and `outside` tells whether the result is entered, so that subtracted surfaces face into the hole they leave.
+    pub(super) fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<PrimitiveIntersection> {
+        let next = |shape: &ShapeData, t_min: Float| shape.intersect(ray, t_min, Float::INFINITY).map(|(_, i)| i);
+
+        let (mut next_a, mut next_b) = (next(&self.a, t_min), next(&self.b, t_min));
+        let mut in_a = next_a.as_ref().is_some_and(|i| !i.outside);
+        let mut in_b = next_b.as_ref().is_some_and(|i| !i.outside);
+
+        loop {
+            let a_first = match (&next_a, &next_b) {
+                (Some(a), Some(b)) => a.t <= b.t,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => return None,
+            };
+            let was_inside = self.op.contains(in_a, in_b);
+
+            let crossing = if a_first {
+                let crossing = next_a.take().unwrap();
+                in_a = crossing.outside;
+                next_a = next(&self.a, step(crossing.t));
+                crossing
+            } else {
+                let crossing = next_b.take().unwrap();
+                in_b = crossing.outside;
+                next_b = next(&self.b, step(crossing.t));
+                crossing
+            };
+
+            if crossing.t > t_max {
+                return None;
+            }
+            let inside = self.op.contains(in_a, in_b);
+            if inside != was_inside {
+                return Some(PrimitiveIntersection { outside: inside, ..crossing });
+            }
+        }
+    }
+    pub(super) fn intersects(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        self.intersect(ray, t_min, t_max).is_some()
+    }
+
+    /// The outward normal of the operand surface at `p`, flipped for the subtracted operand.
+    /// The surface is found by crossing it with short rays along the axes.
+    pub(super) fn outward_normal(&self, p: &Point3<Float>) -> UnitVector3<Float> {
+        let epsilon = self.aabb.diagonal().magnitude() * 1.0e-4;
+
+        for axis in (0..3).map(Vector3::ith_axis) {
+            let ray = Ray::new(p - axis.into_inner() * epsilon, axis);
+
+            for (shape, flip) in [(&self.a, false), (&self.b, self.op == CsgOperation::Difference)] {
+                if let Some((i, _)) = shape.intersect(&ray, 0.0, 2.0 * epsilon) {
+                    let normal = shape.primitives[i].outward_normal(p);
+                    return if flip { -normal } else { normal };
+                }
+            }
+        }
+
+        Vector3::y_axis()
+    }
+
+    /// The area of the surfaces of both operands, which points are sampled on since the area of the result isn't known.
+    pub(super) fn area(&self) -> Float {
+        self.areas.last().copied().unwrap_or(0.0)
+    }
+    pub(super) fn solid_angle(&self, o: Point3<Float>) -> Float {
+        traced_solid_angle(&self.aabb, o, |ray| self.intersects(ray, 0.001, Float::INFINITY))
+    }
+    /// Directions are sampled towards a primitive of either operand picked by area, so the density is the mixture of theirs.
+    /// Parts of them that aren't on the result are either hidden behind it or missed by the ray, so those samples don't count.
+    /// Every primitive is asked, since those of nested shapes sample points outside of their bounds.
+    pub(super) fn direction_pdf(&self, o: Point3<Float>, direction: UnitVector3<Float>) -> Float {
+        let total = self.area();
+
+        self.a.primitives.iter()
+            .chain(&self.b.primitives)
+            .enumerate()
+            .map(|(i, p)| self.primitive_area(i) / total * p.direction_pdf(o, direction))
+            .sum()
+    }
+
+    pub(super) fn random_point_on_surface(&self, rng: &mut dyn Randomness) -> Point3<Float> {
+        self.random_primitive(rng).random_point_on_surface(rng)
+    }
+    pub(super) fn random_direction_towards(&self, o: Point3<Float>, rng: &mut dyn Randomness) -> UnitVector3<Float> {
+        self.random_primitive(rng).random_direction_towards(o, rng)
+    }
+
+    fn random_primitive(&self, rng: &mut dyn Randomness) -> &Primitive {
+        let target = rng.float() * self.area();
+        let i = self.areas.partition_point(|sum| *sum <= target).min(self.areas.len() - 1);
+
+        match i.checked_sub(self.a.primitives.len()) {
+            None => &self.a.primitives[i],
+            Some(i) => &self.b.primitives[i],
+        }
+    }
+    fn primitive_area(&self, i: usize) -> Float {
+        self.areas[i] - if i == 0 { 0.0 } else { self.areas[i - 1] }
+    }
+}
+
+
+/// Where to look for the crossing after the one at `t`.
+fn step(t: Float) -> Float {
+    t + STEP * t.abs().max(1.0)
+}
+
+/// The bounds of all primitives of an operand, or `None` if it has none.
+fn operand_aabb<F: Fn(&Primitive) -> AABB>(shape: &ShapeData, aabb: F) -> Option<AABB> {
+    shape.primitives.iter().map(aabb).reduce(AABB::merged)
+}
+
+/// Bounds of the result from the bounds of the operands, or `None` if the result is certainly empty.
+fn combined_aabb(op: CsgOperation, a: Option<AABB>, b: Option<AABB>) -> Option<AABB> {
+    match op {
+        CsgOperation::Union => match (a, b) {
+            (Some(a), Some(b)) => Some(AABB::merged(a, b)),
+            (a, b) => a.or(b),
+        },
+        CsgOperation::Intersection => {
+            let (a, b) = (a?, b?);
+            let (min, max) = (a.min.sup(&b.min), a.max.inf(&b.max));
+            (0..3).all(|i| min[i] <= max[i]).then(|| AABB::new(min, max))
+        }
+        CsgOperation::Difference => a,
+    }
+}
@@ -0,0 +1,270 @@
+use nalgebra::{Affine3, Isometry3, Point3, Unit, UnitVector3, Vector3};
+use crate::aabb::AABB;
+use crate::Float;
+use crate::randomness::Randomness;
+use crate::ray::Ray;
+use crate::scene::primitive::{intersect_triangle, padded, PrimitiveIntersection, traced_solid_angle, triangle_hit};
+use crate::texture::{Texture2D, TextureCoord2D};
+
+
+/// The primitive of a heightfield shape, which is intersected by walking the grid cells the ray passes over.
+///
+/// Samples are spaced evenly over the xz-plane, centered at the origin, with the rows of the map running along z.
+/// Every cell between four samples is split into two triangles, whose normals are interpolated from the slopes
+/// at the samples. The grid is laid out in its own space, which is placed by `transform`.
+pub struct Heightfield {
+    /// The heights of the samples row by row, already scaled.
+    heights: Vec<Float>,
+    columns: usize,
+    rows: usize,
+    spacing: Float,
+    transform: Isometry3<Float>,
+    bounds: AABB,
+    /// The summed up areas of the triangles, two per cell row by row, to pick them by area.
+    areas: Vec<Float>,
+}
+impl Heightfield {
+    /// Needs at least two samples along either side of the map.
+    pub(crate) fn new(heights: &Texture2D<Float>, horizontal_scale: Float, vertical_scale: Float, transform: Isometry3<Float>) -> Self {
+        let (columns, rows) = (heights.width() as usize, heights.height() as usize);
+        let heights: Vec<Float> = heights.pixels().map(|h| h * vertical_scale).collect();
+
+        let half_width = (columns - 1) as Float * horizontal_scale * 0.5;
+        let half_depth = (rows - 1) as Float * horizontal_scale * 0.5;
+        let (low, high) = heights.iter().fold((Float::INFINITY, Float::NEG_INFINITY), |(low, high), h| (low.min(*h), high.max(*h)));
+        let bounds = padded(AABB::new_unchecked(
+            Point3::new(-half_width, low, -half_depth),
+            Point3::new(half_width, high, half_depth),
+        ));
+
+        let mut heightfield = Self {
+            heights,
+            columns,
+            rows,
+            spacing: horizontal_scale,
+            transform,
+            bounds,
+            areas: Vec::new(),
+        };
+        heightfield.areas = (0..(columns - 1) * (rows - 1) * 2)
+            .scan(0.0, |sum, i| {
+                let [a, b, c] = heightfield.triangle(i / 2, i % 2);
+                *sum += (b - a).cross(&(c - a)).magnitude() * 0.5;
+                Some(*sum)
+            })
+            .collect();
+
+        heightfield
+    }
+
+    pub(super) fn aabb(&self) -> AABB {
+        self.transformed_aabb(&Affine3::identity())
+    }
+    pub(super) fn transformed_aabb(&self, t: &Affine3<Float>) -> AABB {
+        let (min, max) = (self.bounds.min, self.bounds.max);
+        let corners: Vec<Point3<Float>> = (0..8)
+            .map(|i| {
+                let corner = Point3::from(Vector3::from_fn(|a, _| if i & (1 << a) == 0 { min[a] } else { max[a] }));
+                t.transform_point(&(self.transform * corner))
+            })
+            .collect();
+
+        AABB::from_points(&corners)
+    }
+
+    /// Texture coordinates span the grid, with the first row of the map at the top.
+    pub(super) fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<PrimitiveIntersection> {
+        let local_ray = self.ray_to_local(ray);
+
+        let mut closest: Option<(Float, usize, usize)> = None;
+        self.walk(&local_ray, t_min, t_max, |cell| {
+            for half in 0..2 {
+                let t_max = closest.map_or(t_max, |(t, _, _)| t);
+                if let Some((t, _)) = triangle_hit(&self.triangle(cell, half), &local_ray, t_min, t_max) {
+                    closest = Some((t, cell, half));
+                }
+            }
+            closest.is_some()
+        });
+        let (t, cell, half) = closest?;
+
+        let indices = self.triangle_indices(cell, half);
+        let normals = indices.map(|(i, j)| self.vertex_normal(i, j));
+        let tex_coords = indices.map(|(i, j)| TextureCoord2D::new(
+            i as Float / (self.columns - 1) as Float,
+            1.0 - j as Float / (self.rows - 1) as Float,
+        ));
+        let i = intersect_triangle(&self.triangle(cell, half), Some(&normals), Some(&tex_coords), &local_ray, t_min, t)?;
+
+        Some(PrimitiveIntersection {
+            point: ray.point_at(i.t),
+            normal: self.transform.rotation * i.normal,
+            ..i
+        })
+    }
+    pub(super) fn intersects(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        let local_ray = self.ray_to_local(ray);
+
+        let mut hit = false;
+        self.walk(&local_ray, t_min, t_max, |cell| {
+            hit = (0..2).any(|half| triangle_hit(&self.triangle(cell, half), &local_ray, t_min, t_max).is_some());
+            hit
+        });
+        hit
+    }
+
+    /// The normal of the triangle under `p`, without the interpolation from the samples.
+    pub(super) fn outward_normal(&self, p: &Point3<Float>) -> UnitVector3<Float> {
+        let local = self.transform.inverse_transform_point(p);
+        let (i, j) = (self.column_at(local.x), self.row_at(local.z));
+        let [x, _, z] = self.vertex(i, j).coords.into();
+
+        // The first half of a cell is the one on the side of its first row towards the next one.
+        let half = if local.z - z >= local.x - x { 0 } else { 1 };
+        let [a, b, c] = self.triangle(j * (self.columns - 1) + i, half);
+        self.transform.rotation * Unit::new_normalize((b - a).cross(&(c - a)))
+    }
+
+    pub(super) fn area(&self) -> Float {
+        self.areas.last().copied().unwrap_or(0.0)
+    }
+    pub(super) fn solid_angle(&self, o: Point3<Float>) -> Float {
+        traced_solid_angle(&self.aabb(), o, |ray| self.intersects(ray, 0.001, Float::INFINITY))
+    }
+    /// The surface is sampled by area, so a direction is as likely as all of the places it crosses the surface together.
+    pub(super) fn direction_pdf(&self, o: Point3<Float>, direction: UnitVector3<Float>) -> Float {
+        let local_ray = self.ray_to_local(&Ray::new(o, direction));
+
+        let mut pdf = 0.0;
+        self.walk(&local_ray, 0.001, Float::INFINITY, |cell| {
+            for half in 0..2 {
+                let [a, b, c] = self.triangle(cell, half);
+                if let Some((t, _)) = triangle_hit(&[a, b, c], &local_ray, 0.001, Float::INFINITY) {
+                    let cosine = (b - a).cross(&(c - a)).normalize().dot(&local_ray.direction).abs();
+                    pdf += t.powi(2) / (cosine * self.area());
+                }
+            }
+            false
+        });
+        pdf
+    }
+
+    pub(super) fn random_point_on_surface(&self, rng: &mut dyn Randomness) -> Point3<Float> {
+        let target = rng.float() * self.area();
+        let i = self.areas.partition_point(|sum| *sum <= target).min(self.areas.len() - 1);
+        let [a, b, c] = self.triangle(i / 2, i % 2);
+
+        let r0 = rng.float().sqrt();
+        let r1 = rng.float();
+        let p = a + (b - a) * r0 * (1.0 - r1) + (c - a) * r0 * r1;
+
+        self.transform * p
+    }
+    pub(super) fn random_direction_towards(&self, o: Point3<Float>, rng: &mut dyn Randomness) -> UnitVector3<Float> {
+        Unit::new_normalize(self.random_point_on_surface(rng) - o)
+    }
+
+    fn ray_to_local(&self, ray: &Ray) -> Ray {
+        Ray::new(
+            self.transform.inverse_transform_point(&ray.origin),
+            self.transform.inverse_transform_unit_vector(&ray.direction),
+        )
+    }
+
+    /// Visits the cells the ray passes over within the bounds in order, skipping those it passes above or below,
+    /// until `visit` returns true.
+    fn walk<F: FnMut(usize) -> bool>(&self, ray: &Ray, t_min: Float, t_max: Float, mut visit: F) {
+        let Some((t_enter, t_exit)) = self.bounds.ray_interval(ray, t_min, t_max) else { return };
+        let (o, d) = (ray.origin, ray.direction);
+        let entry = ray.point_at(t_enter);
+
+        let (mut i, mut j) = (self.column_at(entry.x), self.row_at(entry.z));
+        let first = self.vertex(0, 0);
+
+        // The distances along the ray to the next cell boundary and between boundaries, for x and z.
+        let boundary = |axis: usize, cell: usize| {
+            if d[axis] == 0.0 {
+                return (Float::INFINITY, Float::INFINITY);
+            }
+            let next = first[axis] + (cell + usize::from(d[axis] > 0.0)) as Float * self.spacing;
+            ((next - o[axis]) / d[axis], self.spacing / d[axis].abs())
+        };
+        let (mut next_x, step_x) = boundary(0, i);
+        let (mut next_z, step_z) = boundary(2, j);
+
+        let mut t = t_enter;
+        loop {
+            let t_leave = next_x.min(next_z).min(t_exit);
+
+            let corners = [(i, j), (i + 1, j), (i, j + 1), (i + 1, j + 1)].map(|(i, j)| self.height(i, j));
+            let (low, high) = corners.iter().fold((Float::INFINITY, Float::NEG_INFINITY), |(low, high), h| (low.min(*h), high.max(*h)));
+            let (y0, y1) = (o.y + d.y * t, o.y + d.y * t_leave);
+            let margin = self.bounds.diagonal().y.max(self.spacing) * 1.0e-4;
+
+            if y0.min(y1) <= high + margin && y0.max(y1) >= low - margin && visit(j * (self.columns - 1) + i) {
+                return;
+            }
+            if t_leave >= t_exit {
+                return;
+            }
+
+            if next_x < next_z {
+                if (d.x > 0.0 && i + 2 >= self.columns) || (d.x < 0.0 && i == 0) {
+                    return;
+                }
+                i = if d.x > 0.0 { i + 1 } else { i - 1 };
+                next_x += step_x;
+            } else {
+                if (d.z > 0.0 && j + 2 >= self.rows) || (d.z < 0.0 && j == 0) {
+                    return;
+                }
+                j = if d.z > 0.0 { j + 1 } else { j - 1 };
+                next_z += step_z;
+            }
+            t = t_leave;
+        }
+    }
+
+    /// The column of cells over `x`, clamped to the grid.
+    fn column_at(&self, x: Float) -> usize {
+        let i = ((x - self.vertex(0, 0).x) / self.spacing).floor().max(0.0) as usize;
+        i.min(self.columns - 2)
+    }
+    /// The row of cells over `z`, clamped to the grid.
+    fn row_at(&self, z: Float) -> usize {
+        let j = ((z - self.vertex(0, 0).z) / self.spacing).floor().max(0.0) as usize;
+        j.min(self.rows - 2)
+    }
+
+    fn height(&self, i: usize, j: usize) -> Float {
+        self.heights[j * self.columns + i]
+    }
+    fn vertex(&self, i: usize, j: usize) -> Point3<Float> {
+        Point3::new(
+            (i as Float - (self.columns - 1) as Float * 0.5) * self.spacing,
+            self.height(i, j),
+            (j as Float - (self.rows - 1) as Float * 0.5) * self.spacing,
+        )
+    }
+    /// The samples at the corners of one of the two triangles of a cell, which both face up.
+    fn triangle_indices(&self, cell: usize, half: usize) -> [(usize, usize); 3] {
+        let (i, j) = (cell % (self.columns - 1), cell / (self.columns - 1));
+        if half == 0 {
+            [(i, j), (i, j + 1), (i + 1, j + 1)]
+        } else {
+            [(i, j), (i + 1, j + 1), (i + 1, j)]
+        }
+    }
+    fn triangle(&self, cell: usize, half: usize) -> [Point3<Float>; 3] {
+        self.triangle_indices(cell, half).map(|(i, j)| self.vertex(i, j))
+    }
+    /// The normal at a sample from the slopes towards its neighbours, which are one-sided at the edges of the grid.
+    fn vertex_normal(&self, i: usize, j: usize) -> UnitVector3<Float> {
+        let (left, right) = (i.saturating_sub(1), (i + 1).min(self.columns - 1));
+        let (back, front) = (j.saturating_sub(1), (j + 1).min(self.rows - 1));
+
+        let slope_x = (self.height(right, j) - self.height(left, j)) / ((right - left) as Float * self.spacing);
+        let slope_z = (self.height(i, front) - self.height(i, back)) / ((front - back) as Float * self.spacing);
+        Unit::new_normalize(Vector3::new(-slope_x, 1.0, -slope_z))
+    }
+}
@@ -0,0 +1,161 @@
+use generational_arena::Index;
+use nalgebra::{Affine3, Matrix3, Point3, Unit, UnitVector3};
+use rayon::prelude::*;
+use crate::aabb::AABB;
+use crate::Float;
+use crate::randomness::Randomness;
+use crate::ray::Ray;
+use crate::scene::bvh::BVH;
+use crate::scene::primitive::{Primitive, PrimitiveIntersection};
+use crate::world::material::MaterialRef;
+
+
+/// The primitives of a shape in object space, with a BVH over them.
+/// Every object using the shape shares them.
+pub(crate) struct ShapeData {
+    pub(crate) primitives: Vec<Primitive>,
+    pub(crate) bvh: BVH,
+}
+impl ShapeData {
+    pub(crate) fn new<R: Randomness>(primitives: Vec<Primitive>, rng: &mut R) -> Self {
+        let mut shape = Self::without_bvh(primitives);
+        shape.bvh = BVH::new(&mut shape.bvh_input(), rng);
+        shape
+    }
+    /// Leaves building the BVH to the caller, e.g. to load it from a cache.
+    pub(crate) fn without_bvh(primitives: Vec<Primitive>) -> Self {
+        Self {
+            primitives,
+            bvh: BVH::default(),
+        }
+    }
+
+    /// What the BVH over the primitives is built from.
+    pub(crate) fn bvh_input(&self) -> Vec<(usize, AABB)> {
+        self.primitives.iter()
+            .enumerate()
+            .map(|(i, p)| (i, p.aabb()))
+            .collect()
+    }
+
+    /// The closest hit in object space, and the index of the primitive that was hit.
+    pub(crate) fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<(usize, PrimitiveIntersection)> {
+        let find = |ray: &Ray, p: usize, t_min: Float, t_max: Float| {
+            self.primitives[p].intersect(ray, t_min, t_max).map(|i| (p, i))
+        };
+
+        self.bvh.find_closest(ray, find, |(_, i)| i.t, t_min, t_max)
+    }
+    pub(crate) fn intersects(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        let hit = |ray: &Ray, p: usize| self.primitives[p].intersects(ray, t_min, t_max);
+
+        self.bvh.find_any(ray, hit, t_min, t_max)
+    }
+}
+
+
+/// An object placed in the scene, which refers to the geometry of its shape instead of copying it.
+/// Rays are moved into object space to be intersected with the shape.
+pub(crate) struct Instance {
+    pub(crate) shape: usize,
+    /// The object in the world this is an instance of.
+    pub(crate) object: Index,
+    pub(crate) material: MaterialRef,
+    pub(crate) transform: Affine3<Float>,
+    /// The world space bounds, as tight as the bounds of the transformed primitives.
+    pub(crate) aabb: AABB,
+    inverse: Affine3<Float>,
+    /// The inverse transpose of the linear part of the transform, which keeps normals perpendicular to the surface.
+    normal_matrix: Matrix3<Float>,
+    /// How much the transform scales volumes.
+    determinant: Float,
+}
+impl Instance {
+    /// Returns `None` if the transform can't be inverted.
+    /// `aabb` may be given if the bounds of the shape with this transform are already known.
+    pub(crate) fn new(
+        shape_id: usize,
+        shape: &ShapeData,
+        object: Index,
+        material: MaterialRef,
+        transform: Affine3<Float>,
+        aabb: Option<AABB>,
+    ) -> Option<Self> {
+        let inverse = transform.try_inverse()?;
+        let linear: Matrix3<Float> = transform.matrix().fixed_slice::<3, 3>(0, 0).into();
+        let inverse_linear: Matrix3<Float> = inverse.matrix().fixed_slice::<3, 3>(0, 0).into();
+
+        let aabb = aabb.unwrap_or_else(|| {
+            shape.primitives.par_iter()
+                .map(|p| p.transformed_aabb(&transform))
+                .reduce(AABB::empty, AABB::merged)
+        });
+
+        Some(Self {
+            shape: shape_id,
+            object,
+            material,
+            transform,
+            aabb,
+            inverse,
+            normal_matrix: inverse_linear.transpose(),
+            determinant: linear.determinant().abs(),
+        })
+    }
+
+    pub(crate) fn intersect(&self, shape: &ShapeData, ray: &Ray, t_min: Float, t_max: Float) -> Option<(usize, PrimitiveIntersection)> {
+        let (local_ray, scale) = self.ray_to_local(ray);
+
+        shape.intersect(&local_ray, t_min * scale, t_max * scale)
+            .map(|(p, int)| (p, self.intersection_to_world(int, scale)))
+    }
+    pub(crate) fn intersects(&self, shape: &ShapeData, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        let (local_ray, scale) = self.ray_to_local(ray);
+        shape.intersects(&local_ray, t_min * scale, t_max * scale)
+    }
+    /// Intersects a single primitive of the instance.
+    pub(crate) fn intersect_primitive(&self, primitive: &Primitive, ray: &Ray, t_min: Float, t_max: Float) -> Option<PrimitiveIntersection> {
+        let (local_ray, scale) = self.ray_to_local(ray);
+
+        primitive.intersect(&local_ray, t_min * scale, t_max * scale)
+            .map(|int| self.intersection_to_world(int, scale))
+    }
+
+    /// The ray in object space, and the factor that turns distances along the ray in world space into those in object space.
+    fn ray_to_local(&self, ray: &Ray) -> (Ray, Float) {
+        let direction = self.inverse.transform_vector(&ray.direction);
+        let scale = direction.magnitude();
+
+        (Ray::new(self.point_to_local(&ray.origin), Unit::new_unchecked(direction / scale)), scale)
+    }
+    pub(crate) fn point_to_local(&self, p: &Point3<Float>) -> Point3<Float> {
+        self.inverse.transform_point(p)
+    }
+    /// The direction in object space, and the factor that turns densities with respect to solid angle in object space
+    /// into those in world space.
+    pub(crate) fn direction_to_local(&self, d: &UnitVector3<Float>) -> (UnitVector3<Float>, Float) {
+        let local = self.inverse.transform_vector(d);
+        let length = local.magnitude();
+
+        (Unit::new_unchecked(local / length), 1.0 / (self.determinant * length.powi(3)))
+    }
+    pub(crate) fn point_to_world(&self, p: &Point3<Float>) -> Point3<Float> {
+        self.transform.transform_point(p)
+    }
+    pub(crate) fn direction_to_world(&self, d: &UnitVector3<Float>) -> UnitVector3<Float> {
+        Unit::new_normalize(self.transform.transform_vector(d))
+    }
+    /// How much the transform scales areas of a surface with the given normal in object space.
+    pub(crate) fn area_scale(&self, normal: &UnitVector3<Float>) -> Float {
+        self.determinant * (self.normal_matrix * normal.into_inner()).magnitude()
+    }
+
+    fn intersection_to_world(&self, int: PrimitiveIntersection, scale: Float) -> PrimitiveIntersection {
+        PrimitiveIntersection {
+            t: int.t / scale,
+            point: self.point_to_world(&int.point),
+            normal: Unit::new_normalize(self.normal_matrix * int.normal.into_inner()),
+            ..int
+        }
+    }
+}
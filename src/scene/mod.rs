@@ -1,78 +1,234 @@
+use std::collections::HashMap;
+use std::path::Path;
+use generational_arena::Index;
+use nalgebra::Isometry3;
+use crate::aabb::AABB;
 use crate::Float;
 use crate::intersection::Intersection;
 use crate::randomness::Randomness;
 use crate::ray::Ray;
-use crate::scene::bvh::BVH;
+use crate::scene::bvh::{BVH, BVHLayout};
+use crate::scene::cache::BVHCacheError;
+use crate::scene::instance::{Instance, ShapeData};
 use crate::scene::primitive::{Primitive, PrimitiveRef};
 use crate::world::material::MaterialRef;
 use crate::world::World;
 
 pub mod primitive;
 pub mod bvh;
+mod bvh4;
+pub mod cache;
+pub mod csg;
+pub mod heightfield;
+pub(crate) mod instance;
+mod revolved;
+pub mod sdf;
 
 
+/// The world prepared for rendering, with a two-level acceleration structure:
+/// every shape gets a BVH over its primitives in object space, and a BVH over the objects refers to them.
 pub struct Scene<'a> {
     pub(crate) world: &'a World,
-    pub(crate) primitives: Vec<PrimitiveData>,
+    pub(crate) shapes: Vec<ShapeData>,
+    /// Where the data of each shape of the world is in [Self::shapes].
+    shape_ids: HashMap<Index, usize>,
+    pub(crate) instances: Vec<Instance>,
     pub(crate) bvh: BVH,
-    pub(crate) materials: Vec<MaterialRef>,
 }
 impl<'a> Scene<'a> {
     pub fn new<R: Randomness>(world: &'a World, rng: &mut R) -> Scene<'a> {
-        let mut primitives = Vec::new();
-        let mut aabbs = Vec::new();
-        let mut materials = Vec::with_capacity(world.objects.len());
-
-        for (object_id, (_, o)) in world.objects.iter().enumerate() {
-            let shape = &world.shapes[o.shape.0];
-            let t = &o.transform;
-            let material = o.material;
-            materials.push(material);
-
-            let t_primitives = shape.as_transformed_primitives(t);
-
-            for p in t_primitives {
-                let primitive_i = primitives.len();
-                let aabb = p.aabb();
-
-                aabbs.push((PrimitiveRef(primitive_i), aabb));
-                primitives.push(PrimitiveData {
-                    primitive: p,
-                    object_id,
-                });
+        Self::update(DetachedScene::default(), world, rng)
+    }
+    /// Like [Self::new], but loads the BVHs from the cache file at `path` if it was written for the same geometry.
+    /// Otherwise they are built and the file is replaced, so that the next run with this geometry can skip building them.
+    ///
+    /// Changing only materials keeps the cache valid.
+    /// A cache file that exists but can't be read is an error rather than built again, so that it isn't overwritten by accident.
+    pub fn new_cached<R: Randomness, P: AsRef<Path>>(world: &'a World, path: P, rng: &mut R) -> Result<Scene<'a>, BVHCacheError> {
+        let path = path.as_ref();
+        let mut scene = Self::assemble(DetachedScene::default(), world, rng, false);
+
+        let mut inputs: Vec<Vec<(usize, AABB)>> = scene.shapes.iter().map(ShapeData::bvh_input).collect();
+        inputs.push(scene.instances.iter().map(|i| i.aabb).enumerate().collect());
+        let hash = cache::geometry_hash(&inputs);
+        let primitive_counts: Vec<usize> = inputs.iter().map(Vec::len).collect();
+
+        let (mut bvhs, loaded) = match cache::load(path, hash, &primitive_counts)? {
+            Some(bvhs) => (bvhs, true),
+            None => (inputs.iter_mut().map(|input| BVH::new(input, rng)).collect(), false),
+        };
+        scene.bvh = bvhs.pop().unwrap();
+        for (shape, bvh) in scene.shapes.iter_mut().zip(bvhs) {
+            shape.bvh = bvh;
+        }
+
+        if !loaded {
+            let bvhs: Vec<&BVH> = scene.shapes.iter().map(|s| &s.bvh).chain([&scene.bvh]).collect();
+            cache::save(path, hash, &bvhs)?;
+        }
+
+        Ok(scene)
+    }
+
+    /// Brings a scene that was built before up to date with the objects of `world`, which may have been added, moved,
+    /// given other materials or removed since.
+    ///
+    /// Shapes that are still used are kept.
+    /// If only transforms changed, the BVH over the objects is refitted instead of built again,
+    /// unless that makes it too slow to traverse.
+    /// Integrators have to be created again, since they refer to primitives of the scene.
+    pub fn update<R: Randomness>(scene: DetachedScene, world: &'a World, rng: &mut R) -> Scene<'a> {
+        Self::assemble(scene, world, rng, true)
+    }
+    /// Does the work of [Self::update], where `build` tells whether the BVHs that can't be reused are built,
+    /// or left empty for the caller to fill in.
+    fn assemble<R: Randomness>(scene: DetachedScene, world: &'a World, rng: &mut R, build: bool) -> Scene<'a> {
+        let DetachedScene { shapes: old_shapes, shape_ids: old_shape_ids, instances: old_instances, mut bvh } = scene;
+        let layout = bvh.layout();
+
+        let old_objects: Vec<Index> = old_instances.iter().map(|i| i.object).collect();
+        let mut old_shapes: Vec<Option<ShapeData>> = old_shapes.into_iter().map(Some).collect();
+        let mut old_instances: HashMap<Index, Instance> = old_instances.into_iter().map(|i| (i.object, i)).collect();
+
+        let mut shapes = Vec::new();
+        let mut shape_ids = HashMap::new();
+        let mut instances = Vec::with_capacity(world.objects.len());
+        let mut moved = false;
+
+        for (object, o) in world.objects.iter() {
+            // Shapes are only built once, no matter how many objects use them.
+            let shape_id = *shape_ids.entry(o.shape.0).or_insert_with(|| {
+                let shape = old_shape_ids.get(&o.shape.0)
+                    .and_then(|&i| old_shapes[i].take())
+                    .unwrap_or_else(|| {
+                        let primitives = world.shapes[o.shape.0].as_transformed_primitives(&Isometry3::identity(), world, rng);
+                        if !build {
+                            return ShapeData::without_bvh(primitives);
+                        }
+
+                        let mut shape = ShapeData::new(primitives, rng);
+                        shape.bvh.set_layout(layout);
+                        shape
+                    });
+                shapes.push(shape);
+                shapes.len() - 1
+            });
+
+            let shape = &shapes[shape_id];
+            if shape.primitives.is_empty() {
+                continue;
+            }
+
+            let aabb = match old_instances.remove(&object) {
+                Some(old) if old.transform == o.transform => Some(old.aabb),
+                Some(_) => {
+                    moved = true;
+                    None
+                }
+                None => None,
+            };
+            if let Some(instance) = Instance::new(shape_id, shape, object, o.material, o.transform, aabb) {
+                instances.push(instance);
             }
         }
 
-        let bvh = BVH::new(&mut aabbs, rng);
+        let aabbs: Vec<AABB> = instances.iter().map(|i| i.aabb).collect();
+        let same_objects = instances.iter().map(|i| i.object).eq(old_objects);
+
+        if !same_objects && build {
+            let mut aabbs: Vec<(usize, AABB)> = aabbs.into_iter().enumerate().collect();
+            bvh = BVH::new(&mut aabbs, rng);
+            bvh.set_layout(layout);
+        }
+        else if moved {
+            bvh.refit(&aabbs, rng);
+        }
 
         Scene {
             world,
-            primitives,
+            shapes,
+            shape_ids,
+            instances,
             bvh,
-            materials,
+        }
+    }
+    /// Releases the world, so that it can be changed and the scene brought up to date with [Self::update].
+    pub fn detach(self) -> DetachedScene {
+        DetachedScene {
+            shapes: self.shapes,
+            shape_ids: self.shape_ids,
+            instances: self.instances,
+            bvh: self.bvh,
+        }
+    }
+
+    pub fn bvh_layout(&self) -> BVHLayout {
+        self.bvh.layout()
+    }
+    /// Changes how all BVHs of the scene are laid out for traversal.
+    /// The layout is kept when the scene is updated, and BVHs built by the update get it as well.
+    pub fn set_bvh_layout(&mut self, layout: BVHLayout) {
+        self.bvh.set_layout(layout);
+        for shape in &mut self.shapes {
+            shape.bvh.set_layout(layout);
         }
     }
 
+    /// The SAH cost of the BVH over the objects, where intersecting an object costs as much as the BVH of its shape.
+    pub fn sah_cost(&self) -> Float {
+        self.bvh.sah_cost_with(|i| self.shapes[self.instances[i].shape].bvh.sah_cost())
+    }
+
     pub fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<Intersection> {
-        let find = |ray: &Ray, p: PrimitiveRef| {
-            let mat = self.materials[self.primitives[p.0].object_id];
-            self.primitives[p.0].primitive.intersect(ray, t_min, t_max).map(|i| i.to_intersection(mat))
+        let find = |ray: &Ray, i: usize, t_min: Float, t_max: Float| {
+            let instance = &self.instances[i];
+            instance.intersect(&self.shapes[instance.shape], ray, t_min, t_max)
+                .map(|(p, int)| int.to_intersection(instance.material, PrimitiveRef { instance: i, primitive: p }))
         };
-        let comp = |a: Intersection, b: Intersection| {
-            if a.t < b.t {
-                a
-            }
-            else {
-                b
-            }
+
+        self.bvh.find_closest(ray, find, |i: &Intersection| i.t, t_min, t_max)
+    }
+    /// Intersects only a single primitive.
+    pub fn intersect_primitive(&self, p: PrimitiveRef, ray: &Ray, t_min: Float, t_max: Float) -> Option<Intersection> {
+        let (instance, primitive) = self.primitive(p);
+
+        instance.intersect_primitive(primitive, ray, t_min, t_max)
+            .map(|i| i.to_intersection(instance.material, p))
+    }
+    /// Whether anything is hit between `t_min` and `t_max`, which is cheaper than finding the closest hit.
+    pub fn occluded(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        let hit = |ray: &Ray, i: usize| {
+            let instance = &self.instances[i];
+            instance.intersects(&self.shapes[instance.shape], ray, t_min, t_max)
         };
 
-        self.bvh.find_intersection(ray, find, comp, t_min, t_max)
+        self.bvh.find_any(ray, hit, t_min, t_max)
+    }
+
+    /// All primitives of all objects.
+    pub(crate) fn primitive_refs(&self) -> impl Iterator<Item = PrimitiveRef> + '_ {
+        self.instances.iter()
+            .enumerate()
+            .flat_map(|(i, instance)| {
+                (0..self.shapes[instance.shape].primitives.len()).map(move |p| PrimitiveRef { instance: i, primitive: p })
+            })
+    }
+    /// The primitive in object space, and the instance that places it in the world.
+    pub(crate) fn primitive(&self, p: PrimitiveRef) -> (&Instance, &Primitive) {
+        let instance = &self.instances[p.instance];
+        (instance, &self.shapes[instance.shape].primitives[p.primitive])
+    }
+    pub(crate) fn primitive_material(&self, p: PrimitiveRef) -> MaterialRef {
+        self.instances[p.instance].material
     }
 }
 
 
-pub(crate) struct PrimitiveData {
-    pub(crate) primitive: Primitive,
-    pub(crate) object_id: usize,
+/// The acceleration structures of a [Scene] without its world.
+#[derive(Default)]
+pub struct DetachedScene {
+    shapes: Vec<ShapeData>,
+    shape_ids: HashMap<Index, usize>,
+    instances: Vec<Instance>,
+    bvh: BVH,
 }
@@ -1,12 +1,17 @@
-use nalgebra::{Point3, Unit, UnitQuaternion, UnitVector3, vector, Vector3};
+use nalgebra::{Affine3, Point3, Unit, UnitQuaternion, UnitVector3, vector, Vector3};
 use num_traits::FloatConst;
 use crate::aabb::AABB;
 use crate::{Float, Randomness, Scene};
 use crate::intersection::Intersection;
 use crate::pdf::PDF;
 use crate::ray::Ray;
+use crate::scene::csg::Csg;
+use crate::scene::heightfield::Heightfield;
+use crate::scene::revolved::{Profile, Revolved};
+use crate::scene::sdf::TracedSdf;
 use crate::texture::TextureCoord2D;
 use crate::world::material::MaterialRef;
+use crate::world::microfacet::ShadingFrame;
 
 pub enum Primitive {
     Sphere {
@@ -14,6 +19,64 @@ pub enum Primitive {
         rotation: UnitQuaternion<Float>,
         radius: Float,
     },
+    Triangle {
+        vertices: [Point3<Float>; 3],
+        normals: Option<[UnitVector3<Float>; 3]>,
+        tex_coords: Option<[TextureCoord2D; 3]>,
+    },
+    /// A parallelogram spanned by two edges from a corner, facing along the cross product of the edges.
+    Quad {
+        corner: Point3<Float>,
+        edge_u: Vector3<Float>,
+        edge_v: Vector3<Float>,
+    },
+    Disk {
+        center: Point3<Float>,
+        normal: UnitVector3<Float>,
+        radius: Float,
+    },
+    /// A solid box, which is axis aligned before the rotation is applied around its center.
+    Box {
+        center: Point3<Float>,
+        half_size: Vector3<Float>,
+        rotation: UnitQuaternion<Float>,
+    },
+    /// A cylinder around the y axis of its rotation, which is open at both ends unless capped.
+    Cylinder {
+        center: Point3<Float>,
+        rotation: UnitQuaternion<Float>,
+        radius: Float,
+        half_height: Float,
+        capped: bool,
+    },
+    /// A cone around the y axis of its rotation, cut off where its radius is `top_radius`, which is a point if it is zero.
+    Cone {
+        center: Point3<Float>,
+        rotation: UnitQuaternion<Float>,
+        base_radius: Float,
+        top_radius: Float,
+        half_height: Float,
+        capped: bool,
+    },
+    /// A ring in the xz-plane of its rotation.
+    Torus {
+        center: Point3<Float>,
+        rotation: UnitQuaternion<Float>,
+        major_radius: Float,
+        minor_radius: Float,
+    },
+    /// A cylinder around the y axis of its rotation, with a hemisphere on either end.
+    Capsule {
+        center: Point3<Float>,
+        rotation: UnitQuaternion<Float>,
+        radius: Float,
+        half_height: Float,
+    },
+    /// The union, intersection or difference of two shapes, which can be made of any primitives themselves.
+    Csg(Box<Csg>),
+    /// A surface given by a signed distance field.
+    Sdf(Box<TracedSdf>),
+    Heightfield(Box<Heightfield>),
 }
 impl Primitive {
     pub fn aabb(&self) -> AABB {
@@ -25,6 +88,62 @@ impl Primitive {
 
                 AABB::new(min, max)
             }
+            Self::Triangle { vertices, .. } => AABB::from_points(vertices),
+            Self::Quad { corner, edge_u, edge_v } => padded(AABB::from_points(&quad_corners(corner, edge_u, edge_v))),
+            Self::Disk { center, normal, radius } => {
+                // The extent of a circle along an axis shrinks the more the circle faces along it.
+                let diff = normal.map(|n| (1.0 - n * n).max(0.0).sqrt() * *radius);
+                padded(AABB::new(center - diff, center + diff))
+            }
+            Self::Box { center, half_size, rotation } => {
+                let diff = rotation.to_rotation_matrix().matrix().abs() * half_size;
+                AABB::new(center - diff, center + diff)
+            }
+            Self::Cylinder { .. } | Self::Cone { .. } | Self::Torus { .. } | Self::Capsule { .. } => self.revolved().aabb(),
+            Self::Csg(csg) => csg.aabb(),
+            Self::Sdf(sdf) => sdf.aabb(),
+            Self::Heightfield(heightfield) => heightfield.aabb(),
+        }
+    }
+    /// The tightest box around the primitive after transforming it.
+    pub fn transformed_aabb(&self, t: &Affine3<Float>) -> AABB {
+        match self {
+            Self::Sphere { origin, radius, .. } => {
+                // The extent of an ellipsoid along an axis is the length of the corresponding row of its matrix.
+                let center = t.transform_point(origin);
+                let linear = t.matrix().fixed_slice::<3, 3>(0, 0);
+                let diff = Vector3::from_fn(|i, _| linear.row(i).norm() * *radius);
+
+                AABB::new(center - diff, center + diff)
+            }
+            Self::Triangle { vertices, .. } => AABB::from_points(&vertices.map(|v| t.transform_point(&v))),
+            Self::Quad { corner, edge_u, edge_v } => {
+                padded(AABB::from_points(&quad_corners(corner, edge_u, edge_v).map(|c| t.transform_point(&c))))
+            }
+            Self::Disk { center, normal, radius } => {
+                // The transformed disk is an ellipse, spanned by the transformed tangents.
+                let frame = ShadingFrame::new(*normal);
+                let a = t.transform_vector(&frame.tangent) * *radius;
+                let b = t.transform_vector(&frame.bitangent) * *radius;
+                let diff = a.zip_map(&b, |a, b| (a * a + b * b).sqrt());
+
+                let center = t.transform_point(center);
+                padded(AABB::new(center - diff, center + diff))
+            }
+            Self::Box { center, half_size, rotation } => {
+                let corners: Vec<Point3<Float>> = (0..8)
+                    .map(|i| {
+                        let corner = Vector3::from_fn(|a, _| if i & (1 << a) == 0 { -half_size[a] } else { half_size[a] });
+                        t.transform_point(&(center + rotation.transform_vector(&corner)))
+                    })
+                    .collect();
+
+                AABB::from_points(&corners)
+            }
+            Self::Cylinder { .. } | Self::Cone { .. } | Self::Torus { .. } | Self::Capsule { .. } => self.revolved().transformed_aabb(t),
+            Self::Csg(csg) => csg.transformed_aabb(t),
+            Self::Sdf(sdf) => sdf.transformed_aabb(t),
+            Self::Heightfield(heightfield) => heightfield.transformed_aabb(t),
         }
     }
 
@@ -40,26 +159,169 @@ impl Primitive {
                     t_max,
                 )
             }
+            Self::Triangle { vertices, normals, tex_coords } => {
+                intersect_triangle(
+                    vertices,
+                    normals.as_ref(),
+                    tex_coords.as_ref(),
+                    ray,
+                    t_min,
+                    t_max,
+                )
+            }
+            Self::Quad { corner, edge_u, edge_v } => {
+                let (t, [u, v]) = quad_hit(corner, edge_u, edge_v, ray, t_min, t_max)?;
+                Some(flat_intersection(ray, t, Unit::new_normalize(edge_u.cross(edge_v)), TextureCoord2D::new(u, v)))
+            }
+            Self::Disk { center, normal, radius } => {
+                let t = disk_hit(center, normal, *radius, ray, t_min, t_max)?;
+
+                // The disk is mapped onto the texture like a picture in a frame.
+                let frame = ShadingFrame::new(*normal);
+                let local = frame.to_local(&(ray.point_at(t) - center)) / (2.0 * *radius);
+                Some(flat_intersection(ray, t, *normal, TextureCoord2D::new(local.x + 0.5, local.y + 0.5)))
+            }
+            Self::Box { center, half_size, rotation } => intersect_box(center, half_size, rotation, ray, t_min, t_max),
+            Self::Cylinder { .. } | Self::Cone { .. } | Self::Torus { .. } | Self::Capsule { .. } => self.revolved().intersect(ray, t_min, t_max),
+            Self::Csg(csg) => csg.intersect(ray, t_min, t_max),
+            Self::Sdf(sdf) => sdf.intersect(ray, t_min, t_max),
+            Self::Heightfield(heightfield) => heightfield.intersect(ray, t_min, t_max),
         }
     }
+    /// Whether the ray hits this primitive at all, without computing anything else about the hit.
     pub fn intersects(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
-        // TODO: find a better way to do this that is super fast and shit
-        self.intersect(ray, t_min, t_max).is_some()
+        match self {
+            Self::Sphere { origin, radius, .. } => sphere_hit(*origin, *radius, ray, t_min, t_max).is_some(),
+            Self::Triangle { vertices, .. } => triangle_hit(vertices, ray, t_min, t_max).is_some(),
+            Self::Quad { corner, edge_u, edge_v } => quad_hit(corner, edge_u, edge_v, ray, t_min, t_max).is_some(),
+            Self::Disk { center, normal, radius } => disk_hit(center, normal, *radius, ray, t_min, t_max).is_some(),
+            Self::Box { center, half_size, rotation } => box_hit(center, half_size, rotation, ray, t_min, t_max).is_some(),
+            Self::Cylinder { .. } | Self::Cone { .. } | Self::Torus { .. } | Self::Capsule { .. } => self.revolved().intersects(ray, t_min, t_max),
+            Self::Csg(csg) => csg.intersects(ray, t_min, t_max),
+            Self::Sdf(sdf) => sdf.intersects(ray, t_min, t_max),
+            Self::Heightfield(heightfield) => heightfield.intersects(ray, t_min, t_max),
+        }
+    }
+
+    /// The normal on the outside of the surface at `p`, which has to be on the surface.
+    pub fn outward_normal(&self, p: &Point3<Float>) -> UnitVector3<Float> {
+        match self {
+            Self::Sphere { origin, .. } => Unit::new_normalize(p - origin),
+            Self::Triangle { vertices: [a, b, c], .. } => Unit::new_normalize((b - a).cross(&(c - a))),
+            Self::Quad { edge_u, edge_v, .. } => Unit::new_normalize(edge_u.cross(edge_v)),
+            Self::Disk { normal, .. } => *normal,
+            Self::Box { center, half_size, rotation } => {
+                // The face the point is on is the one it is relatively the farthest out towards.
+                let local = rotation.inverse_transform_vector(&(p - center)).component_div(half_size);
+                let axis = local.iamax();
+                rotation * Unit::new_unchecked(Vector3::ith(axis, local[axis].signum()))
+            }
+            Self::Cylinder { .. } | Self::Cone { .. } | Self::Torus { .. } | Self::Capsule { .. } => self.revolved().outward_normal(p),
+            Self::Csg(csg) => csg.outward_normal(p),
+            Self::Sdf(sdf) => sdf.outward_normal(p),
+            Self::Heightfield(heightfield) => heightfield.outward_normal(p),
+        }
     }
 
     pub fn area(&self) -> Float {
         match self {
             Self::Sphere { radius, .. } => 4.0 * Float::PI() * radius.powi(2),
+            Self::Triangle { vertices: [a, b, c], .. } => (b - a).cross(&(c - a)).magnitude() * 0.5,
+            Self::Quad { edge_u, edge_v, .. } => edge_u.cross(edge_v).magnitude(),
+            Self::Disk { radius, .. } => Float::PI() * radius.powi(2),
+            Self::Box { half_size: h, .. } => 8.0 * (h.x * h.y + h.y * h.z + h.z * h.x),
+            Self::Cylinder { .. } | Self::Cone { .. } | Self::Torus { .. } | Self::Capsule { .. } => self.revolved().area(),
+            Self::Csg(csg) => csg.area(),
+            Self::Sdf(sdf) => sdf.area(),
+            Self::Heightfield(heightfield) => heightfield.area(),
         }
     }
     pub fn solid_angle(&self, o: Point3<Float>) -> Float {
         match self {
             Self::Sphere { origin, radius, .. } => {
-                let cos_theta_max = (1.0 - radius.powi(2) / (origin - o).magnitude_squared()).sqrt();
-                let solid_angle = 2.0 * Float::PI() * (1.0 - cos_theta_max);
+                let distance_squared = (origin - o).magnitude_squared();
+                if distance_squared <= radius.powi(2) {
+                    return 4.0 * Float::PI();
+                }
+
+                let cos_theta_max = (1.0 - radius.powi(2) / distance_squared).sqrt();
+                2.0 * Float::PI() * (1.0 - cos_theta_max)
+            }
+            Self::Triangle { vertices, .. } => triangle_solid_angle(vertices, o),
+            Self::Quad { corner, edge_u, edge_v } => quad_solid_angle(corner, edge_u, edge_v, o),
+            Self::Disk { center, normal, radius } => disk_solid_angle(center, normal, *radius, o),
+            Self::Box { center, half_size, rotation } => {
+                let faces = box_faces(center, half_size, rotation, &o);
+                if faces.iter().all(|face| face.visible) {
+                    return 4.0 * Float::PI();
+                }
 
-                solid_angle
+                faces.iter()
+                    .filter(|face| face.visible)
+                    .map(|face| quad_solid_angle(&face.corner, &face.edge_u, &face.edge_v, o))
+                    .sum()
             }
+            Self::Cylinder { .. } | Self::Cone { .. } | Self::Torus { .. } | Self::Capsule { .. } => self.revolved().solid_angle(o),
+            Self::Csg(csg) => csg.solid_angle(o),
+            Self::Sdf(sdf) => sdf.solid_angle(o),
+            Self::Heightfield(heightfield) => heightfield.solid_angle(o),
+        }
+    }
+    /// The probability density of sampling `direction` from `o` with [`Self::random_direction_towards`],
+    /// with respect to solid angle.
+    pub fn direction_pdf(&self, o: Point3<Float>, direction: UnitVector3<Float>) -> Float {
+        let ray = Ray::new(o, direction);
+
+        match self {
+            Self::Sphere { .. } => {
+                if !self.intersects(&ray, 0.001, Float::INFINITY) {
+                    return 0.0
+                }
+
+                1.0 / self.solid_angle(o)
+            }
+            Self::Triangle { vertices: [a, b, c], .. } => {
+                match triangle_hit(&[*a, *b, *c], &ray, 0.001, Float::INFINITY) {
+                    Some((t, _)) => {
+                        // The conversion from area to solid angle depends on the actual surface, not the shading normal.
+                        let geometric_normal = (b - a).cross(&(c - a));
+                        let area = geometric_normal.magnitude() * 0.5;
+                        let cosine = geometric_normal.normalize().dot(&direction).abs();
+
+                        t.powi(2) / (cosine * area)
+                    }
+                    None => 0.0,
+                }
+            }
+            Self::Quad { corner, edge_u, edge_v } => match quad_hit(corner, edge_u, edge_v, &ray, 0.001, Float::INFINITY) {
+                Some((t, _)) => {
+                    let normal = edge_u.cross(edge_v);
+                    let cosine = normal.normalize().dot(&direction).abs();
+                    t.powi(2) / (cosine * normal.magnitude())
+                }
+                None => 0.0,
+            },
+            Self::Disk { center, normal, radius } => match disk_hit(center, normal, *radius, &ray, 0.001, Float::INFINITY) {
+                Some(t) => t.powi(2) / (normal.dot(&direction).abs() * self.area()),
+                None => 0.0,
+            },
+            Self::Box { center, half_size, rotation } => match box_hit(center, half_size, rotation, &ray, 0.001, Float::INFINITY) {
+                Some(hit) => {
+                    // Only the faces that can be seen from `o` are sampled, and the first hit is always on one of them.
+                    let visible_area: Float = box_faces(center, half_size, rotation, &o).iter()
+                        .filter(|face| face.visible)
+                        .map(|face| face.edge_u.cross(&face.edge_v).magnitude())
+                        .sum();
+                    let cosine = rotation.inverse_transform_vector(&direction)[hit.axis].abs();
+
+                    hit.t.powi(2) / (cosine * visible_area)
+                }
+                None => 0.0,
+            },
+            Self::Cylinder { .. } | Self::Cone { .. } | Self::Torus { .. } | Self::Capsule { .. } => self.revolved().direction_pdf(o, direction),
+            Self::Csg(csg) => csg.direction_pdf(o, direction),
+            Self::Sdf(sdf) => sdf.direction_pdf(o, direction),
+            Self::Heightfield(heightfield) => heightfield.direction_pdf(o, direction),
         }
     }
 
@@ -67,24 +329,85 @@ impl Primitive {
         match self {
             Self::Sphere { origin, radius, .. } => {
                 let dir = rng.unit_vector();
-                let actual_radius = *radius;
-                let point = origin + dir.into_inner() * actual_radius;
-                point
+                origin + dir.into_inner() * *radius
             }
+            Self::Triangle { vertices: [a, b, c], .. } => {
+                let r0 = rng.float().sqrt();
+                let r1 = rng.float();
+
+                let b0 = 1.0 - r0;
+                let b1 = r0 * (1.0 - r1);
+                let b2 = r0 * r1;
+
+                Point3::from(a.coords * b0 + b.coords * b1 + c.coords * b2)
+            }
+            Self::Quad { corner, edge_u, edge_v } => corner + edge_u * rng.float() + edge_v * rng.float(),
+            Self::Disk { center, normal, radius } => {
+                let r = *radius * rng.float().sqrt();
+                let phi = 2.0 * Float::PI() * rng.float();
+
+                let frame = ShadingFrame::new(*normal);
+                center + frame.to_world(&vector!(r * phi.cos(), r * phi.sin(), 0.0))
+            }
+            Self::Box { center, half_size, rotation } => {
+                // Any point works, so all faces count as visible.
+                let faces = box_faces(center, half_size, rotation, center);
+                sample_box_faces(&faces, rng)
+            }
+            Self::Cylinder { .. } | Self::Cone { .. } | Self::Torus { .. } | Self::Capsule { .. } => self.revolved().random_point_on_surface(rng),
+            Self::Csg(csg) => csg.random_point_on_surface(rng),
+            Self::Sdf(sdf) => sdf.random_point_on_surface(rng),
+            Self::Heightfield(heightfield) => heightfield.random_point_on_surface(rng),
         }
     }
     pub fn random_direction_towards(&self, o: Point3<Float>, rng: &mut dyn Randomness) -> UnitVector3<Float> {
         match self {
             Self::Sphere { origin, radius, .. } => {
-                let dir = rng.unit_vector();
-                // move point slightly into the sphere to that the intersection works when calculating pdf value.
-                let actual_radius = *radius - 0.01;
-                let point = origin + dir.into_inner() * actual_radius;
+                let to_center = origin - o;
+                let distance_squared = to_center.magnitude_squared();
+                if distance_squared <= radius.powi(2) {
+                    return rng.unit_vector();
+                }
 
+                // Uniformly within the cone the sphere covers, so that the density is 1 / solid angle.
+                let cos_theta_max = (1.0 - radius.powi(2) / distance_squared).sqrt();
+                random_direction_in_cone(Unit::new_normalize(to_center), cos_theta_max, rng)
+            }
+            Self::Triangle { .. } | Self::Quad { .. } | Self::Disk { .. } => {
+                let point = self.random_point_on_surface(rng);
                 Unit::new_normalize(point - o)
             }
+            Self::Box { center, half_size, rotation } => {
+                let faces = box_faces(center, half_size, rotation, &o);
+                Unit::new_normalize(sample_box_faces(&faces, rng) - o)
+            }
+            Self::Cylinder { .. } | Self::Cone { .. } | Self::Torus { .. } | Self::Capsule { .. } => self.revolved().random_direction_towards(o, rng),
+            Self::Csg(csg) => csg.random_direction_towards(o, rng),
+            Self::Sdf(sdf) => sdf.random_direction_towards(o, rng),
+            Self::Heightfield(heightfield) => heightfield.random_direction_towards(o, rng),
         }
     }
+
+    /// The primitives that are symmetric around an axis share their implementation.
+    fn revolved(&self) -> Revolved {
+        let (center, rotation, profile) = match *self {
+            Self::Cylinder { center, rotation, radius, half_height, capped } => {
+                (center, rotation, Profile::Frustum { base_radius: radius, top_radius: radius, half_height, capped })
+            }
+            Self::Cone { center, rotation, base_radius, top_radius, half_height, capped } => {
+                (center, rotation, Profile::Frustum { base_radius, top_radius, half_height, capped })
+            }
+            Self::Torus { center, rotation, major_radius, minor_radius } => {
+                (center, rotation, Profile::Torus { major_radius, minor_radius })
+            }
+            Self::Capsule { center, rotation, radius, half_height } => {
+                (center, rotation, Profile::Capsule { radius, half_height })
+            }
+            _ => unreachable!(),
+        };
+
+        Revolved { center, rotation, profile }
+    }
 }
 
 fn intersect_sphere(
@@ -95,12 +418,45 @@ fn intersect_sphere(
     t_min: Float,
     t_max: Float
 ) -> Option<PrimitiveIntersection> {
+    if let Some((t, outside)) = sphere_hit(origin, radius, ray, t_min, t_max) {
+        let point = ray.point_at(t);
+
+        let outward_normal = Unit::new_normalize(point - origin);
+        let uv_normal = rotation.transform_vector(&outward_normal);
+
+        let theta = (-uv_normal[1]).acos();
+        let phi = (-uv_normal[2]).atan2(uv_normal[0]) + Float::PI();
+        let u = phi / (Float::PI() * 2.0);
+        let v = theta / Float::PI();
+
+        let normal = if outside {
+            outward_normal
+        } else {
+            -outward_normal
+        };
+
+
+        Some(PrimitiveIntersection {
+            t,
+            normal,
+            point,
+            outside,
+            tex_coord: TextureCoord2D::new(u, v),
+        })
+    }
+    else {
+        None
+    }
+}
+
+/// The distance to the closest hit with a sphere, and whether it is on the outside.
+fn sphere_hit(origin: Point3<Float>, radius: Float, ray: &Ray, t_min: Float, t_max: Float) -> Option<(Float, bool)> {
     let oc = ray.origin - origin;
     let b = oc.dot(&ray.direction.into_inner());
     let c = oc.magnitude_squared() - radius.powi(2);
     let descrim = b * b - c;
 
-    let t = if descrim > 0.0 {
+    if descrim > 0.0 {
         let desc_sqrt = descrim.sqrt();
 
         let t0 = -b - desc_sqrt;
@@ -117,43 +473,441 @@ fn intersect_sphere(
         }
     } else {
         None
+    }
+}
+
+pub(super) fn intersect_triangle(
+    vertices: &[Point3<Float>; 3],
+    normals: Option<&[UnitVector3<Float>; 3]>,
+    tex_coords: Option<&[TextureCoord2D; 3]>,
+    ray: &Ray,
+    t_min: Float,
+    t_max: Float,
+) -> Option<PrimitiveIntersection> {
+    let (t, barycentric) = triangle_hit(vertices, ray, t_min, t_max)?;
+    let dir = ray.direction;
+
+    let [v0, v1, v2] = vertices;
+    let point = Point3::from(v0.coords * barycentric[0] + v1.coords * barycentric[1] + v2.coords * barycentric[2]);
+
+    let geometric_normal = Unit::new_normalize((v1 - v0).cross(&(v2 - v0)));
+    let outside = geometric_normal.dot(&dir) < 0.0;
+
+    let outward_normal = match normals {
+        Some(n) => {
+            let interpolated = n[0].into_inner() * barycentric[0]
+                + n[1].into_inner() * barycentric[1]
+                + n[2].into_inner() * barycentric[2];
+            Unit::try_new(interpolated, Float::EPSILON).unwrap_or(geometric_normal)
+        }
+        None => geometric_normal,
+    };
+    let normal = if outside {
+        outward_normal
+    } else {
+        -outward_normal
     };
 
+    let tex_coord = match tex_coords {
+        Some(uv) => TextureCoord2D::new(
+            uv[0].x * barycentric[0] + uv[1].x * barycentric[1] + uv[2].x * barycentric[2],
+            uv[0].y * barycentric[0] + uv[1].y * barycentric[1] + uv[2].y * barycentric[2],
+        ),
+        None => TextureCoord2D::new(barycentric[1], barycentric[2]),
+    };
 
-    if let Some((t, outside)) = t {
-        let point = ray.point_at(t);
 
-        let outward_normal = Unit::new_normalize(point - origin);
-        let uv_normal = rotation.transform_vector(&outward_normal);
+    Some(PrimitiveIntersection {
+        t,
+        point,
+        normal,
+        outside,
+        tex_coord,
+    })
+}
 
-        let theta = (-uv_normal[1]).acos();
-        let phi = (-uv_normal[2]).atan2(uv_normal[0]) + Float::PI();
-        let u = phi / (Float::PI() * 2.0);
-        let v = theta / Float::PI();
+/// The distance to the hit with a triangle and its barycentric coordinates.
+/// Watertight ray/triangle intersection after Woop, Benthin and Wald (2013).
+/// Rays hitting a shared edge or vertex of two triangles always hit at least one of them.
+pub(super) fn triangle_hit(vertices: &[Point3<Float>; 3], ray: &Ray, t_min: Float, t_max: Float) -> Option<(Float, [Float; 3])> {
+    let dir = ray.direction;
 
-        let normal = if outside {
-            outward_normal
-        } else {
-            -outward_normal
-        };
+    // Permute the axes so that the largest direction component is z.
+    let kz = dir.iamax();
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    if dir[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
 
+    let sx = dir[kx] / dir[kz];
+    let sy = dir[ky] / dir[kz];
+    let sz = 1.0 / dir[kz];
 
-        Some(PrimitiveIntersection {
-            t,
-            normal,
-            point,
-            outside,
-            tex_coord: TextureCoord2D::new(u, v),
-        })
+    let [a, b, c] = vertices.map(|v| v - ray.origin);
+
+    // Shear the vertices into ray space, where the ray starts at the origin and points along +z.
+    let ax = a[kx] - sx * a[kz];
+    let ay = a[ky] - sy * a[kz];
+    let bx = b[kx] - sx * b[kz];
+    let by = b[ky] - sy * b[kz];
+    let cx = c[kx] - sx * c[kz];
+    let cy = c[ky] - sy * c[kz];
+
+    let [u, v, w] = edge_functions([ax, ay], [bx, by], [cx, cy]);
+
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
+    }
+
+    let az = sz * a[kz];
+    let bz = sz * b[kz];
+    let cz = sz * c[kz];
+    let t = (u * az + v * bz + w * cz) / det;
+
+    if t < t_min || t > t_max {
+        return None;
+    }
+
+    Some((t, [u / det, v / det, w / det]))
+}
+fn edge_functions([ax, ay]: [Float; 2], [bx, by]: [Float; 2], [cx, cy]: [Float; 2]) -> [Float; 3] {
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+
+    // Fall back to double precision on edges to avoid cracks between neighbouring triangles.
+    #[cfg(not(feature = "wide"))]
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        let [ax, ay, bx, by, cx, cy] = [ax, ay, bx, by, cx, cy].map(f64::from);
+        return [
+            (cx * by - cy * bx) as Float,
+            (ax * cy - ay * cx) as Float,
+            (bx * ay - by * ax) as Float,
+        ];
+    }
+
+    [u, v, w]
+}
+
+
+/// The intersection with a flat surface facing along `normal`, which has no inside, so `outside` only tells the side that was hit.
+fn flat_intersection(ray: &Ray, t: Float, normal: UnitVector3<Float>, tex_coord: TextureCoord2D) -> PrimitiveIntersection {
+    let outside = normal.dot(&ray.direction) < 0.0;
+
+    PrimitiveIntersection {
+        t,
+        point: ray.point_at(t),
+        normal: if outside { normal } else { -normal },
+        outside,
+        tex_coord,
+    }
+}
+
+/// How thick the boxes of flat primitives are made, relative to their size or their distance from the origin,
+/// whichever is larger, so that the padding isn't lost to rounding.
+const FLAT_PADDING: Float = 1.0e-4;
+
+pub(super) fn padded(aabb: AABB) -> AABB {
+    let scale = aabb.diagonal().max().max(aabb.min.coords.abs().max()).max(aabb.max.coords.abs().max());
+    aabb.padded(scale * FLAT_PADDING)
+}
+
+fn quad_corners(corner: &Point3<Float>, edge_u: &Vector3<Float>, edge_v: &Vector3<Float>) -> [Point3<Float>; 4] {
+    [*corner, corner + edge_u, corner + edge_u + edge_v, corner + edge_v]
+}
+
+/// The distance to the hit with a parallelogram, and where it was hit in terms of the two edges.
+fn quad_hit(
+    corner: &Point3<Float>,
+    edge_u: &Vector3<Float>,
+    edge_v: &Vector3<Float>,
+    ray: &Ray,
+    t_min: Float,
+    t_max: Float,
+) -> Option<(Float, [Float; 2])> {
+    let normal = edge_u.cross(edge_v);
+    let denominator = normal.dot(&ray.direction);
+    if denominator == 0.0 {
+        return None;
+    }
+
+    let t = normal.dot(&(corner - ray.origin)) / denominator;
+    if !(t >= t_min && t <= t_max) {
+        return None;
+    }
+
+    // Crossing with one edge leaves only the component along the other one.
+    let q = ray.point_at(t) - corner;
+    let w = normal / normal.magnitude_squared();
+    let u = w.dot(&q.cross(edge_v));
+    let v = w.dot(&edge_u.cross(&q));
+    if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+        return None;
+    }
+
+    Some((t, [u, v]))
+}
+
+fn disk_hit(center: &Point3<Float>, normal: &UnitVector3<Float>, radius: Float, ray: &Ray, t_min: Float, t_max: Float) -> Option<Float> {
+    let denominator = normal.dot(&ray.direction);
+    if denominator == 0.0 {
+        return None;
+    }
+
+    let t = normal.dot(&(center - ray.origin)) / denominator;
+    if !(t >= t_min && t <= t_max) || (ray.point_at(t) - center).magnitude_squared() > radius.powi(2) {
+        return None;
+    }
+
+    Some(t)
+}
+
+/// A hit with a box, on the face along `axis` of the box in the direction of `sign`.
+struct BoxHit {
+    t: Float,
+    outside: bool,
+    axis: usize,
+    sign: Float,
+}
+
+fn box_hit(
+    center: &Point3<Float>,
+    half_size: &Vector3<Float>,
+    rotation: &UnitQuaternion<Float>,
+    ray: &Ray,
+    t_min: Float,
+    t_max: Float,
+) -> Option<BoxHit> {
+    let origin = rotation.inverse_transform_vector(&(ray.origin - center));
+    let direction = rotation.inverse_transform_vector(&ray.direction);
+
+    let (mut near, mut near_axis) = (-Float::INFINITY, 0);
+    let (mut far, mut far_axis) = (Float::INFINITY, 0);
+    for a in 0..3 {
+        let inv_d = 1.0 / direction[a];
+        let t0 = (-half_size[a] - origin[a]) * inv_d;
+        let t1 = (half_size[a] - origin[a]) * inv_d;
+        let (t0, t1) = if t0 <= t1 { (t0, t1) } else { (t1, t0) };
+
+        // NaN from rays in the plane of a face fails both comparisons, which leaves the axis out.
+        if t0 > near {
+            near = t0;
+            near_axis = a;
+        }
+        if t1 < far {
+            far = t1;
+            far_axis = a;
+        }
+    }
+
+    if near > far {
+        None
+    }
+    else if near >= t_min && near <= t_max {
+        Some(BoxHit { t: near, outside: true, axis: near_axis, sign: -direction[near_axis].signum() })
+    }
+    else if far >= t_min && far <= t_max {
+        Some(BoxHit { t: far, outside: false, axis: far_axis, sign: direction[far_axis].signum() })
     }
     else {
         None
     }
 }
 
+fn intersect_box(
+    center: &Point3<Float>,
+    half_size: &Vector3<Float>,
+    rotation: &UnitQuaternion<Float>,
+    ray: &Ray,
+    t_min: Float,
+    t_max: Float,
+) -> Option<PrimitiveIntersection> {
+    let hit = box_hit(center, half_size, rotation, ray, t_min, t_max)?;
+    let point = ray.point_at(hit.t);
+
+    let outward_normal = rotation * Unit::new_unchecked(Vector3::ith(hit.axis, hit.sign));
+    let normal = if hit.outside { outward_normal } else { -outward_normal };
+
+    // Each face is mapped onto the whole texture, along the two other axes.
+    let local = rotation.inverse_transform_vector(&(point - center)).component_div(half_size);
+    let (u_axis, v_axis) = ((hit.axis + 1) % 3, (hit.axis + 2) % 3);
+    let tex_coord = TextureCoord2D::new(
+        ((local[u_axis] + 1.0) * 0.5).clamp(0.0, 1.0),
+        ((local[v_axis] + 1.0) * 0.5).clamp(0.0, 1.0),
+    );
+
+    Some(PrimitiveIntersection {
+        t: hit.t,
+        point,
+        normal,
+        outside: hit.outside,
+        tex_coord,
+    })
+}
+
+/// A face of a box as a parallelogram, with edges ordered so that it faces outwards.
+struct BoxFace {
+    corner: Point3<Float>,
+    edge_u: Vector3<Float>,
+    edge_v: Vector3<Float>,
+    /// Whether the outside of the face can be seen from the point the faces were made for.
+    /// All faces count as visible from the inside.
+    visible: bool,
+}
+
+fn box_faces(center: &Point3<Float>, half_size: &Vector3<Float>, rotation: &UnitQuaternion<Float>, o: &Point3<Float>) -> [BoxFace; 6] {
+    let local_o = rotation.inverse_transform_vector(&(o - center));
+    let inside = (0..3).all(|a| local_o[a].abs() <= half_size[a]);
+
+    std::array::from_fn(|i| {
+        let axis = i / 2;
+        let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+
+        let u = Vector3::ith((axis + 1) % 3, 2.0 * half_size[(axis + 1) % 3]);
+        let v = Vector3::ith((axis + 2) % 3, 2.0 * half_size[(axis + 2) % 3]);
+        let (u, v) = if sign > 0.0 { (u, v) } else { (v, u) };
+        let face_center = Vector3::ith(axis, sign * half_size[axis]);
+
+        BoxFace {
+            corner: center + rotation.transform_vector(&(face_center - (u + v) * 0.5)),
+            edge_u: rotation.transform_vector(&u),
+            edge_v: rotation.transform_vector(&v),
+            visible: inside || sign * local_o[axis] > half_size[axis],
+        }
+    })
+}
+
+/// A uniformly distributed point on the visible faces.
+fn sample_box_faces(faces: &[BoxFace; 6], rng: &mut dyn Randomness) -> Point3<Float> {
+    let areas = faces.each_ref().map(|face| if face.visible { face.edge_u.cross(&face.edge_v).magnitude() } else { 0.0 });
+
+    let mut target = rng.float() * areas.iter().sum::<Float>();
+    let mut chosen = 0;
+    for (i, area) in areas.iter().enumerate().filter(|(_, area)| **area > 0.0) {
+        // The last visible face is kept if rounding leaves the target beyond all of them.
+        chosen = i;
+        if target < *area {
+            break;
+        }
+        target -= area;
+    }
+
+    let face = &faces[chosen];
+    face.corner + face.edge_u * rng.float() + face.edge_v * rng.float()
+}
+
+fn triangle_solid_angle(vertices: &[Point3<Float>; 3], o: Point3<Float>) -> Float {
+    // Van Oosterom and Strackee
+    let [a, b, c] = vertices.map(|v| v - o);
+    let (la, lb, lc) = (a.magnitude(), b.magnitude(), c.magnitude());
+
+    let numerator = a.dot(&b.cross(&c));
+    let denominator = la * lb * lc + a.dot(&b) * lc + a.dot(&c) * lb + b.dot(&c) * la;
+
+    2.0 * numerator.atan2(denominator).abs()
+}
+fn quad_solid_angle(corner: &Point3<Float>, edge_u: &Vector3<Float>, edge_v: &Vector3<Float>, o: Point3<Float>) -> Float {
+    let [a, b, c, d] = quad_corners(corner, edge_u, edge_v);
+    triangle_solid_angle(&[a, b, c], o) + triangle_solid_angle(&[a, c, d], o)
+}
+/// The solid angle of a disk has no closed form away from its axis, so it is integrated numerically around the point
+/// below `o` in the plane of the disk. Along each direction from there, the solid angle of the part of the disk
+/// from distance `s0` to `s1` is `h / sqrt(h² + s0²) - h / sqrt(h² + s1²)` at height `h`.
+fn disk_solid_angle(center: &Point3<Float>, normal: &UnitVector3<Float>, radius: Float, o: Point3<Float>) -> Float {
+    const STEPS: usize = 64;
 
+    let to_o = o - center;
+    let h = normal.dot(&to_o).abs();
+    if h == 0.0 {
+        return 0.0;
+    }
+
+    let rho = (to_o - normal.into_inner() * normal.dot(&to_o)).magnitude();
+    let f = |s: Float| h / (h * h + s * s).sqrt();
+    // The distances along the direction at `phi` from the one towards the center where it crosses the circle.
+    let crossings = |phi: Float| {
+        let half_chord = (radius.powi(2) - (rho * phi.sin()).powi(2)).max(0.0).sqrt();
+        (rho * phi.cos() - half_chord, rho * phi.cos() + half_chord)
+    };
+
+    if rho < radius {
+        // Every direction leaves the disk once, and the integrand is smooth and periodic, which suits the trapezoidal rule.
+        let sum: Float = (0..STEPS)
+            .map(|i| 1.0 - f(crossings(2.0 * Float::PI() * i as Float / STEPS as Float).1))
+            .sum();
+        sum * 2.0 * Float::PI() / STEPS as Float
+    }
+    else {
+        // Only directions within `alpha` of the center cross the disk. Substituting `phi = alpha sin(theta)`
+        // smooths out the integrand at the tangents, where the chord shrinks like a square root.
+        let alpha = (radius / rho).asin();
+        let sum: Float = (0..STEPS)
+            .map(|i| {
+                let theta = Float::PI() * ((i as Float + 0.5) / STEPS as Float - 0.5);
+                let (s0, s1) = crossings(alpha * theta.sin());
+                (f(s0) - f(s1)) * alpha * theta.cos()
+            })
+            .sum();
+        sum * Float::PI() / STEPS as Float
+    }
+}
+
+
+/// Estimates the solid angle of a primitive without a closed form by tracing a fixed spiral of directions
+/// through the cone around the bounding sphere of `aabb`.
+pub(super) fn traced_solid_angle<F: Fn(&Ray) -> bool>(aabb: &AABB, o: Point3<Float>, intersects: F) -> Float {
+    const DIRECTIONS: usize = 1024;
+
+    let center = aabb.centroid();
+    let radius_squared = aabb.diagonal().magnitude_squared() * 0.25;
+
+    let to_center = center - o;
+    let cos_theta_max = if to_center.magnitude_squared() <= radius_squared {
+        -1.0
+    } else {
+        (1.0 - radius_squared / to_center.magnitude_squared()).sqrt()
+    };
+    let frame = ShadingFrame::new(Unit::try_new(to_center, 0.0).unwrap_or(Vector3::y_axis()));
+
+    let golden_angle = Float::PI() * (3.0 - (5.0 as Float).sqrt());
+    let hits = (0..DIRECTIONS)
+        .filter(|i| {
+            let cos_theta = 1.0 - (*i as Float + 0.5) / DIRECTIONS as Float * (1.0 - cos_theta_max);
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = golden_angle * *i as Float;
+            let direction = frame.to_world(&vector!(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta));
+
+            intersects(&Ray::new(o, Unit::new_normalize(direction)))
+        })
+        .count();
+
+    2.0 * Float::PI() * (1.0 - cos_theta_max) * hits as Float / DIRECTIONS as Float
+}
+
+/// A direction spread evenly over the directions within the angle with the given cosine around `axis`.
+pub(super) fn random_direction_in_cone(axis: UnitVector3<Float>, cos_theta_max: Float, rng: &mut dyn Randomness) -> UnitVector3<Float> {
+    let cos_theta = 1.0 - rng.float() * (1.0 - cos_theta_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * Float::PI() * rng.float();
+
+    let frame = ShadingFrame::new(axis);
+    let local = vector!(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+
+    Unit::new_normalize(frame.to_world(&local))
+}
+
+/// A primitive of one of the objects in a [Scene].
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
-pub struct PrimitiveRef(pub(crate) usize);
+pub struct PrimitiveRef {
+    pub(crate) instance: usize,
+    pub(crate) primitive: usize,
+}
 
 
 pub struct PrimitiveIntersection {
@@ -164,8 +918,9 @@ pub struct PrimitiveIntersection {
     pub tex_coord: TextureCoord2D,
 }
 impl PrimitiveIntersection {
-    pub fn to_intersection(self, mat: MaterialRef) -> Intersection {
+    pub fn to_intersection(self, mat: MaterialRef, primitive: PrimitiveRef) -> Intersection {
         Intersection {
+            primitive,
             t: self.t,
             point: self.point,
             normal: self.normal,
@@ -191,18 +946,14 @@ impl PrimitiveDirectionPDF {
 }
 impl PDF<UnitVector3<Float>> for PrimitiveDirectionPDF {
     fn value(&self, direction: &UnitVector3<Float>, scene: &Scene) -> Float {
-        let p = &scene.primitives[self.primitive.0].primitive;
-
-        if !p.intersects(&Ray::new(self.o, *direction), 0.001, Float::INFINITY) {
-            return 0.0
-        }
-
-        let solid_angle = p.solid_angle(self.o);
-        1.0 / solid_angle
-
+        let (instance, p) = scene.primitive(self.primitive);
+        let (local_direction, jacobian) = instance.direction_to_local(direction);
+        p.direction_pdf(instance.point_to_local(&self.o), local_direction) * jacobian
     }
     fn generate(&self, rng: &mut dyn Randomness, scene: &Scene) -> UnitVector3<Float> {
-        scene.primitives[self.primitive.0].primitive.random_direction_towards(self.o, &mut *rng)
+        let (instance, p) = scene.primitive(self.primitive);
+        let direction = p.random_direction_towards(instance.point_to_local(&self.o), &mut *rng);
+        instance.direction_to_world(&direction)
     }
 }
 
@@ -218,14 +969,15 @@ impl PrimitiveSurfacePDF {
     }
 }
 impl PDF<Point3<Float>> for PrimitiveSurfacePDF {
-    fn value(&self, _value: &Point3<Float>, scene: &Scene) -> Float {
-        let p = &scene.primitives[self.primitive.0].primitive;
-        let area = p.area();
+    fn value(&self, value: &Point3<Float>, scene: &Scene) -> Float {
+        let (instance, p) = scene.primitive(self.primitive);
+        let normal = p.outward_normal(&instance.point_to_local(value));
+        let area = p.area() * instance.area_scale(&normal);
         1.0 / area
     }
 
     fn generate(&self, rng: &mut dyn Randomness, scene: &Scene) -> Point3<Float> {
-        let p = &scene.primitives[self.primitive.0].primitive;
-        p.random_point_on_surface(rng)
+        let (instance, p) = scene.primitive(self.primitive);
+        instance.point_to_world(&p.random_point_on_surface(rng))
     }
 }
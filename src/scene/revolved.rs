@@ -0,0 +1,497 @@
+use nalgebra::{Affine3, Point3, Unit, UnitQuaternion, UnitVector3, vector, Vector3};
+use num_traits::FloatConst;
+use crate::aabb::AABB;
+use crate::Float;
+use crate::randomness::Randomness;
+use crate::ray::Ray;
+use crate::scene::primitive::{PrimitiveIntersection, traced_solid_angle};
+use crate::texture::TextureCoord2D;
+
+
+/// A primitive that is symmetric around the y axis of its own space, which is rotated and moved into place.
+/// Texture coordinates go around the axis along u, starting at -x like on spheres, and along the profile from bottom to top along v.
+pub(super) struct Revolved {
+    pub(super) center: Point3<Float>,
+    pub(super) rotation: UnitQuaternion<Float>,
+    pub(super) profile: Profile,
+}
+
+/// The shape of a [Revolved] in its own space, centered at the origin.
+#[derive(Copy, Clone, Debug)]
+pub(super) enum Profile {
+    /// A cone cut off at the bottom and top, with the given radii there. Cylinders have the same radius at both ends,
+    /// cones a radius of zero at one of them. The ends are closed off with disks if capped.
+    Frustum {
+        base_radius: Float,
+        top_radius: Float,
+        half_height: Float,
+        capped: bool,
+    },
+    /// A tube of `minor_radius` around a circle of `major_radius` in the xz-plane.
+    Torus {
+        major_radius: Float,
+        minor_radius: Float,
+    },
+    /// All points within `radius` of the segment along the axis, which is a cylinder with a hemisphere on either end.
+    Capsule {
+        radius: Float,
+        half_height: Float,
+    },
+}
+
+impl Revolved {
+    /// The tightest box around the primitive after transforming it, which is made of the bounds of the circles and balls it is swept from.
+    pub(super) fn transformed_aabb(&self, t: &Affine3<Float>) -> AABB {
+        let linear = t.matrix().fixed_slice::<3, 3>(0, 0) * self.rotation.to_rotation_matrix().matrix();
+        let at_height = |y: Float| t.transform_point(&(self.center + self.rotation * Vector3::y() * y));
+
+        // A circle in the xz-plane becomes an ellipse spanned by the transformed x and z axes,
+        // and a ball becomes an ellipsoid, whose extent along an axis is the length of the corresponding row of its matrix.
+        let circle = |radius: Float| Vector3::from_fn(|i, _| (linear[(i, 0)].powi(2) + linear[(i, 2)].powi(2)).sqrt() * radius);
+        let ball = |radius: Float| Vector3::from_fn(|i, _| linear.row(i).norm() * radius);
+        let around = |center: Point3<Float>, diff: Vector3<Float>| AABB::new(center - diff, center + diff);
+
+        match self.profile {
+            Profile::Frustum { base_radius, top_radius, half_height, .. } => {
+                AABB::merged(around(at_height(-half_height), circle(base_radius)), around(at_height(half_height), circle(top_radius)))
+            }
+            Profile::Torus { major_radius, minor_radius } => around(at_height(0.0), circle(major_radius) + ball(minor_radius)),
+            Profile::Capsule { radius, half_height } => {
+                AABB::merged(around(at_height(-half_height), ball(radius)), around(at_height(half_height), ball(radius)))
+            }
+        }
+    }
+    pub(super) fn aabb(&self) -> AABB {
+        self.transformed_aabb(&Affine3::identity())
+    }
+
+    pub(super) fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<PrimitiveIntersection> {
+        let (origin, direction) = self.ray_to_local(ray);
+
+        let mut closest: Option<Float> = None;
+        self.profile.crossings(&origin, &direction, t_min, t_max, |t| closest = Some(closest.map_or(t, |c| c.min(t))));
+        let t = closest?;
+
+        let local = origin + direction * t;
+        let outward_normal = self.rotation * self.profile.outward_normal(&local);
+        // Open surfaces have no inside, so like flat ones, `outside` only tells the side that was hit.
+        let outside = outward_normal.dot(&ray.direction) < 0.0;
+
+        Some(PrimitiveIntersection {
+            t,
+            point: ray.point_at(t),
+            normal: if outside { outward_normal } else { -outward_normal },
+            outside,
+            tex_coord: self.profile.tex_coord(&local),
+        })
+    }
+    pub(super) fn intersects(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        let (origin, direction) = self.ray_to_local(ray);
+
+        let mut hit = false;
+        self.profile.crossings(&origin, &direction, t_min, t_max, |_| hit = true);
+        hit
+    }
+
+    pub(super) fn outward_normal(&self, p: &Point3<Float>) -> UnitVector3<Float> {
+        self.rotation * self.profile.outward_normal(&self.rotation.inverse_transform_vector(&(p - self.center)))
+    }
+
+    pub(super) fn area(&self) -> Float {
+        self.profile.area()
+    }
+
+    /// There is no closed form for these shapes, so the solid angle is estimated by tracing a fixed spiral of directions
+    /// through the cone around their bounding sphere.
+    pub(super) fn solid_angle(&self, o: Point3<Float>) -> Float {
+        traced_solid_angle(&self.aabb(), o, |ray| self.intersects(ray, 0.001, Float::INFINITY))
+    }
+    /// Directions are sampled through points spread evenly over the whole surface, and a direction can be sampled through
+    /// any point where it crosses the surface, so the densities of all of them add up.
+    pub(super) fn direction_pdf(&self, o: Point3<Float>, direction: UnitVector3<Float>) -> Float {
+        let (origin, local_direction) = self.ray_to_local(&Ray::new(o, direction));
+        let area = self.profile.area();
+
+        let mut pdf = 0.0;
+        self.profile.crossings(&origin, &local_direction, 0.001, Float::INFINITY, |t| {
+            let cosine = self.profile.outward_normal(&(origin + local_direction * t)).dot(&local_direction).abs();
+            pdf += t.powi(2) / (cosine * area);
+        });
+        pdf
+    }
+
+    pub(super) fn random_point_on_surface(&self, rng: &mut dyn Randomness) -> Point3<Float> {
+        self.center + self.rotation * self.profile.random_point(rng)
+    }
+    pub(super) fn random_direction_towards(&self, o: Point3<Float>, rng: &mut dyn Randomness) -> UnitVector3<Float> {
+        Unit::new_normalize(self.random_point_on_surface(rng) - o)
+    }
+
+    fn ray_to_local(&self, ray: &Ray) -> (Vector3<Float>, Vector3<Float>) {
+        (
+            self.rotation.inverse_transform_vector(&(ray.origin - self.center)),
+            self.rotation.inverse_transform_vector(&ray.direction),
+        )
+    }
+}
+
+impl Profile {
+    /// Calls `visit` with every distance in `t_min..=t_max` at which the ray crosses the surface, in no particular order.
+    fn crossings<F: FnMut(Float)>(&self, origin: &Vector3<Float>, direction: &Vector3<Float>, t_min: Float, t_max: Float, mut visit: F) {
+        let mut visit = |t: Float| if t >= t_min && t <= t_max {
+            visit(t);
+        };
+
+        match *self {
+            Profile::Frustum { base_radius, top_radius, half_height, capped } => {
+                // The radius changes linearly along the axis, from `radius` at the center with a slope of `slope`.
+                let radius = (base_radius + top_radius) * 0.5;
+                let slope = (top_radius - base_radius) / (2.0 * half_height);
+                let radius_at_origin = radius + slope * origin.y;
+
+                let a = direction.x.powi(2) + direction.z.powi(2) - (slope * direction.y).powi(2);
+                let b = 2.0 * (origin.x * direction.x + origin.z * direction.z - slope * radius_at_origin * direction.y);
+                let c = origin.x.powi(2) + origin.z.powi(2) - radius_at_origin.powi(2);
+                for t in quadratic_roots(a, b, c) {
+                    if (origin.y + direction.y * t).abs() <= half_height {
+                        visit(t);
+                    }
+                }
+
+                if capped && direction.y != 0.0 {
+                    for (y, radius) in [(-half_height, base_radius), (half_height, top_radius)] {
+                        let t = (y - origin.y) / direction.y;
+                        if (origin.x + direction.x * t).powi(2) + (origin.z + direction.z * t).powi(2) <= radius.powi(2) {
+                            visit(t);
+                        }
+                    }
+                }
+            }
+            Profile::Torus { major_radius, minor_radius } => {
+                for t in torus_roots(major_radius, minor_radius, origin, direction, t_min, t_max) {
+                    visit(t);
+                }
+            }
+            Profile::Capsule { radius, half_height } => {
+                let a = direction.x.powi(2) + direction.z.powi(2);
+                let b = 2.0 * (origin.x * direction.x + origin.z * direction.z);
+                let c = origin.x.powi(2) + origin.z.powi(2) - radius.powi(2);
+                for t in quadratic_roots(a, b, c) {
+                    if (origin.y + direction.y * t).abs() <= half_height {
+                        visit(t);
+                    }
+                }
+
+                // Each hemisphere is the part of its sphere beyond the end of the cylinder.
+                for side in [-1.0, 1.0] {
+                    let to_origin = origin - Vector3::y() * side * half_height;
+                    let a = direction.magnitude_squared();
+                    let b = 2.0 * to_origin.dot(direction);
+                    let c = to_origin.magnitude_squared() - radius.powi(2);
+                    for t in quadratic_roots(a, b, c) {
+                        if (to_origin.y + direction.y * t) * side >= 0.0 {
+                            visit(t);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The normal on the outside of the surface at `p`, which has to be on the surface.
+    fn outward_normal(&self, p: &Vector3<Float>) -> UnitVector3<Float> {
+        match *self {
+            Profile::Frustum { base_radius, top_radius, half_height, capped } => {
+                if capped && on_cap(base_radius, top_radius, half_height, p) {
+                    return Unit::new_unchecked(Vector3::y() * p.y.signum());
+                }
+
+                let distance = (p.x.powi(2) + p.z.powi(2)).sqrt();
+                let slope = (top_radius - base_radius) / (2.0 * half_height);
+
+                // The gradient of x² + z² - radius(y)², divided by the distance from the axis, which is the radius on the surface.
+                // At the tip of a cone, the normal points along the axis.
+                let around = if distance > 0.0 { vector!(p.x, 0.0, p.z) / distance } else { Vector3::zeros() };
+                Unit::try_new(around - Vector3::y() * slope, 0.0)
+                    .unwrap_or(Unit::new_unchecked(Vector3::y() * -slope.signum()))
+            }
+            Profile::Torus { major_radius, .. } => {
+                // Away from the closest point on the circle the tube goes around.
+                let around = vector!(p.x, 0.0, p.z);
+                let circle = around * (major_radius / around.magnitude());
+                Unit::new_normalize(p - circle)
+            }
+            Profile::Capsule { half_height, .. } => {
+                // Away from the closest point on the segment.
+                Unit::new_normalize(p - Vector3::y() * p.y.clamp(-half_height, half_height))
+            }
+        }
+    }
+
+    fn tex_coord(&self, p: &Vector3<Float>) -> TextureCoord2D {
+        let u = ((-p.z).atan2(p.x) + Float::PI()) / (2.0 * Float::PI());
+
+        match *self {
+            Profile::Frustum { base_radius, top_radius, half_height, capped } => {
+                let cap_radius = if p.y > 0.0 { top_radius } else { base_radius };
+                if capped && cap_radius > 0.0 && on_cap(base_radius, top_radius, half_height, p) {
+                    // The caps are mapped onto the texture like a picture in a frame, the same as disks.
+                    let (x, z) = (p.x / (2.0 * cap_radius), p.z / (2.0 * cap_radius));
+                    return TextureCoord2D::new(x + 0.5, z + 0.5);
+                }
+
+                TextureCoord2D::new(u, ((p.y + half_height) / (2.0 * half_height)).clamp(0.0, 1.0))
+            }
+            Profile::Torus { major_radius, .. } => {
+                // Around the tube, starting on the inside of the ring and going over the top, so the seam is hidden in the hole.
+                let distance = (p.x.powi(2) + p.z.powi(2)).sqrt();
+                let v = ((-p.y).atan2(distance - major_radius) + Float::PI()) / (2.0 * Float::PI());
+                TextureCoord2D::new(u, v)
+            }
+            Profile::Capsule { radius, half_height } => {
+                // By the length along the profile, from the bottom pole over the side to the top pole.
+                let quarter = radius * Float::FRAC_PI_2();
+                let length = if p.y < -half_height {
+                    radius * ((-half_height - p.y) / radius).clamp(-1.0, 1.0).acos()
+                } else if p.y > half_height {
+                    quarter + 2.0 * half_height + radius * ((p.y - half_height) / radius).clamp(-1.0, 1.0).asin()
+                } else {
+                    quarter + p.y + half_height
+                };
+
+                TextureCoord2D::new(u, length / (2.0 * quarter + 2.0 * half_height))
+            }
+        }
+    }
+
+    fn area(&self) -> Float {
+        match *self {
+            Profile::Frustum { base_radius, top_radius, half_height, capped } => {
+                let slant = ((top_radius - base_radius).powi(2) + (2.0 * half_height).powi(2)).sqrt();
+                let caps = if capped { base_radius.powi(2) + top_radius.powi(2) } else { 0.0 };
+
+                Float::PI() * ((base_radius + top_radius) * slant + caps)
+            }
+            Profile::Torus { major_radius, minor_radius } => 4.0 * Float::PI().powi(2) * major_radius * minor_radius,
+            Profile::Capsule { radius, half_height } => 4.0 * Float::PI() * radius * (radius + half_height),
+        }
+    }
+
+    /// A point spread evenly over the surface.
+    fn random_point(&self, rng: &mut dyn Randomness) -> Vector3<Float> {
+        let phi = 2.0 * Float::PI() * rng.float();
+        let around = |radius: Float, y: Float| vector!(radius * phi.cos(), y, radius * phi.sin());
+
+        match *self {
+            Profile::Frustum { base_radius, top_radius, half_height, capped } => {
+                let side = Profile::Frustum { base_radius, top_radius, half_height, capped: false }.area();
+                let target = rng.float() * self.area();
+
+                if capped && target >= side {
+                    let base = Float::PI() * base_radius.powi(2);
+                    let (y, radius) = if target - side < base { (-half_height, base_radius) } else { (half_height, top_radius) };
+                    return around(radius * rng.float().sqrt(), y);
+                }
+
+                // The side is spread evenly when the squared radius is, since the circumference grows with the radius
+                // and the radius linearly with the height. The height is found without dividing by the difference of the radii.
+                let u = rng.float();
+                let radius = (base_radius.powi(2) + u * (top_radius.powi(2) - base_radius.powi(2))).sqrt();
+                let v = if radius + base_radius > 0.0 { u * (base_radius + top_radius) / (radius + base_radius) } else { 0.0 };
+                around(radius, -half_height + 2.0 * half_height * v.clamp(0.0, 1.0))
+            }
+            Profile::Torus { major_radius, minor_radius } => {
+                // The circumference grows with the distance from the axis, so angles around the tube are rejected in proportion.
+                loop {
+                    let theta = 2.0 * Float::PI() * rng.float();
+                    let distance = major_radius + minor_radius * theta.cos();
+                    if rng.float() * (major_radius + minor_radius) <= distance {
+                        return around(distance, minor_radius * theta.sin());
+                    }
+                }
+            }
+            Profile::Capsule { radius, half_height } => {
+                // The hemispheres together make up a whole sphere.
+                let side = 4.0 * Float::PI() * radius * half_height;
+                if rng.float() * self.area() < side {
+                    return around(radius, half_height * (2.0 * rng.float() - 1.0));
+                }
+
+                let p = rng.unit_vector().into_inner() * radius;
+                p + Vector3::y() * half_height * p.y.signum()
+            }
+        }
+    }
+}
+
+/// Whether a point on the surface of a capped frustum is on one of the caps rather than the side,
+/// which is whichever part of the surface it is closest to.
+fn on_cap(base_radius: Float, top_radius: Float, half_height: Float, p: &Vector3<Float>) -> bool {
+    let radius = base_radius + (top_radius - base_radius) * (p.y + half_height) / (2.0 * half_height);
+    half_height - p.y.abs() < (radius - (p.x.powi(2) + p.z.powi(2)).sqrt()).abs()
+}
+
+/// The real roots of `a t² + b t + c`, computed so that neither loses precision to cancellation.
+fn quadratic_roots(a: Float, b: Float, c: Float) -> impl Iterator<Item=Float> {
+    let roots = if a == 0.0 {
+        [(b != 0.0).then(|| -c / b), None]
+    } else {
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
+            [None, None]
+        } else {
+            let q = -0.5 * (b + discriminant.sqrt().copysign(b));
+            if q == 0.0 { [Some(0.0), None] } else { [Some(q / a), Some(c / q)] }
+        }
+    };
+
+    roots.into_iter().flatten()
+}
+
+/// The distances in `t_min..=t_max` at which a ray crosses a torus.
+///
+/// The quartic is solved in double precision, after moving the origin of the ray to where it enters the box around the torus,
+/// since its coefficients grow with the fourth power of the distance.
+fn torus_roots(
+    major_radius: Float,
+    minor_radius: Float,
+    origin: &Vector3<Float>,
+    direction: &Vector3<Float>,
+    t_min: Float,
+    t_max: Float,
+) -> impl Iterator<Item=Float> {
+    let o = origin.map(f64::from);
+    let d = direction.map(f64::from);
+    let [big_r, r, t_min, t_max] = [major_radius, minor_radius, t_min, t_max].map(f64::from);
+
+    // The box is entered in double precision as well, or a root right where the ray enters it could be rounded away.
+    // It is a little larger than the torus, since the top and bottom of the tube and its outer rim touch its sides,
+    // where the polynomial would be zero up to rounding, and could have either sign.
+    let extent = vector!(big_r + r, r, big_r + r) + Vector3::repeat((big_r + r) * 1.0e-6);
+    let (mut enter, mut exit) = (t_min, t_max);
+    for a in 0..3 {
+        let inv_d = 1.0 / d[a];
+        let t0 = (-extent[a] - o[a]) * inv_d;
+        let t1 = (extent[a] - o[a]) * inv_d;
+
+        // NaN from rays in the plane of a side fails both comparisons, which leaves the axis out.
+        enter = enter.max(t0.min(t1));
+        exit = exit.min(t0.max(t1));
+    }
+
+    let roots = if enter <= exit {
+        let o = o + d * enter;
+
+        // (|p|² + R² - r²)² = 4 R² (x² + z²) along the ray, multiplied out.
+        let big_r2 = big_r * big_r;
+        let dd = d.magnitude_squared();
+        let m = o.dot(&d);
+        let k = o.magnitude_squared() + big_r2 - r * r;
+        let coefficients = [
+            dd * dd,
+            4.0 * dd * m,
+            4.0 * m * m + 2.0 * dd * k - 4.0 * big_r2 * (d.x * d.x + d.z * d.z),
+            4.0 * m * k - 8.0 * big_r2 * (o.x * d.x + o.z * d.z),
+            k * k - 4.0 * big_r2 * (o.x * o.x + o.z * o.z),
+        ];
+
+        polynomial_roots(&coefficients, 0.0, exit - enter)
+    } else {
+        Roots::default()
+    };
+
+    roots.into_values().map(move |s| (enter + s) as Float)
+}
+
+
+/// Up to four real roots of a polynomial.
+#[derive(Default)]
+struct Roots {
+    values: [f64; 4],
+    count: usize,
+}
+impl Roots {
+    fn push(&mut self, root: f64) {
+        if self.count < self.values.len() {
+            self.values[self.count] = root;
+            self.count += 1;
+        }
+    }
+    fn as_slice(&self) -> &[f64] {
+        &self.values[..self.count]
+    }
+    fn into_values(self) -> impl Iterator<Item=f64> {
+        self.values.into_iter().take(self.count)
+    }
+}
+
+/// The real roots within `lo..=hi` in ascending order, of the polynomial of at most fourth degree with the given coefficients,
+/// from the highest power down.
+///
+/// The roots of the derivative split the range into parts where the polynomial is monotonic, each holding at most one root,
+/// which is then found by bisection and Newton steps. Unlike the closed forms, which lose roots to cancellation, this always finds
+/// roots where the sign changes, but not those where the polynomial only touches zero.
+fn polynomial_roots(coefficients: &[f64], lo: f64, hi: f64) -> Roots {
+    let mut roots = Roots::default();
+    let degree = coefficients.len() - 1;
+    if degree == 0 {
+        return roots;
+    }
+
+    let mut derivative = [0.0; 4];
+    for (i, c) in coefficients[..degree].iter().enumerate() {
+        derivative[i] = c * (degree - i) as f64;
+    }
+    let derivative = &derivative[..degree];
+
+    let value = |x: f64| coefficients.iter().fold(0.0, |v, c| v * x + c);
+    let critical = polynomial_roots(derivative, lo, hi);
+
+    let mut a = lo;
+    for b in critical.as_slice().iter().copied().chain([hi]) {
+        let (value_a, value_b) = (value(a), value(b));
+
+        if value_a == 0.0 {
+            if roots.as_slice().last() != Some(&a) {
+                roots.push(a);
+            }
+        } else if value_b != 0.0 && (value_a < 0.0) != (value_b < 0.0) {
+            roots.push(refine_root(&value, derivative, a, b, value_a < 0.0));
+        }
+        a = b;
+    }
+    if value(hi) == 0.0 && roots.as_slice().last() != Some(&hi) {
+        roots.push(hi);
+    }
+
+    roots
+}
+
+/// The root between `a` and `b`, where the polynomial changes its sign once, rising if `rising`.
+/// Newton steps are taken as long as they stay within the bracket, which shrinks with every step.
+fn refine_root<V: Fn(f64) -> f64>(value: &V, derivative: &[f64], mut a: f64, mut b: f64, rising: bool) -> f64 {
+    let slope = |x: f64| derivative.iter().fold(0.0, |v, c| v * x + c);
+
+    let mut x = 0.5 * (a + b);
+    for _ in 0..64 {
+        let v = value(x);
+        if v == 0.0 {
+            return x;
+        }
+        if (v < 0.0) == rising {
+            a = x;
+        } else {
+            b = x;
+        }
+
+        let newton = x - v / slope(x);
+        let next = if newton > a && newton < b { newton } else { 0.5 * (a + b) };
+        if (next - x).abs() <= 1.0e-12 * (1.0 + x.abs()) {
+            return next;
+        }
+        x = next;
+    }
+
+    x
+}
+
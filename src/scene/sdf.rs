@@ -0,0 +1,227 @@
+use nalgebra::{Affine3, Isometry3, Point3, Unit, UnitVector3, Vector3};
+use num_traits::FloatConst;
+use crate::aabb::AABB;
+use crate::Float;
+use crate::randomness::Randomness;
+use crate::ray::Ray;
+use crate::scene::primitive::{PrimitiveIntersection, random_direction_in_cone, traced_solid_angle};
+use crate::texture::TextureCoord2D;
+use crate::world::sdf::Sdf;
+
+
+/// Rays that haven't come within reach of the surface after this many steps miss it.
+const MAX_STEPS: usize = 512;
+/// How close a step has to come to the surface to hit it, relative to the size of the bounds.
+const HIT_DISTANCE: Float = 1.0e-5;
+/// How often a step over the surface is halved to find where it was crossed.
+const BISECTION_STEPS: usize = 24;
+/// The step of the central differences that normals are taken from, relative to the size of the bounds.
+const NORMAL_STEP: Float = 1.0e-4;
+/// The number of cells along each axis of the grid the area is estimated on.
+const AREA_CELLS: usize = 32;
+
+
+/// The primitive of an SDF shape, which is intersected by sphere tracing within its bounds.
+///
+/// The field is evaluated in its own space, which is placed by `transform`. The surface isn't sampled directly
+/// for lights. Directions are sampled within the cone around the bounding sphere of the bounds instead,
+/// and those that miss the surface don't count.
+pub struct TracedSdf {
+    sdf: Sdf,
+    bounds: AABB,
+    transform: Isometry3<Float>,
+    /// How much faster than the distance the field can change anywhere within the bounds.
+    lipschitz: Float,
+    hit_distance: Float,
+    normal_step: Float,
+    area: Float,
+}
+impl TracedSdf {
+    pub(crate) fn new(sdf: Sdf, bounds: AABB, transform: Isometry3<Float>) -> Self {
+        let extent = bounds.min.coords.abs().sup(&bounds.max.coords.abs()).magnitude();
+        let size = bounds.diagonal().magnitude();
+
+        let mut traced = Self {
+            lipschitz: sdf.lipschitz(extent).max(1.0),
+            sdf,
+            bounds,
+            transform,
+            hit_distance: size * HIT_DISTANCE,
+            normal_step: size * NORMAL_STEP,
+            area: 0.0,
+        };
+        traced.area = traced.estimate_area();
+        traced
+    }
+
+    pub(super) fn aabb(&self) -> AABB {
+        self.transformed_aabb(&Affine3::identity())
+    }
+    pub(super) fn transformed_aabb(&self, t: &Affine3<Float>) -> AABB {
+        let (min, max) = (self.bounds.min, self.bounds.max);
+        let corners: Vec<Point3<Float>> = (0..8)
+            .map(|i| {
+                let corner = Point3::from(Vector3::from_fn(|a, _| if i & (1 << a) == 0 { min[a] } else { max[a] }));
+                t.transform_point(&(self.transform * corner))
+            })
+            .collect();
+
+        AABB::from_points(&corners)
+    }
+
+    /// Texture coordinates are taken from the direction towards the hit from the center of the bounds, like on spheres.
+    pub(super) fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<PrimitiveIntersection> {
+        let local_ray = self.ray_to_local(ray);
+        let t = self.march(&local_ray, t_min, t_max)?;
+
+        let local = local_ray.point_at(t);
+        let outward_normal = self.transform.rotation * self.normal(&local);
+        let outside = outward_normal.dot(&ray.direction) < 0.0;
+
+        let around = Unit::try_new(local - self.bounds.centroid(), 0.0).unwrap_or(Vector3::y_axis());
+        let u = ((-around.z).atan2(around.x) + Float::PI()) / (2.0 * Float::PI());
+        let v = (-around.y).clamp(-1.0, 1.0).acos() / Float::PI();
+
+        Some(PrimitiveIntersection {
+            t,
+            point: ray.point_at(t),
+            normal: if outside { outward_normal } else { -outward_normal },
+            outside,
+            tex_coord: TextureCoord2D::new(u, v),
+        })
+    }
+    pub(super) fn intersects(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        self.march(&self.ray_to_local(ray), t_min, t_max).is_some()
+    }
+
+    pub(super) fn outward_normal(&self, p: &Point3<Float>) -> UnitVector3<Float> {
+        self.transform.rotation * self.normal(&self.transform.inverse_transform_point(p))
+    }
+
+    /// An estimate, since the surface is only known implicitly.
+    pub(super) fn area(&self) -> Float {
+        self.area
+    }
+    pub(super) fn solid_angle(&self, o: Point3<Float>) -> Float {
+        traced_solid_angle(&self.aabb(), o, |ray| self.intersects(ray, 0.001, Float::INFINITY))
+    }
+    pub(super) fn direction_pdf(&self, o: Point3<Float>, direction: UnitVector3<Float>) -> Float {
+        match self.bounding_cone(o) {
+            Some((axis, cos_theta_max)) if axis.dot(&direction) >= cos_theta_max => 1.0 / (2.0 * Float::PI() * (1.0 - cos_theta_max)),
+            Some(_) => 0.0,
+            None => 1.0 / (4.0 * Float::PI()),
+        }
+    }
+
+    /// Points are projected onto the surface from all over the bounds, so they aren't spread evenly over it.
+    pub(super) fn random_point_on_surface(&self, rng: &mut dyn Randomness) -> Point3<Float> {
+        let (min, max) = (self.bounds.min, self.bounds.max);
+        let mut p = Point3::from(Vector3::from_fn(|a, _| min[a] + (max[a] - min[a]) * rng.float()));
+
+        for _ in 0..16 {
+            let distance = self.sdf.distance(&p);
+            if distance.abs() < self.hit_distance {
+                break;
+            }
+            p -= self.normal(&p).into_inner() * distance / self.lipschitz;
+        }
+
+        self.transform * p
+    }
+    pub(super) fn random_direction_towards(&self, o: Point3<Float>, rng: &mut dyn Randomness) -> UnitVector3<Float> {
+        match self.bounding_cone(o) {
+            Some((axis, cos_theta_max)) => random_direction_in_cone(axis, cos_theta_max, rng),
+            None => rng.unit_vector(),
+        }
+    }
+
+    fn ray_to_local(&self, ray: &Ray) -> Ray {
+        Ray::new(
+            self.transform.inverse_transform_point(&ray.origin),
+            self.transform.inverse_transform_unit_vector(&ray.direction),
+        )
+    }
+
+    /// The distance to the first crossing of the surface within the bounds.
+    /// Steps are as long as the distance to the surface allows. If bent space makes one step over the surface anyway,
+    /// the crossing is searched for by bisection.
+    fn march(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<Float> {
+        let (t_min, t_max) = self.bounds.ray_interval(ray, t_min, t_max)?;
+        let distance = |t: Float| self.sdf.distance(&ray.point_at(t));
+
+        let mut t = t_min;
+        let mut d = distance(t);
+        let side = d.signum();
+
+        for _ in 0..MAX_STEPS {
+            if d.abs() < self.hit_distance {
+                return Some(t);
+            }
+
+            let previous = t;
+            t += (d.abs() / self.lipschitz).max(self.hit_distance);
+            if t > t_max {
+                return None;
+            }
+
+            d = distance(t);
+            if d * side < 0.0 {
+                let (mut before, mut after) = (previous, t);
+                for _ in 0..BISECTION_STEPS {
+                    let middle = (before + after) * 0.5;
+                    if distance(middle) * side < 0.0 {
+                        after = middle;
+                    } else {
+                        before = middle;
+                    }
+                }
+                return Some(after);
+            }
+        }
+
+        None
+    }
+    /// The gradient of the field from central differences.
+    fn gradient(&self, p: &Point3<Float>) -> Vector3<Float> {
+        Vector3::from_fn(|a, _| {
+            let step = Vector3::ith(a, self.normal_step);
+            (self.sdf.distance(&(p + step)) - self.sdf.distance(&(p - step))) / (2.0 * self.normal_step)
+        })
+    }
+    /// The direction in which the field grows.
+    fn normal(&self, p: &Point3<Float>) -> UnitVector3<Float> {
+        Unit::try_new(self.gradient(p), 0.0).unwrap_or(Vector3::y_axis())
+    }
+
+    /// The axis and angular size of the cone around the bounding sphere seen from `o`, or `None` if `o` is inside of it.
+    fn bounding_cone(&self, o: Point3<Float>) -> Option<(UnitVector3<Float>, Float)> {
+        let to_center = self.transform * self.bounds.centroid() - o;
+        let radius_squared = self.bounds.diagonal().magnitude_squared() * 0.25;
+        let distance_squared = to_center.magnitude_squared();
+        if distance_squared <= radius_squared {
+            return None;
+        }
+
+        Some((Unit::new_normalize(to_center), (1.0 - radius_squared / distance_squared).sqrt()))
+    }
+
+    /// By the coarea formula, the area is the volume of a thin shell around the surface divided by its thickness.
+    /// The shell is found on a grid over the bounds, and is as thick as the cells, so that it doesn't slip through.
+    fn estimate_area(&self) -> Float {
+        let cell = self.bounds.diagonal() / AREA_CELLS as Float;
+        let half_thickness = cell.max();
+
+        let mut shell = 0.0;
+        for i in 0..AREA_CELLS.pow(3) {
+            let index = Vector3::new(i % AREA_CELLS, i / AREA_CELLS % AREA_CELLS, i / AREA_CELLS.pow(2));
+            let p = self.bounds.min + index.map(|i| i as Float + 0.5).component_mul(&cell);
+
+            if self.sdf.distance(&p).abs() < half_thickness {
+                // Where space is bent, the field grows faster than the distance, which makes the shell thinner.
+                shell += self.gradient(&p).magnitude();
+            }
+        }
+
+        shell * cell.product() / (2.0 * half_thickness)
+    }
+}
@@ -1,4 +1,4 @@
-use std::iter::{repeat, repeat_with};
+use std::iter::repeat_with;
 use std::ops::{Index, IndexMut};
 use crate::Float;
 
@@ -22,7 +22,7 @@ impl<T> Texture2D<T> {
     }
     pub fn new_from(width: u32, height: u32, value: T) -> Self
         where T: Clone {
-        let pixels = Vec::from_iter(repeat(value).take(width as usize * height as usize));
+        let pixels = vec![value; width as usize * height as usize];
 
         Self {
             width,
@@ -117,6 +117,7 @@ impl PixelCoord2D {
 
 /// A 2d normalized texture coordinate.
 /// Lower left corner is [0.0, 0.0].
+/// Coordinates outside of [0.0, 1.0) wrap around.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct TextureCoord2D {
     pub x: Float,
@@ -134,11 +135,11 @@ impl TextureCoord2D {
         let fwidth = width as Float;
         let fheight = height as Float;
 
-        let x = self.x * fwidth;
-        let y = self.y * fheight;
+        let x = self.x.rem_euclid(1.0) * fwidth;
+        let y = self.y.rem_euclid(1.0) * fheight;
 
-        let x = x.floor() as u32;
-        let mut y = y.floor() as u32;
+        let x = (x.floor() as u32).min(width - 1);
+        let mut y = (y.floor() as u32).min(height - 1);
 
         y = height - 1 - y;
 
@@ -5,6 +5,8 @@ use crate::{Float, Randomness, Scene};
 use crate::intersection::Intersection;
 use crate::world::albedo::AlbedoRef;
 use crate::world::World;
+use crate::world::microfacet::{conductor_brdf, ConductorIOR, GGX, MicrofacetPDF, ShadingFrame};
+use crate::world::principled::{Principled, PrincipledBSDF, PrincipledPDF};
 use num_traits::identities::Zero;
 use crate::pdf::PDF;
 
@@ -13,6 +15,19 @@ pub enum Material {
     Lambertian(AlbedoRef),
     Mirror,
     Emitting(AlbedoRef, Float),
+    /// A clear, smooth dielectric like glass or water, with the given index of refraction.
+    Dielectric {
+        ior: Float,
+    },
+    /// A metal with microscopically rough surface, described by a GGX distribution.
+    /// The roughness along the two tangent directions may differ, for example for brushed metal.
+    Conductor {
+        ior: ConductorIOR,
+        roughness_u: Float,
+        roughness_v: Float,
+    },
+    /// A physically based material with the usual parameters artists know from other tools.
+    Principled(Box<Principled>),
 }
 impl Material {
     pub fn scatter(&self, ray_in: UnitVector3<Float>, int: &Intersection, scene: &Scene) -> Option<ScatteredRay> {
@@ -40,20 +55,49 @@ impl Material {
                     is_specular: true,
                 })
             }
+            Self::Dielectric { ior } => {
+                let pdf = MaterialPDF::Dielectric(DielectricScatter::new(ray_in, int, *ior));
+
+                Some(ScatteredRay {
+                    pdf,
+                    attenuation: vector!(1.0, 1.0, 1.0),
+                    is_specular: true,
+                })
+            }
+            Self::Conductor { roughness_u, roughness_v, .. } => {
+                let distribution = GGX::from_roughness(*roughness_u, *roughness_v);
+                let pdf = MicrofacetPDF::new(ShadingFrame::new(int.normal), distribution, ray_in);
+
+                Some(ScatteredRay {
+                    pdf: MaterialPDF::Microfacet(pdf),
+                    attenuation: vector!(1.0, 1.0, 1.0),
+                    is_specular: false,
+                })
+            }
+            Self::Principled(p) => {
+                let bsdf = PrincipledBSDF::new(p, int, scene.world);
+
+                Some(ScatteredRay {
+                    pdf: MaterialPDF::Principled(PrincipledPDF::new(bsdf, ray_in)),
+                    attenuation: vector!(1.0, 1.0, 1.0),
+                    is_specular: false,
+                })
+            }
             Self::Emitting(_, _) => None,
         }
     }
 
-    pub fn brdf(&self, ray_in: UnitVector3<Float>, int: &Intersection, ray_out: UnitVector3<Float>) -> Float {
+    /// How much of the light arriving from `ray_in` is reflected towards `ray_out`, including the cosine term.
+    pub fn brdf(&self, ray_in: UnitVector3<Float>, int: &Intersection, ray_out: UnitVector3<Float>, world: &World) -> Vector3<Float> {
         match self {
             Self::Lambertian(_) => {
                 let cosine = int.normal.dot(&ray_in);
 
                 if cosine < 0.0 {
-                    0.0
+                    Vector3::zeros()
                 }
                 else {
-                    cosine / Float::PI()
+                    Vector3::repeat(cosine / Float::PI())
                 }
             }
             Self::Mirror => {
@@ -67,13 +111,34 @@ impl Material {
 
                 // Comparison can have a 1/1000 margin of error cuz of floating point inaccuracies
                 if cos > 0.999 {
-                    1.0
+                    Vector3::repeat(1.0)
                 }
                 else {
-                    0.0
+                    Vector3::zeros()
                 }
             }
-            Self::Emitting(_, _) => 0.0,
+            Self::Dielectric { ior } => {
+                // Refraction isn't symmetric, so the directions have to be computed from the viewing side.
+                let scatter = DielectricScatter::new(ray_out, int, *ior);
+
+                if scatter.reflected.dot(&ray_in) > 0.999 {
+                    Vector3::repeat(scatter.reflectance)
+                }
+                else if scatter.refracted.is_some_and(|r| r.dot(&ray_in) > 0.999) {
+                    Vector3::repeat(1.0 - scatter.reflectance)
+                }
+                else {
+                    Vector3::zeros()
+                }
+            }
+            Self::Conductor { ior, roughness_u, roughness_v } => {
+                let distribution = GGX::from_roughness(*roughness_u, *roughness_v);
+                let frame = ShadingFrame::new(int.normal);
+
+                conductor_brdf(&distribution, ior, &frame, &ray_in, &ray_out)
+            }
+            Self::Principled(p) => PrincipledBSDF::new(p, int, world).eval(&ray_in, &ray_out),
+            Self::Emitting(_, _) => Vector3::zeros(),
         }
     }
     
@@ -81,6 +146,9 @@ impl Material {
         match self {
             Self::Lambertian(_) => Vector3::zeros(),
             Self::Mirror => Vector3::zeros(),
+            Self::Dielectric { .. } => Vector3::zeros(),
+            Self::Conductor { .. } => Vector3::zeros(),
+            Self::Principled(p) => p.emit(int, world),
             Self::Emitting(a, factor) => world.sample_albedo(*a, &int.tex_coord) * *factor,
         }
     }
@@ -89,6 +157,9 @@ impl Material {
         match self {
             Self::Lambertian(_) => false,
             Self::Mirror => false,
+            Self::Dielectric { .. } => false,
+            Self::Conductor { .. } => false,
+            Self::Principled(p) => p.emits(),
             Self::Emitting(_, _) => true,
         }
     }
@@ -106,13 +177,19 @@ pub struct ScatteredRay {
 
 pub enum MaterialPDF {
     Lambertian(CosinePDF),
-    Specular(UnitVector3<Float>)
+    Specular(UnitVector3<Float>),
+    Dielectric(DielectricScatter),
+    Microfacet(MicrofacetPDF),
+    Principled(PrincipledPDF),
 }
 impl PDF<UnitVector3<Float>> for MaterialPDF {
     fn value(&self, direction: &UnitVector3<Float>, scene: &Scene) -> Float {
         match self {
             Self::Lambertian(c) => c.value(direction, scene),
             Self::Specular(v) => if v == direction { 1.0 } else { 0.0 },
+            Self::Dielectric(d) => d.value(direction, scene),
+            Self::Microfacet(m) => m.value(direction, scene),
+            Self::Principled(p) => p.value(direction, scene),
         }
     }
 
@@ -120,6 +197,73 @@ impl PDF<UnitVector3<Float>> for MaterialPDF {
         match self {
             Self::Lambertian(c) => c.generate(rng, scene),
             Self::Specular(v) => *v,
+            Self::Dielectric(d) => d.generate(rng, scene),
+            Self::Microfacet(m) => m.generate(rng, scene),
+            Self::Principled(p) => p.generate(rng, scene),
+        }
+    }
+}
+
+
+/// The two directions light can take at the boundary of a dielectric, and how likely it is to be reflected.
+/// Sampling picks one of them with the probability given by the Fresnel equations.
+pub struct DielectricScatter {
+    reflected: UnitVector3<Float>,
+    /// `None` on total internal reflection.
+    refracted: Option<UnitVector3<Float>>,
+    reflectance: Float,
+}
+impl DielectricScatter {
+    pub fn new(ray_in: UnitVector3<Float>, int: &Intersection, ior: Float) -> Self {
+        // The normal always faces towards the incoming ray, so `outside` tells us which way we are crossing.
+        let eta = if int.outside { 1.0 / ior } else { ior };
+        let normal = int.normal.into_inner();
+        let direction = -ray_in.into_inner();
+
+        let cos_i = ray_in.dot(&int.normal).clamp(0.0, 1.0);
+        let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+
+        let reflected = Unit::new_normalize(direction + normal * (2.0 * cos_i));
+
+        if sin2_t >= 1.0 {
+            return Self {
+                reflected,
+                refracted: None,
+                reflectance: 1.0,
+            };
+        }
+
+        let cos_t = (1.0 - sin2_t).sqrt();
+        let refracted = Unit::new_normalize(direction * eta + normal * (eta * cos_i - cos_t));
+
+        let r_parallel = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+        let r_perpendicular = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+        let reflectance = (r_parallel * r_parallel + r_perpendicular * r_perpendicular) / 2.0;
+
+        Self {
+            reflected,
+            refracted: Some(refracted),
+            reflectance,
+        }
+    }
+}
+impl PDF<UnitVector3<Float>> for DielectricScatter {
+    fn value(&self, direction: &UnitVector3<Float>, _scene: &Scene) -> Float {
+        if *direction == self.reflected {
+            self.reflectance
+        }
+        else if Some(*direction) == self.refracted {
+            1.0 - self.reflectance
+        }
+        else {
+            0.0
+        }
+    }
+
+    fn generate(&self, rng: &mut dyn Randomness, _scene: &Scene) -> UnitVector3<Float> {
+        match self.refracted {
+            Some(refracted) if rng.float() >= self.reflectance => refracted,
+            _ => self.reflected,
         }
     }
 }
@@ -137,7 +281,7 @@ impl CosinePDF {
 }
 impl PDF<UnitVector3<Float>> for CosinePDF {
     fn value(&self, direction: &UnitVector3<Float>, _scene: &Scene) -> Float {
-        self.normal.dot(&direction) / Float::PI()
+        self.normal.dot(direction) / Float::PI()
     }
 
     fn generate(&self, rng: &mut dyn Randomness, _scene: &Scene) -> UnitVector3<Float> {
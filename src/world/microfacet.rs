@@ -0,0 +1,265 @@
+use nalgebra::{Unit, UnitVector3, vector, Vector3};
+use num_traits::FloatConst;
+use crate::{Float, Randomness, Scene};
+use crate::pdf::PDF;
+
+
+/// The smallest alpha we allow, since the distribution degenerates into a delta function at zero.
+const MIN_ALPHA: Float = 1.0e-3;
+
+
+/// An orthonormal basis around a shading normal.
+/// The tangent is derived from the normal alone, so anisotropic roughness is oriented consistently across a surface.
+#[derive(Copy, Clone, Debug)]
+pub struct ShadingFrame {
+    pub tangent: UnitVector3<Float>,
+    pub bitangent: UnitVector3<Float>,
+    pub normal: UnitVector3<Float>,
+}
+impl ShadingFrame {
+    pub fn new(normal: UnitVector3<Float>) -> Self {
+        // Duff et al., "Building an Orthonormal Basis, Revisited"
+        let n = normal.into_inner();
+        let sign = Float::copysign(1.0, n.z);
+        let a = -1.0 / (sign + n.z);
+        let b = n.x * n.y * a;
+
+        let tangent = vector!(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x);
+        let bitangent = vector!(b, sign + n.y * n.y * a, -n.y);
+
+        Self {
+            tangent: Unit::new_normalize(tangent),
+            bitangent: Unit::new_normalize(bitangent),
+            normal,
+        }
+    }
+
+    pub fn to_local(&self, v: &Vector3<Float>) -> Vector3<Float> {
+        vector!(self.tangent.dot(v), self.bitangent.dot(v), self.normal.dot(v))
+    }
+    pub fn to_world(&self, v: &Vector3<Float>) -> Vector3<Float> {
+        self.tangent.into_inner() * v.x + self.bitangent.into_inner() * v.y + self.normal.into_inner() * v.z
+    }
+}
+
+
+/// The anisotropic GGX (Trowbridge-Reitz) distribution of microfacet normals.
+/// All directions are in the local space of a [ShadingFrame], with the normal along z.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GGX {
+    alpha_x: Float,
+    alpha_y: Float,
+}
+impl GGX {
+    pub fn new(alpha_x: Float, alpha_y: Float) -> Self {
+        Self {
+            alpha_x: alpha_x.max(MIN_ALPHA),
+            alpha_y: alpha_y.max(MIN_ALPHA),
+        }
+    }
+    /// Maps artist-friendly roughness in [0, 1] to alpha the usual way, by squaring it.
+    pub fn from_roughness(roughness_u: Float, roughness_v: Float) -> Self {
+        Self::new(roughness_u * roughness_u, roughness_v * roughness_v)
+    }
+
+    /// The density of microfacet normals `h`.
+    pub fn d(&self, h: &Vector3<Float>) -> Float {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+
+        let x = h.x / self.alpha_x;
+        let y = h.y / self.alpha_y;
+        let e = x * x + y * y + h.z * h.z;
+
+        1.0 / (Float::PI() * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    fn lambda(&self, v: &Vector3<Float>) -> Float {
+        let x = self.alpha_x * v.x;
+        let y = self.alpha_y * v.y;
+        let tan2 = (x * x + y * y) / (v.z * v.z);
+
+        ((1.0 + tan2).sqrt() - 1.0) / 2.0
+    }
+    /// The fraction of microfacets visible from `v`.
+    pub fn g1(&self, v: &Vector3<Float>) -> Float {
+        1.0 / (1.0 + self.lambda(v))
+    }
+    /// The height-correlated fraction of microfacets visible from both `v` and `l`.
+    pub fn g2(&self, v: &Vector3<Float>, l: &Vector3<Float>) -> Float {
+        1.0 / (1.0 + self.lambda(v) + self.lambda(l))
+    }
+
+    /// Samples a microfacet normal visible from `v`, proportional to its projected area.
+    /// Heitz, "Sampling the GGX Distribution of Visible Normals", 2018
+    pub fn sample_visible_normal(&self, v: &Vector3<Float>, u1: Float, u2: Float) -> Vector3<Float> {
+        let vh = vector!(self.alpha_x * v.x, self.alpha_y * v.y, v.z).normalize();
+
+        let len2 = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if len2 > 0.0 {
+            vector!(-vh.y, vh.x, 0.0) / len2.sqrt()
+        }
+        else {
+            Vector3::x()
+        };
+        let t2 = vh.cross(&t1);
+
+        let r = u1.sqrt();
+        let phi = 2.0 * Float::PI() * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let p3 = (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+
+        let nh = t1 * p1 + t2 * p2 + vh * p3;
+        vector!(self.alpha_x * nh.x, self.alpha_y * nh.y, nh.z.max(0.0)).normalize()
+    }
+
+    /// The density of sampling `l` by reflecting `v` on a visible normal.
+    pub fn reflection_pdf(&self, v: &Vector3<Float>, l: &Vector3<Float>) -> Float {
+        if v.z <= 0.0 {
+            return 0.0;
+        }
+
+        let h = v + l;
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let h = h.normalize();
+
+        self.g1(v) * self.d(&h) / (4.0 * v.z)
+    }
+}
+
+
+/// The complex index of refraction of a metal, per RGB channel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ConductorIOR {
+    pub eta: Vector3<Float>,
+    pub k: Vector3<Float>,
+}
+impl ConductorIOR {
+    /// Finds the IOR that gives the `reflectivity` at normal incidence and tends towards `edge_tint` at grazing angles.
+    /// Gulbrandsen, "Artist Friendly Metallic Fresnel", 2014
+    pub fn from_edge_tint(reflectivity: Vector3<Float>, edge_tint: Vector3<Float>) -> Self {
+        let mut eta = Vector3::zeros();
+        let mut k = Vector3::zeros();
+
+        for i in 0..3 {
+            let r = reflectivity[i].clamp(0.0, 0.99);
+            let g = edge_tint[i].clamp(0.0, 1.0);
+
+            let n_min = (1.0 - r) / (1.0 + r);
+            let n_max = (1.0 + r.sqrt()) / (1.0 - r.sqrt());
+            let n = g * n_min + (1.0 - g) * n_max;
+
+            let k2 = (r * (n + 1.0) * (n + 1.0) - (n - 1.0) * (n - 1.0)) / (1.0 - r);
+
+            eta[i] = n;
+            k[i] = k2.max(0.0).sqrt();
+        }
+
+        Self {
+            eta,
+            k,
+        }
+    }
+
+    /// The exact Fresnel reflectance of the metal at an angle with the given cosine.
+    pub fn fresnel(&self, cos_theta: Float) -> Vector3<Float> {
+        let cos2 = cos_theta.clamp(0.0, 1.0).powi(2);
+        let sin2 = 1.0 - cos2;
+
+        Vector3::from_fn(|i, _| {
+            let eta2 = self.eta[i] * self.eta[i];
+            let k2 = self.k[i] * self.k[i];
+
+            let t0 = eta2 - k2 - sin2;
+            let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+            let t1 = a2_plus_b2 + cos2;
+            let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+            let t2 = 2.0 * cos_theta * a;
+            let rs = (t1 - t2) / (t1 + t2);
+
+            let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+            let t4 = t2 * sin2;
+            let rp = rs * (t3 - t4) / (t3 + t4);
+
+            (rs + rp) / 2.0
+        })
+    }
+}
+
+
+/// A GGX reflection lobe as seen from one direction, importance sampled by visible normals.
+pub struct MicrofacetPDF {
+    frame: ShadingFrame,
+    distribution: GGX,
+    /// The direction towards the viewer, in local space.
+    view: Vector3<Float>,
+}
+impl MicrofacetPDF {
+    pub fn new(frame: ShadingFrame, distribution: GGX, view: UnitVector3<Float>) -> Self {
+        Self {
+            frame,
+            distribution,
+            view: frame.to_local(&view),
+        }
+    }
+}
+impl PDF<UnitVector3<Float>> for MicrofacetPDF {
+    fn value(&self, direction: &UnitVector3<Float>, _scene: &Scene) -> Float {
+        let l = self.frame.to_local(direction);
+        self.distribution.reflection_pdf(&self.view, &l)
+    }
+
+    fn generate(&self, rng: &mut dyn Randomness, _scene: &Scene) -> UnitVector3<Float> {
+        let v = self.view;
+        let h = self.distribution.sample_visible_normal(&v, rng.float(), rng.float());
+        let l = h * (2.0 * v.dot(&h)) - v;
+
+        Unit::new_normalize(self.frame.to_world(&l))
+    }
+}
+
+
+/// Evaluates a GGX conductor for light arriving from `l` and leaving towards `v`, including the cosine term.
+pub fn conductor_brdf(distribution: &GGX, ior: &ConductorIOR, frame: &ShadingFrame, l: &Vector3<Float>, v: &Vector3<Float>) -> Vector3<Float> {
+    let l = frame.to_local(l);
+    let v = frame.to_local(v);
+    if l.z <= 0.0 || v.z <= 0.0 {
+        return Vector3::zeros();
+    }
+
+    let h = (l + v).normalize();
+    let f = ior.fresnel(v.dot(&h));
+    let d = distribution.d(&h);
+    let g = distribution.g2(&v, &l);
+
+    // D * G * F / (4 cos_l cos_v), times cos_l
+    f * (d * g / (4.0 * v.z))
+}
+
+
+/// The Fresnel reflectance of a dielectric boundary, where `eta` is the index of refraction on the incident side over the one on the other side.
+/// Returns 1 on total internal reflection.
+pub fn dielectric_fresnel(cos_i: Float, eta: Float) -> Float {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parallel = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let r_perpendicular = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+
+    (r_parallel * r_parallel + r_perpendicular * r_perpendicular) / 2.0
+}
+
+/// Schlick's approximation of the Fresnel reflectance, given the reflectance at normal incidence.
+pub fn schlick_fresnel(f0: Vector3<Float>, cos_theta: Float) -> Vector3<Float> {
+    let weight = (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5);
+    f0 + (Vector3::repeat(1.0) - f0) * weight
+}
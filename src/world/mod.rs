@@ -1,17 +1,25 @@
+use std::path::Path;
 use generational_arena::{Arena, Index};
-use nalgebra::{Isometry3, UnitVector3, Vector3};
+use nalgebra::{Affine3, Isometry3, Point3, UnitVector3, Vector3};
 use crate::{Float, Texture2D};
 use crate::intersection::Intersection;
 use crate::randomness::Randomness;
-use crate::scene::Scene;
+use crate::scene::{DetachedScene, Scene};
+use crate::scene::cache::BVHCacheError;
 use crate::texture::TextureCoord2D;
 use crate::world::albedo::{Albedo, AlbedoRef};
 use crate::world::material::{Material, MaterialRef, ScatteredRay};
-use crate::world::shape::{Shape, ShapeRef};
+use crate::world::microfacet::ConductorIOR;
+use crate::world::principled::Principled;
+use crate::world::sdf::Sdf;
+use crate::world::shape::{CsgOperand, CsgOperation, Shape, ShapeRef};
 
 pub mod shape;
 pub mod albedo;
 pub mod material;
+pub mod microfacet;
+pub mod principled;
+pub mod sdf;
 
 pub struct World {
     pub(crate) shapes: Arena<Shape>,
@@ -33,6 +41,114 @@ impl World {
         let i = self.shapes.insert(Shape::Sphere { radius });
         ShapeRef(i)
     }
+    /// Adds an indexed triangle mesh.
+    /// Panics if an index is out of bounds or the per-vertex attributes don't match the amount of positions.
+    pub fn add_triangle_mesh(
+        &mut self,
+        positions: Vec<Point3<Float>>,
+        indices: Vec<[usize; 3]>,
+        normals: Option<Vec<Vector3<Float>>>,
+        tex_coords: Option<Vec<TextureCoord2D>>,
+    ) -> ShapeRef {
+        assert!(indices.iter().flatten().all(|&i| i < positions.len()), "Triangle index out of bounds");
+        if let Some(normals) = &normals {
+            assert_eq!(normals.len(), positions.len(), "Mesh needs exactly one normal per vertex");
+        }
+        if let Some(tex_coords) = &tex_coords {
+            assert_eq!(tex_coords.len(), positions.len(), "Mesh needs exactly one texture coordinate per vertex");
+        }
+
+        let i = self.shapes.insert(Shape::TriangleMesh { positions, indices, normals, tex_coords });
+        ShapeRef(i)
+    }
+    /// Adds a parallelogram spanned by two edges from a corner, facing along the cross product of the edges.
+    /// Panics if the edges are parallel.
+    pub fn add_quad(&mut self, corner: Point3<Float>, edge_u: Vector3<Float>, edge_v: Vector3<Float>) -> ShapeRef {
+        assert!(edge_u.cross(&edge_v).magnitude_squared() > 0.0, "Quad edges must not be parallel");
+
+        let i = self.shapes.insert(Shape::Quad { corner, edge_u, edge_v });
+        ShapeRef(i)
+    }
+    /// Panics if the normal is zero or the radius isn't positive.
+    pub fn add_disk(&mut self, center: Point3<Float>, normal: Vector3<Float>, radius: Float) -> ShapeRef {
+        let normal = UnitVector3::try_new(normal, 0.0).expect("Disk normal must not be zero");
+        assert!(radius > 0.0, "Disk radius must be positive");
+
+        let i = self.shapes.insert(Shape::Disk { center, normal, radius });
+        ShapeRef(i)
+    }
+    /// Adds a solid axis aligned box between two corners.
+    /// Panics if the box is empty or flat along an axis.
+    pub fn add_box(&mut self, min: Point3<Float>, max: Point3<Float>) -> ShapeRef {
+        assert!((0..3).all(|a| min[a] < max[a]), "Box must have a positive size along every axis");
+
+        let i = self.shapes.insert(Shape::Box { min, max });
+        ShapeRef(i)
+    }
+    /// Adds a cylinder around the y axis, centered at the origin, which is closed off with disks if capped.
+    /// Panics if the radius or height isn't positive.
+    pub fn add_cylinder(&mut self, radius: Float, height: Float, capped: bool) -> ShapeRef {
+        assert!(radius > 0.0 && height > 0.0, "Cylinder radius and height must be positive");
+
+        let i = self.shapes.insert(Shape::Cylinder { radius, height, capped });
+        ShapeRef(i)
+    }
+    /// Adds a cone around the y axis, centered at the origin, which is cut off at the top unless `top_radius` is zero.
+    /// Panics if a radius is negative, both are zero, or the height isn't positive.
+    pub fn add_cone(&mut self, base_radius: Float, top_radius: Float, height: Float, capped: bool) -> ShapeRef {
+        assert!(base_radius >= 0.0 && top_radius >= 0.0 && base_radius + top_radius > 0.0, "Cone radii must not be negative, and not both zero");
+        assert!(height > 0.0, "Cone height must be positive");
+
+        let i = self.shapes.insert(Shape::Cone { base_radius, top_radius, height, capped });
+        ShapeRef(i)
+    }
+    /// Adds a ring in the xz-plane around the origin.
+    /// Panics unless the minor radius is positive and less than the major radius, so that there is a hole in the middle.
+    pub fn add_torus(&mut self, major_radius: Float, minor_radius: Float) -> ShapeRef {
+        assert!(minor_radius > 0.0 && minor_radius < major_radius, "Torus minor radius must be positive and less than the major radius");
+
+        let i = self.shapes.insert(Shape::Torus { major_radius, minor_radius });
+        ShapeRef(i)
+    }
+    /// Adds a capsule around the y axis, centered at the origin, where `height` is the one of the cylinder between the hemispheres.
+    /// Panics if the radius isn't positive or the height is negative.
+    pub fn add_capsule(&mut self, radius: Float, height: Float) -> ShapeRef {
+        assert!(radius > 0.0 && height >= 0.0, "Capsule radius must be positive and height not negative");
+
+        let i = self.shapes.insert(Shape::Capsule { radius, height });
+        ShapeRef(i)
+    }
+    /// Adds a boolean combination of two shapes, each of which can be placed with a transform by passing a [CsgOperand].
+    /// The shapes themselves aren't rendered unless objects use them as well.
+    /// Panics if an operand isn't a shape of this world.
+    pub fn add_csg<A: Into<CsgOperand>, B: Into<CsgOperand>>(&mut self, op: CsgOperation, a: A, b: B) -> ShapeRef {
+        let (a, b) = (a.into(), b.into());
+        assert!(self.shapes.contains(a.shape.0) && self.shapes.contains(b.shape.0), "CSG operands must be shapes of the same world");
+
+        let i = self.shapes.insert(Shape::Csg { op, a, b });
+        ShapeRef(i)
+    }
+    /// Adds the surface where `sdf` is zero, which is only looked for within the box from `min` to `max`.
+    /// Panics if `min` isn't below `max` along every axis, or if [Sdf::validate] fails.
+    pub fn add_sdf(&mut self, sdf: Sdf, min: Point3<Float>, max: Point3<Float>) -> ShapeRef {
+        assert!((0..3).all(|a| min[a] < max[a]), "SDF bounds must have min below max along every axis");
+        if let Err(message) = sdf.validate() {
+            panic!("Invalid SDF: {}", message);
+        }
+
+        let i = self.shapes.insert(Shape::Sdf { sdf, min, max });
+        ShapeRef(i)
+    }
+    /// Adds terrain over the xz-plane, centered at the origin, with the heights of the map scaled by `vertical_scale`
+    /// and its pixels `horizontal_scale` apart. The first row of the map is at the far end towards -z.
+    /// Panics unless the map is at least 2 by 2 pixels and the horizontal scale is positive.
+    pub fn add_heightfield(&mut self, heights: Texture2D<Float>, horizontal_scale: Float, vertical_scale: Float) -> ShapeRef {
+        assert!(heights.width() >= 2 && heights.height() >= 2, "Heightfield maps must be at least 2 by 2 pixels");
+        assert!(horizontal_scale > 0.0, "Heightfield horizontal scale must be positive");
+
+        let i = self.shapes.insert(Shape::Heightfield { heights, horizontal_scale, vertical_scale });
+        ShapeRef(i)
+    }
     pub fn add_solid_albedo(&mut self, albedo: Vector3<Float>) -> AlbedoRef {
         let i = self.albedos.insert(Albedo::SolidColor(albedo));
         AlbedoRef(i)
@@ -49,15 +165,47 @@ impl World {
         let i = self.materials.insert(Material::Mirror);
         MaterialRef(i)
     }
+    pub fn add_dielectric_material(&mut self, ior: Float) -> MaterialRef {
+        let i = self.materials.insert(Material::Dielectric { ior });
+        MaterialRef(i)
+    }
+    /// Adds a rough metal. Roughness is given in [0, 1] along the two tangent directions; use the same value twice for isotropic roughness.
+    pub fn add_conductor_material(&mut self, ior: ConductorIOR, roughness_u: Float, roughness_v: Float) -> MaterialRef {
+        let i = self.materials.insert(Material::Conductor { ior, roughness_u, roughness_v });
+        MaterialRef(i)
+    }
+    pub fn add_principled_material(&mut self, principled: Principled) -> MaterialRef {
+        let i = self.materials.insert(Material::Principled(Box::new(principled)));
+        MaterialRef(i)
+    }
     pub fn add_emitting_material(&mut self, albedo: AlbedoRef, factor: Float) -> MaterialRef {
         let i = self.materials.insert(Material::Emitting(albedo, factor));
         MaterialRef(i)
     }
     pub fn add_object(&mut self, shape: ShapeRef, mat: MaterialRef, transform: Isometry3<Float>) -> ObjectRef {
+        self.add_affine_object(shape, mat, nalgebra::convert(transform))
+    }
+    /// Places a shape in the world with a transform that may also scale, shear or mirror it.
+    /// This applies to the shape as a whole, so scaling a sphere makes an ellipsoid.
+    /// Objects whose transform can't be inverted are flat and aren't rendered.
+    pub fn add_affine_object(&mut self, shape: ShapeRef, mat: MaterialRef, transform: Affine3<Float>) -> ObjectRef {
         let i = self.objects.insert(Object { shape, material: mat, transform });
         ObjectRef(i)
     }
 
+    /// Moves an object. Panics if it was removed.
+    pub fn set_object_transform(&mut self, object: ObjectRef, transform: Affine3<Float>) {
+        self.objects[object.0].transform = transform;
+    }
+    /// Panics if the object was removed.
+    pub fn set_object_material(&mut self, object: ObjectRef, material: MaterialRef) {
+        self.objects[object.0].material = material;
+    }
+    /// Returns whether the object was still there.
+    pub fn remove_object(&mut self, object: ObjectRef) -> bool {
+        self.objects.remove(object.0).is_some()
+    }
+
 
     pub fn sample_albedo(&self, albedo: AlbedoRef, coord: &TextureCoord2D) -> Vector3<Float> {
         let a = &self.albedos[albedo.0];
@@ -67,9 +215,9 @@ impl World {
         let m = &self.materials[mat.0];
         m.scatter(ray_in, int, scene)
     }
-    pub fn brdf(&self, mat: MaterialRef, ray_in: UnitVector3<Float>, int: &Intersection, ray_out: UnitVector3<Float>) -> Float {
+    pub fn brdf(&self, mat: MaterialRef, ray_in: UnitVector3<Float>, int: &Intersection, ray_out: UnitVector3<Float>) -> Vector3<Float> {
         let m = &self.materials[mat.0];
-        m.brdf(ray_in, int, ray_out)
+        m.brdf(ray_in, int, ray_out, self)
     }
     pub fn emit(&self, mat: MaterialRef, ray_out: UnitVector3<Float>, int: &Intersection) -> Vector3<Float> {
         let m = &self.materials[mat.0];
@@ -79,9 +227,25 @@ impl World {
         self.materials[mat.0].emits()
     }
 
-    pub fn build_scene<R: Randomness>(&self, rng: &mut R) -> Scene {
+    pub fn build_scene<R: Randomness>(&self, rng: &mut R) -> Scene<'_> {
         Scene::new(self, rng)
     }
+    /// Builds the scene with the BVHs cached in a file, see [Scene::new_cached].
+    pub fn build_scene_cached<R: Randomness, P: AsRef<Path>>(&self, path: P, rng: &mut R) -> Result<Scene<'_>, BVHCacheError> {
+        Scene::new_cached(self, path, rng)
+    }
+    /// Applies the changes made to the objects since `scene` was built, see [Scene::update].
+    pub fn update_scene<R: Randomness>(&self, scene: DetachedScene, rng: &mut R) -> Scene<'_> {
+        Scene::update(scene, self, rng)
+    }
+}
+
+
+
+impl Default for World {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 
@@ -89,7 +253,7 @@ impl World {
 pub struct Object {
     pub(crate) shape: ShapeRef,
     pub(crate) material: MaterialRef,
-    pub(crate) transform: Isometry3<Float>,
+    pub(crate) transform: Affine3<Float>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
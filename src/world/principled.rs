@@ -0,0 +1,365 @@
+use nalgebra::{Unit, UnitVector3, vector, Vector3};
+use num_traits::FloatConst;
+use crate::{Float, Randomness, Scene};
+use crate::intersection::Intersection;
+use crate::pdf::PDF;
+use crate::texture::TextureCoord2D;
+use crate::world::albedo::AlbedoRef;
+use crate::world::microfacet::{dielectric_fresnel, GGX, schlick_fresnel, ShadingFrame};
+use crate::world::World;
+
+
+/// A color that is either the same everywhere or looked up from an albedo.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorParameter {
+    Constant(Vector3<Float>),
+    Texture(AlbedoRef),
+}
+impl ColorParameter {
+    pub fn sample(&self, world: &World, coord: &TextureCoord2D) -> Vector3<Float> {
+        match self {
+            Self::Constant(c) => *c,
+            Self::Texture(a) => world.sample_albedo(*a, coord),
+        }
+    }
+}
+
+/// A number that is either the same everywhere or looked up from one channel of an albedo.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScalarParameter {
+    Constant(Float),
+    Texture(AlbedoRef, usize),
+}
+impl ScalarParameter {
+    pub fn sample(&self, world: &World, coord: &TextureCoord2D) -> Float {
+        match self {
+            Self::Constant(c) => *c,
+            Self::Texture(a, channel) => world.sample_albedo(*a, coord)[*channel],
+        }
+    }
+}
+
+
+/// The parameters of a physically based material in the style of the Disney principled BRDF.
+/// Everything except `ior` and `emission_strength` is in [0, 1].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principled {
+    pub base_color: ColorParameter,
+    pub metallic: ScalarParameter,
+    pub roughness: ScalarParameter,
+    /// Scales the reflectance of dielectrics at normal incidence; 0.5 is 4%.
+    pub specular: ScalarParameter,
+    /// Tints the specular reflection of dielectrics towards the base color.
+    pub specular_tint: ScalarParameter,
+    /// A soft, grazing reflection for cloth.
+    pub sheen: ScalarParameter,
+    pub sheen_tint: ScalarParameter,
+    /// A second, colorless specular layer on top.
+    pub clearcoat: ScalarParameter,
+    pub clearcoat_roughness: ScalarParameter,
+    /// How much of the non-metallic part refracts into the surface instead of being diffuse.
+    pub transmission: ScalarParameter,
+    /// The index of refraction used for transmission.
+    pub ior: Float,
+    pub emission: ColorParameter,
+    pub emission_strength: Float,
+}
+impl Principled {
+    pub fn emit(&self, int: &Intersection, world: &World) -> Vector3<Float> {
+        self.emission.sample(world, &int.tex_coord) * self.emission_strength
+    }
+    pub fn emits(&self) -> bool {
+        self.emission_strength != 0.0 && self.emission != ColorParameter::Constant(Vector3::zeros())
+    }
+}
+impl Default for Principled {
+    fn default() -> Self {
+        Self {
+            base_color: ColorParameter::Constant(vector!(0.8, 0.8, 0.8)),
+            metallic: ScalarParameter::Constant(0.0),
+            roughness: ScalarParameter::Constant(0.5),
+            specular: ScalarParameter::Constant(0.5),
+            specular_tint: ScalarParameter::Constant(0.0),
+            sheen: ScalarParameter::Constant(0.0),
+            sheen_tint: ScalarParameter::Constant(0.5),
+            clearcoat: ScalarParameter::Constant(0.0),
+            clearcoat_roughness: ScalarParameter::Constant(0.03),
+            transmission: ScalarParameter::Constant(0.0),
+            ior: 1.45,
+            emission: ColorParameter::Constant(Vector3::zeros()),
+            emission_strength: 1.0,
+        }
+    }
+}
+
+
+/// A [Principled] material evaluated at one point of a surface.
+///
+/// It consists of four lobes: a diffuse one with sheen, a specular reflection,
+/// a rough dielectric that reflects or refracts and a clearcoat.
+pub struct PrincipledBSDF {
+    frame: ShadingFrame,
+    base_color: Vector3<Float>,
+    roughness: Float,
+    sheen_color: Vector3<Float>,
+    /// The reflectance at normal incidence of the specular lobe.
+    specular_f0: Vector3<Float>,
+    /// The part of `specular_f0` that comes from the dielectric, which the diffuse lobe lies beneath.
+    dielectric_f0: Vector3<Float>,
+    specular: GGX,
+    clearcoat: Float,
+    clearcoat_distribution: GGX,
+    /// The index of refraction behind the surface over the one in front of it.
+    eta: Float,
+    diffuse_weight: Float,
+    glass_weight: Float,
+}
+impl PrincipledBSDF {
+    pub fn new(p: &Principled, int: &Intersection, world: &World) -> Self {
+        let coord = &int.tex_coord;
+        let base_color = p.base_color.sample(world, coord);
+        let metallic = p.metallic.sample(world, coord).clamp(0.0, 1.0);
+        let roughness = p.roughness.sample(world, coord).clamp(0.0, 1.0);
+        let transmission = p.transmission.sample(world, coord).clamp(0.0, 1.0);
+
+        let tint = tint_color(base_color);
+        let specular_tint = Vector3::repeat(1.0).lerp(&tint, p.specular_tint.sample(world, coord));
+        let dielectric_f0 = specular_tint * (0.08 * p.specular.sample(world, coord));
+        let sheen_tint = Vector3::repeat(1.0).lerp(&tint, p.sheen_tint.sample(world, coord));
+
+        let clearcoat_roughness = p.clearcoat_roughness.sample(world, coord);
+
+        Self {
+            frame: ShadingFrame::new(int.normal),
+            base_color,
+            roughness,
+            sheen_color: sheen_tint * p.sheen.sample(world, coord),
+            specular_f0: dielectric_f0.lerp(&base_color, metallic),
+            dielectric_f0,
+            specular: GGX::from_roughness(roughness, roughness),
+            clearcoat: p.clearcoat.sample(world, coord),
+            clearcoat_distribution: GGX::from_roughness(clearcoat_roughness, clearcoat_roughness),
+            eta: if int.outside { p.ior } else { 1.0 / p.ior },
+            diffuse_weight: (1.0 - metallic) * (1.0 - transmission),
+            glass_weight: (1.0 - metallic) * transmission,
+        }
+    }
+
+    /// Evaluates the BSDF for light arriving from `ray_in` and leaving towards `ray_out`, including the cosine term.
+    pub fn eval(&self, ray_in: &Vector3<Float>, ray_out: &Vector3<Float>) -> Vector3<Float> {
+        let l = self.frame.to_local(ray_in);
+        let v = self.frame.to_local(ray_out);
+        if v.z <= 0.0 {
+            return Vector3::zeros();
+        }
+
+        if l.z > 0.0 {
+            self.eval_reflection(&l, &v)
+        }
+        else {
+            self.eval_transmission(&l, &v)
+        }
+    }
+
+    fn eval_reflection(&self, l: &Vector3<Float>, v: &Vector3<Float>) -> Vector3<Float> {
+        let h = (l + v).normalize();
+        let cos_d = l.dot(&h);
+
+        // Burley's diffuse with retro-reflection at grazing angles, renormalized so that it doesn't reflect more than it receives.
+        // Lagarde and de Rousiers, "Moving Frostbite to Physically Based Rendering", 2014
+        let energy_bias = 0.5 * self.roughness;
+        let energy_factor = 1.0 - self.roughness * (1.0 - 1.0 / 1.51);
+        let fd90 = energy_bias + 2.0 * self.roughness * cos_d * cos_d;
+        let fl = schlick_weight(l.z);
+        let fv = schlick_weight(v.z);
+        let diffuse = self.base_color * ((1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv) * energy_factor / Float::PI());
+        let sheen = self.sheen_color * schlick_weight(cos_d);
+
+        // What the specular layer reflects on the way in or out never reaches the diffuse base.
+        let transmitted = (Vector3::repeat(1.0) - schlick_fresnel(self.dielectric_f0, l.z))
+            .component_mul(&(Vector3::repeat(1.0) - schlick_fresnel(self.dielectric_f0, v.z)));
+        let diffuse = (diffuse + sheen).component_mul(&transmitted) * (self.diffuse_weight * l.z);
+
+        let d = self.specular.d(&h);
+        let g = self.specular.g2(v, l);
+        let fresnel = schlick_fresnel(self.specular_f0, v.dot(&h)) * (1.0 - self.glass_weight)
+            + Vector3::repeat(dielectric_fresnel(v.dot(&h), 1.0 / self.eta) * self.glass_weight);
+        let specular = fresnel * (d * g / (4.0 * v.z));
+
+        let cc_d = self.clearcoat_distribution.d(&h);
+        let cc_g = self.clearcoat_distribution.g2(v, l);
+        let cc_f = schlick_fresnel(Vector3::repeat(0.04), v.dot(&h));
+        let clearcoat = cc_f * (0.25 * self.clearcoat * cc_d * cc_g / (4.0 * v.z));
+
+        diffuse + specular + clearcoat
+    }
+
+    fn eval_transmission(&self, l: &Vector3<Float>, v: &Vector3<Float>) -> Vector3<Float> {
+        if self.glass_weight == 0.0 {
+            return Vector3::zeros();
+        }
+        let h = match self.refraction_half_vector(l, v) {
+            Some(h) => h,
+            None => return Vector3::zeros(),
+        };
+
+        let fresnel = dielectric_fresnel(v.dot(&h), 1.0 / self.eta);
+        let denominator = v.dot(&h) + self.eta * l.dot(&h);
+
+        let d = self.specular.d(&h);
+        let g = self.specular.g2(v, l);
+
+        // Walter et al., "Microfacet Models for Refraction through Rough Surfaces", 2007, times the cosine term.
+        // The eta² of the radiance being compressed cancels with the one of the change of measure.
+        let value = (1.0 - fresnel) * d * g * l.dot(&h).abs() * v.dot(&h) / (v.z * denominator * denominator);
+        self.base_color * (self.glass_weight * value)
+    }
+
+    /// The microfacet normal that refracts `v` into `l`, if they are on different sides of it.
+    fn refraction_half_vector(&self, l: &Vector3<Float>, v: &Vector3<Float>) -> Option<Vector3<Float>> {
+        let h = v + l * self.eta;
+        if h.norm_squared() == 0.0 {
+            return None;
+        }
+
+        let h = if h.z < 0.0 { -h.normalize() } else { h.normalize() };
+        if v.dot(&h) <= 0.0 || l.dot(&h) >= 0.0 {
+            return None;
+        }
+
+        Some(h)
+    }
+
+    /// The probabilities of sampling the diffuse, specular, glass and clearcoat lobe.
+    fn lobe_probabilities(&self) -> [Float; 4] {
+        let weights = [
+            self.diffuse_weight,
+            1.0 - self.glass_weight,
+            self.glass_weight,
+            0.25 * self.clearcoat,
+        ];
+        let total: Float = weights.iter().sum();
+
+        weights.map(|w| w / total)
+    }
+
+    /// The density of sampling `l`. Microfacet lobes can scatter to either side of the surface,
+    /// so every lobe contributes regardless of which side `l` is on.
+    fn pdf(&self, l: &Vector3<Float>, v: &Vector3<Float>) -> Float {
+        if v.z <= 0.0 {
+            return 0.0;
+        }
+        let [diffuse, specular, glass, clearcoat] = self.lobe_probabilities();
+
+        let diffuse = diffuse * l.z.max(0.0) / Float::PI();
+
+        let reflection = {
+            let h = l + v;
+            let reflectance = if h.z > 0.0 { dielectric_fresnel(v.dot(&h.normalize()), 1.0 / self.eta) } else { 0.0 };
+
+            (specular + glass * reflectance) * self.specular.reflection_pdf(v, l)
+                + clearcoat * self.clearcoat_distribution.reflection_pdf(v, l)
+        };
+
+        let refraction = match self.refraction_half_vector(l, v) {
+            Some(h) if glass > 0.0 => {
+                let fresnel = dielectric_fresnel(v.dot(&h), 1.0 / self.eta);
+                let visible = self.specular.g1(v) * v.dot(&h) * self.specular.d(&h) / v.z;
+
+                let denominator = v.dot(&h) + self.eta * l.dot(&h);
+                let jacobian = self.eta * self.eta * l.dot(&h).abs() / (denominator * denominator);
+
+                glass * (1.0 - fresnel) * visible * jacobian
+            }
+            _ => 0.0,
+        };
+
+        diffuse + reflection + refraction
+    }
+
+    fn sample(&self, v: &Vector3<Float>, rng: &mut dyn Randomness) -> Vector3<Float> {
+        let [diffuse, specular, glass, _] = self.lobe_probabilities();
+        let choice = rng.float();
+
+        if choice < diffuse {
+            let u1 = rng.float();
+            let u2 = rng.float();
+            let r = u1.sqrt();
+            let phi = 2.0 * Float::PI() * u2;
+
+            vector!(r * phi.cos(), r * phi.sin(), (1.0 - u1).max(0.0).sqrt())
+        }
+        else if choice < diffuse + specular {
+            let h = self.specular.sample_visible_normal(v, rng.float(), rng.float());
+            reflect(v, &h)
+        }
+        else if choice < diffuse + specular + glass {
+            let h = self.specular.sample_visible_normal(v, rng.float(), rng.float());
+            let cos_i = v.dot(&h);
+            let fresnel = dielectric_fresnel(cos_i, 1.0 / self.eta);
+
+            if rng.float() < fresnel {
+                return reflect(v, &h);
+            }
+
+            // Fresnel is 1 on total internal reflection, so we can always refract here.
+            let ratio = 1.0 / self.eta;
+            let cos_t = (1.0 - ratio * ratio * (1.0 - cos_i * cos_i)).max(0.0).sqrt();
+            -v * ratio + h * (ratio * cos_i - cos_t)
+        }
+        else {
+            let h = self.clearcoat_distribution.sample_visible_normal(v, rng.float(), rng.float());
+            reflect(v, &h)
+        }
+    }
+}
+
+
+/// Importance samples a [PrincipledBSDF] as seen from one direction.
+pub struct PrincipledPDF {
+    bsdf: PrincipledBSDF,
+    /// The direction towards the viewer, in local space.
+    view: Vector3<Float>,
+}
+impl PrincipledPDF {
+    pub fn new(bsdf: PrincipledBSDF, view: UnitVector3<Float>) -> Self {
+        let view = bsdf.frame.to_local(&view);
+
+        Self {
+            bsdf,
+            view,
+        }
+    }
+}
+impl PDF<UnitVector3<Float>> for PrincipledPDF {
+    fn value(&self, direction: &UnitVector3<Float>, _scene: &Scene) -> Float {
+        let l = self.bsdf.frame.to_local(direction);
+        self.bsdf.pdf(&l, &self.view)
+    }
+
+    fn generate(&self, rng: &mut dyn Randomness, _scene: &Scene) -> UnitVector3<Float> {
+        let l = self.bsdf.sample(&self.view, rng);
+        Unit::new_normalize(self.bsdf.frame.to_world(&l))
+    }
+}
+
+
+fn reflect(v: &Vector3<Float>, h: &Vector3<Float>) -> Vector3<Float> {
+    h * (2.0 * v.dot(h)) - v
+}
+
+fn schlick_weight(cos_theta: Float) -> Float {
+    (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5)
+}
+
+/// The hue and saturation of a color, at a luminance of 1.
+fn tint_color(color: Vector3<Float>) -> Vector3<Float> {
+    let luminance = color.dot(&vector!(0.2126, 0.7152, 0.0722));
+
+    if luminance > 0.0 {
+        color / luminance
+    }
+    else {
+        Vector3::repeat(1.0)
+    }
+}
@@ -0,0 +1,277 @@
+use nalgebra::{Point3, Unit, UnitQuaternion, vector, Vector2, Vector3};
+use serde::{Deserialize, Serialize};
+use crate::Float;
+
+
+/// An expression for the signed distance to a surface, which is negative inside of it.
+///
+/// Primitives are centered at the origin, and axis symmetric ones have their axis along y like the other shapes.
+/// Operations that bend space, like twisting, don't keep distances exact, which sphere tracing makes up for with smaller steps.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Sdf {
+    Sphere {
+        radius: Float,
+    },
+    /// A box with its edges rounded off by `rounding`, which doesn't make it larger.
+    Box {
+        half_size: [Float; 3],
+        #[serde(default)]
+        rounding: Float,
+    },
+    /// A ring in the xz-plane.
+    Torus {
+        major_radius: Float,
+        minor_radius: Float,
+    },
+    /// A capped cylinder.
+    Cylinder {
+        radius: Float,
+        height: Float,
+    },
+    /// The height is the one of the cylinder between the hemispheres.
+    Capsule {
+        radius: Float,
+        height: Float,
+    },
+    Union {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+    },
+    Intersection {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+    },
+    /// Everything inside of `a`, but not inside of `b`.
+    Subtraction {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+    },
+    /// A union that blends the surfaces together where they are closer than `radius`.
+    SmoothUnion {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        radius: Float,
+    },
+    /// A subtraction that blends the surfaces together where they are closer than `radius`.
+    SmoothSubtraction {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        radius: Float,
+    },
+    /// An intersection that blends the surfaces together where they are closer than `radius`.
+    SmoothIntersection {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        radius: Float,
+    },
+    Translate {
+        offset: [Float; 3],
+        sdf: Box<Sdf>,
+    },
+    Rotate {
+        axis: [Float; 3],
+        degrees: Float,
+        sdf: Box<Sdf>,
+    },
+    Scale {
+        factor: Float,
+        sdf: Box<Sdf>,
+    },
+    /// Turns the xz-plane around the y axis by `rate` radians per unit along it.
+    Twist {
+        rate: Float,
+        sdf: Box<Sdf>,
+    },
+    /// Repeats the cell around the origin endlessly along every axis with a spacing above zero.
+    /// What is inside of the cell should fit into it.
+    Repeat {
+        spacing: [Float; 3],
+        sdf: Box<Sdf>,
+    },
+    /// Grows the surface outwards by `radius`, which rounds off its edges.
+    Round {
+        radius: Float,
+        sdf: Box<Sdf>,
+    },
+    /// A shell of the given thickness on either side of the surface.
+    Onion {
+        thickness: Float,
+        sdf: Box<Sdf>,
+    },
+}
+impl Sdf {
+    pub fn sphere(radius: Float) -> Self {
+        Self::Sphere { radius }
+    }
+    pub fn cuboid(half_size: Vector3<Float>) -> Self {
+        Self::Box { half_size: half_size.into(), rounding: 0.0 }
+    }
+    pub fn rounded_cuboid(half_size: Vector3<Float>, rounding: Float) -> Self {
+        Self::Box { half_size: half_size.into(), rounding }
+    }
+    pub fn torus(major_radius: Float, minor_radius: Float) -> Self {
+        Self::Torus { major_radius, minor_radius }
+    }
+    pub fn cylinder(radius: Float, height: Float) -> Self {
+        Self::Cylinder { radius, height }
+    }
+    pub fn capsule(radius: Float, height: Float) -> Self {
+        Self::Capsule { radius, height }
+    }
+
+    pub fn union(self, other: Sdf) -> Self {
+        Self::Union { a: Box::new(self), b: Box::new(other) }
+    }
+    pub fn intersection(self, other: Sdf) -> Self {
+        Self::Intersection { a: Box::new(self), b: Box::new(other) }
+    }
+    pub fn subtraction(self, other: Sdf) -> Self {
+        Self::Subtraction { a: Box::new(self), b: Box::new(other) }
+    }
+    pub fn smooth_union(self, other: Sdf, radius: Float) -> Self {
+        Self::SmoothUnion { a: Box::new(self), b: Box::new(other), radius }
+    }
+    pub fn smooth_subtraction(self, other: Sdf, radius: Float) -> Self {
+        Self::SmoothSubtraction { a: Box::new(self), b: Box::new(other), radius }
+    }
+    pub fn smooth_intersection(self, other: Sdf, radius: Float) -> Self {
+        Self::SmoothIntersection { a: Box::new(self), b: Box::new(other), radius }
+    }
+    pub fn translated(self, offset: Vector3<Float>) -> Self {
+        Self::Translate { offset: offset.into(), sdf: Box::new(self) }
+    }
+    pub fn rotated(self, axis: Vector3<Float>, degrees: Float) -> Self {
+        Self::Rotate { axis: axis.into(), degrees, sdf: Box::new(self) }
+    }
+    pub fn scaled(self, factor: Float) -> Self {
+        Self::Scale { factor, sdf: Box::new(self) }
+    }
+    pub fn twisted(self, rate: Float) -> Self {
+        Self::Twist { rate, sdf: Box::new(self) }
+    }
+    pub fn repeated(self, spacing: Vector3<Float>) -> Self {
+        Self::Repeat { spacing: spacing.into(), sdf: Box::new(self) }
+    }
+    pub fn rounded(self, radius: Float) -> Self {
+        Self::Round { radius, sdf: Box::new(self) }
+    }
+    pub fn onion(self, thickness: Float) -> Self {
+        Self::Onion { thickness, sdf: Box::new(self) }
+    }
+
+    /// Checks that the expression describes a surface, which it doesn't if a blend radius is negative
+    /// or a scale factor isn't positive, and tells what is wrong with the first part that doesn't.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match self {
+            Self::Sphere { .. } | Self::Box { .. } | Self::Torus { .. } | Self::Cylinder { .. } | Self::Capsule { .. } => Ok(()),
+            Self::SmoothUnion { radius, .. } | Self::SmoothSubtraction { radius, .. } | Self::SmoothIntersection { radius, .. }
+                if !(radius.is_finite() && *radius >= 0.0) => Err("Blend radii must not be negative"),
+            Self::Scale { factor, .. } if !(factor.is_finite() && *factor > 0.0) => Err("Scale factors must be positive"),
+            Self::Union { a, b }
+            | Self::Intersection { a, b }
+            | Self::Subtraction { a, b }
+            | Self::SmoothUnion { a, b, .. }
+            | Self::SmoothSubtraction { a, b, .. }
+            | Self::SmoothIntersection { a, b, .. } => a.validate().and_then(|_| b.validate()),
+            Self::Translate { sdf, .. }
+            | Self::Rotate { sdf, .. }
+            | Self::Scale { sdf, .. }
+            | Self::Twist { sdf, .. }
+            | Self::Repeat { sdf, .. }
+            | Self::Round { sdf, .. }
+            | Self::Onion { sdf, .. } => sdf.validate(),
+        }
+    }
+
+    /// The signed distance from `p` to the surface.
+    /// Where space is bent, this can be up to [Self::lipschitz] times as large as the actual distance.
+    pub fn distance(&self, p: &Point3<Float>) -> Float {
+        match self {
+            Self::Sphere { radius } => p.coords.magnitude() - radius,
+            Self::Box { half_size, rounding } => {
+                let q = p.coords.abs() - (Vector3::from(*half_size) - Vector3::repeat(*rounding));
+                q.sup(&Vector3::zeros()).magnitude() + q.max().min(0.0) - rounding
+            }
+            Self::Torus { major_radius, minor_radius } => {
+                let q = vector!(p.xz().coords.magnitude() - major_radius, p.y);
+                q.magnitude() - minor_radius
+            }
+            Self::Cylinder { radius, height } => {
+                let d = vector!(p.xz().coords.magnitude() - radius, p.y.abs() - height * 0.5);
+                d.max().min(0.0) + d.sup(&Vector2::zeros()).magnitude()
+            }
+            Self::Capsule { radius, height } => {
+                let half_height = height * 0.5;
+                (p.coords - Vector3::y() * p.y.clamp(-half_height, half_height)).magnitude() - radius
+            }
+            Self::Union { a, b } => a.distance(p).min(b.distance(p)),
+            Self::Intersection { a, b } => a.distance(p).max(b.distance(p)),
+            Self::Subtraction { a, b } => a.distance(p).max(-b.distance(p)),
+            // Polynomial blends after Quilez. Without a radius there is nothing to blend,
+            // and where the surfaces meet the blend would divide zero by zero.
+            Self::SmoothUnion { a, b, radius } => {
+                let (a, b) = (a.distance(p), b.distance(p));
+                if *radius == 0.0 {
+                    return a.min(b);
+                }
+                let h = (0.5 + 0.5 * (b - a) / radius).clamp(0.0, 1.0);
+                b + (a - b) * h - radius * h * (1.0 - h)
+            }
+            Self::SmoothSubtraction { a, b, radius } => {
+                let (a, b) = (a.distance(p), b.distance(p));
+                if *radius == 0.0 {
+                    return a.max(-b);
+                }
+                let h = (0.5 - 0.5 * (a + b) / radius).clamp(0.0, 1.0);
+                a + (-b - a) * h + radius * h * (1.0 - h)
+            }
+            Self::SmoothIntersection { a, b, radius } => {
+                let (a, b) = (a.distance(p), b.distance(p));
+                if *radius == 0.0 {
+                    return a.max(b);
+                }
+                let h = (0.5 - 0.5 * (b - a) / radius).clamp(0.0, 1.0);
+                b + (a - b) * h + radius * h * (1.0 - h)
+            }
+            Self::Translate { offset, sdf } => sdf.distance(&(p - Vector3::from(*offset))),
+            Self::Rotate { axis, degrees, sdf } => sdf.distance(&rotation(axis, *degrees).inverse_transform_point(p)),
+            Self::Scale { factor, sdf } => sdf.distance(&(p / *factor)) * factor,
+            Self::Twist { rate, sdf } => {
+                let (sin, cos) = (-rate * p.y).sin_cos();
+                sdf.distance(&Point3::new(cos * p.x + sin * p.z, p.y, cos * p.z - sin * p.x))
+            }
+            Self::Repeat { spacing, sdf } => {
+                let folded = p.coords.zip_map(&Vector3::from(*spacing), |x, s| if s > 0.0 { x - s * (x / s).round() } else { x });
+                sdf.distance(&Point3::from(folded))
+            }
+            Self::Round { radius, sdf } => sdf.distance(p) - radius,
+            Self::Onion { thickness, sdf } => sdf.distance(p).abs() - thickness,
+        }
+    }
+
+    /// How much faster than the distance to the surface [Self::distance] can change at points at most `extent`
+    /// away from the origin, which is 1 unless space is bent.
+    pub fn lipschitz(&self, extent: Float) -> Float {
+        match self {
+            Self::Sphere { .. } | Self::Box { .. } | Self::Torus { .. } | Self::Cylinder { .. } | Self::Capsule { .. } => 1.0,
+            Self::Union { a, b }
+            | Self::Intersection { a, b }
+            | Self::Subtraction { a, b }
+            | Self::SmoothUnion { a, b, .. }
+            | Self::SmoothSubtraction { a, b, .. }
+            | Self::SmoothIntersection { a, b, .. } => a.lipschitz(extent).max(b.lipschitz(extent)),
+            Self::Translate { offset, sdf } => sdf.lipschitz(extent + Vector3::from(*offset).magnitude()),
+            Self::Scale { factor, sdf } => sdf.lipschitz(extent / factor),
+            // Points are moved sideways by up to their distance from the axis times the rate, for every unit along it.
+            Self::Twist { rate, sdf } => sdf.lipschitz(extent) * (1.0 + (rate * extent).powi(2)).sqrt(),
+            // None of these move points farther from the origin.
+            Self::Rotate { sdf, .. } | Self::Repeat { sdf, .. } | Self::Round { sdf, .. } | Self::Onion { sdf, .. } => sdf.lipschitz(extent),
+        }
+    }
+}
+
+/// Rotating around no axis at all leaves everything in place.
+fn rotation(axis: &[Float; 3], degrees: Float) -> UnitQuaternion<Float> {
+    Unit::try_new(Vector3::from(*axis), 0.0)
+        .map_or(UnitQuaternion::identity(), |axis| UnitQuaternion::from_axis_angle(&axis, degrees.to_radians()))
+}
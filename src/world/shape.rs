@@ -1,16 +1,96 @@
 use generational_arena::Index;
-use nalgebra::{Isometry3, Point3};
+use nalgebra::{Isometry3, Point3, Unit, UnitVector3, Vector3};
+use serde::{Deserialize, Serialize};
 use crate::Float;
+use crate::randomness::Randomness;
+use crate::aabb::AABB;
+use crate::scene::csg::Csg;
+use crate::scene::heightfield::Heightfield;
+use crate::scene::instance::ShapeData;
 use crate::scene::primitive::Primitive;
+use crate::scene::sdf::TracedSdf;
+use crate::texture::{Texture2D, TextureCoord2D};
+use crate::world::sdf::Sdf;
+use crate::world::World;
 
 
 pub enum Shape {
     Sphere {
         radius: Float,
     },
+    /// An indexed triangle mesh.
+    /// Normals and texture coordinates, if present, are given per vertex and share the position indices.
+    TriangleMesh {
+        positions: Vec<Point3<Float>>,
+        indices: Vec<[usize; 3]>,
+        normals: Option<Vec<Vector3<Float>>>,
+        tex_coords: Option<Vec<TextureCoord2D>>,
+    },
+    /// A parallelogram spanned by two edges from a corner, facing along the cross product of the edges.
+    Quad {
+        corner: Point3<Float>,
+        edge_u: Vector3<Float>,
+        edge_v: Vector3<Float>,
+    },
+    Disk {
+        center: Point3<Float>,
+        normal: UnitVector3<Float>,
+        radius: Float,
+    },
+    /// A solid axis aligned box.
+    Box {
+        min: Point3<Float>,
+        max: Point3<Float>,
+    },
+    /// A cylinder around the y axis, centered at the origin, which is open at both ends unless capped.
+    Cylinder {
+        radius: Float,
+        height: Float,
+        capped: bool,
+    },
+    /// A cone around the y axis, centered at the origin, with `base_radius` at the bottom and `top_radius` at the top.
+    /// It comes to a point where the radius is zero.
+    Cone {
+        base_radius: Float,
+        top_radius: Float,
+        height: Float,
+        capped: bool,
+    },
+    /// A ring in the xz-plane around the origin, with a tube of `minor_radius` around a circle of `major_radius`.
+    Torus {
+        major_radius: Float,
+        minor_radius: Float,
+    },
+    /// A cylinder around the y axis, centered at the origin, with a hemisphere on either end.
+    /// The height is the one of the cylinder, without the hemispheres.
+    Capsule {
+        radius: Float,
+        height: Float,
+    },
+    /// A boolean combination of two other shapes, which should be closed so that they have an inside.
+    Csg {
+        op: CsgOperation,
+        a: CsgOperand,
+        b: CsgOperand,
+    },
+    /// A signed distance field, which is only traced within the box from `min` to `max`.
+    Sdf {
+        sdf: Sdf,
+        min: Point3<Float>,
+        max: Point3<Float>,
+    },
+    /// Terrain over the xz-plane, centered at the origin, with one height per pixel of the map.
+    /// Pixels are `horizontal_scale` apart, with the rows of the map running along z, and heights are scaled by `vertical_scale`.
+    Heightfield {
+        heights: Texture2D<Float>,
+        horizontal_scale: Float,
+        vertical_scale: Float,
+    },
 }
 impl Shape {
-    pub fn as_transformed_primitives(&self, t: &Isometry3<Float>) -> Vec<Primitive> {
+    /// The primitives of the shape, placed by `t`.
+    /// The operands of CSG shapes are looked up in `world`, and get BVHs of their own.
+    pub fn as_transformed_primitives<R: Randomness>(&self, t: &Isometry3<Float>, world: &World, rng: &mut R) -> Vec<Primitive> {
         match self {
             Self::Sphere { radius } => {
                 let origin: Point3<Float> = t.translation.vector.into();
@@ -21,9 +101,125 @@ impl Shape {
                     radius: *radius,
                 })
             }
+            Self::TriangleMesh { positions, indices, normals, tex_coords } => {
+                indices.iter()
+                    .map(|&[a, b, c]| {
+                        let vertices = [a, b, c].map(|i| t.transform_point(&positions[i]));
+                        // Zero normals, as written for degenerate faces by some exporters, fall back to the triangle's own.
+                        let normals = normals.as_ref().map(|n| {
+                            let geometric = Unit::new_normalize((vertices[1] - vertices[0]).cross(&(vertices[2] - vertices[0])));
+                            [a, b, c].map(|i| Unit::try_new(t.transform_vector(&n[i]), Float::EPSILON).unwrap_or(geometric))
+                        });
+                        let tex_coords = tex_coords.as_ref()
+                            .map(|uv| [a, b, c].map(|i| uv[i]));
+
+                        Primitive::Triangle {
+                            vertices,
+                            normals,
+                            tex_coords,
+                        }
+                    })
+                    .collect()
+            }
+            Self::Quad { corner, edge_u, edge_v } => vec![Primitive::Quad {
+                corner: t.transform_point(corner),
+                edge_u: t.transform_vector(edge_u),
+                edge_v: t.transform_vector(edge_v),
+            }],
+            Self::Disk { center, normal, radius } => vec![Primitive::Disk {
+                center: t.transform_point(center),
+                normal: t.rotation * *normal,
+                radius: *radius,
+            }],
+            Self::Box { min, max } => vec![Primitive::Box {
+                center: t.transform_point(&nalgebra::center(min, max)),
+                half_size: (max - min) * 0.5,
+                rotation: t.rotation,
+            }],
+            Self::Cylinder { radius, height, capped } => vec![Primitive::Cylinder {
+                center: t.translation.vector.into(),
+                rotation: t.rotation,
+                radius: *radius,
+                half_height: height * 0.5,
+                capped: *capped,
+            }],
+            Self::Cone { base_radius, top_radius, height, capped } => vec![Primitive::Cone {
+                center: t.translation.vector.into(),
+                rotation: t.rotation,
+                base_radius: *base_radius,
+                top_radius: *top_radius,
+                half_height: height * 0.5,
+                capped: *capped,
+            }],
+            Self::Torus { major_radius, minor_radius } => vec![Primitive::Torus {
+                center: t.translation.vector.into(),
+                rotation: t.rotation,
+                major_radius: *major_radius,
+                minor_radius: *minor_radius,
+            }],
+            Self::Capsule { radius, height } => vec![Primitive::Capsule {
+                center: t.translation.vector.into(),
+                rotation: t.rotation,
+                radius: *radius,
+                half_height: height * 0.5,
+            }],
+            Self::Csg { op, a, b } => {
+                let mut operand = |o: &CsgOperand| {
+                    let primitives = world.shapes[o.shape.0].as_transformed_primitives(&(t * o.transform), world, rng);
+                    ShapeData::new(primitives, rng)
+                };
+                let (a, b) = (operand(a), operand(b));
+
+                Csg::new(*op, a, b).map(|csg| Primitive::Csg(Box::new(csg))).into_iter().collect()
+            }
+            Self::Sdf { sdf, min, max } => vec![Primitive::Sdf(Box::new(TracedSdf::new(sdf.clone(), AABB::new(*min, *max), *t)))],
+            Self::Heightfield { heights, horizontal_scale, vertical_scale } => {
+                vec![Primitive::Heightfield(Box::new(Heightfield::new(heights, *horizontal_scale, *vertical_scale, *t)))]
+            }
+        }
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CsgOperation {
+    /// Everything inside of either operand.
+    Union,
+    /// Everything inside of both operands.
+    Intersection,
+    /// Everything inside of the first operand, but not inside of the second one.
+    Difference,
+}
+impl CsgOperation {
+    /// Whether a point is inside of the result, given whether it is inside of each operand.
+    pub fn contains(&self, in_a: bool, in_b: bool) -> bool {
+        match self {
+            Self::Union => in_a || in_b,
+            Self::Intersection => in_a && in_b,
+            Self::Difference => in_a && !in_b,
+        }
+    }
+}
+
+/// A shape used by a CSG shape, placed within it by a transform.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CsgOperand {
+    pub shape: ShapeRef,
+    pub transform: Isometry3<Float>,
+}
+impl CsgOperand {
+    pub fn new(shape: ShapeRef, transform: Isometry3<Float>) -> Self {
+        Self {
+            shape,
+            transform,
         }
     }
 }
+impl From<ShapeRef> for CsgOperand {
+    fn from(shape: ShapeRef) -> Self {
+        Self::new(shape, Isometry3::identity())
+    }
+}
 
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
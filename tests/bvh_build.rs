@@ -0,0 +1,60 @@
+use nalgebra::{Point3, Vector3};
+use reflection::aabb::AABB;
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::scene::bvh::BVH;
+use reflection::Float;
+
+/// Enough primitives that the top levels are built in parallel.
+const COUNT: usize = 20_000;
+
+/// Random boxes, mixed with flat ones like those of axis aligned triangles, and clusters of boxes that share their centroid.
+fn primitives<R: Randomness>(rng: &mut R) -> Vec<(usize, AABB)> {
+    let mut random_point = |scale: Float| Point3::new(rng.float(), rng.float(), rng.float()) * scale;
+    let cluster = random_point(10.0);
+
+    (0..COUNT)
+        .map(|i| {
+            let corner = random_point(10.0);
+            let size = Vector3::new(0.2, 0.3, 0.1);
+            let aabb = match i % 5 {
+                // Flat along y, like a triangle on the floor.
+                0 => AABB::new(corner, corner + Vector3::new(size.x, 0.0, size.z)),
+                // Flat along two axes.
+                1 => AABB::new(corner, corner + Vector3::new(0.0, 0.0, size.z)),
+                // Different sizes around the same centroid.
+                2 => AABB::new(cluster - size * (i % 7) as Float, cluster + size * (i % 7) as Float),
+                _ => AABB::new(corner, corner + size),
+            };
+            (i, aabb)
+        })
+        .collect()
+}
+
+
+#[test]
+fn parallel_builds_match_serial_ones() {
+    for seed in 0..2 {
+        let mut rng = DefaultRandomness::new(seed);
+        let mut input = primitives(&mut rng);
+
+        let serial = BVH::new_serial(&mut input.clone(), &mut rng);
+        // Threads finish in a different order every time.
+        for _ in 0..2 {
+            let parallel = BVH::new(&mut input, &mut rng);
+            assert!(parallel == serial, "Parallel build differs for seed {}", seed);
+            assert_eq!(parallel.sah_cost(), serial.sah_cost());
+        }
+    }
+}
+
+#[test]
+fn parallel_builds_match_serial_ones_for_coincident_primitives() {
+    // Every centroid is in the same place, so every node is split in the middle.
+    let mut rng = DefaultRandomness::new(5);
+    let aabb = AABB::new(Point3::new(-1.0, 0.0, -1.0), Point3::new(1.0, 0.0, 1.0));
+    let mut input: Vec<_> = (0..COUNT).map(|i| (i, aabb)).collect();
+
+    let serial = BVH::new_serial(&mut input.clone(), &mut rng);
+    let parallel = BVH::new(&mut input, &mut rng);
+    assert!(parallel == serial);
+}
@@ -0,0 +1,99 @@
+use std::path::PathBuf;
+use nalgebra::{Isometry3, Point3, Vector3};
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::ray::Ray;
+use reflection::scene::cache::BVHCacheError;
+use reflection::scene::Scene;
+use reflection::world::World;
+use reflection::Float;
+
+/// A grid of spheres of two sizes, with `offset` moving the second size.
+fn build_world(offset: Float) -> World {
+    let mut world = World::new();
+    let small = world.add_sphere(0.2);
+    let large = world.add_sphere(0.4);
+    let albedo = world.add_solid_albedo(Vector3::repeat(0.5));
+    let material = world.add_lambertian_material(albedo);
+
+    for x in 0..20 {
+        for z in 0..20 {
+            let (shape, y) = if (x + z) % 2 == 0 { (small, 0.0) } else { (large, offset) };
+            world.add_object(shape, material, Isometry3::translation(x as Float, y, z as Float));
+        }
+    }
+
+    world
+}
+
+/// A cache file of its own for every test, since they run in parallel.
+fn cache_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("reflection-{}-{}.bvh", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn assert_same_hits(a: &Scene, b: &Scene) {
+    let mut rng = DefaultRandomness::new(7);
+
+    for _ in 0..2000 {
+        let origin = Point3::new(rng.float() * 30.0 - 5.0, rng.float() * 10.0 - 5.0, rng.float() * 30.0 - 5.0);
+        let ray = Ray::new(origin, rng.unit_vector());
+
+        let hit_a = a.intersect(&ray, 0.001, Float::INFINITY).map(|i| i.t);
+        let hit_b = b.intersect(&ray, 0.001, Float::INFINITY).map(|i| i.t);
+        assert_eq!(hit_a, hit_b);
+    }
+}
+
+#[test]
+fn cached_bvhs_are_loaded() {
+    let path = cache_path("loaded");
+    let world = build_world(0.0);
+    let mut rng = DefaultRandomness::new(1);
+
+    let built = world.build_scene_cached(&path, &mut rng).unwrap();
+    let written = std::fs::metadata(&path).unwrap().modified().unwrap();
+
+    let loaded = world.build_scene_cached(&path, &mut rng).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), written, "The cache was written again");
+
+    assert_eq!(loaded.sah_cost(), built.sah_cost());
+    assert_same_hits(&loaded, &world.build_scene(&mut rng));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn changed_geometry_is_built_again() {
+    let path = cache_path("changed");
+    let mut rng = DefaultRandomness::new(2);
+
+    let world = build_world(0.0);
+    world.build_scene_cached(&path, &mut rng).unwrap();
+    let written = std::fs::read(&path).unwrap();
+
+    let moved = build_world(1.0);
+    let scene = moved.build_scene_cached(&path, &mut rng).unwrap();
+    assert_ne!(std::fs::read(&path).unwrap(), written);
+    assert_same_hits(&scene, &moved.build_scene(&mut rng));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn corrupt_caches_are_errors() {
+    let path = cache_path("corrupt");
+    let world = build_world(0.0);
+    let mut rng = DefaultRandomness::new(3);
+
+    world.build_scene_cached(&path, &mut rng).unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+
+    std::fs::write(&path, &bytes[..bytes.len() - 5]).unwrap();
+    assert!(matches!(world.build_scene_cached(&path, &mut rng), Err(BVHCacheError::Corrupt(..))));
+
+    std::fs::write(&path, b"not a cache").unwrap();
+    assert!(matches!(world.build_scene_cached(&path, &mut rng), Err(BVHCacheError::Corrupt(..))));
+
+    std::fs::remove_file(&path).unwrap();
+}
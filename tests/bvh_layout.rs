@@ -0,0 +1,91 @@
+use nalgebra::{Isometry3, Point3, Vector3};
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::ray::Ray;
+use reflection::scene::bvh::BVHLayout;
+use reflection::scene::Scene;
+use reflection::world::{ObjectRef, World};
+use reflection::Float;
+
+/// Spheres and copies of a mesh of random triangles, which includes axis aligned ones with flat bounds.
+fn build_world<R: Randomness>(rng: &mut R) -> (World, Vec<ObjectRef>) {
+    let mut world = World::new();
+    let albedo = world.add_solid_albedo(Vector3::repeat(0.5));
+    let material = world.add_lambertian_material(albedo);
+
+    let mut positions = Vec::new();
+    let mut indices = Vec::new();
+    for i in 0..500 {
+        let corner = Point3::new(rng.float() * 4.0, rng.float() * 4.0, rng.float() * 4.0);
+        let a = corner + Vector3::new(rng.float(), rng.float(), rng.float()) * 0.5;
+        let b = corner + Vector3::new(rng.float(), rng.float(), rng.float()) * 0.5;
+        // Every fifth triangle lies in a plane of constant y.
+        let (a, b) = if i % 5 == 0 { (Point3::new(a.x, corner.y, a.z), Point3::new(b.x, corner.y, b.z)) } else { (a, b) };
+
+        indices.push([positions.len(), positions.len() + 1, positions.len() + 2]);
+        positions.extend([corner, a, b]);
+    }
+    let mesh = world.add_triangle_mesh(positions, indices, None, None);
+    let sphere = world.add_sphere(0.3);
+
+    let mut objects = Vec::new();
+    for i in 0..3 {
+        objects.push(world.add_object(mesh, material, Isometry3::translation(i as Float * 5.0, 0.0, 0.0)));
+    }
+    for i in 0..40 {
+        objects.push(world.add_object(sphere, material, Isometry3::translation((i % 8) as Float * 2.0, 5.0, (i / 8) as Float)));
+    }
+
+    (world, objects)
+}
+
+/// Asserts that both scenes give exactly the same closest hits and occlusion for rays from all around,
+/// including rays along the axes.
+fn assert_same_hits(a: &Scene, b: &Scene) {
+    let mut rng = DefaultRandomness::new(7);
+
+    for i in 0..5000 {
+        let origin = Point3::new(rng.float() * 20.0 - 2.0, rng.float() * 10.0 - 2.0, rng.float() * 10.0 - 2.0);
+        let direction = match i % 4 {
+            0 => Vector3::ith_axis(i / 4 % 3),
+            _ => rng.unit_vector(),
+        };
+        let ray = Ray::new(origin, direction);
+
+        let hit_a = a.intersect(&ray, 0.001, Float::INFINITY).map(|i| i.t);
+        let hit_b = b.intersect(&ray, 0.001, Float::INFINITY).map(|i| i.t);
+        assert_eq!(hit_a, hit_b);
+        assert_eq!(a.occluded(&ray, 0.001, 3.0), b.occluded(&ray, 0.001, 3.0));
+    }
+}
+
+#[test]
+fn wide_layout_finds_the_same_hits() {
+    let mut rng = DefaultRandomness::new(1);
+    let (world, _) = build_world(&mut rng);
+
+    let binary = world.build_scene(&mut rng);
+    let mut wide = world.build_scene(&mut rng);
+    wide.set_bvh_layout(BVHLayout::Wide);
+
+    assert_same_hits(&binary, &wide);
+}
+
+#[test]
+fn wide_layout_is_kept_by_updates() {
+    let mut rng = DefaultRandomness::new(2);
+    let (mut world, objects) = build_world(&mut rng);
+
+    let mut scene = world.build_scene(&mut rng);
+    scene.set_bvh_layout(BVHLayout::Wide);
+    let scene = scene.detach();
+
+    world.set_object_transform(objects[0], nalgebra::convert(Isometry3::translation(0.0, -3.0, 1.0)));
+    world.remove_object(objects[5]);
+    let sphere = world.add_sphere(0.5);
+    let material = world.add_mirror_material();
+    world.add_object(sphere, material, Isometry3::translation(3.0, 3.0, 3.0));
+
+    let updated = world.update_scene(scene, &mut rng);
+    assert_eq!(updated.bvh_layout(), BVHLayout::Wide);
+    assert_same_hits(&updated, &world.build_scene(&mut rng));
+}
@@ -0,0 +1,23 @@
+// Shared by all the integration tests, which don't each use every helper.
+#![allow(dead_code)]
+
+use nalgebra::{Affine3, Isometry3, Vector3};
+use reflection::world::shape::ShapeRef;
+use reflection::world::World;
+use reflection::Float;
+
+/// A world with one grey, diffuse object of the shape made by `add_shape`, placed by `transform`.
+pub fn build_world<F: FnOnce(&mut World) -> ShapeRef>(add_shape: F, transform: Isometry3<Float>) -> World {
+    build_affine_world(add_shape, nalgebra::convert(transform))
+}
+
+/// Like [build_world], with a transform that may also scale, shear or mirror the shape.
+pub fn build_affine_world<F: FnOnce(&mut World) -> ShapeRef>(add_shape: F, transform: Affine3<Float>) -> World {
+    let mut world = World::new();
+    let shape = add_shape(&mut world);
+    let albedo = world.add_solid_albedo(Vector3::repeat(0.5));
+    let material = world.add_lambertian_material(albedo);
+    world.add_affine_object(shape, material, transform);
+
+    world
+}
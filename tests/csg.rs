@@ -0,0 +1,188 @@
+mod common;
+
+use common::build_world;
+use nalgebra::{Isometry3, Point3, Unit, UnitVector3, Vector3};
+use num_traits::FloatConst;
+use reflection::loader::scene_file::{SceneFile, SceneFileError};
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::ray::Ray;
+use reflection::world::shape::{CsgOperand, CsgOperation, ShapeRef};
+use reflection::world::World;
+use reflection::Float;
+
+/// All surfaces the ray crosses, with whether it enters through them and their normal pointing out of the shape.
+fn crossings(world: &World, ray: &Ray) -> Vec<(Float, bool, UnitVector3<Float>)> {
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+
+    let mut crossings = Vec::new();
+    let mut t_min = 0.0;
+    while let Some(i) = scene.intersect(ray, t_min, Float::INFINITY) {
+        let outward = if i.outside { i.normal } else { -i.normal };
+        crossings.push((i.t, i.outside, outward));
+        t_min = i.t + 1.0e-3;
+    }
+
+    crossings
+}
+
+fn assert_crossings(world: &World, ray: &Ray, expected: &[(Float, bool, Vector3<Float>)]) {
+    let found = crossings(world, ray);
+    assert_eq!(found.len(), expected.len(), "Crossed at {:?}", found.iter().map(|c| c.0).collect::<Vec<_>>());
+
+    for ((t, enters, normal), (expected_t, expected_enters, expected_normal)) in found.iter().zip(expected) {
+        assert!((t - expected_t).abs() < 1.0e-3, "Crossed at {} instead of {}", t, expected_t);
+        assert_eq!(enters, expected_enters, "Crossing at {} goes the wrong way", t);
+        assert!((normal.into_inner() - expected_normal).magnitude() < 1.0e-3, "Normal at {} is {:?}", t, normal);
+    }
+}
+
+/// A unit sphere with a cube of half its size cut out of the middle.
+fn add_hollow_sphere(world: &mut World) -> ShapeRef {
+    let sphere = world.add_sphere(1.0);
+    let cube = world.add_box(Point3::new(-0.5, -0.5, -0.5), Point3::new(0.5, 0.5, 0.5));
+    world.add_csg(CsgOperation::Difference, sphere, cube)
+}
+
+/// Two unit spheres, moved half a unit apart from the origin along x.
+fn add_sphere_pair(world: &mut World, op: CsgOperation) -> ShapeRef {
+    let sphere = world.add_sphere(1.0);
+    let left = CsgOperand::new(sphere, Isometry3::translation(-0.5, 0.0, 0.0));
+    let right = CsgOperand::new(sphere, Isometry3::translation(0.5, 0.0, 0.0));
+    world.add_csg(op, left, right)
+}
+
+
+#[test]
+fn subtracted_surfaces_face_into_the_hole() {
+    let world = build_world(add_hollow_sphere, Isometry3::identity());
+    let ray = Ray::new(Point3::new(-3.0, 0.0, 0.0), Vector3::x_axis());
+
+    assert_crossings(&world, &ray, &[
+        (2.0, true, -Vector3::x()),
+        (2.5, false, Vector3::x()),
+        (3.5, true, -Vector3::x()),
+        (4.0, false, Vector3::x()),
+    ]);
+}
+
+#[test]
+fn intersection_keeps_the_overlap() {
+    let world = build_world(|world| add_sphere_pair(world, CsgOperation::Intersection), Isometry3::identity());
+    let ray = Ray::new(Point3::new(-3.0, 0.0, 0.0), Vector3::x_axis());
+
+    assert_crossings(&world, &ray, &[(2.5, true, -Vector3::x()), (3.5, false, Vector3::x())]);
+}
+
+#[test]
+fn union_hides_inner_surfaces() {
+    let world = build_world(|world| add_sphere_pair(world, CsgOperation::Union), Isometry3::identity());
+    let ray = Ray::new(Point3::new(-3.0, 0.0, 0.0), Vector3::x_axis());
+
+    assert_crossings(&world, &ray, &[(1.5, true, -Vector3::x()), (4.5, false, Vector3::x())]);
+}
+
+#[test]
+fn rays_from_inside_find_the_way_out() {
+    let world = build_world(add_hollow_sphere, Isometry3::identity());
+    let ray = Ray::new(Point3::new(-0.75, 0.0, 0.0), Vector3::x_axis());
+
+    assert_crossings(&world, &ray, &[
+        (0.25, false, Vector3::x()),
+        (1.25, true, -Vector3::x()),
+        (1.75, false, Vector3::x()),
+    ]);
+}
+
+#[test]
+fn shapes_can_be_nested() {
+    // Two crossed cylinders with a ball cut out where they meet.
+    let world = build_world(|world| {
+        let cylinder = world.add_cylinder(0.5, 4.0, true);
+        let across = CsgOperand::new(cylinder, Isometry3::rotation(Vector3::z() * Float::FRAC_PI_2()));
+        let cross = world.add_csg(CsgOperation::Union, cylinder, across);
+        let ball = world.add_sphere(0.3);
+        world.add_csg(CsgOperation::Difference, cross, ball)
+    }, Isometry3::identity());
+
+    // Along the upright cylinder, where the other one is inside of it.
+    let ray = Ray::new(Point3::new(0.0, -3.0, 0.0), Vector3::y_axis());
+    assert_crossings(&world, &ray, &[
+        (1.0, true, -Vector3::y()),
+        (2.7, false, Vector3::y()),
+        (3.3, true, -Vector3::y()),
+        (5.0, false, Vector3::y()),
+    ]);
+
+    // Along the one across, which is turned by its operand transform.
+    let ray = Ray::new(Point3::new(3.0, 0.0, 0.0), -Vector3::x_axis());
+    assert_crossings(&world, &ray, &[
+        (1.0, true, Vector3::x()),
+        (2.7, false, -Vector3::x()),
+        (3.3, true, Vector3::x()),
+        (5.0, false, -Vector3::x()),
+    ]);
+}
+
+#[test]
+fn crossings_agree_with_what_is_inside() {
+    let world = build_world(add_hollow_sphere, Isometry3::identity());
+    let inside = |p: Point3<Float>| p.coords.magnitude() < 1.0 && p.coords.amax() > 0.5;
+    let mut rng = DefaultRandomness::new(5);
+
+    for _ in 0..500 {
+        let origin = Point3::from(rng.unit_vector().into_inner() * 3.0);
+        let target = Point3::new(rng.float(), rng.float(), rng.float()) - Vector3::repeat(0.5);
+        let ray = Ray::new(origin, Unit::new_normalize(target - origin));
+
+        // Whether the ray is inside must change at every crossing, and only there.
+        let mut t = 0.0;
+        let mut was_inside = false;
+        for (t_crossing, enters, _) in crossings(&world, &ray) {
+            assert_eq!(enters, !was_inside, "Crossing at {} goes the wrong way", t_crossing);
+            let between = ray.point_at((t + t_crossing) * 0.5);
+            assert_eq!(inside(between), was_inside, "Missed a crossing before {}", t_crossing);
+
+            t = t_crossing;
+            was_inside = enters;
+        }
+        assert!(!was_inside, "Never left the shape");
+    }
+}
+
+#[test]
+fn scene_files_refer_to_operands_by_name() {
+    let file = SceneFile::parse(r#"(
+        camera: (look_from: (0.0, 0.0, 5.0), look_at: (0.0, 0.0, 0.0), vfov: 40.0),
+        shapes: {
+            "ball": Sphere(radius: 1.0),
+            "cube": Box(min: (-0.5, -0.5, -0.5), max: (0.5, 0.5, 0.5)),
+            "hollow": Csg(
+                op: Difference,
+                a: (shape: "ball"),
+                b: (shape: "cube", transform: (translation: (1.0, 0.0, 0.0))),
+            ),
+        },
+        albedos: { "grey": Solid((0.5, 0.5, 0.5)) },
+        materials: { "grey": Lambertian("grey") },
+        objects: [(shape: "hollow", material: "grey")],
+    )"#).unwrap();
+    let world = file.build_world("").unwrap();
+    let ray = Ray::new(Point3::new(-3.0, 0.0, 0.0), Vector3::x_axis());
+
+    assert_crossings(&world, &ray, &[(2.0, true, -Vector3::x()), (3.5, false, Vector3::x())]);
+}
+
+#[test]
+fn scene_files_reject_shapes_made_of_themselves() {
+    let file = SceneFile::parse(r#"(
+        camera: (look_from: (0.0, 0.0, 5.0), look_at: (0.0, 0.0, 0.0), vfov: 40.0),
+        shapes: {
+            "ball": Sphere(radius: 1.0),
+            "a": Csg(op: Union, a: (shape: "ball"), b: (shape: "b")),
+            "b": Csg(op: Union, a: (shape: "a"), b: (shape: "ball")),
+        },
+    )"#).unwrap();
+
+    assert!(matches!(file.build_world(""), Err(SceneFileError::InvalidShape { .. })));
+}
@@ -0,0 +1,83 @@
+use nalgebra::{Isometry3, Point3, Vector3};
+use reflection::camera::Camera;
+use reflection::integrator::path_integrator::PathTracingIntegrator;
+use reflection::randomness::DefaultRandomness;
+use reflection::world::World;
+use reflection::{render, Float, RenderDescriptor};
+
+const WIDTH: u32 = 24;
+const HEIGHT: u32 = 16;
+
+fn build_world() -> World {
+    let mut world = World::new();
+
+    let ground = world.add_sphere(100.0);
+    let sphere = world.add_sphere(1.0);
+
+    let grey = world.add_solid_albedo(Vector3::new(0.5, 0.5, 0.5));
+    let white = world.add_solid_albedo(Vector3::new(1.0, 1.0, 1.0));
+    let diffuse = world.add_lambertian_material(grey);
+    let mirror = world.add_mirror_material();
+    let light = world.add_emitting_material(white, 4.0);
+
+    world.add_object(ground, diffuse, Isometry3::translation(0.0, -100.0, 0.0));
+    world.add_object(sphere, diffuse, Isometry3::translation(-1.0, 1.0, 0.0));
+    world.add_object(sphere, mirror, Isometry3::translation(1.5, 1.0, 0.0));
+    world.add_object(sphere, light, Isometry3::translation(0.0, 4.0, 2.0));
+
+    world
+}
+
+fn render_with_threads(seed: u64, threads: usize) -> Vec<Vector3<Float>> {
+    let world = build_world();
+    let mut rng = DefaultRandomness::new(seed);
+    let scene = world.build_scene(&mut rng);
+
+    let camera = Camera::new(
+        Point3::new(0.0, 2.0, 8.0),
+        Point3::new(0.0, 1.0, 0.0),
+        Vector3::y(),
+        (40.0 as Float).to_radians(),
+        WIDTH as Float / HEIGHT as Float,
+    );
+    let integrator = PathTracingIntegrator::new(4, Vector3::new(0.2, 0.2, 0.2), &scene);
+
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+    let image = pool.install(|| render(RenderDescriptor {
+        width: WIDTH,
+        height: HEIGHT,
+        samples: 4,
+        t_min: 0.001,
+        t_max: Float::INFINITY,
+        integrator,
+        rng: &mut rng,
+        scene,
+        camera,
+    }));
+
+    image.into_pixels().collect()
+}
+
+/// Compares exact bit patterns, so that even the smallest differences are caught.
+fn bits(pixels: &[Vector3<Float>]) -> Vec<Vector3<impl nalgebra::Scalar>> {
+    pixels.iter().map(|p| p.map(Float::to_bits)).collect()
+}
+
+
+#[test]
+fn same_seed_gives_identical_images_across_thread_counts() {
+    let single = render_with_threads(7, 1);
+    let many = render_with_threads(7, 4);
+    let again = render_with_threads(7, 4);
+
+    assert_eq!(bits(&single), bits(&many));
+    assert_eq!(bits(&many), bits(&again));
+}
+
+#[test]
+fn different_seeds_give_different_images() {
+    let a = render_with_threads(7, 2);
+    let b = render_with_threads(8, 2);
+
+    assert_ne!(bits(&a), bits(&b));
+}
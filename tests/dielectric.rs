@@ -0,0 +1,176 @@
+use nalgebra::{Isometry3, Point3, Unit, UnitVector3, Vector3};
+use reflection::camera::Camera;
+use reflection::integrator::path_integrator::PathTracingIntegrator;
+use reflection::pdf::PDF;
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::ray::Ray;
+use reflection::scene::Scene;
+use reflection::world::material::DielectricScatter;
+use reflection::world::World;
+use reflection::{render, Float, RenderDescriptor};
+
+const IOR: Float = 1.5;
+
+/// Always returns the same number, to pick either of the two directions of a [DielectricScatter].
+struct Fixed(Float);
+impl Randomness for Fixed {
+    fn float(&mut self) -> Float {
+        self.0
+    }
+    fn usize_range_exclusive(&mut self, min: usize, _max: usize) -> usize {
+        min
+    }
+    fn unit_vector(&mut self) -> UnitVector3<Float> {
+        Vector3::z_axis()
+    }
+}
+
+/// A glass sphere with a radius of one around the origin.
+fn glass_sphere() -> World {
+    let mut world = World::new();
+    let sphere = world.add_sphere(1.0);
+    let glass = world.add_dielectric_material(IOR);
+    world.add_object(sphere, glass, Isometry3::identity());
+
+    world
+}
+
+/// The reflected and refracted directions where `ray` hits the glass, and the reflectance.
+fn scatter(scene: &Scene, ray: &Ray) -> (UnitVector3<Float>, Option<UnitVector3<Float>>, Float) {
+    let int = scene.intersect(ray, 0.001, Float::INFINITY).unwrap();
+    let scatter = DielectricScatter::new(-ray.direction, &int, IOR);
+
+    let reflected = scatter.generate(&mut Fixed(0.0), scene);
+    let refracted = Some(scatter.generate(&mut Fixed(1.0), scene)).filter(|r| *r != reflected);
+    let reflectance = scatter.value(&reflected, scene);
+
+    if let Some(refracted) = refracted {
+        assert!((scatter.value(&refracted, scene) - (1.0 - reflectance)).abs() < 1.0e-6);
+    }
+
+    (reflected, refracted, reflectance)
+}
+
+/// A ray hitting the top of the sphere from the outside, at `angle` to the normal.
+fn ray_onto_top(angle: Float) -> Ray {
+    let direction = Vector3::new(angle.sin(), angle.cos(), 0.0);
+    Ray::new(Point3::new(0.0, 1.0, 0.0) + direction * 2.0, Unit::new_normalize(-direction))
+}
+
+/// The Fresnel reflectance of unpolarized light going from `n1` into `n2`.
+fn fresnel(n1: Float, n2: Float, angle: Float) -> Float {
+    let cos_i = angle.cos();
+    let cos_t = (1.0 - (n1 / n2 * angle.sin()).powi(2)).sqrt();
+
+    let s = (n1 * cos_i - n2 * cos_t) / (n1 * cos_i + n2 * cos_t);
+    let p = (n1 * cos_t - n2 * cos_i) / (n1 * cos_t + n2 * cos_i);
+    (s * s + p * p) / 2.0
+}
+
+
+#[test]
+fn reflectance_at_normal_incidence() {
+    let world = glass_sphere();
+    let scene = world.build_scene(&mut DefaultRandomness::new(3));
+
+    // ((n - 1) / (n + 1))² from either side.
+    let (reflected, refracted, reflectance) = scatter(&scene, &ray_onto_top(0.0));
+    assert!((reflectance - 0.04).abs() < 1.0e-5, "Reflectance {} from the outside", reflectance);
+    assert!((reflected.into_inner() - Vector3::y()).magnitude() < 1.0e-5);
+    assert!((refracted.unwrap().into_inner() + Vector3::y()).magnitude() < 1.0e-5);
+
+    let (_, _, reflectance) = scatter(&scene, &Ray::new(Point3::origin(), Vector3::x_axis()));
+    assert!((reflectance - 0.04).abs() < 1.0e-5, "Reflectance {} from the inside", reflectance);
+}
+
+#[test]
+fn reflectance_follows_the_fresnel_equations() {
+    let world = glass_sphere();
+    let scene = world.build_scene(&mut DefaultRandomness::new(3));
+
+    // Including Brewster's angle, where only s-polarized light is reflected.
+    let brewster = IOR.atan();
+    for angle in [0.2, 0.5, brewster, 1.0, 1.3, 1.5] {
+        let (reflected, refracted, reflectance) = scatter(&scene, &ray_onto_top(angle));
+        let expected = fresnel(1.0, IOR, angle);
+        assert!((reflectance - expected).abs() < 1.0e-4, "Reflectance {} instead of {} at {}", reflectance, expected, angle);
+
+        // Mirrored around the normal, and bent towards it following Snell's law.
+        let mirrored = Vector3::new(-angle.sin(), angle.cos(), 0.0);
+        assert!((reflected.into_inner() - mirrored).magnitude() < 1.0e-4, "Reflected along {:?} at {}", reflected, angle);
+        let refracted = refracted.unwrap();
+        assert!((refracted.x + angle.sin() / IOR).abs() < 1.0e-4, "Refracted along {:?} at {}", refracted, angle);
+        assert!(refracted.y < 0.0);
+    }
+}
+
+#[test]
+fn grazing_rays_are_reflected() {
+    let world = glass_sphere();
+    let scene = world.build_scene(&mut DefaultRandomness::new(3));
+
+    let mut previous = 0.0;
+    for angle in [1.4, 1.5, 1.55, 1.565] {
+        let (_, _, reflectance) = scatter(&scene, &ray_onto_top(angle));
+        let expected = fresnel(1.0, IOR, angle);
+
+        assert!((reflectance - expected).abs() < 1.0e-2, "Reflectance {} instead of {} at {}", reflectance, expected, angle);
+        assert!(reflectance > previous, "Reflectance {} at {} isn't increasing", reflectance, angle);
+        previous = reflectance;
+    }
+    assert!(previous > 0.95, "Only reflected {} at a grazing angle", previous);
+}
+
+#[test]
+fn total_internal_reflection_beyond_the_critical_angle() {
+    let world = glass_sphere();
+    let scene = world.build_scene(&mut DefaultRandomness::new(3));
+    let critical = (1.0 / IOR).asin();
+
+    // From the inside, a ray at height h hits the sphere at asin(h) to the normal.
+    for angle in [critical - 0.05, critical - 0.01] {
+        let ray = Ray::new(Point3::new(0.0, angle.sin(), 0.0), Vector3::x_axis());
+        let (_, refracted, reflectance) = scatter(&scene, &ray);
+        let expected = fresnel(IOR, 1.0, angle);
+
+        assert!(refracted.is_some(), "No refraction at {} below the critical angle", critical - angle);
+        assert!((reflectance - expected).abs() < 1.0e-3, "Reflectance {} instead of {} at {}", reflectance, expected, angle);
+    }
+
+    for angle in [critical + 0.01, critical + 0.3, 1.4] {
+        let ray = Ray::new(Point3::new(0.0, angle.sin(), 0.0), Vector3::x_axis());
+        let (reflected, refracted, reflectance) = scatter(&scene, &ray);
+
+        assert!(refracted.is_none(), "Refracted at {} beyond the critical angle", angle - critical);
+        assert_eq!(reflectance, 1.0);
+        // Back into the sphere.
+        assert!(reflected.x < 0.0 || reflected.y < 0.0, "Reflected out along {:?}", reflected);
+    }
+}
+
+#[test]
+fn glass_sphere_in_a_white_furnace() {
+    // Clear glass neither absorbs nor emits, so it has to vanish in front of a uniform background.
+    let world = glass_sphere();
+    let mut rng = DefaultRandomness::new(5);
+    let scene = world.build_scene(&mut rng);
+
+    let camera = Camera::new(Point3::new(0.0, 0.0, 4.0), Point3::origin(), Vector3::y(), (30.0 as Float).to_radians(), 1.0);
+    let integrator = PathTracingIntegrator::new(64, Vector3::repeat(1.0), &scene);
+
+    let image = render(RenderDescriptor {
+        width: 8,
+        height: 8,
+        samples: 256,
+        t_min: 0.001,
+        t_max: Float::INFINITY,
+        integrator,
+        rng: &mut rng,
+        scene,
+        camera,
+    });
+
+    for pixel in image.into_pixels() {
+        assert!((pixel - Vector3::repeat(1.0)).abs().max() < 1.0e-2, "Pixel is {:?}", pixel);
+    }
+}
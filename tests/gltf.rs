@@ -0,0 +1,202 @@
+use std::io::Cursor;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use image::{ImageOutputFormat, Rgb, RgbImage};
+use nalgebra::{Point3, Vector3};
+use reflection::camera::Camera;
+use reflection::intersection::Intersection;
+use reflection::loader::gltf::{load_gltf, GltfError, GltfScene};
+use reflection::loader::scene_file::{AlbedoDescription, ColorDescription, MaterialDescription, SceneFile, ScalarDescription};
+use reflection::randomness::DefaultRandomness;
+use reflection::ray::Ray;
+use reflection::scene::Scene;
+use reflection::world::World;
+use reflection::{Float, RenderSettings};
+
+/// A red and a blue pixel, encoded as a PNG.
+fn png() -> Vec<u8> {
+    let image = RgbImage::from_fn(2, 1, |x, _| if x == 0 { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) });
+    let mut bytes = Cursor::new(Vec::new());
+    image.write_to(&mut bytes, ImageOutputFormat::Png).unwrap();
+    bytes.into_inner()
+}
+
+/// A glTF file with a single triangle mesh, which is placed by two nodes, and a camera.
+///
+/// The buffer holds the positions of the triangle, its indices and the PNG, which is the first image.
+/// `image_view_length` overrides the length of the buffer view of the PNG.
+fn fixture(materials: &str, image_view_length: Option<usize>) -> String {
+    let mut buffer = Vec::new();
+    for position in [[0.0f32, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]] {
+        buffer.extend(position.iter().flat_map(|c| c.to_le_bytes()));
+    }
+    buffer.extend([0u16, 1, 2].iter().flat_map(|i| i.to_le_bytes()));
+    buffer.resize(44, 0);
+    let png = png();
+    let image_view_length = image_view_length.unwrap_or(png.len());
+    buffer.extend(png);
+
+    format!(r#"{{
+        "asset": {{ "version": "2.0" }},
+        "scene": 0,
+        "scenes": [{{ "nodes": [0, 1, 3] }}],
+        "nodes": [
+            {{ "mesh": 0, "translation": [0, 0, -5] }},
+            {{ "scale": [2, 2, 2], "translation": [10, 0, 0], "children": [2] }},
+            {{ "mesh": 0, "translation": [0, 0, -1] }},
+            {{ "camera": 0, "translation": [0, 1, 3], "rotation": [0, 0.7071068, 0, 0.7071068] }}
+        ],
+        "cameras": [{{ "type": "perspective", "perspective": {{ "yfov": 0.75, "aspectRatio": 1.5, "znear": 0.1 }} }}],
+        "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "indices": 1, "material": 0 }}] }}],
+        "materials": {materials},
+        "textures": [{{ "source": 0 }}],
+        "images": [{{ "bufferView": 2, "mimeType": "image/png" }}],
+        "buffers": [{{ "byteLength": {buffer_length}, "uri": "data:application/octet-stream;base64,{data}" }}],
+        "bufferViews": [
+            {{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }},
+            {{ "buffer": 0, "byteOffset": 36, "byteLength": 6 }},
+            {{ "buffer": 0, "byteOffset": 44, "byteLength": {image_view_length} }}
+        ],
+        "accessors": [
+            {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] }},
+            {{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }}
+        ]
+    }}"#, buffer_length = buffer.len(), data = BASE64.encode(&buffer))
+}
+
+const PLAIN_MATERIAL: &str = r#"[{
+    "pbrMetallicRoughness": { "baseColorFactor": [0.5, 0.25, 1, 1], "metallicFactor": 0.75, "roughnessFactor": 0.5 },
+    "emissiveFactor": [1, 0.5, 0]
+}]"#;
+const TEXTURED_MATERIAL: &str = r#"[{
+    "pbrMetallicRoughness": { "baseColorFactor": [0.5, 1, 1, 1], "baseColorTexture": { "index": 0 } }
+}]"#;
+
+/// Writes the file and loads it into a fresh world.
+fn load(name: &str, source: &str) -> (Result<GltfScene, GltfError>, World) {
+    let path = std::env::temp_dir().join(format!("reflection-{}-{}.gltf", name, std::process::id()));
+    std::fs::write(&path, source).unwrap();
+
+    let mut world = World::new();
+    let scene = load_gltf(&path, &mut world, 1.0);
+    std::fs::remove_file(&path).unwrap();
+
+    (scene, world)
+}
+
+/// What a ray straight down the z axis hits at `x` and `y`.
+fn down(scene: &Scene, x: Float, y: Float) -> Option<Intersection> {
+    scene.intersect(&Ray::new(Point3::new(x, y, 10.0), -Vector3::z_axis()), 0.001, Float::INFINITY)
+}
+
+fn describe(world: &World) -> SceneFile {
+    let camera = Camera::new(Point3::new(0.0, 0.0, 5.0), Point3::origin(), Vector3::y(), 0.5, 1.0);
+    SceneFile::from_world(world, &camera, RenderSettings::default())
+}
+
+fn assert_close(a: Point3<Float>, b: Point3<Float>) {
+    assert!((a - b).magnitude() < 1.0e-4, "{} instead of {}", a, b);
+}
+
+
+#[test]
+fn meshes_are_placed_by_their_nodes() {
+    let (scene, world) = load("gltf-nodes", &fixture(PLAIN_MATERIAL, None));
+    let scene = scene.unwrap();
+    assert!(scene.warnings.is_empty(), "Warnings: {:?}", scene.warnings);
+    assert_eq!(scene.objects.len(), 2);
+
+    // Both nodes share the shape of the mesh.
+    let description = describe(&world);
+    assert_eq!(description.shapes.len(), 1);
+    assert_eq!(description.objects.len(), 2);
+
+    let scene = world.build_scene(&mut DefaultRandomness::new(3));
+    let down = |x: Float, y: Float| down(&scene, x, y);
+
+    // The first node only translates.
+    assert_close(down(0.25, 0.25).unwrap().point, Point3::new(0.25, 0.25, -5.0));
+    assert!(down(0.75, 0.75).is_none());
+
+    // The second one is translated and scaled by its parent.
+    assert_close(down(11.5, 0.2).unwrap().point, Point3::new(11.5, 0.2, -2.0));
+    assert_close(down(10.2, 1.7).unwrap().point, Point3::new(10.2, 1.7, -2.0));
+    assert!(down(11.5, 1.5).is_none());
+}
+
+#[test]
+fn cameras_keep_their_field_of_view() {
+    let (scene, _) = load("gltf-camera", &fixture(PLAIN_MATERIAL, None));
+    let scene = scene.unwrap();
+    assert_eq!(scene.cameras.len(), 1);
+
+    // Turned by a quarter around y, from looking along -z to looking along -x.
+    let center = scene.cameras[0].get_ray(0.5, 0.5);
+    assert_close(center.origin, Point3::new(0.0, 1.0, 3.0));
+    let forward = center.direction;
+    assert!((forward.into_inner() + Vector3::x()).magnitude() < 1.0e-4, "Looking along {:?}", forward);
+
+    // The ray through the top center of the image is half of the field of view above the forward direction,
+    // and the one through the right center is wider by the aspect ratio.
+    let top = scene.cameras[0].get_ray(0.5, 1.0);
+    let angle = top.direction.angle(&forward);
+    assert!(top.direction.y > 0.0 && (angle - 0.375).abs() < 1.0e-4, "Top of the image is {} from the center", angle);
+    let right = scene.cameras[0].get_ray(1.0, 0.5);
+    let expected = ((0.375 as Float).tan() * 1.5).atan();
+    assert!((right.direction.angle(&forward) - expected).abs() < 1.0e-4, "Right of the image is {} from the center", right.direction.angle(&forward));
+}
+
+#[test]
+fn materials_become_principled() {
+    let (scene, world) = load("gltf-material", &fixture(PLAIN_MATERIAL, None));
+    scene.unwrap();
+    let description = describe(&world);
+    assert_eq!(description.materials.len(), 1);
+
+    match description.materials.values().next().unwrap() {
+        MaterialDescription::Principled(p) => {
+            assert_eq!(p.base_color, ColorDescription::Constant([0.5, 0.25, 1.0]));
+            assert_eq!(p.metallic, ScalarDescription::Constant(0.75));
+            assert_eq!(p.roughness, ScalarDescription::Constant(0.5));
+            assert_eq!(p.emission, ColorDescription::Constant([1.0, 0.5, 0.0]));
+        }
+        m => panic!("Material became {:?}", m),
+    }
+}
+
+#[test]
+fn base_color_textures_are_read_from_buffer_views() {
+    let (scene, world) = load("gltf-texture", &fixture(TEXTURED_MATERIAL, None));
+    scene.unwrap();
+    let description = describe(&world);
+
+    let albedo = match description.materials.values().next().unwrap() {
+        MaterialDescription::Principled(p) => match &p.base_color {
+            ColorDescription::Texture(albedo) => &description.albedos[albedo],
+            c => panic!("Base color became {:?}", c),
+        },
+        m => panic!("Material became {:?}", m),
+    };
+    match albedo {
+        AlbedoDescription::Pixels { width, height, pixels } => {
+            assert_eq!((*width, *height), (2, 1));
+            // Multiplied by the factor, after converting from sRGB.
+            let expected = [[0.5, 0.0, 0.0], [0.0, 0.0, 1.0]];
+            for (pixel, expected) in pixels.iter().zip(expected) {
+                assert!((Vector3::from(*pixel) - Vector3::from(expected)).magnitude() < 1.0e-4, "Pixel {:?} instead of {:?}", pixel, expected);
+            }
+        }
+        a => panic!("Texture became {:?}", a),
+    }
+}
+
+#[test]
+fn buffer_views_past_the_end_of_their_buffer_are_errors() {
+    let (scene, _) = load("gltf-range", &fixture(TEXTURED_MATERIAL, Some(100_000)));
+
+    match scene {
+        Err(GltfError::BufferViewRange { view, buffer }) => assert_eq!((view, buffer), (2, 0)),
+        Err(e) => panic!("Failed with {} instead", e),
+        Ok(_) => panic!("Loaded a buffer view past the end of its buffer"),
+    }
+}
@@ -0,0 +1,216 @@
+mod common;
+
+use common::build_world;
+use image::{ImageBuffer, Luma};
+use nalgebra::{Isometry3, Point3, Unit, Vector3};
+use reflection::loader::load_heightmap;
+use reflection::loader::scene_file::SceneFile;
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::ray::Ray;
+use reflection::texture::Texture2D;
+use reflection::Float;
+
+/// A map with the height of every pixel given by `height`, which gets its column and row.
+fn heights<F: FnMut(usize, usize) -> Float>(width: u32, height: u32, mut f: F) -> Texture2D<Float> {
+    let pixels = (0..width as usize * height as usize)
+        .map(|i| f(i % width as usize, i / width as usize))
+        .collect();
+    Texture2D::new_from_pixels(width, height, pixels)
+}
+
+/// Random rays from all around the origin, towards the box of the given size around it.
+fn random_rays(count: usize, size: Vector3<Float>) -> impl Iterator<Item = Ray> {
+    let mut rng = DefaultRandomness::new(5);
+
+    (0..count).map(move |_| {
+        let origin = Point3::from(rng.unit_vector().into_inner() * 6.0);
+        let target = Point3::from((Vector3::new(rng.float(), rng.float(), rng.float()) - Vector3::repeat(0.5)).component_mul(&size));
+        Ray::new(origin, Unit::new_normalize(target - origin))
+    })
+}
+
+fn straight_down(x: Float, z: Float) -> Ray {
+    Ray::new(Point3::new(x, 5.0, z), -Vector3::y_axis())
+}
+
+
+#[test]
+fn flat_maps_are_hit_like_quads() {
+    // 4 by 2 units, at a height of 0.5.
+    let flat = build_world(|world| world.add_heightfield(heights(9, 5, |_, _| 0.25), 0.5, 2.0), Isometry3::identity());
+    let quad = build_world(|world| world.add_quad(Point3::new(-2.0, 0.5, -1.0), Vector3::z() * 2.0, Vector3::x() * 4.0), Isometry3::identity());
+
+    let mut rng = DefaultRandomness::new(3);
+    let (flat, quad) = (flat.build_scene(&mut rng), quad.build_scene(&mut rng));
+
+    for ray in random_rays(2000, Vector3::new(5.0, 1.0, 3.0)) {
+        let hit = flat.intersect(&ray, 0.001, Float::INFINITY);
+        let expected = quad.intersect(&ray, 0.001, Float::INFINITY);
+
+        match (hit, expected) {
+            (Some(hit), Some(expected)) => {
+                assert!((hit.t - expected.t).abs() < 1.0e-3, "Hit at {} instead of {}", hit.t, expected.t);
+                assert!((hit.normal.into_inner() - expected.normal.into_inner()).magnitude() < 1.0e-3, "Normal {:?} instead of {:?}", hit.normal, expected.normal);
+                assert_eq!(hit.outside, expected.outside);
+            }
+            (None, None) => {}
+            (hit, expected) => {
+                // Only rays through the very edge may disagree.
+                let point = hit.or(expected).unwrap().point;
+                assert!(point.x.abs() > 1.999 || point.z.abs() > 0.999, "Only one of them was hit at {}", point);
+            }
+        }
+    }
+}
+
+#[test]
+fn slopes_get_their_normal_everywhere() {
+    // A plane rising along x and falling along z, whose interpolated normals are the same as the geometric ones.
+    let world = build_world(|world| world.add_heightfield(heights(6, 4, |i, j| 0.2 * i as Float - 0.1 * j as Float), 0.5, 1.0), Isometry3::identity());
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+    let expected = Vector3::new(-0.4, 1.0, 0.2).normalize();
+
+    for (x, z) in [(-1.24, -0.74), (1.24, 0.74), (0.1, -0.3), (-0.6, 0.5)] {
+        let hit = scene.intersect(&straight_down(x, z), 0.001, Float::INFINITY).unwrap();
+        let height = 0.4 * (x + 1.25) - 0.2 * (z + 0.75);
+
+        assert!((hit.point.y - height).abs() < 1.0e-4, "Hit at {} instead of {}", hit.point.y, height);
+        assert!((hit.normal.into_inner() - expected).magnitude() < 1.0e-4, "Normal {:?} at {}, {}", hit.normal, x, z);
+        assert!(hit.outside);
+    }
+}
+
+#[test]
+fn normals_are_smooth_across_cells() {
+    let world = build_world(|world| world.add_heightfield(heights(8, 8, |i, j| ((i as Float) * 0.9).sin() * ((j as Float) * 0.7).cos()), 1.0, 0.5), Isometry3::identity());
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+
+    // Either side of the edge between two cells, and of the diagonal within one.
+    for ((x0, z0), (x1, z1)) in [((-0.501, 0.3), (-0.499, 0.3)), ((1.2, 0.499), (1.2, 0.501)), ((0.7, -0.299), (0.7, -0.301))] {
+        let a = scene.intersect(&straight_down(x0, z0), 0.001, Float::INFINITY).unwrap();
+        let b = scene.intersect(&straight_down(x1, z1), 0.001, Float::INFINITY).unwrap();
+
+        assert!((a.normal.into_inner() - b.normal.into_inner()).magnitude() < 1.0e-2, "Normal jumps from {:?} to {:?}", a.normal, b.normal);
+    }
+}
+
+#[test]
+fn tex_coords_map_to_the_grid() {
+    let world = build_world(|world| world.add_heightfield(heights(5, 3, |i, j| (i * j) as Float * 0.1), 1.0, 1.0), Isometry3::identity());
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+
+    // The first row of the map is at the top of the texture, and at the far end towards -z.
+    for (x, z, u, v) in [(-2.0, -1.0, 0.0, 1.0), (2.0, -1.0, 1.0, 1.0), (-2.0, 1.0, 0.0, 0.0), (2.0, 1.0, 1.0, 0.0), (0.5, 0.0, 0.625, 0.5)] {
+        let ray = straight_down((x as Float).clamp(-1.999, 1.999), (z as Float).clamp(-0.999, 0.999));
+        let hit = scene.intersect(&ray, 0.001, Float::INFINITY).unwrap();
+
+        assert!((hit.tex_coord.x - u).abs() < 1.0e-3 && (hit.tex_coord.y - v).abs() < 1.0e-3, "Texture coordinates {:?} at {}, {}", hit.tex_coord, x, z);
+    }
+}
+
+#[test]
+fn heightfields_match_equivalent_meshes() {
+    let (columns, rows, spacing) = (9, 7, 0.4);
+    let mut rng = DefaultRandomness::new(7);
+    let map = heights(columns, rows, |_, _| rng.float());
+
+    // The same two triangles per cell, laid out the same way.
+    let positions: Vec<Point3<Float>> = map.pixels()
+        .enumerate()
+        .map(|(k, h)| {
+            let (i, j) = ((k % columns as usize) as Float, (k / columns as usize) as Float);
+            Point3::new((i - (columns - 1) as Float * 0.5) * spacing, h * 1.5, (j - (rows - 1) as Float * 0.5) * spacing)
+        })
+        .collect();
+    let vertex = |i: usize, j: usize| j * columns as usize + i;
+    let indices = (0..rows as usize - 1)
+        .flat_map(|j| (0..columns as usize - 1).map(move |i| (i, j)))
+        .flat_map(|(i, j)| [
+            [vertex(i, j), vertex(i, j + 1), vertex(i + 1, j + 1)],
+            [vertex(i, j), vertex(i + 1, j + 1), vertex(i + 1, j)],
+        ])
+        .collect();
+
+    let transform = Isometry3::new(Vector3::new(0.3, -0.2, 0.1), Vector3::new(0.5, 0.2, -0.4));
+    let heightfield = build_world(|world| world.add_heightfield(map.clone(), spacing, 1.5), transform);
+    let mesh = build_world(|world| world.add_triangle_mesh(positions, indices, None, None), transform);
+
+    let mut rng = DefaultRandomness::new(3);
+    let (heightfield, mesh) = (heightfield.build_scene(&mut rng), mesh.build_scene(&mut rng));
+    let mut hits = 0;
+
+    for ray in random_rays(4000, Vector3::new(3.2, 1.5, 2.4)) {
+        let hit = heightfield.intersect(&ray, 0.001, Float::INFINITY);
+        let expected = mesh.intersect(&ray, 0.001, Float::INFINITY);
+
+        match (hit, expected) {
+            (Some(hit), Some(expected)) => {
+                hits += 1;
+                assert!((hit.t - expected.t).abs() < 1.0e-3, "Hit at {} instead of {}", hit.t, expected.t);
+                assert_eq!(hit.outside, expected.outside);
+            }
+            (None, None) => {}
+            (hit, expected) => panic!("Hit {:?} but expected {:?}", hit.map(|i| i.t), expected.map(|i| i.t)),
+        }
+    }
+
+    assert!(hits > 500, "Only hit {} times", hits);
+}
+
+#[test]
+fn heightmaps_load_from_16_bit_pngs() {
+    // Steps of one in 16 bits, which would be lost in 8.
+    let image = ImageBuffer::from_fn(4, 3, |x, y| Luma([(30_000 + x + 4 * y) as u16]));
+    let path = std::env::temp_dir().join(format!("reflection-heightmap-{}.png", std::process::id()));
+    image.save(&path).unwrap();
+
+    let map = load_heightmap(&path);
+    std::fs::remove_file(&path).unwrap();
+    let map = map.unwrap();
+
+    assert_eq!((map.width(), map.height()), (4, 3));
+    for (k, h) in map.pixels().enumerate() {
+        let expected = (30_000 + k) as Float / 65_535.0;
+        assert!((h - expected).abs() < 1.0e-6, "Height {} instead of {} at {}", h, expected, k);
+    }
+}
+
+#[test]
+fn scene_files_describe_heightfields() {
+    let image = ImageBuffer::from_fn(3, 3, |x, _| Luma([if x == 1 { u16::MAX } else { 0 }]));
+    let dir = std::env::temp_dir().join(format!("reflection-heightfield-scene-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    image.save(dir.join("ridge.png")).unwrap();
+
+    let file = SceneFile::parse(r#"(
+        camera: (look_from: (0.0, 5.0, 0.0), look_at: (0.0, 0.0, 0.0), vfov: 40.0),
+        shapes: {
+            "ridge": Heightfield(heights: Image("ridge.png"), horizontal_scale: 1.0, vertical_scale: 2.0),
+            "ramp": Heightfield(
+                heights: Samples(width: 2, height: 2, heights: [0.0, 1.0, 0.0, 1.0]),
+                horizontal_scale: 2.0,
+                vertical_scale: 1.0,
+            ),
+        },
+        albedos: { "grey": Solid((0.5, 0.5, 0.5)) },
+        materials: { "grey": Lambertian("grey") },
+        objects: [
+            (shape: "ridge", material: "grey"),
+            (shape: "ramp", material: "grey", transform: (translation: (5.0, 0.0, 0.0))),
+        ],
+    )"#).unwrap();
+    let world = file.build_world(&dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+    let world = world.unwrap();
+
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+
+    let ridge = scene.intersect(&straight_down(0.0, 0.0), 0.001, Float::INFINITY).unwrap();
+    assert!((ridge.point.y - 2.0).abs() < 1.0e-3, "Ridge at {}", ridge.point.y);
+    let ramp = scene.intersect(&straight_down(5.5, 0.0), 0.001, Float::INFINITY).unwrap();
+    assert!((ramp.point.y - 0.75).abs() < 1.0e-3, "Ramp at {}", ramp.point.y);
+}
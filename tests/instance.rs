@@ -0,0 +1,221 @@
+mod common;
+
+use common::build_affine_world;
+use nalgebra::{Affine3, Matrix3, Matrix4, Point3, Unit, UnitQuaternion, Vector3};
+use reflection::aabb::AABB;
+use reflection::intersection::Intersection;
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::ray::Ray;
+use reflection::scene::primitive::Primitive;
+use reflection::world::shape::ShapeRef;
+use reflection::world::World;
+use reflection::Float;
+
+/// The transform with `linear` as its linear part, followed by `translation`.
+fn affine(linear: Matrix3<Float>, translation: Vector3<Float>) -> Affine3<Float> {
+    Affine3::from_matrix_unchecked(Matrix4::new_translation(&translation) * linear.to_homogeneous())
+}
+
+/// A ray from a random direction around `center`, aimed somewhere within `spread` of it.
+fn random_ray(rng: &mut DefaultRandomness, center: Point3<Float>, spread: Float) -> Ray {
+    let origin = center + rng.unit_vector().into_inner() * 6.0;
+    let target = center + Vector3::new(rng.float() - 0.5, rng.float() - 0.5, rng.float() - 0.5) * 2.0 * spread;
+    Ray::new(origin, Unit::new_normalize(target - origin))
+}
+
+/// Where the ray first hits the ellipsoid `((p - center) / radii)² = 1`, and the normal there.
+fn ellipsoid_hit(ray: &Ray, center: Point3<Float>, radii: Vector3<Float>) -> Option<(Float, Vector3<Float>)> {
+    let o = (ray.origin - center).component_div(&radii);
+    let d = ray.direction.component_div(&radii);
+
+    let (a, b, c) = (d.dot(&d), 2.0 * o.dot(&d), o.dot(&o) - 1.0);
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let t = (-b - discriminant.sqrt()) / (2.0 * a);
+    // The gradient of the implicit function.
+    let normal = (o + d * t).component_div(&radii).normalize();
+    Some((t, normal))
+}
+
+/// Adds a shape to a world, and tells whether a point in object space is inside the shape, which it is around the given point.
+type SolidShape = (fn(&mut World) -> ShapeRef, fn(&Point3<Float>) -> bool, Point3<Float>);
+
+fn bounds(aabb: &AABB) -> (Point3<Float>, Point3<Float>) {
+    let half = aabb.diagonal() / 2.0;
+    (aabb.centroid() - half, aabb.centroid() + half)
+}
+
+
+#[test]
+fn non_uniformly_scaled_spheres_are_ellipsoids() {
+    let radii = Vector3::new(2.0, 0.5, 1.0);
+    let center = Point3::new(1.0, -1.0, 3.0);
+    let world = build_affine_world(|w| w.add_sphere(1.0), affine(Matrix3::from_diagonal(&radii), center.coords));
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+
+    let mut hits = 0;
+    for _ in 0..1000 {
+        let ray = random_ray(&mut rng, center, 1.0);
+        let found = scene.intersect(&ray, 0.001, Float::INFINITY);
+
+        let Some((t, normal)) = ellipsoid_hit(&ray, center, radii) else {
+            assert!(found.is_none(), "Hit at {:?} where the ellipsoid is missed", found.map(|i| i.point));
+            continue;
+        };
+        // Rays just touching the ellipsoid may go either way.
+        let Some(found) = found else {
+            let o = (ray.origin - center).component_div(&radii);
+            let d = ray.direction.component_div(&radii).normalize();
+            assert!((o - d * o.dot(&d)).magnitude() > 1.0 - 1.0e-3, "Missed the ellipsoid at {}", t);
+            continue;
+        };
+        hits += 1;
+
+        assert!((found.t - t).abs() < 1.0e-3, "Hit at {} instead of {}", found.t, t);
+        assert!((found.point - ray.point_at(t)).magnitude() < 1.0e-3, "Hit {:?} instead of {:?}", found.point, ray.point_at(t));
+        assert!((found.normal.into_inner() - normal).magnitude() < 1.0e-3, "Normal {:?} instead of {:?}", found.normal, normal);
+        assert!(found.outside);
+    }
+    assert!(hits > 300, "Only {} rays hit", hits);
+}
+
+#[test]
+fn normals_stay_perpendicular_under_shear() {
+    let shear = Matrix3::new(
+        1.0, 0.8, 0.0,
+        0.0, 1.0, 0.0,
+        0.3, -0.5, 1.0,
+    );
+    let center = Point3::new(0.0, 2.0, -1.0);
+    let world = build_affine_world(|w| w.add_sphere(1.0), affine(shear, center.coords));
+    let mut rng = DefaultRandomness::new(5);
+    let scene = world.build_scene(&mut rng);
+    let inverse = shear.try_inverse().unwrap();
+
+    let hit = |ray: &Ray| -> Option<Intersection> { scene.intersect(ray, 0.001, Float::INFINITY) };
+
+    let mut hits = 0;
+    for _ in 0..1000 {
+        let ray = random_ray(&mut rng, center, 0.3);
+        let found = hit(&ray).expect("Missed the sheared sphere");
+
+        // The inverse transpose of the shear, applied to the normal of the unit sphere.
+        let local = inverse * (found.point - center);
+        let expected = (inverse.transpose() * local).normalize();
+        assert!((found.normal.into_inner() - expected).magnitude() < 1.0e-3, "Normal {:?} instead of {:?}", found.normal, expected);
+        assert!(found.normal.dot(&ray.direction) < 0.0);
+
+        // Chords through nearby hits on either side lie in the tangent plane.
+        let frame = [ray.direction.cross(&Vector3::x()).normalize(), ray.direction.cross(&Vector3::z()).normalize()];
+        for offset in frame.map(|f| f * 1.0e-2) {
+            let (Some(a), Some(b)) = (hit(&Ray::new(ray.origin + offset, ray.direction)), hit(&Ray::new(ray.origin - offset, ray.direction))) else {
+                continue;
+            };
+            let chord = (a.point - b.point).normalize();
+            assert!(chord.dot(&found.normal).abs() < 1.0e-2, "Normal {:?} isn't perpendicular to {:?}", found.normal, chord);
+            hits += 1;
+        }
+    }
+    assert!(hits > 1000, "Only {} chords were checked", hits);
+}
+
+#[test]
+fn mirrored_objects_keep_their_outside() {
+    let mirrors = [
+        Matrix3::from_diagonal(&Vector3::new(-1.0, 1.0, 1.0)),
+        Matrix3::from_diagonal(&Vector3::new(-2.0, 1.0, 0.5)),
+        // A reflection through a tilted plane, followed by a rotation.
+        UnitQuaternion::from_euler_angles(0.3, 0.5, -0.2).to_rotation_matrix().into_inner()
+            * (Matrix3::identity() - Vector3::new(1.0, 1.0, 0.0).normalize() * Vector3::new(1.0, 1.0, 0.0).normalize().transpose() * 2.0),
+    ];
+    // A box away from the origin of its shape, so that mirroring moves it, and a sphere.
+    let shapes: [SolidShape; 2] = [
+        (
+            |w| w.add_box(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 2.0, 3.0)),
+            |p| (0..3).all(|a| p[a] >= 0.0 && p[a] <= [1.0, 2.0, 3.0][a]),
+            Point3::new(0.5, 1.0, 1.5),
+        ),
+        (|w| w.add_sphere(1.0), |p| p.coords.magnitude() <= 1.0, Point3::origin()),
+    ];
+
+    for mirror in mirrors {
+        assert!(mirror.determinant() < 0.0);
+        let transform = affine(mirror, Vector3::new(3.0, -1.0, 2.0));
+        let inverse = transform.inverse();
+
+        for (add_shape, contains, local_center) in shapes {
+            let world = build_affine_world(add_shape, transform);
+            let mut rng = DefaultRandomness::new(7);
+            let scene = world.build_scene(&mut rng);
+            let center = transform.transform_point(&local_center);
+
+            // Whether the point a little way out from the hit along the outward normal is outside the shape.
+            let outward_is_outside = |i: &Intersection| {
+                let outward = if i.outside { i.normal.into_inner() } else { -i.normal.into_inner() };
+                !contains(&inverse.transform_point(&(i.point + outward * 1.0e-2)))
+                    && contains(&inverse.transform_point(&(i.point - outward * 1.0e-2)))
+            };
+
+            for _ in 0..200 {
+                let direction = rng.unit_vector();
+
+                let from_outside = Ray::new(center + direction.into_inner() * 10.0, -direction);
+                let hit = scene.intersect(&from_outside, 0.001, Float::INFINITY).expect("Missed the mirrored shape");
+                assert!(hit.outside, "Entered the mirrored shape from the inside");
+                assert!(hit.normal.dot(&from_outside.direction) < 0.0, "Normal {:?} faces away from the ray", hit.normal);
+                assert!(outward_is_outside(&hit), "Normal {:?} points into the shape", hit.normal);
+
+                let from_inside = Ray::new(center, direction);
+                let hit = scene.intersect(&from_inside, 0.001, Float::INFINITY).expect("Missed the mirrored shape from the inside");
+                assert!(!hit.outside, "Left the mirrored shape from the outside");
+                assert!(hit.normal.dot(&from_inside.direction) < 0.0, "Normal {:?} faces away from the ray", hit.normal);
+                assert!(outward_is_outside(&hit), "Normal {:?} points out of the shape", hit.normal);
+            }
+        }
+    }
+}
+
+#[test]
+fn transformed_bounds_are_tight() {
+    let rotation = |roll, pitch, yaw| UnitQuaternion::from_euler_angles(roll, pitch, yaw);
+    let primitives = [
+        Primitive::Sphere { origin: Point3::new(0.5, -0.2, 0.1), rotation: rotation(0.0, 0.0, 0.0), radius: 0.7 },
+        Primitive::Box { center: Point3::new(0.1, 0.2, 0.3), half_size: Vector3::new(0.5, 1.0, 0.2), rotation: rotation(0.4, -0.3, 1.1) },
+        Primitive::Quad { corner: Point3::new(-0.5, 0.0, -0.5), edge_u: Vector3::new(1.0, 0.0, 0.2), edge_v: Vector3::new(0.0, 0.0, 1.5) },
+        Primitive::Disk { center: Point3::new(0.0, 1.0, 0.0), normal: Unit::new_normalize(Vector3::new(0.2, 1.0, -0.4)), radius: 0.8 },
+        Primitive::Torus { center: Point3::origin(), rotation: rotation(0.7, 0.1, -0.5), major_radius: 1.0, minor_radius: 0.3 },
+        Primitive::Cylinder { center: Point3::origin(), rotation: rotation(0.2, 0.9, 0.0), radius: 0.4, half_height: 1.0, capped: true },
+        Primitive::Capsule { center: Point3::new(0.0, 0.0, 1.0), rotation: rotation(-0.6, 0.0, 0.3), radius: 0.3, half_height: 0.5 },
+    ];
+    let transforms = [
+        affine(Matrix3::from_diagonal(&Vector3::new(2.0, 0.5, 1.0)), Vector3::new(1.0, 2.0, 3.0)),
+        affine(Matrix3::new(1.0, 0.8, 0.0, 0.0, 1.0, 0.0, 0.3, -0.5, 1.0), Vector3::zeros()),
+        affine(Matrix3::from_diagonal(&Vector3::new(-1.0, 1.5, 1.0)) * rotation(0.3, 0.2, 0.1).to_rotation_matrix().into_inner(), Vector3::new(-4.0, 0.0, 1.0)),
+    ];
+    let mut rng = DefaultRandomness::new(9);
+
+    for (i, primitive) in primitives.iter().enumerate() {
+        for (j, transform) in transforms.iter().enumerate() {
+            let (min, max) = bounds(&primitive.transformed_aabb(transform));
+
+            let mut samples = AABB::empty();
+            for _ in 0..20_000 {
+                let p = transform.transform_point(&primitive.random_point_on_surface(&mut rng));
+                samples.grow(&p);
+            }
+            let (sample_min, sample_max) = bounds(&samples);
+
+            // Flat primitives are padded a little, and the extremes of curved ones are only sampled so closely.
+            let tolerance = 2.0e-2 * samples.diagonal().max();
+            for a in 0..3 {
+                assert!(min[a] <= sample_min[a] + 1.0e-5 && max[a] >= sample_max[a] - 1.0e-5, "Primitive {} with transform {} reaches out of its bounds along {}", i, j, a);
+                assert!(sample_min[a] - min[a] < tolerance && max[a] - sample_max[a] < tolerance,
+                        "Bounds {:?} to {:?} of primitive {} with transform {} are loose around {:?} to {:?}", min, max, i, j, sample_min, sample_max);
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use nalgebra::{Unit, UnitVector3, Vector3};
+use num_traits::FloatConst;
+use reflection::pdf::PDF;
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::world::microfacet::{conductor_brdf, ConductorIOR, GGX, MicrofacetPDF, ShadingFrame};
+use reflection::world::World;
+use reflection::Float;
+
+/// A direction at `theta` from the normal of the frame, around it by `phi`.
+fn direction(frame: &ShadingFrame, theta: Float, phi: Float) -> UnitVector3<Float> {
+    let local = Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+    Unit::new_normalize(frame.to_world(&local))
+}
+
+/// A random direction in the hemisphere around the normal of the frame.
+fn random_direction(frame: &ShadingFrame, rng: &mut DefaultRandomness) -> UnitVector3<Float> {
+    let v = rng.unit_vector().into_inner();
+    Unit::new_normalize(if frame.normal.dot(&v) < 0.0 { -v } else { v })
+}
+
+fn frame() -> ShadingFrame {
+    ShadingFrame::new(Unit::new_normalize(Vector3::new(0.3, 1.0, -0.2)))
+}
+
+
+#[test]
+fn sampled_directions_follow_the_pdf() {
+    let world = World::new();
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+    let frame = frame();
+
+    // Bins over the whole sphere, evenly spaced in the cosine to the normal and around it.
+    const COS_BINS: usize = 16;
+    const PHI_BINS: usize = 32;
+    const SAMPLES: usize = 200_000;
+    let bin_of = |l: &Vector3<Float>| {
+        let l = frame.to_local(l);
+        let i = (((l.z + 1.0) / 2.0 * COS_BINS as Float) as usize).min(COS_BINS - 1);
+        let phi = l.y.atan2(l.x).rem_euclid(2.0 * Float::PI());
+        let j = ((phi / (2.0 * Float::PI()) * PHI_BINS as Float) as usize).min(PHI_BINS - 1);
+        i * PHI_BINS + j
+    };
+
+    for (alpha_x, alpha_y, theta, phi) in [(0.3, 0.3, 0.0, 0.0), (0.5, 0.5, 1.0, 0.5), (0.25, 0.7, 0.6, 2.0), (0.8, 0.4, 1.3, -1.0)] {
+        let pdf = MicrofacetPDF::new(frame, GGX::new(alpha_x, alpha_y), direction(&frame, theta, phi));
+
+        let mut histogram = vec![0.0; COS_BINS * PHI_BINS];
+        for _ in 0..SAMPLES {
+            let l = pdf.generate(&mut rng, &scene);
+            histogram[bin_of(&l)] += 1.0 / SAMPLES as Float;
+        }
+
+        // Integrates the pdf over every bin with the midpoint rule on a finer grid.
+        const SUBDIVISIONS: usize = 8;
+        let (d_cos, d_phi) = (2.0 / (COS_BINS * SUBDIVISIONS) as Float, 2.0 * Float::PI() / (PHI_BINS * SUBDIVISIONS) as Float);
+        let mut expected = vec![0.0; COS_BINS * PHI_BINS];
+        for i in 0..COS_BINS * SUBDIVISIONS {
+            for j in 0..PHI_BINS * SUBDIVISIONS {
+                let cos_theta = -1.0 + (i as Float + 0.5) * d_cos;
+                let l = direction(&frame, cos_theta.acos(), (j as Float + 0.5) * d_phi);
+                expected[bin_of(&l)] += pdf.value(&l, &scene) * d_cos * d_phi;
+            }
+        }
+
+        let total: Float = expected.iter().sum();
+        assert!((total - 1.0).abs() < 2.0e-2, "Pdf integrates to {} for alpha {}, {}", total, alpha_x, alpha_y);
+        // Five standard deviations of the sampled fraction, plus some room for the integration.
+        for (bin, (h, e)) in histogram.iter().zip(&expected).enumerate() {
+            let tolerance = 5.0 * (e / SAMPLES as Float).sqrt() + 2.0e-2 * e + 1.0e-5;
+            assert!((h - e).abs() < tolerance, "Sampled {} instead of {} in bin {} for alpha {}, {} at {}", h, e, bin, alpha_x, alpha_y, theta);
+        }
+    }
+}
+
+#[test]
+fn brdf_is_reciprocal() {
+    let mut rng = DefaultRandomness::new(5);
+    let frame = frame();
+    let ior = ConductorIOR::from_edge_tint(Vector3::new(0.95, 0.64, 0.54), Vector3::new(1.0, 0.8, 0.6));
+
+    for (roughness_u, roughness_v) in [(0.1, 0.1), (0.4, 0.4), (0.2, 0.8), (1.0, 0.5)] {
+        let distribution = GGX::from_roughness(roughness_u, roughness_v);
+
+        for _ in 0..1000 {
+            let (l, v) = (random_direction(&frame, &mut rng), random_direction(&frame, &mut rng));
+
+            // Without the cosine term, which is for the incoming direction.
+            let forward = conductor_brdf(&distribution, &ior, &frame, &l, &v) / frame.normal.dot(&l);
+            let backward = conductor_brdf(&distribution, &ior, &frame, &v, &l) / frame.normal.dot(&v);
+            let scale = forward.max().max(1.0);
+            assert!((forward - backward).abs().max() < 1.0e-3 * scale, "{:?} one way and {:?} the other", forward, backward);
+        }
+    }
+}
+
+#[test]
+fn perfect_reflectors_never_gain_energy() {
+    // No absorption at any angle, so only the energy lost to masking is missing.
+    let ior = ConductorIOR {
+        eta: Vector3::zeros(),
+        k: Vector3::repeat(1.0),
+    };
+    assert_eq!(ior.fresnel(0.3), Vector3::repeat(1.0));
+
+    let world = World::new();
+    let mut rng = DefaultRandomness::new(7);
+    let scene = world.build_scene(&mut rng);
+    let frame = frame();
+    const SAMPLES: usize = 20_000;
+
+    for roughness in [0.01, 0.1, 0.25, 0.5, 0.75, 1.0] {
+        let distribution = GGX::from_roughness(roughness, roughness);
+
+        for theta in [0.0, 0.5, 1.0, 1.4, 1.55] {
+            let v = direction(&frame, theta, 0.7);
+            let pdf = MicrofacetPDF::new(frame, distribution, v);
+
+            let mut albedo = Vector3::zeros();
+            for _ in 0..SAMPLES {
+                let l = pdf.generate(&mut rng, &scene);
+                let p = pdf.value(&l, &scene);
+                if p > 0.0 {
+                    albedo += conductor_brdf(&distribution, &ior, &frame, &l, &v) / p / SAMPLES as Float;
+                }
+            }
+
+            assert!(albedo.max() <= 1.0 + 1.0e-3, "Reflected {:?} at roughness {} and {}", albedo, roughness, theta);
+            if roughness <= 0.1 && theta < 1.2 {
+                assert!(albedo.min() > 0.98, "Smooth metal only reflected {:?} at {}", albedo, theta);
+            }
+        }
+    }
+}
+
+#[test]
+fn edge_tint_gives_back_the_ior() {
+    // Roughly gold, copper, aluminium and chromium.
+    for (eta, k) in [(0.18, 3.42), (0.27, 2.78), (1.35, 7.47), (3.1, 3.3)] {
+        let r = ((eta - 1.0) * (eta - 1.0) + k * k) / ((eta + 1.0) * (eta + 1.0) + k * k);
+        let (n_min, n_max) = ((1.0 - r) / (1.0 + r), (1.0 + Float::sqrt(r)) / (1.0 - Float::sqrt(r)));
+        let g = (n_max - eta) / (n_max - n_min);
+
+        let ior = ConductorIOR::from_edge_tint(Vector3::repeat(r), Vector3::repeat(g));
+        assert!((ior.fresnel(1.0).x - r).abs() < 1.0e-4, "Reflectivity {} instead of {}", ior.fresnel(1.0).x, r);
+        assert!((ior.eta.x - eta).abs() < 1.0e-3 * eta.max(1.0), "eta {} instead of {}", ior.eta.x, eta);
+        assert!((ior.k.x - k).abs() < 1.0e-3 * k, "k {} instead of {}", ior.k.x, k);
+    }
+}
@@ -0,0 +1,322 @@
+use std::path::PathBuf;
+use image::{Rgb, RgbImage};
+use nalgebra::{Affine3, Point3, Unit, Vector3};
+use reflection::intersection::Intersection;
+use reflection::loader::obj::{load_obj, ObjError, ObjGroup};
+use reflection::randomness::DefaultRandomness;
+use reflection::ray::Ray;
+use reflection::scene::Scene;
+use reflection::world::material::{MaterialPDF, ScatteredRay};
+use reflection::world::World;
+use reflection::Float;
+
+/// A fresh directory for the files of one test.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("reflection-obj-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes the files into a fresh directory and loads `scene.obj` from it.
+fn load(name: &str, files: &[(&str, &str)]) -> (Result<Vec<ObjGroup>, ObjError>, World) {
+    let dir = test_dir(name);
+    for (file, source) in files {
+        std::fs::write(dir.join(file), source).unwrap();
+    }
+
+    let (groups, world) = load_dir(&dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+    (groups, world)
+}
+
+fn load_dir(dir: &std::path::Path) -> (Result<Vec<ObjGroup>, ObjError>, World) {
+    let mut world = World::new();
+    let groups = load_obj(dir.join("scene.obj"), &mut world, Affine3::identity());
+    (groups, world)
+}
+
+/// How far away a ray from `origin` along `direction` hits something.
+fn distance(scene: &Scene, origin: Point3<Float>, direction: Unit<Vector3<Float>>) -> Option<Float> {
+    scene.intersect(&Ray::new(origin, direction), 0.001, Float::INFINITY).map(|i| i.t)
+}
+
+/// What a ray straight down the z axis hits at `x` and `y`.
+fn hit_from_front(scene: &Scene, x: Float, y: Float) -> Option<Intersection> {
+    scene.intersect(&Ray::new(Point3::new(x, y, 5.0), -Vector3::z_axis()), 0.001, Float::INFINITY)
+}
+
+/// How the surface hit at `x` and `y` scatters a ray coming from the front.
+fn scatter(world: &World, scene: &Scene, x: Float, y: Float) -> ScatteredRay {
+    let int = hit_from_front(scene, x, y).unwrap_or_else(|| panic!("Missed the face at {:?}", (x, y)));
+    world.scatter_ray(int.material, -Vector3::z_axis(), &int, scene).expect("Absorbed the ray")
+}
+
+/// The color a diffuse surface hit at `x` and `y` reflects, and whether it reflects like a mirror.
+fn reflection(world: &World, scene: &Scene, x: Float, y: Float) -> (Vector3<Float>, bool) {
+    let scattered = scatter(world, scene, x, y);
+    (scattered.attenuation, scattered.is_specular)
+}
+
+fn parse_error(result: Result<Vec<ObjGroup>, ObjError>) -> (PathBuf, usize, String) {
+    match result {
+        Err(ObjError::Parse { path, line, message }) => (path, line, message),
+        other => panic!("Expected a parse error, got {:?}", other.map(|_| ())),
+    }
+}
+
+fn assert_color(color: Vector3<Float>, expected: Vector3<Float>) {
+    assert!((color - expected).amax() < 1.0e-4, "Color {:?} instead of {:?}", color, expected);
+}
+
+
+#[test]
+fn negative_indices_count_back_from_the_latest_vertex() {
+    // One triangle in the plane of x and y and, twice, one in the plane of y and z.
+    let (groups, world) = load("negative", &[("scene.obj", "\
+        v 0 0 0\n\
+        v 1 0 0\n\
+        v 0 1 0\n\
+        f -3 -2 -1\n\
+        v 0 0 1\n\
+        f -4 -2 -1\n\
+        f 1 3 4\n\
+    ")]);
+    groups.unwrap();
+    let scene = world.build_scene(&mut DefaultRandomness::new(3));
+
+    assert!(distance(&scene, Point3::new(0.2, 0.2, 5.0), -Vector3::z_axis()).is_some_and(|t| (t - 5.0).abs() < 1.0e-4));
+    assert!(distance(&scene, Point3::new(5.0, 0.2, 0.2), -Vector3::x_axis()).is_some_and(|t| (t - 5.0).abs() < 1.0e-4));
+    assert!(distance(&scene, Point3::new(0.2, 5.0, 0.2), -Vector3::y_axis()).is_none(), "Found a triangle in the plane of x and z");
+}
+
+#[test]
+fn attribute_indices_are_resolved_separately() {
+    let (groups, world) = load("attributes", &[("scene.obj", "\
+        v 0 0 0\n\
+        v 1 0 0\n\
+        v 0 1 0\n\
+        vt 0.5 0.5\n\
+        vt 1 0\n\
+        vn 0 0 1\n\
+        f 1/-1/1 2/1/-1 3/-2/1\n\
+    ")]);
+    groups.unwrap();
+    let scene = world.build_scene(&mut DefaultRandomness::new(3));
+
+    // Weights of 0.6, 0.2 and 0.2 for the corners, which have the texture coordinates (1, 0), (0.5, 0.5) and (0.5, 0.5).
+    let int = hit_from_front(&scene, 0.2, 0.2).unwrap();
+    assert!((int.normal.into_inner() - Vector3::z()).magnitude() < 1.0e-4, "Normal {:?}", int.normal);
+    assert!((int.tex_coord.x - 0.8).abs() < 1.0e-4 && (int.tex_coord.y - 0.2).abs() < 1.0e-4, "Texture coordinates {:?}", (int.tex_coord.x, int.tex_coord.y));
+}
+
+#[test]
+fn polygons_are_triangulated_as_fans() {
+    let (groups, world) = load("fan", &[("scene.obj", "\
+        v 0 0 0\n\
+        v 2 0 0\n\
+        v 3 1 0\n\
+        v 1 2 0\n\
+        v -1 1 0\n\
+        f 1 2 3 4 5\n\
+    ")]);
+    groups.unwrap();
+    let scene = world.build_scene(&mut DefaultRandomness::new(3));
+
+    // The whole polygon is covered, from the first corner out to all of the others, and nothing beyond it.
+    for (x, y) in [(1.0, 0.2), (2.5, 1.0), (1.0, 1.8), (-0.8, 1.0), (1.0, 1.0), (0.1, 0.5)] {
+        assert!(hit_from_front(&scene, x, y).is_some(), "Missed the polygon at {:?}", (x, y));
+    }
+    for (x, y) in [(2.5, 0.2), (2.5, 1.8), (-0.5, 0.2), (-0.5, 1.8)] {
+        assert!(hit_from_front(&scene, x, y).is_none(), "Hit outside of the polygon at {:?}", (x, y));
+    }
+}
+
+#[test]
+fn groups_get_one_object_per_material() {
+    // Every face is a triangle of its own along x, so that rays can tell them apart.
+    let (groups, world) = load("groups", &[
+        ("scene.obj", "\
+            mtllib scene.mtl\n\
+            v 0 0 0\nv 1 0 0\nv 0 1 0\n\
+            v 2 0 0\nv 3 0 0\nv 2 1 0\n\
+            v 4 0 0\nv 5 0 0\nv 4 1 0\n\
+            v 6 0 0\nv 7 0 0\nv 6 1 0\n\
+            v 8 0 0\nv 9 0 0\nv 8 1 0\n\
+            f 1 2 3\n\
+            o first\n\
+            usemtl red\n\
+            f 4 5 6\n\
+            usemtl blue\n\
+            f 7 8 9\n\
+            g second\n\
+            f 10 11 12\n\
+            g\n\
+            g empty\n\
+            o first\n\
+            usemtl red\n\
+            f 13 14 15\n\
+        "),
+        ("scene.mtl", "\
+            newmtl red\n\
+            Kd 1 0 0\n\
+            newmtl blue\n\
+            Kd 0 0 1\n\
+        "),
+    ]);
+    let groups = groups.unwrap();
+
+    // Faces before the first group go to "default", and the empty group is dropped.
+    let names: Vec<_> = groups.iter().map(|g| g.name.as_str()).collect();
+    assert_eq!(names, ["default", "first", "second"]);
+    // Going back to a group adds to its mesh for the material.
+    let counts: Vec<_> = groups.iter().map(|g| g.objects.len()).collect();
+    assert_eq!(counts, [1, 2, 1]);
+
+    // Materials are shared between the groups that use them.
+    let scene = world.build_scene(&mut DefaultRandomness::new(3));
+    let material = |face: usize| hit_from_front(&scene, face as Float * 2.0 + 0.2, 0.2).expect("Missed a face").material;
+    assert_eq!(material(1), material(4));
+    assert_eq!(material(2), material(3));
+    assert_ne!(material(1), material(2));
+    assert_ne!(material(0), material(1));
+    assert_color(reflection(&world, &scene, 2.2, 0.2).0, Vector3::new(1.0, 0.0, 0.0));
+    assert_color(reflection(&world, &scene, 6.2, 0.2).0, Vector3::new(0.0, 0.0, 1.0));
+}
+
+#[test]
+fn mtl_materials_become_world_materials() {
+    let dir = test_dir("mtl");
+    std::fs::create_dir_all(dir.join("textures")).unwrap();
+    RgbImage::from_fn(2, 1, |x, _| if x == 0 { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) })
+        .save(dir.join("textures").join("checker.png"))
+        .unwrap();
+    std::fs::write(dir.join("scene.obj"), "\
+        mtllib scene.mtl\n\
+        v 0 0 0\nv 1 0 0\nv 0 1 0\n\
+        v 2 0 0\nv 3 0 0\nv 2 1 0\n\
+        v 4 0 0\nv 5 0 0\nv 4 1 0\n\
+        v 6 0 0\nv 7 0 0\nv 6 1 0\n\
+        v 8 0 0\nv 9 0 0\nv 8 1 0\n\
+        vt 0 0\n\
+        usemtl plain\n\
+        f 1 2 3\n\
+        usemtl textured\n\
+        f 4/1 5/1 6/1\n\
+        usemtl lamp\n\
+        f 7 8 9\n\
+        usemtl mirror\n\
+        f 10 11 12\n\
+        usemtl glass\n\
+        f 13 14 15\n\
+    ").unwrap();
+    std::fs::write(dir.join("scene.mtl"), "\
+        # Comments and unknown properties are skipped.\n\
+        newmtl plain\n\
+        Ka 1 1 1\n\
+        Kd 0.25 0.5 0.75\n\
+        newmtl textured\n\
+        Kd 1 1 1\n\
+        map_Kd -bm 0.5 textures/checker.png\n\
+        newmtl lamp\n\
+        Kd 0.5 0.5 0.5\n\
+        Ke 4 3 2\n\
+        newmtl mirror\n\
+        illum 3\n\
+        newmtl glass\n\
+        Ni 1.5\n\
+        illum 7\n\
+    ").unwrap();
+
+    let (groups, world) = load_dir(&dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+    groups.unwrap();
+    let scene = world.build_scene(&mut DefaultRandomness::new(3));
+
+    assert_eq!(reflection(&world, &scene, 0.2, 0.2), (Vector3::new(0.25, 0.5, 0.75), false));
+    // Every corner is on the red pixel of the texture.
+    let (textured, _) = reflection(&world, &scene, 2.2, 0.2);
+    assert!(textured.x > 0.9 && textured.z < 0.1, "map_Kd gave {:?}", textured);
+
+    let lamp = hit_from_front(&scene, 4.2, 0.2).unwrap();
+    assert!(world.emits(lamp.material));
+    assert_color(world.emit(lamp.material, Vector3::z_axis(), &lamp), Vector3::new(4.0, 3.0, 2.0));
+
+    let (_, specular) = reflection(&world, &scene, 6.2, 0.2);
+    assert!(specular, "illum 3 isn't a mirror");
+
+    let glass = scatter(&world, &scene, 8.2, 0.2);
+    assert!(matches!(glass.pdf, MaterialPDF::Dielectric(_)), "illum 7 isn't glass");
+}
+
+#[test]
+fn faces_without_material_get_the_default() {
+    let (groups, world) = load("default-material", &[("scene.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n")]);
+    groups.unwrap();
+    let scene = world.build_scene(&mut DefaultRandomness::new(3));
+
+    assert_eq!(reflection(&world, &scene, 0.2, 0.2), (Vector3::repeat(0.8), false));
+}
+
+#[test]
+fn malformed_lines_are_reported_with_their_line() {
+    let vertices = "v 0 0 0\nv 1 0 0\nv 0 1 0\n";
+    let cases = [
+        ("v 1 2\n", 1, "Expected a number"),
+        ("v 1 x 2\n", 1, "Invalid number 'x'"),
+        ("vt\n", 1, "Expected a number"),
+        ("f 1 2 x\n", 4, "Invalid index 'x'"),
+        ("f 0 1 2\n", 4, "Invalid index '0'"),
+        ("f 1 2 4\n", 4, "Index 4 out of range, only 3 elements defined so far"),
+        ("f -4 1 2\n", 4, "Invalid index '-4'"),
+        ("f 1/2 2 3\n", 4, "Index 2 out of range, only 0 elements defined so far"),
+        ("f 1 2\n", 4, "Face needs at least 3 vertices"),
+        ("usemtl\n", 4, "Missing material name"),
+        ("\nusemtl missing\nf 1 2 3\n", 5, "Unknown material 'missing'"),
+    ];
+
+    for (k, (source, line, message)) in cases.into_iter().enumerate() {
+        // The broken line is the first or the one after the vertices, depending on whether it needs them.
+        let source = if line == 1 { source.to_owned() } else { format!("{}{}", vertices, source) };
+        let (result, _) = load(&format!("malformed-{}", k), &[("scene.obj", &source)]);
+        let (path, error_line, error_message) = parse_error(result);
+
+        assert!(path.ends_with("scene.obj"));
+        assert_eq!(error_line, line, "Wrong line for {:?}", source);
+        assert!(error_message.starts_with(message), "'{}' instead of '{}' for {:?}", error_message, message, source);
+    }
+}
+
+#[test]
+fn malformed_mtl_lines_are_reported_in_the_mtl() {
+    let obj = "mtllib scene.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n";
+    let cases = [
+        ("Kd 1 1 1\n", 1, "Material property before newmtl"),
+        ("newmtl\n", 1, "Missing material name"),
+        ("newmtl a\nKd 1 one 1\n", 2, "Invalid number 'one'"),
+        ("newmtl a\n\nillum glass\n", 3, "Invalid illumination model 'glass'"),
+    ];
+
+    for (k, (mtl, line, message)) in cases.into_iter().enumerate() {
+        let (result, _) = load(&format!("malformed-mtl-{}", k), &[("scene.obj", obj), ("scene.mtl", mtl)]);
+        let (path, error_line, error_message) = parse_error(result);
+
+        assert!(path.ends_with("scene.mtl"));
+        assert_eq!(error_line, line, "Wrong line for {:?}", mtl);
+        assert!(error_message.starts_with(message), "'{}' instead of '{}' for {:?}", error_message, message, mtl);
+    }
+}
+
+#[test]
+fn missing_files_are_io_errors() {
+    let (result, _) = load("missing-obj", &[]);
+    assert!(matches!(result, Err(ObjError::Io(path, _)) if path.ends_with("scene.obj")));
+
+    let (result, _) = load("missing-mtl", &[("scene.obj", "mtllib other.mtl\n")]);
+    assert!(matches!(result, Err(ObjError::Io(path, _)) if path.ends_with("other.mtl")));
+
+    let (result, _) = load("missing-texture", &[
+        ("scene.obj", "mtllib scene.mtl\nusemtl a\nv 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n"),
+        ("scene.mtl", "newmtl a\nmap_Kd missing.png\n"),
+    ]);
+    assert!(matches!(result, Err(ObjError::Texture(path, _)) if path.ends_with("missing.png")));
+}
@@ -0,0 +1,324 @@
+use nalgebra::{Affine3, Isometry3, Matrix4, Point3, Vector3};
+use reflection::camera::Camera;
+use reflection::integrator::path_integrator::PathTracingIntegrator;
+use reflection::randomness::DefaultRandomness;
+use reflection::texture::Texture2D;
+use num_traits::FloatConst;
+use reflection::world::sdf::Sdf;
+use reflection::world::shape::{CsgOperand, CsgOperation, ShapeRef};
+use reflection::world::World;
+use reflection::{render, Float, RenderDescriptor};
+
+const ALBEDO: Float = 0.5;
+const RADIANCE: Float = 4.0;
+
+/// Renders a single pixel looking straight down at the origin of a diffuse ground,
+/// which is lit by one spherical light and nothing else.
+fn render_lit_ground(light_center: Point3<Float>, light_radius: Float, depth: u32) -> Vector3<Float> {
+    render_lit_ground_scaled(light_center, light_radius, 1.0, depth)
+}
+/// Like [render_lit_ground], with a light that is scaled by its transform instead of its radius.
+fn render_lit_ground_scaled(light_center: Point3<Float>, light_radius: Float, scale: Float, depth: u32) -> Vector3<Float> {
+    let light_transform = Isometry3::translation(light_center.x, light_center.y, light_center.z).to_homogeneous() * Matrix4::new_scaling(scale);
+    render_lit_ground_with(|world| world.add_sphere(light_radius / scale), Affine3::from_matrix_unchecked(light_transform), depth)
+}
+/// Like [render_lit_ground], with a light of any shape.
+fn render_lit_ground_with<F: FnOnce(&mut World) -> ShapeRef>(add_light: F, light_transform: Affine3<Float>, depth: u32) -> Vector3<Float> {
+    let mut world = World::new();
+
+    // Large enough to be flat around the origin.
+    let ground = world.add_sphere(10_000.0);
+    let light = add_light(&mut world);
+
+    let grey = world.add_solid_albedo(Vector3::repeat(ALBEDO));
+    let white = world.add_solid_albedo(Vector3::repeat(1.0));
+    let diffuse = world.add_lambertian_material(grey);
+    let emitting = world.add_emitting_material(white, RADIANCE);
+
+    world.add_object(ground, diffuse, Isometry3::translation(0.0, -10_000.0, 0.0));
+    world.add_affine_object(light, emitting, light_transform);
+
+    let mut rng = DefaultRandomness::new(11);
+    let scene = world.build_scene(&mut rng);
+
+    let camera = Camera::new(
+        Point3::new(0.0, 1.0, 0.0),
+        Point3::origin(),
+        Vector3::z(),
+        (0.01 as Float).to_radians(),
+        1.0,
+    );
+    let integrator = PathTracingIntegrator::new(depth, Vector3::zeros(), &scene);
+
+    let image = render(RenderDescriptor {
+        width: 1,
+        height: 1,
+        samples: 20_000,
+        t_min: 0.001,
+        t_max: Float::INFINITY,
+        integrator,
+        rng: &mut rng,
+        scene,
+        camera,
+    });
+
+    image.into_pixels().next().unwrap()
+}
+
+/// The radiance reflected by a lambertian surface at the origin, lit by a sphere that is entirely above the horizon.
+/// The irradiance of such a sphere is pi * L * sin²(alpha) * cos(theta), where alpha is its angular radius.
+fn expected_radiance(light_center: Point3<Float>, light_radius: Float) -> Float {
+    let distance = light_center.coords.magnitude();
+    let sin2_alpha = (light_radius / distance).powi(2);
+    let cos_theta = light_center.y / distance;
+
+    ALBEDO * RADIANCE * sin2_alpha * cos_theta
+}
+
+/// The radiance reflected by a lambertian surface at the origin, lit by a rectangle of the given size centered above it
+/// at `height`, facing down. The view factor of each quarter of the rectangle, which has a corner above the origin,
+/// is known in closed form.
+fn expected_rectangle_radiance(width: Float, depth: Float, height: Float) -> Float {
+    let (a, b) = (width * 0.5 / height, depth * 0.5 / height);
+    let (sa, sb) = ((1.0 + a * a).sqrt(), (1.0 + b * b).sqrt());
+    let quarter = (a / sa * (b / sa).atan() + b / sb * (a / sb).atan()) / (2.0 * Float::PI());
+
+    ALBEDO * RADIANCE * 4.0 * quarter
+}
+
+fn assert_unbiased(light_center: Point3<Float>, light_radius: Float) {
+    // One bounce, so that only direct light is gathered.
+    let color = render_lit_ground(light_center, light_radius, 2);
+    assert_close(color, expected_radiance(light_center, light_radius));
+}
+fn assert_close(color: Vector3<Float>, expected: Float) {
+    for c in color.iter() {
+        let error = (c - expected).abs() / expected;
+        assert!(error < 0.02, "Expected {}, but got {} ({:.1}% off)", expected, c, error * 100.0);
+    }
+}
+
+
+#[test]
+fn large_close_light_is_unbiased() {
+    assert_unbiased(Point3::new(1.0, 1.5, 0.0), 0.8);
+}
+
+#[test]
+fn small_distant_light_is_unbiased() {
+    assert_unbiased(Point3::new(-6.0, 8.0, 3.0), 0.1);
+}
+
+#[test]
+fn scaled_light_is_unbiased() {
+    // Sampling happens in object space, where the light is a quarter of its size.
+    let (center, radius) = (Point3::new(1.0, 1.5, 0.0), 0.8);
+    let color = render_lit_ground_scaled(center, radius, 4.0, 2);
+
+    assert_close(color, expected_radiance(center, radius));
+}
+
+#[test]
+fn quad_light_is_unbiased() {
+    // Spanned in the xz-plane, and turned upside down by the transform so that it faces the ground.
+    let transform = nalgebra::convert(Isometry3::new(Vector3::new(0.0, 1.5, 0.0), Vector3::new(Float::PI(), 0.0, 0.0)));
+    let color = render_lit_ground_with(|world| world.add_quad(Point3::new(-1.0, 0.0, -0.5), Vector3::z(), Vector3::x() * 2.0), transform, 2);
+
+    assert_close(color, expected_rectangle_radiance(2.0, 1.0, 1.5));
+}
+
+#[test]
+fn disk_light_is_unbiased() {
+    let (radius, height) = (0.7, 1.2);
+    let transform = nalgebra::convert(Isometry3::translation(0.0, height, 0.0));
+    let color = render_lit_ground_with(|world| world.add_disk(Point3::origin(), Vector3::y(), radius), transform, 2);
+
+    assert_close(color, expected_disk_radiance(radius, height));
+}
+
+#[test]
+fn box_light_is_unbiased() {
+    // Only the bottom of the box can be seen from the origin, the other faces must not be sampled.
+    let transform = Affine3::from_matrix_unchecked(Matrix4::new_translation(&Vector3::new(0.0, 1.5, 0.0)) * Matrix4::new_nonuniform_scaling(&Vector3::new(2.0, 1.0, 1.0)));
+    let color = render_lit_ground_with(|world| world.add_box(Point3::new(-0.5, 0.0, -0.5), Point3::new(0.5, 0.8, 0.5)), transform, 2);
+
+    assert_close(color, expected_rectangle_radiance(2.0, 1.0, 1.5));
+}
+
+/// The radiance reflected by a lambertian surface at the origin, lit by a disk centered above it at `height`, facing down,
+/// whose irradiance is pi * L * r² / (h² + r²).
+fn expected_disk_radiance(radius: Float, height: Float) -> Float {
+    ALBEDO * RADIANCE * radius.powi(2) / (height.powi(2) + radius.powi(2))
+}
+
+#[test]
+fn cylinder_light_is_unbiased() {
+    // Only the bottom cap can be seen from the origin, but the whole surface is sampled.
+    let transform = nalgebra::convert(Isometry3::translation(0.0, 1.6, 0.0));
+    let color = render_lit_ground_with(|world| world.add_cylinder(0.7, 0.8, true), transform, 2);
+
+    assert_close(color, expected_disk_radiance(0.7, 1.2));
+}
+
+#[test]
+fn open_cylinder_light_is_unbiased() {
+    // The inside of the tube is seen through the bottom opening, except where the top opening is seen through it.
+    let transform = nalgebra::convert(Isometry3::translation(0.0, 1.7, 0.0));
+    let color = render_lit_ground_with(|world| world.add_cylinder(0.5, 1.0, false), transform, 2);
+
+    assert_close(color, expected_disk_radiance(0.5, 1.2) - expected_disk_radiance(0.5, 2.2));
+}
+
+#[test]
+fn cone_light_is_unbiased() {
+    // Pointing down, so that its outline seen from the origin is the top.
+    let transform = nalgebra::convert(Isometry3::translation(0.0, 1.5, 0.0));
+    let color = render_lit_ground_with(|world| world.add_cone(0.0, 0.6, 0.8, true), transform, 2);
+
+    assert_close(color, expected_disk_radiance(0.6, 1.9));
+}
+
+#[test]
+fn torus_light_is_unbiased() {
+    // Seen from its axis, a torus covers the directions within the angular radius of its tube around the direction
+    // towards the circle, in every plane through the axis. The irradiance is pi * L * (sin²(theta_1) - sin²(theta_0))
+    // between the polar angles they span.
+    let (major_radius, minor_radius, height): (Float, Float, Float) = (1.0, 0.3, 1.5);
+    let transform = nalgebra::convert(Isometry3::translation(0.0, height, 0.0));
+    let color = render_lit_ground_with(|world| world.add_torus(major_radius, minor_radius), transform, 2);
+
+    let towards_circle = major_radius.atan2(height);
+    let tube = (minor_radius / major_radius.hypot(height)).asin();
+    let (theta_0, theta_1) = (towards_circle - tube, towards_circle + tube);
+    assert_close(color, ALBEDO * RADIANCE * (theta_1.sin().powi(2) - theta_0.sin().powi(2)));
+}
+
+#[test]
+fn capsule_light_is_unbiased() {
+    // Standing upright, so that its outline seen from the origin is the one of the lower hemisphere.
+    let transform = nalgebra::convert(Isometry3::translation(0.0, 2.1, 0.0));
+    let color = render_lit_ground_with(|world| world.add_capsule(0.4, 1.0), transform, 2);
+
+    assert_close(color, expected_radiance(Point3::new(0.0, 1.6, 0.0), 0.4));
+}
+
+#[test]
+fn hollow_csg_light_is_unbiased() {
+    // The ball cut out of the inside is sampled as well, but can't be seen through the outer sphere.
+    let transform = nalgebra::convert(Isometry3::translation(0.0, 2.0, 0.0));
+    let color = render_lit_ground_with(|world| {
+        let (sphere, ball) = (world.add_sphere(0.8), world.add_sphere(0.4));
+        world.add_csg(CsgOperation::Difference, sphere, CsgOperand::new(ball, Isometry3::translation(0.2, -0.1, 0.0)))
+    }, transform, 2);
+
+    assert_close(color, expected_radiance(Point3::new(0.0, 2.0, 0.0), 0.8));
+}
+
+#[test]
+fn intersected_csg_light_is_unbiased() {
+    // Cut off above its center, which leaves the part that can be seen from the origin.
+    // Most of the box is sampled as well, but misses the result.
+    let transform = nalgebra::convert(Isometry3::translation(0.0, 2.0, 0.0));
+    let color = render_lit_ground_with(|world| {
+        let sphere = world.add_sphere(0.8);
+        let slab = world.add_box(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 0.0, 1.0));
+        world.add_csg(CsgOperation::Intersection, sphere, slab)
+    }, transform, 2);
+
+    assert_close(color, expected_radiance(Point3::new(0.0, 2.0, 0.0), 0.8));
+}
+
+#[test]
+fn sdf_light_is_unbiased() {
+    // Directions are sampled towards the bounds, which the surface only fills part of.
+    let transform = nalgebra::convert(Isometry3::translation(0.0, 2.0, 0.0));
+    let color = render_lit_ground_with(|world| {
+        world.add_sdf(Sdf::sphere(0.5), Point3::new(-0.7, -0.7, -0.7), Point3::new(0.7, 0.7, 0.7))
+    }, transform, 2);
+
+    assert_close(color, expected_radiance(Point3::new(0.0, 2.0, 0.0), 0.5));
+}
+
+#[test]
+fn heightfield_light_is_unbiased() {
+    // A flat map of 2 by 1 units, turned upside down by the transform so that it faces the ground.
+    let transform = nalgebra::convert(Isometry3::new(Vector3::new(0.0, 1.5, 0.0), Vector3::new(Float::PI(), 0.0, 0.0)));
+    let color = render_lit_ground_with(|world| world.add_heightfield(Texture2D::new_from(5, 3, 0.0), 0.5, 1.0), transform, 2);
+
+    assert_close(color, expected_rectangle_radiance(2.0, 1.0, 1.5));
+}
+
+#[test]
+fn light_seen_directly_is_counted_once() {
+    // Looking at the light itself must give its radiance, without next event estimation adding to it.
+    let mut world = World::new();
+    let light = world.add_sphere(1.0);
+    let white = world.add_solid_albedo(Vector3::repeat(1.0));
+    let emitting = world.add_emitting_material(white, RADIANCE);
+    world.add_object(light, emitting, Isometry3::identity());
+
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+    let camera = Camera::new(Point3::new(0.0, 0.0, 5.0), Point3::origin(), Vector3::y(), (1.0 as Float).to_radians(), 1.0);
+    let integrator = PathTracingIntegrator::new(4, Vector3::zeros(), &scene);
+
+    let image = render(RenderDescriptor {
+        width: 1,
+        height: 1,
+        samples: 16,
+        t_min: 0.001,
+        t_max: Float::INFINITY,
+        integrator,
+        rng: &mut rng,
+        scene,
+        camera,
+    });
+
+    let color = image.into_pixels().next().unwrap();
+    assert!((color - Vector3::repeat(RADIANCE)).amax() < 1e-4, "Got {}", color);
+}
+
+#[test]
+fn russian_roulette_is_unbiased_for_deep_paths() {
+    // A closed, very bright sphere with a small light in its center, where most light arrives after many bounces.
+    // Every point of the inside of a sphere sees the same fraction of the rest of it, so the radiance of the walls
+    // solves B = rho * (E + B * (1 - s)), with s the part of the hemisphere the light covers.
+    const ROOM_ALBEDO: Float = 0.8;
+    let (room_radius, light_radius) = (10.0, 0.5);
+
+    let mut world = World::new();
+    let room = world.add_sphere(room_radius);
+    let light = world.add_sphere(light_radius);
+    let walls = world.add_solid_albedo(Vector3::repeat(ROOM_ALBEDO));
+    let white = world.add_solid_albedo(Vector3::repeat(1.0));
+    let diffuse = world.add_lambertian_material(walls);
+    let emitting = world.add_emitting_material(white, RADIANCE);
+    world.add_object(room, diffuse, Isometry3::identity());
+    world.add_object(light, emitting, Isometry3::identity());
+
+    let mut rng = DefaultRandomness::new(5);
+    let scene = world.build_scene(&mut rng);
+    let camera = Camera::new(Point3::new(0.0, 0.0, 5.0), Point3::new(0.0, 0.0, 10.0), Vector3::y(), (0.01 as Float).to_radians(), 1.0);
+    let integrator = PathTracingIntegrator::new(10_000, Vector3::zeros(), &scene).with_min_depth(2);
+
+    let image = render(RenderDescriptor {
+        width: 1,
+        height: 1,
+        samples: 20_000,
+        t_min: 0.001,
+        t_max: Float::INFINITY,
+        integrator,
+        rng: &mut rng,
+        scene,
+        camera,
+    });
+    let color = image.into_pixels().next().unwrap();
+
+    let s = (light_radius / room_radius).powi(2);
+    let expected = ROOM_ALBEDO * RADIANCE * s / (1.0 - ROOM_ALBEDO * (1.0 - s));
+
+    for c in color.iter() {
+        let error = (c - expected).abs() / expected;
+        assert!(error < 0.03, "Expected {}, but got {} ({:.1}% off)", expected, c, error * 100.0);
+    }
+}
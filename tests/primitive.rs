@@ -0,0 +1,203 @@
+use nalgebra::{Affine3, Isometry3, Matrix4, Point3, Unit, UnitQuaternion, Vector3};
+use reflection::aabb::AABB;
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::ray::Ray;
+use reflection::scene::primitive::{Primitive, PrimitiveIntersection};
+use reflection::world::microfacet::ShadingFrame;
+use reflection::world::World;
+use reflection::Float;
+
+fn intersect(primitive: &Primitive, ray: &Ray) -> Option<PrimitiveIntersection> {
+    primitive.intersect(ray, 0.001, Float::INFINITY)
+}
+
+/// A ray from `distance` away along `towards`, aimed at `target`.
+fn ray_at(target: Point3<Float>, towards: Vector3<Float>, distance: Float) -> Ray {
+    let towards = towards.normalize();
+    Ray::new(target + towards * distance, Unit::new_unchecked(-towards))
+}
+
+fn assert_facing(hit: &PrimitiveIntersection, ray: &Ray, surface_normal: &Vector3<Float>) {
+    assert!(hit.normal.dot(&ray.direction) < 0.0, "Normal {:?} faces away from the ray", hit.normal);
+    assert!((hit.normal.into_inner() - surface_normal * surface_normal.dot(&hit.normal).signum()).magnitude() < 1.0e-4,
+            "Normal {:?} instead of {:?}", hit.normal, surface_normal);
+}
+
+fn assert_tex_coord(hit: &PrimitiveIntersection, u: Float, v: Float) {
+    assert!((hit.tex_coord.x - u).abs() < 1.0e-4 && (hit.tex_coord.y - v).abs() < 1.0e-4,
+            "Texture coordinate {:?} instead of {:?}", (hit.tex_coord.x, hit.tex_coord.y), (u, v));
+}
+
+fn bounds(aabb: &AABB) -> (Point3<Float>, Point3<Float>) {
+    let half = aabb.diagonal() / 2.0;
+    (aabb.centroid() - half, aabb.centroid() + half)
+}
+
+
+#[test]
+fn quads_are_hit_between_their_edges() {
+    let (corner, edge_u, edge_v) = (Point3::new(1.0, 0.5, -1.0), Vector3::new(2.0, 0.3, 0.5), Vector3::new(-0.4, 0.2, 1.5));
+    let quad = Primitive::Quad { corner, edge_u, edge_v };
+    let normal = edge_u.cross(&edge_v).normalize();
+
+    for towards in [normal + Vector3::new(0.3, 0.0, -0.2), -normal + Vector3::new(0.0, 0.4, 0.1)] {
+        for i in 0..=14 {
+            for j in 0..=14 {
+                let (u, v) = (i as Float / 10.0 - 0.2, j as Float / 10.0 - 0.2);
+                let target = corner + edge_u * u + edge_v * v;
+                let ray = ray_at(target, towards, 3.0);
+                let hit = intersect(&quad, &ray);
+
+                // The texture is laid out along the edges, with its origin at the corner.
+                let inside = |x: Float, margin: Float| x > margin && x < 1.0 - margin;
+                if inside(u, 1.0e-3) && inside(v, 1.0e-3) {
+                    let hit = hit.unwrap_or_else(|| panic!("Missed the quad at {:?}", (u, v)));
+                    assert!((hit.t - 3.0).abs() < 1.0e-4 && (hit.point - target).magnitude() < 1.0e-4, "Hit at {} instead of 3", hit.t);
+                    assert_eq!(hit.outside, towards.dot(&normal) > 0.0);
+                    assert_facing(&hit, &ray, &normal);
+                    assert_tex_coord(&hit, u, v);
+                }
+                else if !inside(u, -1.0e-3) || !inside(v, -1.0e-3) {
+                    assert!(hit.is_none(), "Hit the quad outside of its edges at {:?}", (u, v));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn disks_are_hit_within_their_radius() {
+    let (center, radius) = (Point3::new(-1.0, 2.0, 0.5), 0.8);
+    let normal = Unit::new_normalize(Vector3::new(0.3, 1.0, -0.6));
+    let disk = Primitive::Disk { center, normal, radius };
+    let frame = ShadingFrame::new(normal);
+
+    let mut rng = DefaultRandomness::new(3);
+    for _ in 0..2000 {
+        let (x, y) = ((rng.float() * 2.0 - 1.0) * 1.2 * radius, (rng.float() * 2.0 - 1.0) * 1.2 * radius);
+        let target = center + frame.tangent.into_inner() * x + frame.bitangent.into_inner() * y;
+        let towards = rng.unit_vector().into_inner();
+        if towards.dot(&normal).abs() < 0.1 {
+            continue;
+        }
+        let ray = ray_at(target, towards, 4.0);
+        let hit = intersect(&disk, &ray);
+
+        let distance = (x * x + y * y).sqrt();
+        if distance < radius * (1.0 - 1.0e-3) {
+            let hit = hit.unwrap_or_else(|| panic!("Missed the disk at {} from its center", distance));
+            assert!((hit.t - 4.0).abs() < 1.0e-4, "Hit at {} instead of 4", hit.t);
+            assert_eq!(hit.outside, towards.dot(&normal) > 0.0);
+            assert_facing(&hit, &ray, &normal);
+            // Like a picture in a frame, with the tangent across and the bitangent up.
+            assert_tex_coord(&hit, 0.5 + x / (2.0 * radius), 0.5 + y / (2.0 * radius));
+        }
+        else if distance > radius * (1.0 + 1.0e-3) {
+            assert!(hit.is_none(), "Hit the disk at {} from its center", distance);
+        }
+    }
+}
+
+#[test]
+fn boxes_map_every_face_onto_the_whole_texture() {
+    let (center, half_size) = (Point3::new(0.5, -1.0, 2.0), Vector3::new(0.5, 1.0, 1.5));
+    let rotation = UnitQuaternion::from_euler_angles(0.3, -0.7, 1.2);
+    let cube = Primitive::Box { center, half_size, rotation };
+
+    for axis in 0..3 {
+        for sign in [-1.0, 1.0] {
+            let outward = rotation * Vector3::ith(axis, sign);
+            let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+
+            for (u, v) in [(0.5, 0.5), (0.1, 0.8), (0.95, 0.05), (0.3, 0.3)] {
+                let mut local = Vector3::ith(axis, sign * half_size[axis]);
+                local[u_axis] = (u * 2.0 - 1.0) * half_size[u_axis];
+                local[v_axis] = (v * 2.0 - 1.0) * half_size[v_axis];
+                let target = center + rotation * local;
+
+                let ray = ray_at(target, outward + rotation * Vector3::new(0.1, -0.2, 0.15), 5.0);
+                let hit = intersect(&cube, &ray).unwrap_or_else(|| panic!("Missed face {} {} at {:?}", axis, sign, (u, v)));
+                assert!((hit.t - 5.0).abs() < 1.0e-4, "Hit face {} {} at {} instead of 5", axis, sign, hit.t);
+                assert!(hit.outside);
+                assert_facing(&hit, &ray, &outward);
+                assert_tex_coord(&hit, u, v);
+            }
+        }
+    }
+}
+
+#[test]
+fn boxes_are_hit_from_the_inside() {
+    let (center, half_size) = (Point3::new(0.5, -1.0, 2.0), Vector3::new(0.5, 1.0, 1.5));
+    let rotation = UnitQuaternion::from_euler_angles(0.3, -0.7, 1.2);
+    let cube = Primitive::Box { center, half_size, rotation };
+
+    let mut rng = DefaultRandomness::new(5);
+    for _ in 0..1000 {
+        let local_origin = Vector3::new(rng.float() * 2.0 - 1.0, rng.float() * 2.0 - 1.0, rng.float() * 2.0 - 1.0).component_mul(&half_size) * 0.9;
+        let direction = rng.unit_vector();
+        let ray = Ray::new(center + rotation * local_origin, direction);
+
+        // The closest face along the ray, in the space of the box.
+        let local_direction = rotation.inverse() * direction.into_inner();
+        let (t, axis) = (0..3)
+            .map(|a| ((half_size[a] * local_direction[a].signum() - local_origin[a]) / local_direction[a], a))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap();
+
+        let hit = intersect(&cube, &ray).expect("Missed the box from the inside");
+        assert!(!hit.outside, "Entered the box from the inside");
+        assert!((hit.t - t).abs() < 1.0e-4, "Left the box at {} instead of {}", hit.t, t);
+        assert_facing(&hit, &ray, &(rotation * Vector3::ith(axis, local_direction[axis].signum())));
+    }
+
+    // Through the scene as well, which mustn't skip the box because the ray starts in its bounds.
+    let mut world = World::new();
+    let room = world.add_box(Point3::new(-2.0, 0.0, -3.0), Point3::new(2.0, 2.5, 3.0));
+    let albedo = world.add_solid_albedo(Vector3::repeat(0.5));
+    let material = world.add_lambertian_material(albedo);
+    world.add_object(room, material, Isometry3::translation(1.0, 0.0, 0.0));
+    let scene = world.build_scene(&mut rng);
+
+    for (direction, t) in [(Vector3::x_axis(), 2.0), (-Vector3::x_axis(), 2.0), (Vector3::y_axis(), 1.5), (-Vector3::y_axis(), 1.0), (Vector3::z_axis(), 3.0)] {
+        let ray = Ray::new(Point3::new(1.0, 1.0, 0.0), direction);
+        let hit = scene.intersect(&ray, 0.001, Float::INFINITY).expect("Missed the room from the inside");
+        assert!(!hit.outside);
+        assert!((hit.t - t).abs() < 1.0e-4, "Hit the wall at {} instead of {}", hit.t, t);
+        assert!((hit.normal.into_inner() + direction.into_inner()).magnitude() < 1.0e-4, "Normal {:?} on the wall", hit.normal);
+    }
+}
+
+#[test]
+fn flat_shapes_have_padded_bounds() {
+    let scales = [1.0, 1.0e-3, 1.0e4];
+    for scale in scales {
+        // Axis aligned, so that their bounds would have no thickness at all, and away from the origin.
+        let offset = Vector3::new(3.0, 2.0, -1.0) * scale;
+        let flat = [
+            Primitive::Quad { corner: Point3::from(offset), edge_u: Vector3::x() * scale, edge_v: Vector3::z() * 2.0 * scale },
+            Primitive::Disk { center: Point3::from(offset), normal: Vector3::y_axis(), radius: scale },
+            Primitive::Disk { center: Point3::from(offset), normal: -Vector3::z_axis(), radius: scale },
+        ];
+        let stretch = Affine3::from_matrix_unchecked(Matrix4::new_nonuniform_scaling(&Vector3::new(2.0, 3.0, 0.5)));
+
+        for primitive in &flat {
+            for aabb in [primitive.aabb(), primitive.transformed_aabb(&stretch)] {
+                assert!(!aabb.is_flat(), "Bounds {:?} are flat at scale {}", bounds(&aabb), scale);
+
+                let (min, max) = bounds(&aabb);
+                let size = aabb.diagonal().max();
+                let thickness = aabb.diagonal().min();
+                // Thin compared to the shape, but not so thin that rounding could take it away.
+                assert!(thickness < size * 1.0e-2, "Bounds {:?} are padded by {} at scale {}", (min, max), thickness, scale);
+                let magnitude = min.coords.abs().max().max(max.coords.abs().max());
+                assert!(thickness > magnitude * Float::EPSILON * 16.0, "Bounds {:?} are only padded by {} at scale {}", (min, max), thickness, scale);
+            }
+
+            // Rays straight onto the shape and through its bounds find it.
+            let target = primitive.aabb().centroid();
+            let hit = intersect(primitive, &ray_at(target, Vector3::new(0.2, 1.0, -1.0), 2.0 * scale));
+            assert!(hit.is_some_and(|hit| (hit.point - target).magnitude() < 1.0e-4 * scale.max(1.0) * 4.0), "Missed the flat shape at scale {}", scale);
+        }
+    }
+}
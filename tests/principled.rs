@@ -0,0 +1,145 @@
+use nalgebra::{Isometry3, Point3, Unit, UnitVector3, Vector3};
+use num_traits::FloatConst;
+use reflection::intersection::Intersection;
+use reflection::pdf::PDF;
+use reflection::randomness::DefaultRandomness;
+use reflection::ray::Ray;
+use reflection::world::principled::{ColorParameter, Principled, PrincipledBSDF, PrincipledPDF, ScalarParameter};
+use reflection::world::World;
+use reflection::Float;
+
+/// A white principled material with the given parameters.
+fn principled(metallic: Float, roughness: Float, transmission: Float) -> Principled {
+    Principled {
+        base_color: ColorParameter::Constant(Vector3::repeat(1.0)),
+        metallic: ScalarParameter::Constant(metallic),
+        roughness: ScalarParameter::Constant(roughness),
+        transmission: ScalarParameter::Constant(transmission),
+        ..Principled::default()
+    }
+}
+
+/// A sphere with a radius of one around the origin, made of `material`.
+fn sphere(material: Principled) -> World {
+    let mut world = World::new();
+    let sphere = world.add_sphere(1.0);
+    let material = world.add_principled_material(material);
+    world.add_object(sphere, material, Isometry3::identity());
+
+    world
+}
+
+/// The direction at `theta` from the y axis, which is the normal at the top of the sphere.
+fn view(theta: Float) -> UnitVector3<Float> {
+    Unit::new_normalize(Vector3::new(theta.sin(), theta.cos(), 0.0))
+}
+
+/// Where a ray straight down hits the top of the sphere.
+fn top(world: &World, rng: &mut DefaultRandomness) -> Intersection {
+    let scene = world.build_scene(rng);
+    scene.intersect(&Ray::new(Point3::new(0.0, 5.0, 0.0), -Vector3::y_axis()), 0.001, Float::INFINITY).unwrap()
+}
+
+const MATERIALS: [(Float, Float, Float); 6] = [
+    (0.0, 0.5, 0.0),
+    (0.0, 0.2, 0.0),
+    (1.0, 0.3, 0.0),
+    (1.0, 0.8, 0.0),
+    (0.0, 0.3, 1.0),
+    (0.5, 0.4, 0.5),
+];
+
+
+#[test]
+fn sampled_directions_follow_the_lobe_mixture() {
+    const COS_BINS: usize = 16;
+    const PHI_BINS: usize = 16;
+    const SAMPLES: usize = 200_000;
+    // The cosine to the normal and the angle around it.
+    let bin_of = |l: &Vector3<Float>| {
+        let i = (((l.y + 1.0) / 2.0 * COS_BINS as Float) as usize).min(COS_BINS - 1);
+        let phi = l.z.atan2(l.x).rem_euclid(2.0 * Float::PI());
+        let j = ((phi / (2.0 * Float::PI()) * PHI_BINS as Float) as usize).min(PHI_BINS - 1);
+        i * PHI_BINS + j
+    };
+
+    for (metallic, roughness, transmission) in MATERIALS {
+        let mut rng = DefaultRandomness::new(3);
+        let material = principled(metallic, roughness, transmission);
+        let world = sphere(material.clone());
+        let int = top(&world, &mut rng);
+        let scene = world.build_scene(&mut rng);
+
+        let pdf = PrincipledPDF::new(PrincipledBSDF::new(&material, &int, &world), view(0.8));
+
+        let mut histogram = vec![0.0; COS_BINS * PHI_BINS];
+        for _ in 0..SAMPLES {
+            let l = pdf.generate(&mut rng, &scene);
+            histogram[bin_of(&l)] += 1.0 / SAMPLES as Float;
+        }
+
+        const SUBDIVISIONS: usize = 8;
+        let (d_cos, d_phi) = (2.0 / (COS_BINS * SUBDIVISIONS) as Float, 2.0 * Float::PI() / (PHI_BINS * SUBDIVISIONS) as Float);
+        let mut expected = vec![0.0; COS_BINS * PHI_BINS];
+        for i in 0..COS_BINS * SUBDIVISIONS {
+            for j in 0..PHI_BINS * SUBDIVISIONS {
+                let cos_theta = -1.0 + (i as Float + 0.5) * d_cos;
+                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                let phi = (j as Float + 0.5) * d_phi;
+                let l = Unit::new_normalize(Vector3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin()));
+                expected[bin_of(&l)] += pdf.value(&l, &scene) * d_cos * d_phi;
+            }
+        }
+
+        let total: Float = expected.iter().sum();
+        assert!((total - 1.0).abs() < 2.0e-2, "Pdf integrates to {} for {:?}", total, (metallic, roughness, transmission));
+        // Five standard deviations of the sampled fraction, plus some room for the integration.
+        for (bin, (h, e)) in histogram.iter().zip(&expected).enumerate() {
+            let tolerance = 5.0 * (e / SAMPLES as Float).sqrt() + 2.0e-2 * e + 1.0e-5;
+            assert!((h - e).abs() < tolerance, "Sampled {} instead of {} in bin {} for {:?}", h, e, bin, (metallic, roughness, transmission));
+        }
+    }
+}
+
+#[test]
+fn white_materials_never_gain_energy() {
+    const SAMPLES: usize = 50_000;
+
+    for (metallic, roughness, transmission) in [(0.0, 0.5, 0.0), (0.0, 0.1, 0.0), (1.0, 0.1, 0.0), (1.0, 0.6, 0.0), (0.0, 0.1, 1.0), (0.0, 0.6, 1.0)] {
+        let mut rng = DefaultRandomness::new(5);
+        let material = principled(metallic, roughness, transmission);
+        let world = sphere(material.clone());
+        let int = top(&world, &mut rng);
+        let scene = world.build_scene(&mut rng);
+
+        for theta in [0.0, 0.6, 1.2, 1.5] {
+            let v = view(theta);
+            let bsdf = PrincipledBSDF::new(&material, &int, &world);
+            let pdf = PrincipledPDF::new(PrincipledBSDF::new(&material, &int, &world), v);
+
+            let mut albedo = Vector3::zeros();
+            for _ in 0..SAMPLES {
+                let l = pdf.generate(&mut rng, &scene);
+                let p = pdf.value(&l, &scene);
+                if p > 0.0 {
+                    albedo += bsdf.eval(&l, &v) / p / SAMPLES as Float;
+                }
+            }
+
+            let parameters = (metallic, roughness, transmission, theta);
+            assert!(albedo.max() <= 1.0 + 1.0e-2, "Scattered {:?} for {:?}", albedo, parameters);
+
+            if transmission == 0.0 && theta < 1.4 {
+                assert!(albedo.min() > 0.5, "Only scattered {:?} for {:?}", albedo, parameters);
+            }
+            // Smooth glass seen from the front reflects a little and refracts the rest,
+            // whose radiance is spread out by ior² on its way out of the denser medium.
+            if transmission == 1.0 && roughness < 0.2 && theta == 0.0 {
+                let ior = material.ior;
+                let reflectance = ((ior - 1.0) / (ior + 1.0)).powi(2);
+                let expected = reflectance + (1.0 - reflectance) / (ior * ior);
+                assert!((albedo.x - expected).abs() < 2.0e-2, "Scattered {:?} instead of {} through glass", albedo, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use nalgebra::{Point3, Unit, UnitQuaternion, Vector3};
+use num_traits::FloatConst;
+use reflection::aabb::AABB;
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::ray::Ray;
+use reflection::scene::primitive::Primitive;
+use reflection::Float;
+
+const MAJOR_RADIUS: Float = 1.0;
+const MINOR_RADIUS: Float = 0.3;
+
+/// A torus around `center` with its axis tilted by `rotation`.
+fn torus(center: Point3<Float>, rotation: UnitQuaternion<Float>) -> Primitive {
+    Primitive::Torus { center, rotation, major_radius: MAJOR_RADIUS, minor_radius: MINOR_RADIUS }
+}
+
+/// The first distance at which a ray in the space of the torus crosses it, found by marching along the ray in double precision
+/// and bisecting the first step that crosses the surface, and the smallest distance to the surface on the way there or overall.
+fn reference_torus_hit(origin: Vector3<f64>, direction: Vector3<f64>) -> (Option<f64>, f64) {
+    let (big_r, r): (f64, f64) = (nalgebra::convert(MAJOR_RADIUS), nalgebra::convert(MINOR_RADIUS));
+    let distance = |t: f64| {
+        let p = origin + direction * t;
+        ((p.x.hypot(p.z) - big_r).powi(2) + p.y * p.y).sqrt() - r
+    };
+
+    // Only the part of the ray within the sphere around the torus is searched.
+    // Written without the difference of the two large squares, which rays from far away would lose to rounding.
+    let (a, b) = (direction.magnitude_squared(), origin.dot(&direction));
+    let discriminant = a * (big_r + r + 1.0e-3).powi(2) - origin.cross(&direction).magnitude_squared();
+    if discriminant < 0.0 {
+        return (None, f64::INFINITY);
+    }
+    let (enter, exit) = (((-b - discriminant.sqrt()) / a).max(0.0), (-b + discriminant.sqrt()) / a);
+
+    const STEP: f64 = 2.0e-4;
+    let mut closest = distance(enter);
+    let mut t = enter;
+    while t < exit {
+        let next = (t + STEP).min(exit);
+        let d = distance(next);
+        if d < 0.0 {
+            let (mut lo, mut hi) = (t, next);
+            for _ in 0..64 {
+                let mid = 0.5 * (lo + hi);
+                if distance(mid) < 0.0 { hi = mid } else { lo = mid }
+            }
+            return (Some(lo), d.min(closest));
+        }
+        closest = closest.min(d);
+        t = next;
+    }
+
+    (None, closest)
+}
+
+/// The point at `phi` around the axis, starting at -x and going towards +z, at `height` and `radius` from the axis.
+fn around(phi: Float, radius: Float, height: Float) -> Vector3<Float> {
+    Vector3::new(-phi.cos() * radius, height, phi.sin() * radius)
+}
+
+/// A point in the space of a primitive and the way out of the surface there, and the texture coordinates expected there.
+/// Around the poles, where every u meets, u isn't checked.
+type ExpectedTexCoord = (Vector3<Float>, Vector3<Float>, Option<Float>, Float);
+
+fn bounds(aabb: &AABB) -> (Point3<Float>, Point3<Float>) {
+    let half = aabb.diagonal() / 2.0;
+    (aabb.centroid() - half, aabb.centroid() + half)
+}
+
+
+#[test]
+fn rotated_bounds_are_tight() {
+    let mut rng = DefaultRandomness::new(3);
+
+    for i in 0..10 {
+        let rotation = UnitQuaternion::from_euler_angles(rng.float() * 6.0, rng.float() * 6.0, rng.float() * 6.0);
+        let center = Point3::new(rng.float(), rng.float(), rng.float()) * 4.0 - Vector3::repeat(2.0);
+        let primitives = [
+            Primitive::Cylinder { center, rotation, radius: 0.4, half_height: 1.0, capped: i % 2 == 0 },
+            Primitive::Cone { center, rotation, base_radius: 0.8, top_radius: 0.0, half_height: 0.6, capped: true },
+            Primitive::Cone { center, rotation, base_radius: 0.2, top_radius: 0.7, half_height: 0.4, capped: false },
+            torus(center, rotation),
+            Primitive::Capsule { center, rotation, radius: 0.3, half_height: 0.8 },
+        ];
+
+        for (j, primitive) in primitives.iter().enumerate() {
+            let (min, max) = bounds(&primitive.aabb());
+
+            let mut samples = AABB::empty();
+            for _ in 0..20_000 {
+                samples.grow(&primitive.random_point_on_surface(&mut rng));
+            }
+            let (sample_min, sample_max) = bounds(&samples);
+
+            // The extremes of the surface are only sampled so closely.
+            let tolerance = 2.0e-2 * samples.diagonal().max();
+            for a in 0..3 {
+                assert!(min[a] <= sample_min[a] + 1.0e-5 && max[a] >= sample_max[a] - 1.0e-5, "Primitive {} reaches out of its bounds along {} with {:?}", j, a, rotation);
+                assert!(sample_min[a] - min[a] < tolerance && max[a] - sample_max[a] < tolerance,
+                        "Bounds {:?} to {:?} of primitive {} are loose around {:?} to {:?} with {:?}", min, max, j, sample_min, sample_max, rotation);
+            }
+        }
+    }
+}
+
+#[test]
+fn torus_hits_match_the_reference() {
+    let center = Point3::new(0.5, -1.0, 2.0);
+    let rotation = UnitQuaternion::from_euler_angles(0.4, -0.3, 0.8);
+    let primitive = torus(center, rotation);
+    let mut rng = DefaultRandomness::new(5);
+
+    // Rays just grazing the tube from nearby, and rays from far away aimed anywhere at the torus.
+    let mut rays = Vec::new();
+    for _ in 0..400 {
+        let (phi, psi) = (rng.float() * 2.0 * Float::PI(), rng.float() * 2.0 * Float::PI());
+        let normal = around(phi, -psi.cos(), psi.sin());
+        let point = around(phi, MAJOR_RADIUS, 0.0) + normal * MINOR_RADIUS;
+        let tangent = normal.cross(&rng.unit_vector()).normalize();
+
+        for offset in [-1.0e-3, -1.0e-4, 1.0e-4, 1.0e-3] {
+            let target = center + rotation * (point + normal * offset);
+            rays.push(Ray::new(target - rotation * tangent * 3.0, Unit::new_unchecked(rotation * tangent)));
+        }
+    }
+    for distance in [1.0e2, 1.0e3, 1.0e4] {
+        for _ in 0..200 {
+            let target = center + Vector3::new(rng.float() - 0.5, rng.float() - 0.5, rng.float() - 0.5) * 2.0 * (MAJOR_RADIUS + MINOR_RADIUS);
+            let direction = rng.unit_vector();
+            rays.push(Ray::new(target - direction.into_inner() * distance, direction));
+        }
+    }
+
+    let mut hits = 0;
+    for ray in &rays {
+        let origin = rotation.cast::<f64>().inverse_transform_vector(&(ray.origin - center).cast::<f64>());
+        let direction = rotation.cast::<f64>().inverse_transform_vector(&ray.direction.into_inner().cast::<f64>());
+        let (expected, closest) = reference_torus_hit(origin, direction);
+        let found = primitive.intersect(ray, 0.001, Float::INFINITY);
+
+        // Rays that only just touch the surface could go either way.
+        let scale: f64 = nalgebra::convert(ray.origin.coords.magnitude().max(1.0));
+        let margin = 1.0e-5 + scale * 1.0e-7;
+        match (expected, found) {
+            (Some(t), Some(found)) => {
+                let found_t: f64 = nalgebra::convert(found.t);
+                // Rays from far away start where single precision is coarse.
+                assert!((found_t - t).abs() < 1.0e-4 + scale * 2.0e-6, "Hit at {} instead of {} from {:?}", found_t, t, ray.origin);
+                hits += 1;
+            }
+            (Some(t), None) => assert!(closest > -margin, "Missed the torus at {}, {} deep", t, -closest),
+            (None, Some(found)) => assert!(closest < margin, "Hit at {}, {} away from the torus", found.t, closest),
+            (None, None) => {}
+        }
+    }
+    assert!(hits > rays.len() / 3, "Only {} of {} rays hit", hits, rays.len());
+}
+
+#[test]
+fn texture_seams_are_at_minus_x() {
+    let center = Point3::new(1.0, 2.0, -0.5);
+    let rotation = UnitQuaternion::from_euler_angles(0.2, 0.9, -0.4);
+    let (radius, half_height) = (0.5, 0.75);
+    let profiles = [
+        Primitive::Cylinder { center, rotation, radius, half_height, capped: true },
+        Primitive::Cone { center, rotation, base_radius: radius, top_radius: 0.0, half_height, capped: true },
+        torus(center, rotation),
+        Primitive::Capsule { center, rotation, radius, half_height },
+    ];
+
+    for (i, primitive) in profiles.iter().enumerate() {
+        // Just after the seam, around the circle, and just before the seam.
+        for phi in [1.0e-3, 0.5, Float::FRAC_PI_2(), 2.0, Float::PI(), 4.5, 2.0 * Float::PI() - 1.0e-3] {
+            // Halfway up the side, or on the outer equator of the torus.
+            let (distance, normal) = match i {
+                1 => (radius * 0.5, Unit::new_normalize(around(phi, 1.0, radius / (2.0 * half_height)))),
+                2 => (MAJOR_RADIUS + MINOR_RADIUS, Unit::new_normalize(around(phi, 1.0, 0.0))),
+                _ => (radius, Unit::new_normalize(around(phi, 1.0, 0.0))),
+            };
+            let point = around(phi, distance, 0.0);
+
+            let ray = Ray::new(center + rotation * (point + normal.into_inner() * 0.05), Unit::new_unchecked(rotation * -normal.into_inner()));
+            let hit = primitive.intersect(&ray, 0.001, Float::INFINITY).unwrap_or_else(|| panic!("Missed profile {} at {}", i, phi));
+            let expected = phi / (2.0 * Float::PI());
+            assert!((hit.tex_coord.x - expected).abs() < 1.0e-4, "u is {} instead of {} on profile {}", hit.tex_coord.x, expected, i);
+        }
+    }
+}
+
+#[test]
+fn texture_coordinates_follow_the_profiles() {
+    let center = Point3::new(1.0, 2.0, -0.5);
+    let rotation = UnitQuaternion::from_euler_angles(0.2, 0.9, -0.4);
+    let (radius, half_height) = (0.5, 0.75);
+    let phi = 2.0;
+
+    let hits_on = |primitive: Primitive, points: &[ExpectedTexCoord]| {
+        for (point, normal, u, v) in points {
+            let normal = normal.normalize();
+            let ray = Ray::new(center + rotation * (point + normal * 0.05), Unit::new_unchecked(rotation * -normal));
+            let hit = primitive.intersect(&ray, 0.001, Float::INFINITY).unwrap_or_else(|| panic!("Missed {:?}", point));
+            let u_matches = u.iter().all(|u| (hit.tex_coord.x - u).abs() < 1.0e-4);
+            assert!(u_matches && (hit.tex_coord.y - v).abs() < 1.0e-4,
+                    "Texture coordinate {:?} instead of {:?} at {:?}", (hit.tex_coord.x, hit.tex_coord.y), (u, v), point);
+        }
+    };
+    let u = Some(phi / (2.0 * Float::PI()));
+    let side = around(phi, 1.0, 0.0);
+
+    // From the bottom of the side to its top, and like a picture in a frame on the caps.
+    hits_on(Primitive::Cylinder { center, rotation, radius, half_height, capped: true }, &[
+        (around(phi, radius, -half_height * 0.9), side, u, 0.05),
+        (around(phi, radius, half_height * 0.5), side, u, 0.75),
+        (Vector3::new(0.0, half_height, 0.0), Vector3::y(), Some(0.5), 0.5),
+        (Vector3::new(0.2, -half_height, -0.1), -Vector3::y(), Some(0.7), 0.4),
+    ]);
+
+    // Around the tube, starting and ending on the inside of the ring and going over the top.
+    let inside = around(phi, MAJOR_RADIUS - MINOR_RADIUS, 0.0);
+    hits_on(torus(center, rotation), &[
+        (inside + Vector3::y() * 1.0e-3, -side, u, 1.0e-3 / (2.0 * Float::PI() * MINOR_RADIUS)),
+        (around(phi, MAJOR_RADIUS, MINOR_RADIUS), Vector3::y(), u, 0.25),
+        (around(phi, MAJOR_RADIUS + MINOR_RADIUS, 0.0), side, u, 0.5),
+        (around(phi, MAJOR_RADIUS, -MINOR_RADIUS), -Vector3::y(), u, 0.75),
+        (inside - Vector3::y() * 1.0e-3, -side, u, 1.0 - 1.0e-3 / (2.0 * Float::PI() * MINOR_RADIUS)),
+    ]);
+
+    // By the length along the profile, from the bottom pole to the top pole.
+    let length = Float::PI() * radius + 2.0 * half_height;
+    hits_on(Primitive::Capsule { center, rotation, radius, half_height }, &[
+        (Vector3::new(0.0, -half_height - radius, 0.0), -Vector3::y(), None, 0.0),
+        (around(phi, radius, -half_height), side, u, Float::FRAC_PI_2() * radius / length),
+        (around(phi, radius, half_height), side, u, (Float::FRAC_PI_2() * radius + 2.0 * half_height) / length),
+        (Vector3::new(0.0, half_height + radius, 0.0), Vector3::y(), None, 1.0),
+    ]);
+}
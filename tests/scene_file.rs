@@ -0,0 +1,149 @@
+use nalgebra::{Affine3, Isometry3, Matrix4, Point3, Vector3};
+use reflection::camera::Camera;
+use reflection::loader::scene_file::{RotationDescription, SceneFile, SceneFileError, TransformDescription};
+use reflection::texture::{Texture2D, TextureCoord2D};
+use reflection::world::microfacet::ConductorIOR;
+use reflection::world::principled::{ColorParameter, Principled, ScalarParameter};
+use reflection::world::World;
+use reflection::{Float, RenderSettings};
+
+/// A world with one of everything a scene file can describe without referring to other files.
+fn build_world() -> World {
+    let mut world = World::new();
+
+    let sphere = world.add_sphere(0.75);
+    let mesh = world.add_triangle_mesh(
+        vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0), Point3::new(1.0, 1.0, 0.5)],
+        vec![[0, 1, 2], [2, 1, 3]],
+        Some(vec![Vector3::z(), Vector3::z(), Vector3::new(0.0, 0.6, 0.8), Vector3::new(0.6, 0.0, 0.8)]),
+        Some(vec![TextureCoord2D::new(0.0, 0.0), TextureCoord2D::new(1.0, 0.0), TextureCoord2D::new(0.0, 1.0), TextureCoord2D::new(1.0, 1.0)]),
+    );
+    let quad = world.add_quad(Point3::new(-1.0, 2.0, -1.0), Vector3::x() * 2.0, Vector3::z() * 2.0);
+
+    let grey = world.add_solid_albedo(Vector3::new(0.25, 0.5, 0.75));
+    let checker = world.add_texture_albedo(Texture2D::new_from_pixels(2, 2, vec![
+        Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0),
+        Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.5, 0.5, 0.5),
+    ]));
+
+    let matte = world.add_lambertian_material(grey);
+    let textured = world.add_lambertian_material(checker);
+    let lamp = world.add_emitting_material(grey, 4.0);
+    let glass = world.add_dielectric_material(1.5);
+    let metal = world.add_conductor_material(ConductorIOR::from_edge_tint(Vector3::new(0.9, 0.6, 0.3), Vector3::new(1.0, 0.8, 0.5)), 0.25, 0.5);
+    let principled = world.add_principled_material(Principled {
+        base_color: ColorParameter::Texture(checker),
+        metallic: ScalarParameter::Texture(checker, 2),
+        roughness: ScalarParameter::Constant(0.375),
+        ..Principled::default()
+    });
+
+    world.add_object(sphere, matte, Isometry3::new(Vector3::new(1.0, 2.0, 3.0), Vector3::new(0.25, -0.5, 0.125)));
+    world.add_object(sphere, glass, Isometry3::translation(-1.0, 0.0, 0.0));
+    world.add_affine_object(mesh, textured, Affine3::from_matrix_unchecked(Matrix4::new(
+        2.0, 0.5, 0.0, 1.0,
+        0.0, 1.0, 0.0, -2.0,
+        0.0, 0.0, -1.0, 0.5,
+        0.0, 0.0, 0.0, 1.0,
+    )));
+    world.add_object(mesh, principled, Isometry3::identity());
+    world.add_object(quad, lamp, Isometry3::identity());
+    world.add_object(sphere, metal, Isometry3::translation(0.0, -3.0, 0.0));
+
+    world
+}
+
+fn settings() -> RenderSettings {
+    RenderSettings {
+        width: 320,
+        height: 200,
+        samples: 64,
+        depth: 12,
+        min_depth: 3,
+        t_min: 0.002,
+        t_max: 500.0,
+    }
+}
+
+/// Builds a scene with one object of the given shape and material, rotated by `rotation`.
+fn build_scene(shape: &str, material: &str, rotation: &str) -> Result<World, SceneFileError> {
+    SceneFile::parse(&format!(r#"(
+        camera: (look_from: (0.0, 0.0, 5.0), look_at: (0.0, 0.0, 0.0), vfov: 40.0),
+        shapes: {{ "shape": {} }},
+        materials: {{ "material": {} }},
+        objects: [(shape: "shape", material: "material", transform: (rotation: {}))],
+    )"#, shape, material, rotation)).unwrap().build_world("")
+}
+
+/// Rotations are renormalized when they are built, which may change them in the last digit.
+fn assert_same_transform(a: &TransformDescription, b: &TransformDescription) {
+    assert_eq!((a.translation, a.scale, a.matrix), (b.translation, b.scale, b.matrix));
+    match (&a.rotation, &b.rotation) {
+        (RotationDescription::Quaternion(q), RotationDescription::Quaternion(r)) => {
+            let difference = q.iter().zip(r).map(|(q, r)| (q - r).abs()).fold(0.0, Float::max);
+            assert!(difference < 1.0e-6, "Rotation {:?} instead of {:?}", q, r);
+        }
+        (q, r) => assert_eq!(q, r),
+    }
+}
+
+#[test]
+fn worlds_survive_a_round_trip_through_ron() {
+    let world = build_world();
+    let camera = Camera::new(Point3::new(1.0, 2.0, 8.0), Point3::new(0.0, 0.5, 0.0), Vector3::y(), (35.0 as Float).to_radians(), 1.6);
+    let file = SceneFile::from_world(&world, &camera, settings());
+
+    let source = file.to_ron_string().unwrap();
+    let parsed = SceneFile::parse(&source).unwrap();
+    assert_eq!(parsed, file, "Changed by serializing:\n{}", source);
+
+    let rebuilt = parsed.build_world("").unwrap();
+    let rebuilt_camera = parsed.build_camera();
+    let round_trip = SceneFile::from_world(&rebuilt, &rebuilt_camera, parsed.settings);
+
+    assert_eq!(round_trip.settings, settings());
+    assert_eq!(round_trip.shapes, file.shapes);
+    assert_eq!(round_trip.albedos, file.albedos);
+    assert_eq!(round_trip.materials, file.materials);
+    assert_eq!(round_trip.objects.len(), file.objects.len());
+    for (object, expected) in round_trip.objects.iter().zip(&file.objects) {
+        assert_eq!((&object.shape, &object.material), (&expected.shape, &expected.material));
+        assert_same_transform(&object.transform, &expected.transform);
+    }
+    assert_eq!(round_trip.camera, file.camera);
+
+    let (parameters, expected) = (rebuilt_camera.parameters(), camera.parameters());
+    assert!((parameters.look_from - expected.look_from).magnitude() < 1.0e-5);
+    assert!((parameters.look_at - expected.look_at).magnitude() < 1.0e-5);
+    assert!((parameters.vfov_radians - expected.vfov_radians).abs() < 1.0e-5);
+    assert!((parameters.aspect_ratio - expected.aspect_ratio).abs() < 1.0e-5);
+}
+
+#[test]
+fn scene_files_reject_invalid_values() {
+    assert!(build_scene("Sphere(radius: 1.0)", "Dielectric(ior: 1.5)", "AxisAngle(axis: (0.0, 2.0, 0.0), degrees: 30.0)").is_ok());
+
+    for radius in ["0.0", "-1.0", "NaN"] {
+        let world = build_scene(&format!("Sphere(radius: {})", radius), "Mirror", "Identity");
+        assert!(matches!(world, Err(SceneFileError::InvalidShape { .. })), "Accepted a radius of {}", radius);
+    }
+    for ior in ["0.0", "-1.5"] {
+        let world = build_scene("Sphere(radius: 1.0)", &format!("Dielectric(ior: {})", ior), "Identity");
+        assert!(matches!(world, Err(SceneFileError::InvalidMaterial { .. })), "Accepted an index of refraction of {}", ior);
+    }
+    for rotation in ["AxisAngle(axis: (0.0, 0.0, 0.0), degrees: 30.0)", "Quaternion((0.0, 0.0, 0.0, 0.0))"] {
+        let world = build_scene("Sphere(radius: 1.0)", "Mirror", rotation);
+        assert!(matches!(world, Err(SceneFileError::InvalidRotation(_))), "Accepted {}", rotation);
+    }
+}
+
+#[test]
+fn scene_files_reject_roughness_outside_of_the_unit_interval() {
+    let conductor = |roughness: &str| format!("Conductor(ior: EdgeTint(reflectivity: (0.9, 0.6, 0.3), edge_tint: (1.0, 0.8, 0.5)), {})", roughness);
+    assert!(build_scene("Sphere(radius: 1.0)", &conductor("roughness: 0.0, roughness_v: Some(1.0)"), "Identity").is_ok());
+
+    for roughness in ["roughness: -0.1", "roughness: 1.5", "roughness: 0.5, roughness_v: Some(-0.5)", "roughness: NaN"] {
+        let world = build_scene("Sphere(radius: 1.0)", &conductor(roughness), "Identity");
+        assert!(matches!(world, Err(SceneFileError::InvalidMaterial { .. })), "Accepted {}", roughness);
+    }
+}
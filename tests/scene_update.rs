@@ -0,0 +1,119 @@
+use nalgebra::{Affine3, Isometry3, Point3, Vector3};
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::ray::Ray;
+use reflection::scene::Scene;
+use reflection::world::{ObjectRef, World};
+use reflection::Float;
+
+/// A row of spheres along x.
+fn build_world() -> (World, Vec<ObjectRef>) {
+    let mut world = World::new();
+    let sphere = world.add_sphere(0.4);
+    let albedo = world.add_solid_albedo(Vector3::repeat(0.5));
+    let material = world.add_lambertian_material(albedo);
+
+    let objects = (0..50)
+        .map(|i| world.add_object(sphere, material, Isometry3::translation(i as Float, 0.0, 0.0)))
+        .collect();
+
+    (world, objects)
+}
+
+fn translation(x: Float, y: Float, z: Float) -> Affine3<Float> {
+    nalgebra::convert(Isometry3::translation(x, y, z))
+}
+
+/// Asserts that both scenes give the same closest hits for rays from all around.
+fn assert_same_hits(a: &Scene, b: &Scene) {
+    let mut rng = DefaultRandomness::new(7);
+
+    for _ in 0..2000 {
+        let origin = Point3::new(rng.float() * 60.0 - 5.0, rng.float() * 20.0 - 10.0, rng.float() * 20.0 - 10.0);
+        let ray = Ray::new(origin, rng.unit_vector());
+
+        let hit_a = a.intersect(&ray, 0.001, Float::INFINITY).map(|i| i.t);
+        let hit_b = b.intersect(&ray, 0.001, Float::INFINITY).map(|i| i.t);
+        match (hit_a, hit_b) {
+            (Some(ta), Some(tb)) => assert!((ta - tb).abs() < 1e-3, "Hit at {} instead of {}", ta, tb),
+            (None, None) => {}
+            _ => panic!("Hit {:?} instead of {:?}", hit_a, hit_b),
+        }
+    }
+}
+
+#[test]
+fn moved_objects_are_hit_at_their_new_place() {
+    let (mut world, objects) = build_world();
+    let mut rng = DefaultRandomness::new(1);
+    let scene = world.build_scene(&mut rng).detach();
+
+    // Small moves, which only need refitting.
+    for (i, object) in objects.iter().enumerate() {
+        world.set_object_transform(*object, translation(i as Float + 0.3, 0.2 * (i % 3) as Float, 0.0));
+    }
+    let updated = world.update_scene(scene, &mut rng);
+    let rebuilt = world.build_scene(&mut rng);
+
+    assert_same_hits(&updated, &rebuilt);
+}
+
+#[test]
+fn far_moves_keep_the_bvh_efficient() {
+    let (mut world, objects) = build_world();
+    let mut rng = DefaultRandomness::new(2);
+    let scene = world.build_scene(&mut rng).detach();
+
+    // Shuffling the spheres around makes the old tree useless.
+    for (i, object) in objects.iter().enumerate() {
+        let x = ((i * 37) % 50) as Float;
+        world.set_object_transform(*object, translation(x, 10.0 * (i % 2) as Float, 0.0));
+    }
+    let updated = world.update_scene(scene, &mut rng);
+    let rebuilt = world.build_scene(&mut rng);
+
+    assert_same_hits(&updated, &rebuilt);
+    assert!(updated.sah_cost() <= rebuilt.sah_cost() * 1.5, "Cost {} against {} after rebuilding", updated.sah_cost(), rebuilt.sah_cost());
+}
+
+#[test]
+fn local_moves_keep_the_bvh_efficient() {
+    let (mut world, objects) = build_world();
+    let mut rng = DefaultRandomness::new(4);
+    let scene = world.build_scene(&mut rng).detach();
+
+    // Shuffling the first half leaves the bounds of the scene as they are, but scatters the leaves over it.
+    for (i, object) in objects.iter().enumerate().take(25) {
+        world.set_object_transform(*object, translation((i * 7 % 25) as Float, 0.0, 0.0));
+    }
+    let updated = world.update_scene(scene, &mut rng);
+    let rebuilt = world.build_scene(&mut rng);
+
+    assert_same_hits(&updated, &rebuilt);
+    assert!(updated.sah_cost() <= rebuilt.sah_cost() * 1.5, "Cost {} against {} after rebuilding", updated.sah_cost(), rebuilt.sah_cost());
+}
+
+#[test]
+fn objects_can_be_added_and_removed() {
+    let (mut world, objects) = build_world();
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng).detach();
+
+    for object in objects.iter().step_by(2) {
+        assert!(world.remove_object(*object));
+    }
+    let triangle = world.add_triangle_mesh(
+        vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0)],
+        vec![[0, 1, 2]],
+        None,
+        None,
+    );
+    let albedo = world.add_solid_albedo(Vector3::repeat(0.5));
+    let material = world.add_lambertian_material(albedo);
+    world.add_object(triangle, material, Isometry3::translation(10.0, 2.0, 0.0));
+
+    let updated = world.update_scene(scene, &mut rng);
+    let rebuilt = world.build_scene(&mut rng);
+
+    assert_same_hits(&updated, &rebuilt);
+    assert!(updated.intersect(&Ray::new(Point3::new(0.0, 5.0, 0.0), -Vector3::y_axis()), 0.001, Float::INFINITY).is_none());
+}
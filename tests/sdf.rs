@@ -0,0 +1,215 @@
+mod common;
+
+use common::build_world;
+use nalgebra::{Isometry3, Point3, Unit, Vector3};
+use reflection::loader::scene_file::{SceneFile, SceneFileError};
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::ray::Ray;
+use reflection::world::sdf::Sdf;
+use reflection::world::World;
+use reflection::Float;
+
+/// Random rays from around the origin towards the unit cube.
+fn random_rays(count: usize) -> impl Iterator<Item = Ray> {
+    let mut rng = DefaultRandomness::new(5);
+
+    (0..count).map(move |_| {
+        let origin = Point3::from(rng.unit_vector().into_inner() * 4.0);
+        let target = Point3::new(rng.float(), rng.float(), rng.float()) * 2.0 - Vector3::repeat(1.0);
+        Ray::new(origin, Unit::new_normalize(target - origin))
+    })
+}
+
+/// Asserts that every hit is on the surface of `sdf`, which is placed by `transform`, and faces the way its field grows.
+fn assert_hits_on_surface(sdf: &Sdf, world: &World, transform: Isometry3<Float>) {
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+    let mut hits = 0;
+
+    for ray in random_rays(2000) {
+        let Some(i) = scene.intersect(&ray, 0.001, Float::INFINITY) else { continue };
+        hits += 1;
+
+        let local = transform.inverse_transform_point(&i.point);
+        assert!(sdf.distance(&local).abs() < 1.0e-3, "Hit at {} is off the surface by {}", local, sdf.distance(&local));
+
+        let outward = if i.outside { i.normal } else { -i.normal };
+        let outward = transform.inverse_transform_vector(&outward);
+        let outer = sdf.distance(&(local + outward * 1.0e-2));
+        let inner = sdf.distance(&(local - outward * 1.0e-2));
+        assert!(outer > inner, "Normal at {} points inwards", local);
+    }
+
+    assert!(hits > 100, "Only hit {} times", hits);
+}
+
+
+#[test]
+fn spheres_are_hit_like_analytic_ones() {
+    let transform = Isometry3::translation(0.2, -0.1, 0.3);
+    let traced = build_world(|world| world.add_sdf(Sdf::sphere(0.8), Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0)), transform);
+    let analytic = build_world(|world| world.add_sphere(0.8), transform);
+
+    let mut rng = DefaultRandomness::new(3);
+    let (traced, analytic) = (traced.build_scene(&mut rng), analytic.build_scene(&mut rng));
+
+    for ray in random_rays(2000) {
+        let hit = traced.intersect(&ray, 0.001, Float::INFINITY);
+        let expected = analytic.intersect(&ray, 0.001, Float::INFINITY);
+
+        match (hit, expected) {
+            (Some(hit), Some(expected)) => {
+                // The surface is hit within a distance of it, which is farther along rays that only graze it.
+                let cosine = ray.direction.dot(&expected.normal).abs();
+                assert!((hit.t - expected.t).abs() * cosine < 1.0e-4, "Hit at {} instead of {}", hit.t, expected.t);
+                if cosine < 0.1 {
+                    continue;
+                }
+                assert!((hit.normal.into_inner() - expected.normal.into_inner()).magnitude() < 1.0e-3, "Normal {:?} instead of {:?}", hit.normal, expected.normal);
+                assert_eq!(hit.outside, expected.outside);
+            }
+            // Rays that only graze the sphere may fall on either side of it.
+            (Some(hit), None) => assert!((hit.point - Point3::new(0.2, -0.1, 0.3)).magnitude() > 0.799, "Hit at {} inside of the sphere", hit.point),
+            (None, Some(expected)) => assert!(ray.direction.dot(&expected.normal).abs() < 1.0e-2, "Missed at {}", expected.t),
+            (None, None) => {}
+        }
+    }
+}
+
+#[test]
+fn blended_shapes_are_hit_on_their_surface() {
+    let sdf = Sdf::sphere(0.5)
+        .smooth_union(Sdf::torus(0.7, 0.15), 0.2)
+        .smooth_subtraction(Sdf::cylinder(0.2, 2.0), 0.05);
+    let transform = Isometry3::rotation(Vector3::new(0.4, 0.0, 0.2));
+    let world = build_world(|world| world.add_sdf(sdf.clone(), Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0)), transform);
+
+    assert_hits_on_surface(&sdf, &world, transform);
+}
+
+#[test]
+fn bent_and_repeated_shapes_are_hit_on_their_surface() {
+    // Twisting makes the field grow faster than the distance, which steps have to make up for.
+    let twisted = Sdf::rounded_cuboid(Vector3::new(0.6, 1.0, 0.15), 0.05).twisted(2.0);
+    let world = build_world(|world| world.add_sdf(twisted.clone(), Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0)), Isometry3::identity());
+    assert_hits_on_surface(&twisted, &world, Isometry3::identity());
+
+    let repeated = Sdf::capsule(0.1, 0.3).rotated(Vector3::x(), 90.0).repeated(Vector3::new(0.5, 0.0, 0.5)).onion(0.02);
+    let world = build_world(|world| world.add_sdf(repeated.clone(), Point3::new(-1.0, -0.5, -1.0), Point3::new(1.0, 0.5, 1.0)), Isometry3::identity());
+    assert_hits_on_surface(&repeated, &world, Isometry3::identity());
+}
+
+#[test]
+fn surfaces_are_cut_off_at_the_bounds() {
+    // Only the middle of the endless repetition is within the bounds.
+    let sdf = Sdf::sphere(0.2).repeated(Vector3::new(0.5, 0.5, 0.5));
+    let world = build_world(|world| world.add_sdf(sdf, Point3::new(-0.25, -0.25, -0.25), Point3::new(0.25, 0.25, 0.25)), Isometry3::identity());
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+
+    let hit = scene.intersect(&Ray::new(Point3::new(-3.0, 0.0, 0.0), Vector3::x_axis()), 0.001, Float::INFINITY).unwrap();
+    assert!((hit.t - 2.8).abs() < 1.0e-3, "Hit at {}", hit.t);
+
+    let beside = Ray::new(Point3::new(-3.0, 0.5, 0.0), Vector3::x_axis());
+    assert!(scene.intersect(&beside, 0.001, Float::INFINITY).is_none());
+}
+
+#[test]
+fn rays_from_inside_find_the_way_out() {
+    let sdf = Sdf::cuboid(Vector3::new(0.5, 0.5, 0.5));
+    let world = build_world(|world| world.add_sdf(sdf, Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0)), Isometry3::identity());
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+
+    let hit = scene.intersect(&Ray::new(Point3::new(0.1, 0.0, 0.0), Vector3::x_axis()), 0.001, Float::INFINITY).unwrap();
+    assert!((hit.t - 0.4).abs() < 1.0e-3, "Hit at {}", hit.t);
+    assert!(!hit.outside);
+    assert!((hit.normal.into_inner() + Vector3::x()).magnitude() < 1.0e-3, "Normal {:?} doesn't face the ray", hit.normal);
+}
+
+#[test]
+fn scene_files_describe_sdfs() {
+    let file = SceneFile::parse(r#"(
+        camera: (look_from: (0.0, 0.0, 5.0), look_at: (0.0, 0.0, 0.0), vfov: 40.0),
+        shapes: {
+            "blob": Sdf(
+                sdf: SmoothUnion(
+                    a: Sphere(radius: 0.5),
+                    b: Translate(offset: (0.6, 0.0, 0.0), sdf: Sphere(radius: 0.3)),
+                    radius: 0.1,
+                ),
+                min: (-1.0, -1.0, -1.0),
+                max: (1.0, 1.0, 1.0),
+            ),
+        },
+        albedos: { "white": Solid((1.0, 1.0, 1.0)) },
+        materials: { "light": Emitting(albedo: "white", factor: 2.0) },
+        objects: [(shape: "blob", material: "light")],
+    )"#).unwrap();
+    let world = file.build_world("").unwrap();
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+
+    let hit = scene.intersect(&Ray::new(Point3::new(3.0, 0.0, 0.0), -Vector3::x_axis()), 0.001, Float::INFINITY).unwrap();
+    assert!((hit.t - 2.1).abs() < 1.0e-3, "Hit at {}", hit.t);
+}
+
+#[test]
+fn blends_without_a_radius_are_hard_operations() {
+    // The same sphere twice, so that both distances are equal everywhere.
+    let (a, b) = (Sdf::sphere(0.5), Sdf::sphere(0.5).translated(Vector3::new(0.3, 0.0, 0.0)));
+    let pairs = [
+        (a.clone().smooth_union(a.clone(), 0.0), a.clone().union(a.clone())),
+        (a.clone().smooth_intersection(a.clone(), 0.0), a.clone().intersection(a.clone())),
+        (a.clone().smooth_union(b.clone(), 0.0), a.clone().union(b.clone())),
+        (a.clone().smooth_subtraction(b.clone(), 0.0), a.clone().subtraction(b.clone())),
+        (a.clone().smooth_intersection(b.clone(), 0.0), a.clone().intersection(b.clone())),
+    ];
+
+    let mut rng = DefaultRandomness::new(7);
+    for (smooth, hard) in &pairs {
+        // Including the points where the surfaces cross.
+        let points = (0..1000)
+            .map(|_| Point3::new(rng.float(), rng.float(), rng.float()) * 2.0 - Vector3::repeat(1.0))
+            .chain([Point3::new(0.15, 0.0, 0.0), Point3::new(0.15, 0.4, 0.0), Point3::origin()]);
+
+        for p in points {
+            assert_eq!(smooth.distance(&p), hard.distance(&p), "Blend without a radius differs at {}", p);
+        }
+    }
+
+    let (smooth, hard) = &pairs[3];
+    let world = build_world(|world| world.add_sdf(smooth.clone(), Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0)), Isometry3::identity());
+    assert_hits_on_surface(hard, &world, Isometry3::identity());
+}
+
+#[test]
+#[should_panic(expected = "Blend radii must not be negative")]
+fn negative_blend_radii_are_rejected() {
+    let sdf = Sdf::sphere(0.5).translated(Vector3::new(0.2, 0.0, 0.0)).smooth_union(Sdf::sphere(0.3), -0.1);
+    World::new().add_sdf(sdf.scaled(2.0), Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+}
+
+#[test]
+#[should_panic(expected = "Scale factors must be positive")]
+fn scale_factors_below_zero_are_rejected() {
+    let sdf = Sdf::sphere(0.5).union(Sdf::cylinder(0.2, 1.0).scaled(-1.0));
+    World::new().add_sdf(sdf, Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+}
+
+#[test]
+fn scene_files_reject_invalid_sdfs() {
+    for sdf in ["Scale(factor: 0.0, sdf: Sphere(radius: 0.5))", "Round(radius: 0.1, sdf: SmoothIntersection(a: Sphere(radius: 0.5), b: Sphere(radius: 0.4), radius: -0.2))"] {
+        let file = SceneFile::parse(&format!(r#"(
+            camera: (look_from: (0.0, 0.0, 5.0), look_at: (0.0, 0.0, 0.0), vfov: 40.0),
+            shapes: {{
+                "blob": Sdf(sdf: {}, min: (-1.0, -1.0, -1.0), max: (1.0, 1.0, 1.0)),
+            }},
+        )"#, sdf)).unwrap();
+
+        assert!(matches!(file.build_world(""), Err(SceneFileError::InvalidShape { .. })), "Accepted {}", sdf);
+    }
+
+    assert_eq!(Sdf::sphere(1.0).scaled(0.0).validate(), Err("Scale factors must be positive"));
+    assert_eq!(Sdf::sphere(1.0).smooth_subtraction(Sdf::sphere(0.5), 0.0).validate(), Ok(()));
+}
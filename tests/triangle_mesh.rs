@@ -0,0 +1,175 @@
+mod common;
+
+use common::build_world;
+use nalgebra::{Isometry3, Point3, Unit, Vector3};
+use num_traits::FloatConst;
+use reflection::randomness::{DefaultRandomness, Randomness};
+use reflection::ray::Ray;
+use reflection::texture::TextureCoord2D;
+use reflection::world::World;
+use reflection::Float;
+
+/// An uneven fan of triangles around a center vertex in the xz-plane.
+fn fan() -> (Vec<Point3<Float>>, Vec<[usize; 3]>) {
+    let radii = [1.0, 0.7, 1.3, 0.9, 1.1, 0.6, 1.2];
+
+    let mut positions = vec![Point3::new(0.13, 0.0, -0.07)];
+    positions.extend(radii.iter().enumerate().map(|(k, radius)| {
+        let angle = k as Float / radii.len() as Float * 2.0 * Float::PI();
+        Point3::new(angle.cos() * radius, 0.0, angle.sin() * radius)
+    }));
+    let indices = (0..radii.len()).map(|k| [0, 1 + (k + 1) % radii.len(), 1 + k]).collect();
+
+    (positions, indices)
+}
+
+/// A closed octahedron with its vertices on the axes, one unit from the origin.
+fn octahedron() -> (Vec<Point3<Float>>, Vec<[usize; 3]>) {
+    let positions = vec![
+        Point3::new(1.0, 0.0, 0.0), Point3::new(-1.0, 0.0, 0.0),
+        Point3::new(0.0, 1.0, 0.0), Point3::new(0.0, -1.0, 0.0),
+        Point3::new(0.0, 0.0, 1.0), Point3::new(0.0, 0.0, -1.0),
+    ];
+    let indices = vec![[0, 2, 4], [4, 2, 1], [1, 2, 5], [5, 2, 0], [4, 3, 0], [1, 3, 4], [5, 3, 1], [0, 3, 5]];
+
+    (positions, indices)
+}
+
+
+#[test]
+fn rays_through_shared_edges_and_vertices_hit() {
+    // Tilted by the transform, so that the edges aren't aligned with any axis.
+    let (positions, indices) = fan();
+    let transform = Isometry3::new(Vector3::new(0.2, -0.3, 0.1), Vector3::new(0.3, 1.1, -0.2));
+    let world = build_world(|world| world.add_triangle_mesh(positions.clone(), indices, None, None), transform);
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+
+    // The center vertex, which all triangles share, and points along the edges between neighbouring triangles.
+    let mut targets = vec![positions[0]];
+    for ring_vertex in &positions[1..] {
+        for s in [0.1, 0.25, 0.5, 0.75, 0.9] {
+            targets.push(positions[0] + (ring_vertex - positions[0]) * s);
+        }
+    }
+
+    for target in targets {
+        let target = transform * target;
+        for _ in 0..200 {
+            let origin = target + rng.unit_vector().into_inner() * 3.0;
+            let ray = Ray::new(origin, Unit::new_normalize(target - origin));
+
+            let hit = scene.intersect(&ray, 0.001, Float::INFINITY);
+            let hit = hit.unwrap_or_else(|| panic!("Ray from {} slipped through at {}", origin, target));
+            // Rays that almost graze the fan move far along it from the slightest rounding of its transform.
+            let grazing = ray.direction.dot(&(transform * Vector3::y())).abs() < 0.01;
+            assert!(grazing || (hit.t - 3.0).abs() < 1.0e-3, "Hit at {} instead of 3", hit.t);
+        }
+    }
+}
+
+#[test]
+fn rays_out_of_closed_meshes_always_hit() {
+    // Every ray from the inside has to leave through some triangle, also where it passes exactly between them.
+    let (positions, indices) = octahedron();
+    let transform = Isometry3::new(Vector3::new(0.5, 0.2, -0.1), Vector3::new(-0.4, 0.7, 0.2));
+    let world = build_world(|world| world.add_triangle_mesh(positions.clone(), indices.clone(), None, None), transform);
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+
+    let mut directions: Vec<Vector3<Float>> = positions.iter().map(|p| p.coords).collect();
+    for [a, b, c] in &indices {
+        for s in [0.01, 0.3, 0.5, 0.7, 0.99] {
+            directions.push(positions[*a].coords * s + positions[*b].coords * (1.0 - s));
+            directions.push(positions[*b].coords * s + positions[*c].coords * (1.0 - s));
+        }
+    }
+    directions.extend((0..2000).map(|_| rng.unit_vector().into_inner()));
+
+    for direction in directions {
+        let ray = Ray::new(transform * Point3::origin(), Unit::new_normalize(transform * direction));
+        let hit = scene.intersect(&ray, 0.0, Float::INFINITY);
+        let hit = hit.unwrap_or_else(|| panic!("Ray along {} slipped through", direction));
+
+        // The faces are where the sum of the absolute coordinates is 1.
+        let expected = 1.0 / direction.normalize().abs().sum();
+        assert!((hit.t - expected).abs() < 1.0e-4, "Hit at {} instead of {}", hit.t, expected);
+        assert!(!hit.outside, "Left along {} through the outside of a face", direction);
+    }
+}
+
+#[test]
+fn normals_and_tex_coords_are_interpolated() {
+    let positions = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(0.0, 0.0, 1.0), Point3::new(1.0, 0.0, 0.0)];
+    let normals = vec![Vector3::new(-1.0, 1.0, 0.0), Vector3::new(0.0, 1.0, 1.0), Vector3::new(0.0, 2.0, 0.0)];
+    let tex_coords = vec![TextureCoord2D::new(0.0, 0.0), TextureCoord2D::new(0.0, 1.0), TextureCoord2D::new(0.5, 0.25)];
+    let world = build_world(|world| world.add_triangle_mesh(positions, vec![[0, 1, 2]], Some(normals.clone()), Some(tex_coords)), Isometry3::identity());
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+
+    // Weights of 0.2, 0.3 and 0.5 for the three vertices.
+    let point = Point3::new(0.5, 0.0, 0.3);
+    let expected_normal = normals.iter()
+        .zip([0.2, 0.3, 0.5])
+        .map(|(n, w)| n.normalize() * w)
+        .sum::<Vector3<Float>>()
+        .normalize();
+
+    let above = scene.intersect(&Ray::new(point + Vector3::y() * 2.0, -Vector3::y_axis()), 0.001, Float::INFINITY).unwrap();
+    assert!(above.outside);
+    assert!((above.normal.into_inner() - expected_normal).magnitude() < 1.0e-4, "Normal {:?} instead of {:?}", above.normal, expected_normal);
+    assert!((above.tex_coord.x - 0.25).abs() < 1.0e-4 && (above.tex_coord.y - 0.425).abs() < 1.0e-4, "Texture coordinates {:?}", above.tex_coord);
+
+    // From behind, the shading normal is flipped to face the ray as well.
+    let below = scene.intersect(&Ray::new(point - Vector3::y() * 2.0, Vector3::y_axis()), 0.001, Float::INFINITY).unwrap();
+    assert!(!below.outside);
+    assert!((below.normal.into_inner() + expected_normal).magnitude() < 1.0e-4, "Normal {:?} doesn't face the ray", below.normal);
+    assert_eq!(below.tex_coord, above.tex_coord);
+}
+
+#[test]
+fn zero_normals_fall_back_to_the_triangle() {
+    let positions = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(0.0, 0.0, 1.0), Point3::new(1.0, 0.0, 0.0)];
+    let normals = vec![Vector3::zeros(), Vector3::new(0.0, 1.0, 1.0), Vector3::zeros()];
+    let world = build_world(|world| world.add_triangle_mesh(positions, vec![[0, 1, 2]], Some(normals), None), Isometry3::identity());
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+
+    // Weights of 0.2, 0.3 and 0.5 again, with the triangle's own normal, up along y, in place of the zero ones.
+    let expected_normal = (Vector3::y() * 0.7 + Vector3::new(0.0, 1.0, 1.0).normalize() * 0.3).normalize();
+    let hit = scene.intersect(&Ray::new(Point3::new(0.5, 2.0, 0.3), -Vector3::y_axis()), 0.001, Float::INFINITY).unwrap();
+    assert!(hit.outside);
+    assert!((hit.normal.into_inner() - expected_normal).magnitude() < 1.0e-4, "Normal {:?} instead of {:?}", hit.normal, expected_normal);
+}
+
+#[test]
+fn flat_normals_face_along_the_winding() {
+    let positions = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0)];
+    let world = build_world(|world| world.add_triangle_mesh(positions, vec![[0, 1, 2]], None, None), Isometry3::identity());
+    let mut rng = DefaultRandomness::new(3);
+    let scene = world.build_scene(&mut rng);
+
+    let hit = scene.intersect(&Ray::new(Point3::new(0.25, 0.25, 1.0), -Vector3::z_axis()), 0.001, Float::INFINITY).unwrap();
+    assert!(hit.outside);
+    assert!((hit.normal.into_inner() - Vector3::z()).magnitude() < 1.0e-6);
+
+    let edge = Ray::new(Point3::new(0.5, 0.5, 1.0), -Vector3::z_axis());
+    assert!(scene.intersect(&edge, 0.001, Float::INFINITY).is_some(), "Missed the hypotenuse");
+    let beside = Ray::new(Point3::new(0.6, 0.6, 1.0), -Vector3::z_axis());
+    assert!(scene.intersect(&beside, 0.001, Float::INFINITY).is_none());
+}
+
+#[test]
+#[should_panic(expected = "Triangle index out of bounds")]
+fn meshes_reject_indices_out_of_bounds() {
+    let mut world = World::new();
+    world.add_triangle_mesh(vec![Point3::origin(), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0)], vec![[0, 1, 3]], None, None);
+}
+
+#[test]
+#[should_panic(expected = "one normal per vertex")]
+fn meshes_reject_missing_normals() {
+    let mut world = World::new();
+    let positions = vec![Point3::origin(), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0)];
+    world.add_triangle_mesh(positions, vec![[0, 1, 2]], Some(vec![Vector3::z(); 2]), None);
+}